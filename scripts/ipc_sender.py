@@ -0,0 +1,47 @@
+#!/usr/bin/env python3
+"""Writes an Arrow IPC stream for a waterman-bridge IPC source (requires pyarrow).
+
+Usage:
+    python3 scripts/ipc_sender.py | waterman-bridge --ipc-stdin python
+    python3 scripts/ipc_sender.py --socket /tmp/waterman.sock
+"""
+import argparse
+import math
+import socket
+import sys
+import time
+
+import pyarrow as pa
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__, formatter_class=argparse.RawDescriptionHelpFormatter)
+    parser.add_argument("--socket", help="Unix domain socket path, stdout if omitted")
+    parser.add_argument("--rows", type=int, default=50, help="rows per batch")
+    parser.add_argument("--rate", type=float, default=20.0, help="batches per second")
+    args = parser.parse_args()
+
+    if args.socket:
+        sock = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+        sock.connect(args.socket)
+        sink = sock.makefile("wb")
+    else:
+        sink = sys.stdout.buffer
+
+    schema = pa.schema([("timestamp", pa.uint64()), ("value", pa.float64())])
+    with pa.ipc.new_stream(sink, schema) as writer:
+        i = 0
+        while True:
+            now = int(time.time() * 1000)
+            batch = pa.record_batch([
+                pa.array([now + n for n in range(args.rows)], pa.uint64()),
+                pa.array([math.sin((i + n) * 0.05) for n in range(args.rows)], pa.float64()),
+            ], schema=schema)
+            writer.write_batch(batch)
+            sink.flush()
+            i += args.rows
+            time.sleep(1.0 / args.rate)
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,97 @@
+#!/usr/bin/env python3
+"""Feeds MAVLink v2 over UDP to a waterman-bridge MAVLink source.
+
+Usage:
+    python3 scripts/mavlink_sender.py --port 14550                  # synthetic ATTITUDE + GLOBAL_POSITION_INT
+    python3 scripts/mavlink_sender.py --port 14550 --tlog flight.tlog  # replay a recorded .tlog with original timing
+"""
+import argparse
+import math
+import socket
+import struct
+import time
+
+# message id -> (crc_extra, struct format of the wire-ordered payload)
+MESSAGES = {
+    0: (50, "<IBBBBB"),            # HEARTBEAT
+    30: (39, "<Iffffff"),          # ATTITUDE
+    33: (104, "<IiiiihhhH"),       # GLOBAL_POSITION_INT
+}
+
+
+def crc_accumulate(crc, byte):
+    tmp = (byte ^ (crc & 0xFF)) & 0xFF
+    tmp = (tmp ^ (tmp << 4)) & 0xFF
+    return ((crc >> 8) ^ (tmp << 8) ^ (tmp << 3) ^ (tmp >> 4)) & 0xFFFF
+
+
+def frame_v2(seq, msg_id, payload, sysid=1, compid=1):
+    header = struct.pack("<BBBBBB", len(payload), 0, 0, seq & 0xFF, sysid, compid) + struct.pack("<I", msg_id)[:3]
+    crc = 0xFFFF
+    for b in header + payload:
+        crc = crc_accumulate(crc, b)
+    crc = crc_accumulate(crc, MESSAGES[msg_id][0])
+    return b"\xfd" + header + payload + struct.pack("<H", crc)
+
+
+def synthetic(sock, target, rate):
+    seq = 0
+    start = time.time()
+    while True:
+        t = time.time() - start
+        boot_ms = int(t * 1000)
+        frames = [
+            (30, struct.pack(MESSAGES[30][1], boot_ms, 0.3 * math.sin(t), 0.2 * math.cos(t), t % (2 * math.pi), 0.1, 0.1, 0.05)),
+            (33, struct.pack(MESSAGES[33][1], boot_ms, 473977420 + int(1000 * math.sin(t / 10)), 85455940,
+                             500000 + int(1000 * t) % 10000, 20000, 100, -50, 0, int(t * 100) % 36000)),
+        ]
+        if seq % int(max(rate, 1)) == 0:
+            frames.append((0, struct.pack(MESSAGES[0][1], 0, 2, 3, 81, 4, 3)))
+        for msg_id, payload in frames:
+            sock.sendto(frame_v2(seq, msg_id, payload), target)
+            seq += 1
+        time.sleep(1.0 / rate)
+
+
+def replay_tlog(sock, target, path):
+    # A .tlog is a sequence of 8 byte big-endian microsecond timestamps, each followed by one raw frame
+    data = open(path, "rb").read()
+    offset = 0
+    first_log_us = None
+    start = time.time()
+    while offset + 9 < len(data):
+        (log_us,) = struct.unpack(">Q", data[offset:offset + 8])
+        stx, length = data[offset + 8], data[offset + 9]
+        if stx == 0xFD:
+            frame_len = 12 + length + (13 if data[offset + 10] & 0x01 else 0)
+        elif stx == 0xFE:
+            frame_len = 8 + length
+        else:
+            offset += 1
+            continue
+        first_log_us = first_log_us if first_log_us is not None else log_us
+        delay = (log_us - first_log_us) / 1e6 - (time.time() - start)
+        if delay > 0:
+            time.sleep(delay)
+        sock.sendto(data[offset + 8:offset + 8 + frame_len], target)
+        offset += 8 + frame_len
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__, formatter_class=argparse.RawDescriptionHelpFormatter)
+    parser.add_argument("--host", default="127.0.0.1")
+    parser.add_argument("--port", type=int, default=14550)
+    parser.add_argument("--rate", type=float, default=50.0, help="synthetic message rate in Hz")
+    parser.add_argument("--tlog", help="replay this .tlog instead of synthetic data")
+    args = parser.parse_args()
+
+    sock = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+    target = (args.host, args.port)
+    if args.tlog:
+        replay_tlog(sock, target, args.tlog)
+    else:
+        synthetic(sock, target, args.rate)
+
+
+if __name__ == "__main__":
+    main()
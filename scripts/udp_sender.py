@@ -0,0 +1,48 @@
+#!/usr/bin/env python3
+"""Stand-in for vehicle software: sends telemetry to a waterman-bridge UDP source,
+such as the one in config/sources.example.yaml.
+
+Usage:
+    python3 scripts/udp_sender.py --port 14600 --rate 200
+    python3 scripts/udp_sender.py --port 14601 --binary   # packed little-endian layout
+"""
+import argparse
+import json
+import math
+import socket
+import struct
+import time
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__, formatter_class=argparse.RawDescriptionHelpFormatter)
+    parser.add_argument("--host", default="127.0.0.1")
+    parser.add_argument("--port", type=int, default=14600)
+    parser.add_argument("--rate", type=float, default=100.0, help="samples per second")
+    parser.add_argument("--binary", action="store_true",
+                        help="send <Q d f ? (timestamp, altitude, airspeed, armed) instead of JSON lines")
+    args = parser.parse_args()
+
+    sock = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+    interval = 1.0 / args.rate
+    i = 0
+    print(f"Sending to {args.host}:{args.port} at {args.rate} Hz, Ctrl+C to stop")
+    while True:
+        t = time.time()
+        sample = {
+            "timestamp": int(t * 1000),
+            "altitude": 100.0 + 10.0 * math.sin(i * 0.01),
+            "airspeed": 15.0 + math.cos(i * 0.05),
+            "armed": (i // 500) % 2 == 0,
+        }
+        if args.binary:
+            packet = struct.pack("<Qdf?", sample["timestamp"], sample["altitude"], sample["airspeed"], sample["armed"])
+        else:
+            packet = (json.dumps(sample) + "\n").encode()
+        sock.sendto(packet, (args.host, args.port))
+        i += 1
+        time.sleep(max(0.0, interval - (time.time() - t)))
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,183 @@
+//! The bridge under test: one that is already running, or one spawned per run,
+//! and its CPU and memory use while a run is measured
+
+use crate::http;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::process::{Child, Command};
+use tokio::task::JoinHandle;
+
+/// How often the bridge's memory use is sampled during a run
+const RSS_SAMPLE_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BridgeConfig {
+    pub url: Option<String>,        // `http://host:port` of a running bridge; spawn one when omitted
+    pub pid: Option<u32>,           // of the running bridge, to measure its CPU and memory use
+    pub command: String,            // bridge binary to spawn
+    pub args: Vec<String>,          // extra arguments; `--address` and `--port` are set by the runner
+    pub port: u16,
+    pub working_dir: Option<String>,    // where spawned bridges run; a scratch directory in the output by default
+    pub startup_timeout_secs: f64,
+}
+
+impl Default for BridgeConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            pid: None,
+            command: "target/release/waterman-bridge".to_string(),
+            args: Vec::new(),
+            port: 3131,
+            working_dir: None,
+            startup_timeout_secs: 10.0,
+        }
+    }
+}
+
+/// A bridge ready to take requests
+pub struct Bridge {
+    pub http: String,       // `http://host:port`
+    pub ws: String,         // `ws://host:port`
+    pub pid: Option<u32>,
+    child: Option<Child>,
+}
+
+impl Bridge {
+    /// Connect to the configured bridge, or start a new one
+    pub async fn start(config: &BridgeConfig, scratch_dir: &Path, log_file: &Path) -> Result<Self, String> {
+        if let Some(url) = &config.url {
+            let http = url.trim_end_matches('/').to_string();
+            http::get_json(&http, "/status").await?;
+            return Ok(Self {
+                ws: http.replacen("http://", "ws://", 1),
+                http,
+                pid: config.pid,
+                child: None,
+            });
+        }
+
+        let working_dir = config.working_dir.as_ref().map(PathBuf::from).unwrap_or_else(|| scratch_dir.to_path_buf());
+        std::fs::create_dir_all(&working_dir).map_err(|e| format!("Failed to create {}: {}", working_dir.display(), e))?;
+        let log = File::create(log_file).map_err(|e| format!("Failed to create {}: {}", log_file.display(), e))?;
+        // Relative paths are relative to where the runner was started, not the bridge's working directory
+        let command = std::fs::canonicalize(&config.command).unwrap_or_else(|_| PathBuf::from(&config.command));
+
+        let mut child = Command::new(&command)
+            .args(&config.args)
+            .arg("--address=127.0.0.1")
+            .arg(format!("--port={}", config.port))
+            .current_dir(&working_dir)
+            .env("RUST_LOG", std::env::var("RUST_LOG").unwrap_or_else(|_| "info".to_string()))
+            .stdin(Stdio::null())
+            .stdout(log.try_clone().map_err(|e| e.to_string())?)
+            .stderr(log)
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| format!("Failed to start {}: {}", command.display(), e))?;
+
+        let http = format!("http://127.0.0.1:{}", config.port);
+        let deadline = Instant::now() + Duration::from_secs_f64(config.startup_timeout_secs);
+        loop {
+            if let Ok(Some(status)) = child.try_wait() {
+                return Err(format!("Bridge exited during startup ({}), see {}", status, log_file.display()));
+            }
+            if http::get_json(&http, "/status").await.is_ok() {
+                break;
+            }
+            if Instant::now() > deadline {
+                return Err(format!("Bridge did not start within {}s, see {}", config.startup_timeout_secs, log_file.display()));
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        info!("Started bridge {} on port {}", command.display(), config.port);
+
+        Ok(Self {
+            ws: format!("ws://127.0.0.1:{}", config.port),
+            http,
+            pid: child.id(),
+            child: Some(child),
+        })
+    }
+
+    /// Stop a spawned bridge; a running one is left alone
+    pub async fn stop(mut self) {
+        if let Some(child) = self.child.as_mut()
+            && let Err(e) = child.kill().await
+        {
+            warn!("Failed to stop the bridge: {}", e);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResourceUsage {
+    pub cpu_percent: f64,       // of one core, averaged over the run
+    pub rss_peak_mb: f64,
+    pub rss_mean_mb: f64,
+}
+
+/// CPU time (user + system) of a process, in seconds
+pub fn cpu_seconds(pid: u32) -> Option<f64> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name may contain spaces; the fields after it don't
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    let ticks: u64 = fields.get(11)?.parse::<u64>().ok()? + fields.get(12)?.parse::<u64>().ok()?;
+    // SAFETY: sysconf only reads a system constant
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    (ticks_per_second > 0).then(|| ticks as f64 / ticks_per_second as f64)
+}
+
+/// Resident set size of a process, in MiB
+pub fn rss_mb(pid: u32) -> Option<f64> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kib: f64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib / 1024.0)
+}
+
+/// Samples a process's CPU and memory use from `/proc` (Linux only)
+pub struct ResourceMonitor {
+    pid: u32,
+    started: Instant,
+    cpu_at_start: f64,
+    rss_samples: Arc<Mutex<Vec<f64>>>,
+    sampler: JoinHandle<()>,
+}
+
+impl ResourceMonitor {
+    pub fn start(pid: u32) -> Option<Self> {
+        let cpu_at_start = cpu_seconds(pid)?;
+        let rss_samples = Arc::new(Mutex::new(Vec::new()));
+        let samples = rss_samples.clone();
+        let sampler = tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(RSS_SAMPLE_INTERVAL);
+            loop {
+                ticker.tick().await;
+                let Some(rss) = rss_mb(pid) else { break };
+                samples.lock().unwrap().push(rss);
+            }
+        });
+        Some(Self { pid, started: Instant::now(), cpu_at_start, rss_samples, sampler })
+    }
+
+    pub fn finish(self) -> Option<ResourceUsage> {
+        self.sampler.abort();
+        let cpu = cpu_seconds(self.pid)? - self.cpu_at_start;
+        let samples = self.rss_samples.lock().unwrap();
+        if samples.is_empty() {
+            return None;
+        }
+        Some(ResourceUsage {
+            cpu_percent: cpu / self.started.elapsed().as_secs_f64() * 100.0,
+            rss_peak_mb: samples.iter().cloned().fold(0.0, f64::max),
+            rss_mean_mb: samples.iter().sum::<f64>() / samples.len() as f64,
+        })
+    }
+}
@@ -0,0 +1,338 @@
+//! One benchmark connection: receive from the bridge, decode every frame and measure
+
+use crate::stats::{Distribution, DistributionSummary, GapSummary, GapTracker};
+use arrow::ipc::reader::FileReader;
+use futures_util::{SinkExt, StreamExt};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::Message;
+
+/// What to connect to and how long to measure
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    pub url: String,
+    pub duration: Duration,
+    pub progress_interval: Option<Duration>,   // None = no progress lines
+    pub timestamp_column: String,
+    pub gap_factor: f64,
+    pub expected_interval: Option<i64>,     // spacing of the timestamp column, None = the smallest step seen
+    pub echo: bool,     // send the stamps of every batch back, for the bridge's latency histograms
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Report {
+    pub url: String,
+    pub duration_secs: f64,         // from connecting until the end of the run
+    pub end_reason: String,
+    pub encoding: String,           // `?encoding=` of the URL, `arrow` by default
+    pub messages: u64,              // data frames
+    pub text_messages: u64,         // control messages, not decoded
+    pub batches: u64,
+    pub rows: u64,
+    pub bytes: u64,
+    pub decode_errors: u64,
+    #[serde(default)]
+    pub dropped_batches: u64,       // the bridge skipped for this connection because it fell behind
+    pub messages_per_sec: f64,
+    pub rows_per_sec: f64,
+    pub bytes_per_sec: f64,
+    pub decode: DistributionSummary,
+    pub gaps: GapSummary,
+    pub latency: LatencyReport,
+}
+
+/// Latency seen by the client, from the stamps the bridge puts in each batch's schema metadata
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LatencyReport {
+    pub connection_id: Option<u64>,     // for the bridge's `/latency/{id}`
+    pub echoes: u64,
+    pub missing_sequences: u64,         // messages the bridge numbered but never delivered
+    pub one_way: DistributionSummary,   // bridge send until receive, assumes synchronized clocks
+    pub end_to_end: DistributionSummary,    // generation until receive, live batches only
+}
+
+/// Stamps the bridge adds to every batch it sends
+#[derive(Debug, Clone, Copy)]
+pub struct Stamps {
+    pub connection_id: Option<u64>,
+    pub sequence: u64,
+    pub sent_at_us: u64,
+    pub generated_at_us: Option<u64>,
+}
+
+impl Stamps {
+    fn from_metadata(metadata: &HashMap<String, String>) -> Option<Self> {
+        let number = |key: &str| metadata.get(key).and_then(|v| v.parse::<u64>().ok());
+        Some(Self {
+            connection_id: number("waterman.connection"),
+            sequence: number("waterman.sequence")?,
+            sent_at_us: number("waterman.sent_at_us")?,
+            generated_at_us: number("waterman.generated_at_us"),
+        })
+    }
+}
+
+pub fn unix_time_us() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or(0)
+}
+
+/// How the bridge serializes batches on this connection, picked by the URL's `encoding` parameter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireEncoding {
+    Arrow,
+    Json,
+    Msgpack,
+    Cbor,
+}
+
+impl WireEncoding {
+    pub fn from_url(url: &str) -> Self {
+        let query = url.split_once('?').map(|(_, query)| query).unwrap_or("");
+        let encoding = query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == "encoding")
+            .map(|(_, value)| value);
+        match encoding {
+            Some("json") => WireEncoding::Json,
+            Some("msgpack") => WireEncoding::Msgpack,
+            Some("cbor") => WireEncoding::Cbor,
+            _ => WireEncoding::Arrow,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            WireEncoding::Arrow => "arrow",
+            WireEncoding::Json => "json",
+            WireEncoding::Msgpack => "msgpack",
+            WireEncoding::Cbor => "cbor",
+        }
+    }
+}
+
+/// Counters shared by the whole run and each progress window
+#[derive(Debug, Default, Clone, Copy)]
+struct Totals {
+    messages: u64,
+    text_messages: u64,
+    batches: u64,
+    rows: u64,
+    bytes: u64,
+    decode_errors: u64,
+}
+
+/// Whether `text` is one of the bridge's control messages (`lagged`, `run_started`, query
+/// replies, ...) rather than data: those are objects with a string `type`, which JSON data in
+/// either layout never has
+pub fn is_control(text: &str) -> bool {
+    #[derive(Deserialize)]
+    struct Control {
+        #[serde(rename = "type")]
+        _kind: String,
+    }
+    text.starts_with('{') && serde_json::from_str::<Control>(text).is_ok()
+}
+
+/// Total batches dropped so far, if `text` is the bridge's `lagged` notice
+pub fn lagged_total(text: &str) -> Option<u64> {
+    let notice: serde_json::Value = serde_json::from_str(text).ok()?;
+    (notice["type"] == "lagged").then(|| notice["total"].as_u64()).flatten()
+}
+
+/// Whether `text` is the bridge's message that the benchmark run on the stream has ended
+pub fn is_run_end(text: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(text).is_ok_and(|message| message["type"] == "run_ended")
+}
+
+/// Rows in a decoded JSON, MessagePack or CBOR message, either layout
+fn count_rows(value: &serde_json::Value) -> u64 {
+    match value {
+        serde_json::Value::Array(rows) => rows.len() as u64,
+        serde_json::Value::Object(columns) => columns
+            .values()
+            .next()
+            .and_then(|column| column.as_array())
+            .map_or(0, |column| column.len() as u64),
+        _ => 0,
+    }
+}
+
+/// Decode one frame, returning the number of batches and rows and the bridge's stamps.
+/// Only Arrow frames carry stamps and a timestamp column to look for gaps in.
+pub fn decode(encoding: WireEncoding, payload: Vec<u8>, gaps: &mut GapTracker, decode_times: &mut Distribution) -> Result<(u64, u64, Option<Stamps>), String> {
+    let started = Instant::now();
+    let value = match encoding {
+        WireEncoding::Arrow => None,
+        WireEncoding::Json => Some(serde_json::from_slice::<serde_json::Value>(&payload).map_err(|e| e.to_string())?),
+        WireEncoding::Msgpack => Some(rmp_serde::from_slice::<serde_json::Value>(&payload).map_err(|e| e.to_string())?),
+        WireEncoding::Cbor => Some(ciborium::from_reader::<serde_json::Value, _>(payload.as_slice()).map_err(|e| e.to_string())?),
+    };
+    if let Some(value) = value {
+        decode_times.record(started.elapsed());
+        return Ok((1, count_rows(&value), None));
+    }
+
+    let reader = FileReader::try_new(Cursor::new(payload), None).map_err(|e| e.to_string())?;
+    let stamps = Stamps::from_metadata(reader.schema().metadata());
+    let batches = reader.collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+    decode_times.record(started.elapsed());
+
+    let rows = batches.iter().map(|batch| batch.num_rows() as u64).sum();
+    for batch in &batches {
+        gaps.observe(batch);
+    }
+    Ok((batches.len() as u64, rows, stamps))
+}
+
+pub async fn run(options: &BenchOptions) -> Result<Report, String> {
+    let (socket, _) = connect_async(options.url.as_str())
+        .await
+        .map_err(|e| format!("Failed to connect to {}: {}", options.url, e))?;
+    info!("Connected to {}", options.url);
+    let (mut outgoing, mut incoming) = socket.split();
+    let encoding = WireEncoding::from_url(&options.url);
+
+    let started = Instant::now();
+    let deadline = tokio::time::sleep(options.duration);
+    tokio::pin!(deadline);
+    let mut progress = options.progress_interval.map(|period| tokio::time::interval_at(tokio::time::Instant::now() + period, period));
+
+    let mut totals = Totals::default();
+    let mut window = (Instant::now(), totals);
+    let mut decode_times = Distribution::default();
+    let mut gaps = GapTracker::new(&options.timestamp_column, options.gap_factor).with_interval(options.expected_interval);
+    let mut one_way = Distribution::default();
+    let mut end_to_end = Distribution::default();
+    let mut connection_id = None;
+    let mut next_sequence = 0u64;
+    let mut missing_sequences = 0u64;
+    let mut echoes = 0u64;
+    let mut dropped_batches = 0u64;
+
+    let end_reason = loop {
+        tokio::select! {
+            _ = &mut deadline => break "duration elapsed".to_string(),
+            _ = async { progress.as_mut().unwrap().tick().await }, if progress.is_some() => {
+                let (since, before) = window;
+                let seconds = since.elapsed().as_secs_f64();
+                eprintln!(
+                    "[{:>6.1}s] {:>8.1} msg/s {:>10.1} rows/s {:>10.1} KiB/s",
+                    started.elapsed().as_secs_f64(),
+                    (totals.messages - before.messages) as f64 / seconds,
+                    (totals.rows - before.rows) as f64 / seconds,
+                    (totals.bytes - before.bytes) as f64 / seconds / 1024.0,
+                );
+                window = (Instant::now(), totals);
+            },
+            message = incoming.next() => {
+                // JSON arrives in text frames, every other encoding in binary ones
+                let payload = match message {
+                    Some(Ok(Message::Binary(payload))) => payload,
+                    Some(Ok(Message::Text(text))) if encoding == WireEncoding::Json && !is_control(&text) => text.into_bytes(),
+                    Some(Ok(Message::Text(text))) => {
+                        totals.text_messages += 1;
+                        totals.bytes += text.len() as u64;
+                        dropped_batches = lagged_total(&text).unwrap_or(dropped_batches);
+                        if is_run_end(&text) {
+                            break "run ended".to_string();
+                        }
+                        continue;
+                    },
+                    Some(Ok(Message::Close(_))) | None => break "closed by server".to_string(),
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => break format!("connection error: {}", e),
+                };
+
+                let received_at_us = unix_time_us();
+                totals.messages += 1;
+                totals.bytes += payload.len() as u64;
+                match decode(encoding, payload, &mut gaps, &mut decode_times) {
+                    Ok((batches, rows, stamps)) => {
+                        totals.batches += batches;
+                        totals.rows += rows;
+                        let Some(stamps) = stamps else { continue };
+
+                        connection_id = connection_id.or(stamps.connection_id);
+                        missing_sequences += stamps.sequence.saturating_sub(next_sequence);
+                        next_sequence = next_sequence.max(stamps.sequence + 1);
+                        one_way.record_us(received_at_us as f64 - stamps.sent_at_us as f64);
+                        if let Some(generated_at_us) = stamps.generated_at_us {
+                            end_to_end.record_us(received_at_us as f64 - generated_at_us as f64);
+                        }
+
+                        if options.echo {
+                            let echo = json!({
+                                "type": "echo",
+                                "sequence": stamps.sequence,
+                                "sent_at_us": stamps.sent_at_us,
+                                "generated_at_us": stamps.generated_at_us,
+                                "received_at_us": received_at_us,
+                            });
+                            if let Err(e) = outgoing.send(Message::Text(echo.to_string())).await {
+                                break format!("connection error: {}", e);
+                            }
+                            echoes += 1;
+                        }
+                    },
+                    Err(e) => {
+                        if totals.decode_errors == 0 {
+                            warn!("Failed to decode message {}: {}", totals.messages, e);
+                        }
+                        totals.decode_errors += 1;
+                    }
+                }
+            },
+        }
+    };
+
+    let seconds = started.elapsed().as_secs_f64();
+    Ok(Report {
+        url: options.url.clone(),
+        encoding: encoding.name().to_string(),
+        duration_secs: seconds,
+        end_reason,
+        messages: totals.messages,
+        text_messages: totals.text_messages,
+        batches: totals.batches,
+        rows: totals.rows,
+        bytes: totals.bytes,
+        decode_errors: totals.decode_errors,
+        dropped_batches,
+        messages_per_sec: totals.messages as f64 / seconds,
+        rows_per_sec: totals.rows as f64 / seconds,
+        bytes_per_sec: totals.bytes as f64 / seconds,
+        decode: decode_times.summary(),
+        gaps: gaps.summary(),
+        latency: LatencyReport {
+            connection_id,
+            echoes,
+            missing_sequences,
+            one_way: one_way.summary(),
+            end_to_end: end_to_end.summary(),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_messages_are_told_apart_from_json_data() {
+        assert!(is_control(r#"{"type":"lagged","skipped":3,"total":5}"#));
+        assert!(is_control(r#"{"duration_secs":10.0,"id":1,"topic":"synthetic","type":"run_started"}"#));
+        assert!(is_control(r#"{"id":2,"rows":10,"type":"query_complete"}"#));
+
+        // Row and column layouts, the latter even with a column called `type`
+        assert!(!is_control(r#"[{"timestamp":1,"type":"a"}]"#));
+        assert!(!is_control(r#"{"timestamp":[1,2],"channel_0":[0.5,0.25]}"#));
+        assert!(!is_control(r#"{"timestamp":[1],"type":["a"]}"#));
+        assert!(!is_control("not json"));
+    }
+}
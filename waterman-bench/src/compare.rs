@@ -0,0 +1,328 @@
+//! Compare two benchmark results and flag throughput and latency regressions
+
+use crate::client::Report;
+use crate::report;
+use crate::sweep::{RunResult, SweepReport};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Metrics where more is better
+const THROUGHPUT_METRICS: [&str; 3] = ["messages_per_sec", "rows_per_sec", "mib_per_sec"];
+
+/// Metrics where less is better, all in microseconds
+const LATENCY_METRICS: [&str; 8] = [
+    "decode_p50_us",
+    "decode_p99_us",
+    "one_way_p50_us",
+    "one_way_p99_us",
+    "end_to_end_p50_us",
+    "end_to_end_p99_us",
+    "round_trip_p50_us",
+    "round_trip_p99_us",
+];
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Thresholds {
+    pub throughput_percent: f64,    // a larger drop is a regression
+    pub latency_percent: f64,       // a larger rise is a regression...
+    pub min_latency_delta_us: f64,  // ...if it is also at least this many microseconds
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Regression,
+    Improvement,
+    Unchanged,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricChange {
+    pub scenario: String,
+    pub metric: String,
+    pub baseline: f64,
+    pub candidate: f64,
+    pub change_percent: f64,
+    pub status: Status,
+}
+
+/// A metric the baseline measured and the candidate did not, e.g. latency without echoes
+#[derive(Debug, Clone, Serialize)]
+pub struct MissingMetric {
+    pub scenario: String,
+    pub metric: String,
+    pub baseline: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Comparison {
+    pub baseline: String,
+    pub candidate: String,
+    pub thresholds: Thresholds,
+    pub changes: Vec<MetricChange>,
+    pub missing: Vec<MissingMetric>,        // count as regressions, since they hide any
+    pub failed: Vec<String>,                // scenarios that failed in the candidate but not the baseline
+    pub only_in_baseline: Vec<String>,
+    pub only_in_candidate: Vec<String>,
+}
+
+impl Comparison {
+    pub fn regressions(&self) -> usize {
+        self.changes.iter().filter(|c| c.status == Status::Regression).count() + self.missing.len() + self.failed.len()
+    }
+
+    pub fn improvements(&self) -> usize {
+        self.changes.iter().filter(|c| c.status == Status::Improvement).count()
+    }
+}
+
+/// A sweep report, or a single run as written by `--json`
+pub fn load(path: &Path) -> Result<SweepReport, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    if let Ok(sweep) = serde_json::from_str::<SweepReport>(&text) {
+        return Ok(sweep);
+    }
+    let run: Report = serde_json::from_str(&text).map_err(|e| format!("{} is not a benchmark report: {}", path.display(), e))?;
+    Ok(SweepReport {
+        name: path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default(),
+        started_at: 0,
+        axes: Vec::new(),
+        runs: vec![RunResult { settings: Vec::new(), report: Some(run), round_trip: None, resources: None, error: None }],
+    })
+}
+
+/// Runs are matched up by their settings, e.g. `num_channels=100 encoding=arrow`
+fn scenario_name(run: &RunResult) -> String {
+    if run.settings.is_empty() {
+        return "(single run)".to_string();
+    }
+    run.settings.iter().map(|(name, value)| format!("{}={}", name, value)).collect::<Vec<_>>().join(" ")
+}
+
+fn is_compared(metric: &str) -> bool {
+    THROUGHPUT_METRICS.contains(&metric) || LATENCY_METRICS.contains(&metric)
+}
+
+fn classify(metric: &str, baseline: f64, candidate: f64, thresholds: &Thresholds) -> Option<(f64, Status)> {
+    let change_percent = if baseline == 0.0 {
+        if candidate == 0.0 { 0.0 } else { f64::INFINITY.copysign(candidate) }
+    } else {
+        (candidate - baseline) / baseline.abs() * 100.0
+    };
+
+    let status = if THROUGHPUT_METRICS.contains(&metric) {
+        match change_percent {
+            c if c < -thresholds.throughput_percent => Status::Regression,
+            c if c > thresholds.throughput_percent => Status::Improvement,
+            _ => Status::Unchanged,
+        }
+    } else if LATENCY_METRICS.contains(&metric) {
+        let significant = (candidate - baseline).abs() >= thresholds.min_latency_delta_us;
+        match change_percent {
+            c if significant && c > thresholds.latency_percent => Status::Regression,
+            c if significant && c < -thresholds.latency_percent => Status::Improvement,
+            _ => Status::Unchanged,
+        }
+    } else {
+        return None;
+    };
+    Some((change_percent, status))
+}
+
+pub fn compare(baseline: &SweepReport, candidate: &SweepReport, baseline_name: &str, candidate_name: &str, thresholds: Thresholds) -> Comparison {
+    let baseline_runs: BTreeMap<String, &RunResult> = baseline.runs.iter().map(|run| (scenario_name(run), run)).collect();
+    let candidate_runs: BTreeMap<String, &RunResult> = candidate.runs.iter().map(|run| (scenario_name(run), run)).collect();
+
+    let mut comparison = Comparison {
+        baseline: baseline_name.to_string(),
+        candidate: candidate_name.to_string(),
+        thresholds,
+        changes: Vec::new(),
+        missing: Vec::new(),
+        failed: Vec::new(),
+        only_in_baseline: baseline_runs.keys().filter(|name| !candidate_runs.contains_key(*name)).cloned().collect(),
+        only_in_candidate: candidate_runs.keys().filter(|name| !baseline_runs.contains_key(*name)).cloned().collect(),
+    };
+
+    // In the candidate's order, which is the matrix order
+    for run in &candidate.runs {
+        let scenario = scenario_name(run);
+        let Some(base) = baseline_runs.get(&scenario) else { continue };
+        if run.error.is_some() && base.error.is_none() {
+            comparison.failed.push(scenario);
+            continue;
+        }
+
+        let base_metrics: BTreeMap<&str, Option<f64>> = report::metrics(base).into_iter().collect();
+        for (metric, value) in report::metrics(run) {
+            let Some(Some(before)) = base_metrics.get(metric) else { continue };
+            let Some(after) = value else {
+                if is_compared(metric) {
+                    comparison.missing.push(MissingMetric { scenario: scenario.clone(), metric: metric.to_string(), baseline: *before });
+                }
+                continue;
+            };
+            if let Some((change_percent, status)) = classify(metric, *before, after, &thresholds) {
+                comparison.changes.push(MetricChange {
+                    scenario: scenario.clone(),
+                    metric: metric.to_string(),
+                    baseline: *before,
+                    candidate: after,
+                    change_percent,
+                    status,
+                });
+            }
+        }
+    }
+    comparison
+}
+
+/// The report; unchanged metrics are only listed with `show_unchanged`
+pub fn to_markdown(comparison: &Comparison, show_unchanged: bool) -> String {
+    let thresholds = &comparison.thresholds;
+    let mut markdown = format!(
+        "# Benchmark comparison\n\nBaseline `{}`, candidate `{}`. Thresholds: throughput -{}%, latency +{}% and at least {} us.\n\n",
+        comparison.baseline, comparison.candidate,
+        thresholds.throughput_percent, thresholds.latency_percent, thresholds.min_latency_delta_us,
+    );
+    markdown += &format!("**{} regressions, {} improvements**\n\n", comparison.regressions(), comparison.improvements());
+
+    for scenario in &comparison.failed {
+        markdown += &format!("- Failed in the candidate: `{}`\n", scenario);
+    }
+    for missing in &comparison.missing {
+        markdown += &format!("- Not measured in the candidate: `{}` of `{}` (baseline {:.1})\n", missing.metric, missing.scenario, missing.baseline);
+    }
+    for scenario in &comparison.only_in_baseline {
+        markdown += &format!("- Only in the baseline: `{}`\n", scenario);
+    }
+    for scenario in &comparison.only_in_candidate {
+        markdown += &format!("- Only in the candidate: `{}`\n", scenario);
+    }
+    let listed = [&comparison.failed, &comparison.only_in_baseline, &comparison.only_in_candidate];
+    if !(comparison.missing.is_empty() && listed.iter().all(|scenarios| scenarios.is_empty())) {
+        markdown += "\n";
+    }
+
+    markdown += "| Scenario | Metric | Baseline | Candidate | Change | Status |\n|---|---|---|---|---|---|\n";
+    for change in comparison.changes.iter().filter(|c| show_unchanged || c.status != Status::Unchanged) {
+        let status = match change.status {
+            Status::Regression => "**regression**",
+            Status::Improvement => "improvement",
+            Status::Unchanged => "",
+        };
+        markdown += &format!(
+            "| {} | {} | {:.1} | {:.1} | {:+.1}% | {} |\n",
+            change.scenario, change.metric, change.baseline, change.candidate, change.change_percent, status,
+        );
+    }
+    markdown
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::LatencyReport;
+    use crate::stats::{Distribution, GapTracker};
+
+    const THRESHOLDS: Thresholds = Thresholds { throughput_percent: 5.0, latency_percent: 10.0, min_latency_delta_us: 50.0 };
+
+    fn distribution(samples: &[f64]) -> Distribution {
+        let mut distribution = Distribution::default();
+        for sample in samples {
+            distribution.record_us(*sample);
+        }
+        distribution
+    }
+
+    /// A run of `encoding=<encoding>` with the given throughput and one-way latency, if echoed
+    fn run(encoding: &str, messages_per_sec: f64, one_way_us: Option<f64>) -> RunResult {
+        let report = Report {
+            url: "ws://localhost:3031/ws".to_string(),
+            duration_secs: 10.0,
+            end_reason: "duration".to_string(),
+            encoding: encoding.to_string(),
+            messages: (messages_per_sec * 10.0) as u64,
+            text_messages: 0,
+            batches: (messages_per_sec * 10.0) as u64,
+            rows: (messages_per_sec * 1000.0) as u64,
+            bytes: 0,
+            decode_errors: 0,
+            dropped_batches: 0,
+            messages_per_sec,
+            rows_per_sec: messages_per_sec * 100.0,
+            bytes_per_sec: 0.0,
+            decode: distribution(&[]).summary(),
+            gaps: GapTracker::new("timestamp", 2.0).summary(),
+            latency: LatencyReport {
+                connection_id: None,
+                echoes: 0,
+                missing_sequences: 0,
+                one_way: distribution(one_way_us.as_slice()).summary(),
+                end_to_end: distribution(&[]).summary(),
+            },
+        };
+        let settings = vec![("encoding".to_string(), encoding.to_string())];
+        RunResult { settings, report: Some(report), round_trip: None, resources: None, error: None }
+    }
+
+    fn sweep(runs: Vec<RunResult>) -> SweepReport {
+        SweepReport { name: "sweep".to_string(), started_at: 0, axes: Vec::new(), runs }
+    }
+
+    fn status(metric: &str, baseline: f64, candidate: f64) -> Option<Status> {
+        classify(metric, baseline, candidate, &THRESHOLDS).map(|(_, status)| status)
+    }
+
+    #[test]
+    fn throughput_changes_beyond_the_threshold_count() {
+        assert_eq!(status("messages_per_sec", 100.0, 95.0), Some(Status::Unchanged));
+        assert_eq!(status("messages_per_sec", 100.0, 94.9), Some(Status::Regression));
+        assert_eq!(status("messages_per_sec", 100.0, 105.0), Some(Status::Unchanged));
+        assert_eq!(status("rows_per_sec", 100.0, 105.1), Some(Status::Improvement));
+        assert_eq!(status("dropped_batches", 0.0, 100.0), None);
+    }
+
+    #[test]
+    fn latency_changes_need_both_thresholds() {
+        assert_eq!(status("one_way_p50_us", 1000.0, 1100.0), Some(Status::Unchanged));
+        assert_eq!(status("one_way_p50_us", 1000.0, 1101.0), Some(Status::Regression));
+        assert_eq!(status("one_way_p99_us", 1000.0, 899.0), Some(Status::Improvement));
+        // 50% slower, but only by 40 us
+        assert_eq!(status("decode_p50_us", 80.0, 120.0), Some(Status::Unchanged));
+        assert_eq!(status("decode_p50_us", 80.0, 130.0), Some(Status::Regression));
+    }
+
+    #[test]
+    fn a_zero_baseline_changes_by_infinity() {
+        assert_eq!(classify("messages_per_sec", 0.0, 0.0, &THRESHOLDS), Some((0.0, Status::Unchanged)));
+        assert_eq!(classify("messages_per_sec", 0.0, 10.0, &THRESHOLDS), Some((f64::INFINITY, Status::Improvement)));
+        assert_eq!(classify("decode_p99_us", 0.0, 10.0, &THRESHOLDS), Some((f64::INFINITY, Status::Unchanged)));
+        assert_eq!(classify("decode_p99_us", 0.0, 100.0, &THRESHOLDS), Some((f64::INFINITY, Status::Regression)));
+    }
+
+    #[test]
+    fn matches_scenarios_and_reports_failures_and_lost_metrics() {
+        let baseline = sweep(vec![run("arrow", 100.0, Some(1000.0)), run("json", 50.0, Some(1000.0)), run("cbor", 10.0, None)]);
+        let mut failed = run("json", 50.0, None);
+        failed.error = Some("connection refused".to_string());
+        let candidate = sweep(vec![run("arrow", 90.0, None), failed, run("msgpack", 10.0, None)]);
+
+        let comparison = compare(&baseline, &candidate, "base", "cand", THRESHOLDS);
+        assert_eq!(comparison.failed, ["encoding=json"]);
+        assert_eq!(comparison.only_in_baseline, ["encoding=cbor"]);
+        assert_eq!(comparison.only_in_candidate, ["encoding=msgpack"]);
+
+        let throughput = comparison.changes.iter().find(|c| c.metric == "messages_per_sec").unwrap();
+        assert_eq!((throughput.scenario.as_str(), throughput.change_percent, throughput.status), ("encoding=arrow", -10.0, Status::Regression));
+        let missing: Vec<&str> = comparison.missing.iter().map(|m| m.metric.as_str()).collect();
+        assert_eq!(missing, ["one_way_p50_us", "one_way_p99_us"]);
+
+        // Throughput and rows per second, both lost latencies and the failed run
+        assert_eq!(comparison.regressions(), 5);
+        let markdown = to_markdown(&comparison, false);
+        assert!(markdown.contains("- Not measured in the candidate: `one_way_p50_us` of `encoding=arrow` (baseline 1000.0)"));
+        assert!(markdown.contains("- Failed in the candidate: `encoding=json`"));
+    }
+}
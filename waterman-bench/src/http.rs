@@ -0,0 +1,80 @@
+//! Just enough HTTP/1.1 to drive the bridge's REST API
+
+use serde_json::Value;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// `host:port` of an `http://host:port` base URL
+fn authority(base: &str) -> &str {
+    base.trim_start_matches("http://").trim_end_matches('/')
+}
+
+/// Undo `Transfer-Encoding: chunked`
+fn dechunk(mut body: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::new();
+    while let Some(line_end) = body.windows(2).position(|w| w == b"\r\n") {
+        let size = std::str::from_utf8(&body[..line_end])
+            .ok()
+            .and_then(|line| usize::from_str_radix(line.split(';').next().unwrap_or("").trim(), 16).ok())
+            .unwrap_or(0);
+        let start = line_end + 2;
+        if size == 0 || start + size > body.len() {
+            break;
+        }
+        decoded.extend_from_slice(&body[start..start + size]);
+        body = &body[(start + size + 2).min(body.len())..];
+    }
+    decoded
+}
+
+/// Send one request and return the status code and body
+pub async fn request(base: &str, method: &str, path: &str, body: Option<&Value>) -> Result<(u16, String), String> {
+    let authority = authority(base);
+    let mut stream = TcpStream::connect(authority)
+        .await
+        .map_err(|e| format!("Failed to connect to {}: {}", authority, e))?;
+
+    let body = body.map(|b| b.to_string()).unwrap_or_default();
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        method, path, authority, body.len(), body,
+    );
+    stream.write_all(request.as_bytes()).await.map_err(|e| e.to_string())?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.map_err(|e| e.to_string())?;
+    let header_end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| format!("Malformed response to {} {}", method, path))?;
+    let head = String::from_utf8_lossy(&response[..header_end]).to_string();
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| format!("Malformed status line in response to {} {}", method, path))?;
+
+    let payload = &response[header_end + 4..];
+    let chunked = head.lines().any(|line| {
+        let line = line.to_ascii_lowercase();
+        line.starts_with("transfer-encoding:") && line.contains("chunked")
+    });
+    let payload = if chunked { dechunk(payload) } else { payload.to_vec() };
+    Ok((status, String::from_utf8_lossy(&payload).to_string()))
+}
+
+async fn json_request(base: &str, method: &str, path: &str, body: Option<&Value>) -> Result<Value, String> {
+    let (status, text) = request(base, method, path, body).await?;
+    if !(200..300).contains(&status) {
+        return Err(format!("{} {} returned {}: {}", method, path, status, text));
+    }
+    serde_json::from_str(&text).map_err(|e| format!("Invalid JSON from {} {}: {}", method, path, e))
+}
+
+pub async fn get_json(base: &str, path: &str) -> Result<Value, String> {
+    json_request(base, "GET", path, None).await
+}
+
+pub async fn put_json(base: &str, path: &str, body: &Value) -> Result<Value, String> {
+    json_request(base, "PUT", path, Some(body)).await
+}
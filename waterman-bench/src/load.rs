@@ -0,0 +1,647 @@
+//! Load tests: many concurrent viewers of one bridge, with a mix of subscriptions,
+//! slow readers and connect/disconnect churn, described in YAML
+
+use crate::bridge::{self, Bridge, BridgeConfig, ResourceMonitor, ResourceUsage};
+use crate::client::{self, WireEncoding};
+use crate::http;
+use crate::stats::{Distribution, DistributionSummary, GapTracker};
+use crate::sweep;
+use futures_util::{SinkExt, StreamExt};
+use log::{info, warn};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::Message;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LoadScenario {
+    pub name: String,
+    pub bridge: BridgeConfig,
+    pub duration_secs: f64,
+    pub ramp_up_secs: f64,          // clients are started evenly over this period
+    pub sample_secs: f64,           // interval of the timeline
+    pub parameters: BTreeMap<String, Value>,    // topic -> overrides, laid out like parameters.yaml
+    pub groups: Vec<ClientGroup>,
+}
+
+impl Default for LoadScenario {
+    fn default() -> Self {
+        Self {
+            name: "load".to_string(),
+            bridge: BridgeConfig::default(),
+            duration_secs: 60.0,
+            ramp_up_secs: 0.0,
+            sample_secs: 1.0,
+            parameters: BTreeMap::new(),
+            groups: Vec::new(),
+        }
+    }
+}
+
+/// Clients that behave alike
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ClientGroup {
+    pub name: String,
+    pub clients: usize,
+    pub path: String,                       // path and query of the WebSocket URL: topic, encoding, filter...
+    pub read_delay_ms: f64,                 // pause after every frame, to act as a slow reader
+    pub lifetime_secs: Option<(f64, f64)>,  // disconnect after a random time in this range, then reconnect
+    pub reconnect_delay_secs: f64,
+    pub echo: bool,                         // echo stamps back, for the bridge's `/latency` histograms
+}
+
+impl Default for ClientGroup {
+    fn default() -> Self {
+        Self {
+            name: "clients".to_string(),
+            clients: 1,
+            path: "/ws/synthetic".to_string(),
+            read_delay_ms: 0.0,
+            lifetime_secs: None,
+            reconnect_delay_secs: 1.0,
+            echo: false,
+        }
+    }
+}
+
+impl LoadScenario {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let scenario: Self = serde_yaml::from_str(&text).map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+        if scenario.groups.iter().all(|group| group.clients == 0) {
+            return Err(format!("{} has no clients", path.display()));
+        }
+        for group in &scenario.groups {
+            if let Some((min, max)) = group.lifetime_secs
+                && !(min > 0.0 && min <= max)
+            {
+                return Err(format!("Group '{}': lifetime_secs must be [min, max] with 0 < min <= max", group.name));
+            }
+        }
+        Ok(scenario)
+    }
+}
+
+/// Counters of all clients together, read by the timeline sampler
+#[derive(Debug, Default)]
+struct LiveCounters {
+    connected: AtomicU64,
+    messages: AtomicU64,
+    bytes: AtomicU64,
+    dropped_batches: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientStats {
+    pub group: String,
+    pub client: usize,              // index within the group
+    pub connections: u64,
+    pub connect_failures: u64,
+    pub server_closes: u64,         // connections the bridge ended
+    pub connected_secs: f64,
+    pub messages: u64,
+    pub rows: u64,
+    pub bytes: u64,
+    pub dropped_batches: u64,       // the bridge skipped because the client fell behind
+    pub missing_sequences: u64,     // numbered by the bridge but never delivered
+    pub gaps: u64,
+    pub decode_errors: u64,
+    pub messages_per_sec: f64,      // while connected
+    pub one_way: DistributionSummary,
+    pub last_error: Option<String>,
+}
+
+/// What one client measured, with the samples kept for the group and overall summaries
+struct ClientResult {
+    stats: ClientStats,
+    one_way: Distribution,
+    decode_times: Distribution,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sample {
+    pub elapsed_secs: f64,
+    pub connected: u64,
+    pub messages_per_sec: f64,
+    pub mib_per_sec: f64,
+    pub dropped_batches: u64,       // so far
+    pub bridge_rss_mb: Option<f64>,
+    pub bridge_cpu_percent: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupSummary {
+    pub name: String,
+    pub path: String,
+    pub clients: usize,
+    pub connections: u64,
+    pub connect_failures: u64,
+    pub messages: u64,
+    pub dropped_batches: u64,
+    pub missing_sequences: u64,
+    pub clients_with_drops: usize,
+    pub messages_per_sec_mean: f64,     // per client
+    pub messages_per_sec_min: f64,
+    pub one_way: DistributionSummary,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoadReport {
+    pub name: String,
+    pub started_at: u64,            // seconds since the Unix epoch
+    pub duration_secs: f64,
+    pub clients: usize,
+    pub peak_connected: u64,
+    pub connections: u64,
+    pub connect_failures: u64,
+    pub messages: u64,
+    pub bytes: u64,
+    pub dropped_batches: u64,
+    pub missing_sequences: u64,
+    pub messages_per_sec: f64,
+    pub one_way: DistributionSummary,
+    pub decode: DistributionSummary,
+    pub first_drop: Option<Sample>,     // first timeline sample with dropped batches
+    pub resources: Option<ResourceUsage>,   // of the bridge, over the whole test
+    pub groups: Vec<GroupSummary>,
+    pub timeline: Vec<Sample>,
+    pub client_stats: Vec<ClientStats>,
+}
+
+/// Raise the open file limit as far as allowed, as every client holds a socket
+#[cfg(unix)]
+fn raise_file_limit() {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    // SAFETY: getrlimit and setrlimit only read and write the struct passed in
+    unsafe {
+        if libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) == 0 && limit.rlim_cur < limit.rlim_max {
+            limit.rlim_cur = limit.rlim_max;
+            libc::setrlimit(libc::RLIMIT_NOFILE, &limit);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn raise_file_limit() {}
+
+/// One connection, until `until` or the bridge ends it; returns the reason it ended
+async fn receive(url: &str, group: &ClientGroup, until: tokio::time::Instant, result: &mut ClientResult, live: &LiveCounters) -> Result<String, String> {
+    let (socket, _) = tokio::select! {
+        connected = connect_async(url) => connected.map_err(|e| e.to_string())?,
+        _ = tokio::time::sleep_until(until) => return Ok("stopped".to_string()),
+    };
+    let (mut outgoing, mut incoming) = socket.split();
+    let encoding = WireEncoding::from_url(url);
+    let read_delay = (group.read_delay_ms > 0.0).then(|| Duration::from_secs_f64(group.read_delay_ms / 1000.0));
+    let stats = &mut result.stats;
+    stats.connections += 1;
+    live.connected.fetch_add(1, Ordering::Relaxed);
+    let connected_at = Instant::now();
+
+    let mut gaps = GapTracker::new("timestamp", 2.0);
+    let mut next_sequence = 0u64;
+    let mut dropped_batches = 0u64;
+    let end_reason = loop {
+        let message = tokio::select! {
+            message = incoming.next() => message,
+            _ = tokio::time::sleep_until(until) => break Ok("stopped".to_string()),
+        };
+        let payload = match message {
+            Some(Ok(Message::Binary(payload))) => payload,
+            Some(Ok(Message::Text(text))) if encoding == WireEncoding::Json && !client::is_control(&text) => text.into_bytes(),
+            Some(Ok(Message::Text(text))) => {
+                stats.bytes += text.len() as u64;
+                if let Some(total) = client::lagged_total(&text) {
+                    let skipped = total.saturating_sub(dropped_batches);
+                    dropped_batches = total;
+                    stats.dropped_batches += skipped;
+                    live.dropped_batches.fetch_add(skipped, Ordering::Relaxed);
+                }
+                continue;
+            },
+            Some(Ok(Message::Close(_))) | None => break Ok("closed by server".to_string()),
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => break Err(e.to_string()),
+        };
+
+        let received_at_us = client::unix_time_us();
+        stats.messages += 1;
+        stats.bytes += payload.len() as u64;
+        live.messages.fetch_add(1, Ordering::Relaxed);
+        live.bytes.fetch_add(payload.len() as u64, Ordering::Relaxed);
+        match client::decode(encoding, payload, &mut gaps, &mut result.decode_times) {
+            Ok((_, rows, stamps)) => {
+                stats.rows += rows;
+                if let Some(stamps) = stamps {
+                    stats.missing_sequences += stamps.sequence.saturating_sub(next_sequence);
+                    next_sequence = next_sequence.max(stamps.sequence + 1);
+                    result.one_way.record_us(received_at_us as f64 - stamps.sent_at_us as f64);
+                    if group.echo {
+                        let echo = json!({
+                            "type": "echo",
+                            "sequence": stamps.sequence,
+                            "sent_at_us": stamps.sent_at_us,
+                            "generated_at_us": stamps.generated_at_us,
+                            "received_at_us": received_at_us,
+                        });
+                        if let Err(e) = outgoing.send(Message::Text(echo.to_string())).await {
+                            break Err(e.to_string());
+                        }
+                    }
+                }
+            },
+            Err(_) => stats.decode_errors += 1,
+        }
+        if let Some(delay) = read_delay {
+            tokio::time::sleep(delay).await;
+        }
+    };
+
+    stats.gaps += gaps.summary().gaps;
+    stats.connected_secs += connected_at.elapsed().as_secs_f64();
+    live.connected.fetch_sub(1, Ordering::Relaxed);
+    let _ = outgoing.close().await;
+    end_reason
+}
+
+/// One simulated viewer: connect after `start_delay`, reconnecting until `deadline`
+async fn run_client(url: String, group: Arc<ClientGroup>, index: usize, start_delay: Duration, deadline: tokio::time::Instant, live: Arc<LiveCounters>) -> ClientResult {
+    let mut result = ClientResult {
+        stats: ClientStats {
+            group: group.name.clone(),
+            client: index,
+            connections: 0,
+            connect_failures: 0,
+            server_closes: 0,
+            connected_secs: 0.0,
+            messages: 0,
+            rows: 0,
+            bytes: 0,
+            dropped_batches: 0,
+            missing_sequences: 0,
+            gaps: 0,
+            decode_errors: 0,
+            messages_per_sec: 0.0,
+            one_way: Distribution::default().summary(),
+            last_error: None,
+        },
+        one_way: Distribution::default(),
+        decode_times: Distribution::default(),
+    };
+    tokio::time::sleep(start_delay).await;
+
+    while tokio::time::Instant::now() < deadline {
+        let until = match group.lifetime_secs {
+            Some((min, max)) => {
+                let lifetime = rand::thread_rng().gen_range(min..=max);
+                deadline.min(tokio::time::Instant::now() + Duration::from_secs_f64(lifetime))
+            },
+            None => deadline,
+        };
+        let connections = result.stats.connections;
+        match receive(&url, &group, until, &mut result, &live).await {
+            Ok(reason) if reason == "closed by server" => result.stats.server_closes += 1,
+            Ok(_) => {},
+            Err(e) => {
+                if result.stats.connections == connections {
+                    result.stats.connect_failures += 1;
+                }
+                result.stats.last_error = Some(e);
+            },
+        }
+        let reconnect_at = tokio::time::Instant::now() + Duration::from_secs_f64(group.reconnect_delay_secs);
+        tokio::time::sleep_until(reconnect_at.min(deadline)).await;
+    }
+
+    let stats = &mut result.stats;
+    stats.one_way = result.one_way.summary();
+    if stats.connected_secs > 0.0 {
+        stats.messages_per_sec = stats.messages as f64 / stats.connected_secs;
+    }
+    result
+}
+
+/// Sample the live counters and the bridge's memory and CPU use every `period`
+fn start_timeline(period: Duration, pid: Option<u32>, live: Arc<LiveCounters>, samples: Arc<Mutex<Vec<Sample>>>) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let started = Instant::now();
+        let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        let mut last = (Instant::now(), 0u64, 0u64, pid.and_then(bridge::cpu_seconds));
+        loop {
+            ticker.tick().await;
+            let (since, messages, bytes, cpu) = last;
+            let seconds = since.elapsed().as_secs_f64();
+            let now = (
+                Instant::now(),
+                live.messages.load(Ordering::Relaxed),
+                live.bytes.load(Ordering::Relaxed),
+                pid.and_then(bridge::cpu_seconds),
+            );
+            let sample = Sample {
+                elapsed_secs: started.elapsed().as_secs_f64(),
+                connected: live.connected.load(Ordering::Relaxed),
+                messages_per_sec: (now.1 - messages) as f64 / seconds,
+                mib_per_sec: (now.2 - bytes) as f64 / seconds / (1024.0 * 1024.0),
+                dropped_batches: live.dropped_batches.load(Ordering::Relaxed),
+                bridge_rss_mb: pid.and_then(bridge::rss_mb),
+                bridge_cpu_percent: cpu.zip(now.3).map(|(before, after)| (after - before) / seconds * 100.0),
+            };
+            eprintln!(
+                "[{:>6.1}s] {:>5} connected {:>10.1} msg/s {:>8.2} MiB/s {:>7} dropped{}",
+                sample.elapsed_secs, sample.connected, sample.messages_per_sec, sample.mib_per_sec, sample.dropped_batches,
+                sample.bridge_rss_mb.map(|rss| format!("   bridge {:.1} MiB", rss)).unwrap_or_default(),
+            );
+            samples.lock().unwrap().push(sample);
+            last = now;
+        }
+    })
+}
+
+fn summarize_group(group: &ClientGroup, results: &[&ClientResult]) -> GroupSummary {
+    let mut one_way = Distribution::default();
+    for result in results {
+        one_way.extend(&result.one_way);
+    }
+    let rates: Vec<f64> = results.iter().map(|r| r.stats.messages_per_sec).collect();
+    GroupSummary {
+        name: group.name.clone(),
+        path: group.path.clone(),
+        clients: results.len(),
+        connections: results.iter().map(|r| r.stats.connections).sum(),
+        connect_failures: results.iter().map(|r| r.stats.connect_failures).sum(),
+        messages: results.iter().map(|r| r.stats.messages).sum(),
+        dropped_batches: results.iter().map(|r| r.stats.dropped_batches).sum(),
+        missing_sequences: results.iter().map(|r| r.stats.missing_sequences).sum(),
+        clients_with_drops: results.iter().filter(|r| r.stats.dropped_batches + r.stats.missing_sequences > 0).count(),
+        messages_per_sec_mean: if rates.is_empty() { 0.0 } else { rates.iter().sum::<f64>() / rates.len() as f64 },
+        messages_per_sec_min: rates.iter().cloned().reduce(f64::min).unwrap_or(0.0),
+        one_way: one_way.summary(),
+    }
+}
+
+pub async fn run(scenario: &LoadScenario, output_dir: &Path) -> Result<LoadReport, String> {
+    raise_file_limit();
+    let scratch_dir = output_dir.join(format!("{}-bridge", scenario.name));
+    let bridge = Bridge::start(&scenario.bridge, &scratch_dir, &scratch_dir.join("bridge.log")).await?;
+
+    // The generators must not stop before the test does; a running bridge gets its parameters back
+    let mut originals = Vec::new();
+    for (topic, overrides) in &scenario.parameters {
+        let original = sweep::topic_parameters(&bridge, topic).await?;
+        let mut parameters = original.clone();
+        sweep::merge(&mut parameters, overrides);
+        sweep::merge(&mut parameters, &json!({ "data_volume": { "test_duration": "OpenEnded" } }));
+        http::put_json(&bridge.http, &format!("/topics/{}/parameters", topic), &parameters).await?;
+        originals.push((topic.clone(), original));
+    }
+
+    let total = client_count(scenario);
+    info!("Load test '{}': {} clients in {} groups for {}s", scenario.name, total, scenario.groups.len(), scenario.duration_secs);
+    let started_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let started = Instant::now();
+    let deadline = tokio::time::Instant::now() + Duration::from_secs_f64(scenario.duration_secs);
+
+    let live = Arc::new(LiveCounters::default());
+    let samples = Arc::new(Mutex::new(Vec::new()));
+    let timeline = start_timeline(Duration::from_secs_f64(scenario.sample_secs), bridge.pid, live.clone(), samples.clone());
+    let monitor = bridge.pid.and_then(ResourceMonitor::start);
+
+    let mut clients = Vec::new();
+    for group in &scenario.groups {
+        let group = Arc::new(group.clone());
+        for index in 0..group.clients {
+            let start_delay = Duration::from_secs_f64(scenario.ramp_up_secs * clients.len() as f64 / total as f64);
+            let url = format!("{}{}", bridge.ws, group.path);
+            clients.push(tokio::spawn(run_client(url, group.clone(), index, start_delay, deadline, live.clone())));
+        }
+    }
+    let mut results = Vec::new();
+    for client in clients {
+        results.push(client.await.map_err(|e| format!("Client task failed: {}", e))?);
+    }
+
+    timeline.abort();
+    let resources = monitor.and_then(ResourceMonitor::finish);
+    if scenario.bridge.url.is_some() {
+        for (topic, original) in originals {
+            if let Err(e) = http::put_json(&bridge.http, &format!("/topics/{}/parameters", topic), &original).await {
+                warn!("Failed to restore the parameters of '{}': {}", topic, e);
+            }
+        }
+    }
+    bridge.stop().await;
+
+    let timeline = samples.lock().unwrap().clone();
+    Ok(summarize(scenario, started_at, started.elapsed().as_secs_f64(), results, timeline, resources))
+}
+
+fn client_count(scenario: &LoadScenario) -> usize {
+    scenario.groups.iter().map(|group| group.clients).sum()
+}
+
+/// The whole test, per group and overall, from what every client measured
+fn summarize(scenario: &LoadScenario, started_at: u64, duration_secs: f64, results: Vec<ClientResult>, timeline: Vec<Sample>, resources: Option<ResourceUsage>) -> LoadReport {
+    let mut one_way = Distribution::default();
+    let mut decode = Distribution::default();
+    for result in &results {
+        one_way.extend(&result.one_way);
+        decode.extend(&result.decode_times);
+    }
+    let groups = scenario
+        .groups
+        .iter()
+        .map(|group| {
+            let members: Vec<&ClientResult> = results.iter().filter(|r| r.stats.group == group.name).collect();
+            summarize_group(group, &members)
+        })
+        .collect();
+    let stats: Vec<ClientStats> = results.into_iter().map(|result| result.stats).collect();
+    let messages = stats.iter().map(|s| s.messages).sum();
+
+    LoadReport {
+        name: scenario.name.clone(),
+        started_at,
+        duration_secs,
+        clients: client_count(scenario),
+        peak_connected: timeline.iter().map(|s| s.connected).max().unwrap_or(0),
+        connections: stats.iter().map(|s| s.connections).sum(),
+        connect_failures: stats.iter().map(|s| s.connect_failures).sum(),
+        messages,
+        bytes: stats.iter().map(|s| s.bytes).sum(),
+        dropped_batches: stats.iter().map(|s| s.dropped_batches).sum(),
+        missing_sequences: stats.iter().map(|s| s.missing_sequences).sum(),
+        messages_per_sec: messages as f64 / duration_secs,
+        one_way: one_way.summary(),
+        decode: decode.summary(),
+        first_drop: timeline.iter().find(|s| s.dropped_batches > 0).cloned(),
+        resources,
+        groups,
+        timeline,
+        client_stats: stats,
+    }
+}
+
+fn format_optional(value: Option<f64>) -> String {
+    value.map(|v| format!("{:.1}", v)).unwrap_or_default()
+}
+
+pub fn to_markdown(report: &LoadReport) -> String {
+    let mut markdown = format!(
+        "# Load test `{}`\n\n{} clients, {} connections ({} failed) over {:.1} s, started at {} (Unix time)\n\n",
+        report.name, report.clients, report.connections, report.connect_failures, report.duration_secs, report.started_at,
+    );
+    markdown += &format!("- Peak connected: {}\n", report.peak_connected);
+    markdown += &format!("- Received: {} messages, {:.1} msg/s, {:.1} MiB\n", report.messages, report.messages_per_sec, report.bytes as f64 / (1024.0 * 1024.0));
+    markdown += &format!("- Dropped by the bridge: {} batches, {} missing sequence numbers\n", report.dropped_batches, report.missing_sequences);
+    if report.one_way.count > 0 {
+        markdown += &format!("- One way (us): p50 {:.0}, p99 {:.0}, max {:.0}\n", report.one_way.p50_us, report.one_way.p99_us, report.one_way.max_us);
+    }
+    if let Some(first_drop) = &report.first_drop {
+        markdown += &format!("- First drops by {:.1} s, with {} clients connected\n", first_drop.elapsed_secs, first_drop.connected);
+    }
+    if let Some(resources) = &report.resources {
+        markdown += &format!(
+            "- Bridge: {:.1}% CPU, {:.1} MiB peak / {:.1} MiB mean resident memory\n",
+            resources.cpu_percent, resources.rss_peak_mb, resources.rss_mean_mb,
+        );
+    }
+
+    markdown += "\n## Groups\n\n| Group | Path | Clients | Connections | Failed | Messages | msg/s per client (mean / min) | Dropped | Missing | Clients with drops | One way p50 / p99 (us) |\n";
+    markdown += "|---|---|---|---|---|---|---|---|---|---|---|\n";
+    for group in &report.groups {
+        markdown += &format!(
+            "| {} | `{}` | {} | {} | {} | {} | {:.1} / {:.1} | {} | {} | {} | {:.0} / {:.0} |\n",
+            group.name, group.path, group.clients, group.connections, group.connect_failures, group.messages,
+            group.messages_per_sec_mean, group.messages_per_sec_min, group.dropped_batches, group.missing_sequences,
+            group.clients_with_drops, group.one_way.p50_us, group.one_way.p99_us,
+        );
+    }
+
+    markdown += "\n## Timeline\n\n| Time (s) | Connected | msg/s | MiB/s | Dropped | Bridge RSS (MiB) | Bridge CPU (%) |\n|---|---|---|---|---|---|---|\n";
+    for sample in &report.timeline {
+        markdown += &format!(
+            "| {:.1} | {} | {:.1} | {:.2} | {} | {} | {} |\n",
+            sample.elapsed_secs, sample.connected, sample.messages_per_sec, sample.mib_per_sec, sample.dropped_batches,
+            format_optional(sample.bridge_rss_mb), format_optional(sample.bridge_cpu_percent),
+        );
+    }
+    markdown
+}
+
+/// One row per client
+pub fn clients_csv(report: &LoadReport) -> String {
+    let mut csv = "group,client,connections,connect_failures,server_closes,connected_secs,messages,rows,bytes,dropped_batches,missing_sequences,gaps,decode_errors,messages_per_sec,one_way_p50_us,one_way_p99_us\n".to_string();
+    for s in &report.client_stats {
+        csv += &format!(
+            "{},{},{},{},{},{:.2},{},{},{},{},{},{},{},{:.1},{:.0},{:.0}\n",
+            s.group, s.client, s.connections, s.connect_failures, s.server_closes, s.connected_secs, s.messages, s.rows,
+            s.bytes, s.dropped_batches, s.missing_sequences, s.gaps, s.decode_errors, s.messages_per_sec,
+            s.one_way.p50_us, s.one_way.p99_us,
+        );
+    }
+    csv
+}
+
+/// Write `<name>.json`, `<name>.md` and `<name>-clients.csv` into `directory`, returning their paths
+pub fn write(report: &LoadReport, directory: &Path) -> Result<Vec<PathBuf>, String> {
+    std::fs::create_dir_all(directory).map_err(|e| format!("Failed to create {}: {}", directory.display(), e))?;
+    let json = serde_json::to_string_pretty(report).map_err(|e| e.to_string())?;
+    let files = [
+        (format!("{}.json", report.name), json),
+        (format!("{}.md", report.name), to_markdown(report)),
+        (format!("{}-clients.csv", report.name), clients_csv(report)),
+    ];
+
+    files
+        .into_iter()
+        .map(|(file, content)| {
+            let path = directory.join(file);
+            std::fs::write(&path, content).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+            Ok(path)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(group: &str, index: usize, messages: u64, dropped_batches: u64, one_way_us: &[f64]) -> ClientResult {
+        let mut one_way = Distribution::default();
+        for sample in one_way_us {
+            one_way.record_us(*sample);
+        }
+        ClientResult {
+            stats: ClientStats {
+                group: group.to_string(),
+                client: index,
+                connections: 1,
+                connect_failures: 0,
+                server_closes: 0,
+                connected_secs: 10.0,
+                messages,
+                rows: messages * 10,
+                bytes: messages * 100,
+                dropped_batches,
+                missing_sequences: 0,
+                gaps: 0,
+                decode_errors: 0,
+                messages_per_sec: messages as f64 / 10.0,
+                one_way: one_way.summary(),
+                last_error: None,
+            },
+            one_way,
+            decode_times: Distribution::default(),
+        }
+    }
+
+    fn sample(elapsed_secs: f64, connected: u64, dropped_batches: u64) -> Sample {
+        Sample { elapsed_secs, connected, messages_per_sec: 0.0, mib_per_sec: 0.0, dropped_batches, bridge_rss_mb: None, bridge_cpu_percent: None }
+    }
+
+    fn group(name: &str, clients: usize) -> ClientGroup {
+        ClientGroup { name: name.to_string(), clients, ..ClientGroup::default() }
+    }
+
+    #[test]
+    fn summarizes_a_group() {
+        let results = [client("fast", 0, 1000, 0, &[100.0, 300.0]), client("fast", 1, 500, 3, &[200.0])];
+        let summary = summarize_group(&group("fast", 2), &results.iter().collect::<Vec<_>>());
+        assert_eq!((summary.clients, summary.connections, summary.messages, summary.dropped_batches), (2, 2, 1500, 3));
+        assert_eq!(summary.clients_with_drops, 1);
+        assert_eq!((summary.messages_per_sec_mean, summary.messages_per_sec_min), (75.0, 50.0));
+        assert_eq!((summary.one_way.count, summary.one_way.p50_us), (3, 200.0));
+
+        let empty = summarize_group(&group("idle", 0), &[]);
+        assert_eq!((empty.clients, empty.messages_per_sec_mean, empty.messages_per_sec_min), (0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn summarizes_the_whole_test() {
+        let scenario = LoadScenario { groups: vec![group("fast", 2), group("slow", 1)], ..LoadScenario::default() };
+        let results = vec![
+            client("fast", 0, 1000, 0, &[100.0]),
+            client("slow", 0, 200, 5, &[900.0]),
+            client("fast", 1, 800, 0, &[200.0]),
+        ];
+        let timeline = vec![sample(1.0, 2, 0), sample(2.0, 3, 5), sample(3.0, 3, 5)];
+        let report = summarize(&scenario, 1_700_000_000, 20.0, results, timeline, None);
+
+        assert_eq!((report.clients, report.peak_connected, report.connections), (3, 3, 3));
+        assert_eq!((report.messages, report.bytes, report.dropped_batches), (2000, 200_000, 5));
+        assert_eq!(report.messages_per_sec, 100.0);
+        assert_eq!((report.one_way.count, report.one_way.max_us), (3, 900.0));
+        assert_eq!(report.first_drop.map(|s| s.elapsed_secs), Some(2.0));
+        let groups: Vec<(&str, u64)> = report.groups.iter().map(|g| (g.name.as_str(), g.messages)).collect();
+        assert_eq!(groups, [("fast", 1800), ("slow", 200)]);
+        assert_eq!(report.client_stats.len(), 3);
+    }
+}
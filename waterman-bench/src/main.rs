@@ -0,0 +1,285 @@
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
+use std::time::Duration;
+
+mod bridge;
+mod client;
+mod compare;
+mod http;
+mod load;
+mod report;
+mod stats;
+mod sweep;
+
+use client::{BenchOptions, Report};
+
+/// Headless benchmark client: connects to the bridge, decodes every message
+/// and reports throughput, decode times, latency and gaps in the data
+#[derive(Parser, Debug, Clone)]
+#[clap(author, version, about, args_conflicts_with_subcommands = true)]
+pub struct BenchArgs {
+    #[clap(subcommand)]
+    pub command: Option<BenchCommand>,
+
+    #[clap(flatten)]
+    pub run: RunArgs,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum BenchCommand {
+    /// Measure every combination of a parameter matrix described in a YAML scenario
+    Sweep(SweepArgs),
+    /// Compare two results and exit with status 1 on throughput or latency regressions
+    Compare(CompareArgs),
+    /// Run many concurrent clients described in a YAML scenario against one bridge
+    Load(LoadArgs),
+}
+
+/// A single measurement, the default without a subcommand
+#[derive(Args, Debug, Clone)]
+pub struct RunArgs {
+    /// WebSocket URL to connect to, including any query parameters
+    #[clap(short, long, default_value = "ws://127.0.0.1:3031/ws")]
+    pub url: String,
+
+    /// Seconds to measure for
+    #[clap(short, long, default_value_t = 10.0)]
+    pub duration: f64,
+
+    /// Seconds between progress lines on stderr (0 disables)
+    #[clap(long, default_value_t = 1.0)]
+    pub progress: f64,
+
+    /// Column holding the row timestamps used to find gaps
+    #[clap(long, default_value = "timestamp")]
+    pub timestamp_column: String,
+
+    /// A step between rows larger than this many times the expected spacing is a gap
+    #[clap(long, default_value_t = 2.0)]
+    pub gap_factor: f64,
+
+    /// Expected spacing of the timestamp column, in its own units (default: the smallest step seen)
+    #[clap(long)]
+    pub expected_interval: Option<i64>,
+
+    /// Don't echo the bridge's stamps back (no `/latency` histograms on the bridge)
+    #[clap(long)]
+    pub no_echo: bool,
+
+    /// Also write the report as JSON to this file
+    #[clap(long)]
+    pub json: Option<String>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct SweepArgs {
+    /// Scenario file with the bridge to use and the parameter matrix
+    pub scenario: PathBuf,
+
+    /// Directory the JSON, CSV and Markdown reports are written to
+    #[clap(short, long, default_value = "bench-reports")]
+    pub output: PathBuf,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct LoadArgs {
+    /// Scenario file with the bridge to use and the groups of clients
+    pub scenario: PathBuf,
+
+    /// Directory the JSON, Markdown and per-client CSV reports are written to
+    #[clap(short, long, default_value = "bench-reports")]
+    pub output: PathBuf,
+}
+
+fn print_report(report: &Report) {
+    println!("URL:              {} ({})", report.url, report.encoding);
+    println!("Duration:         {:.2} s ({})", report.duration_secs, report.end_reason);
+    println!("Messages:         {} data, {} control", report.messages, report.text_messages);
+    println!("Batches / rows:   {} / {}", report.batches, report.rows);
+    println!("Decode errors:    {}", report.decode_errors);
+    println!("Dropped batches:  {} (skipped by the bridge)", report.dropped_batches);
+    println!();
+    println!("Throughput:       {:.1} msg/s, {:.1} rows/s, {:.1} KiB/s",
+        report.messages_per_sec, report.rows_per_sec, report.bytes_per_sec / 1024.0);
+
+    let decode = &report.decode;
+    println!("Decode time (us): mean {:.1}, min {:.1}, p50 {:.1}, p90 {:.1}, p99 {:.1}, max {:.1}",
+        decode.mean_us, decode.min_us, decode.p50_us, decode.p90_us, decode.p99_us, decode.max_us);
+
+    let gaps = &report.gaps;
+    match gaps.interval {
+        Some(interval) => println!(
+            "Gaps ('{}'):   {} (spacing {}, largest {}, total {}), {} rows out of order",
+            gaps.column, gaps.gaps, interval, gaps.largest_gap, gaps.total_gap, gaps.out_of_order,
+        ),
+        None => println!("Gaps ('{}'):   no spacing observed", gaps.column),
+    }
+    if gaps.batches_without_column > 0 {
+        println!("                  {} batches without a usable '{}' column", gaps.batches_without_column, gaps.column);
+    }
+
+    let latency = &report.latency;
+    println!();
+    for (name, summary) in [("One way (us):    ", &latency.one_way), ("End to end (us): ", &latency.end_to_end)] {
+        if summary.count > 0 {
+            println!("{}mean {:.1}, min {:.1}, p50 {:.1}, p90 {:.1}, p99 {:.1}, max {:.1}",
+                name, summary.mean_us, summary.min_us, summary.p50_us, summary.p90_us, summary.p99_us, summary.max_us);
+        }
+    }
+    println!("Sequence:         {} messages missing", latency.missing_sequences);
+    if let Some(connection_id) = latency.connection_id {
+        println!("Connection:       {} ({} echoes sent, see /latency/{})", connection_id, latency.echoes, connection_id);
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct CompareArgs {
+    /// Result to compare against: a sweep's JSON report or a single run's `--json` output
+    pub baseline: PathBuf,
+
+    /// Result to check for regressions, of the same kind
+    pub candidate: PathBuf,
+
+    /// A drop in throughput larger than this many percent is a regression
+    #[clap(long, default_value_t = 10.0)]
+    pub throughput_threshold: f64,
+
+    /// A rise in latency larger than this many percent is a regression
+    #[clap(long, default_value_t = 20.0)]
+    pub latency_threshold: f64,
+
+    /// Latency changes smaller than this many microseconds are never regressions
+    #[clap(long, default_value_t = 100.0)]
+    pub min_latency_delta_us: f64,
+
+    /// List unchanged metrics too
+    #[clap(long)]
+    pub all: bool,
+
+    /// Also write the comparison as JSON to this file
+    #[clap(long)]
+    pub json: Option<PathBuf>,
+}
+
+async fn run_once(args: RunArgs) {
+    let options = BenchOptions {
+        url: args.url.clone(),
+        duration: Duration::from_secs_f64(args.duration),
+        progress_interval: (args.progress > 0.0).then(|| Duration::from_secs_f64(args.progress)),
+        timestamp_column: args.timestamp_column.clone(),
+        gap_factor: args.gap_factor,
+        expected_interval: args.expected_interval,
+        echo: !args.no_echo,
+    };
+
+    let report = match client::run(&options).await {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    print_report(&report);
+
+    if let Some(path) = &args.json {
+        let json = serde_json::to_string_pretty(&report).expect("report serializes");
+        if let Err(e) = std::fs::write(path, json) {
+            eprintln!("Failed to write {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn run_sweep(args: SweepArgs) {
+    let result = match sweep::Scenario::load(&args.scenario) {
+        Ok(scenario) => sweep::run(&scenario, &args.output).await,
+        Err(e) => Err(e),
+    };
+    let written = result.and_then(|sweep| {
+        print!("{}", report::to_markdown(&sweep));
+        report::write(&sweep, &args.output)
+    });
+    match written {
+        Ok(paths) => {
+            for path in paths {
+                eprintln!("Wrote {}", path.display());
+            }
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn run_load(args: LoadArgs) {
+    let result = match load::LoadScenario::load(&args.scenario) {
+        Ok(scenario) => load::run(&scenario, &args.output).await,
+        Err(e) => Err(e),
+    };
+    let written = result.and_then(|report| {
+        print!("{}", load::to_markdown(&report));
+        load::write(&report, &args.output)
+    });
+    match written {
+        Ok(paths) => {
+            for path in paths {
+                eprintln!("Wrote {}", path.display());
+            }
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Exits with 1 when there are regressions and 2 when the comparison could not be made
+fn run_compare(args: CompareArgs) {
+    let thresholds = compare::Thresholds {
+        throughput_percent: args.throughput_threshold,
+        latency_percent: args.latency_threshold,
+        min_latency_delta_us: args.min_latency_delta_us,
+    };
+    let loaded = compare::load(&args.baseline).and_then(|baseline| Ok((baseline, compare::load(&args.candidate)?)));
+    let (baseline, candidate) = match loaded {
+        Ok(reports) => reports,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let comparison = compare::compare(
+        &baseline,
+        &candidate,
+        &args.baseline.display().to_string(),
+        &args.candidate.display().to_string(),
+        thresholds,
+    );
+    print!("{}", compare::to_markdown(&comparison, args.all));
+
+    if let Some(path) = &args.json {
+        let json = serde_json::to_string_pretty(&comparison).expect("comparison serializes");
+        if let Err(e) = std::fs::write(path, json) {
+            eprintln!("Failed to write {}: {}", path.display(), e);
+            std::process::exit(2);
+        }
+    }
+    if comparison.regressions() > 0 {
+        std::process::exit(1);
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args = BenchArgs::parse();
+    pretty_env_logger::init();
+
+    match args.command {
+        Some(BenchCommand::Sweep(sweep)) => run_sweep(sweep).await,
+        Some(BenchCommand::Compare(compare)) => run_compare(compare),
+        Some(BenchCommand::Load(load)) => run_load(load).await,
+        None => run_once(args.run).await,
+    }
+}
@@ -0,0 +1,102 @@
+//! Sweep reports as JSON, CSV and Markdown
+
+use crate::sweep::{RunResult, SweepReport};
+use std::path::{Path, PathBuf};
+
+/// Measured values of one run, in report column order; `None` where nothing was measured
+pub fn metrics(run: &RunResult) -> Vec<(&'static str, Option<f64>)> {
+    let report = run.report.as_ref();
+    let round_trip = |key: &str| run.round_trip.as_ref().and_then(|r| r.get(key)).and_then(|v| v.as_f64());
+    let latency = |count: Option<usize>, value: Option<f64>| value.filter(|_| count.unwrap_or(0) > 0);
+    vec![
+        ("messages_per_sec", report.map(|r| r.messages_per_sec)),
+        ("rows_per_sec", report.map(|r| r.rows_per_sec)),
+        ("mib_per_sec", report.map(|r| r.bytes_per_sec / (1024.0 * 1024.0))),
+        ("decode_p50_us", report.map(|r| r.decode.p50_us)),
+        ("decode_p99_us", report.map(|r| r.decode.p99_us)),
+        ("one_way_p50_us", latency(report.map(|r| r.latency.one_way.count), report.map(|r| r.latency.one_way.p50_us))),
+        ("one_way_p99_us", latency(report.map(|r| r.latency.one_way.count), report.map(|r| r.latency.one_way.p99_us))),
+        ("end_to_end_p50_us", latency(report.map(|r| r.latency.end_to_end.count), report.map(|r| r.latency.end_to_end.p50_us))),
+        ("end_to_end_p99_us", latency(report.map(|r| r.latency.end_to_end.count), report.map(|r| r.latency.end_to_end.p99_us))),
+        ("round_trip_p50_us", round_trip("p50_us").filter(|_| round_trip("count").unwrap_or(0.0) > 0.0)),
+        ("round_trip_p99_us", round_trip("p99_us").filter(|_| round_trip("count").unwrap_or(0.0) > 0.0)),
+        ("missing_messages", report.map(|r| r.latency.missing_sequences as f64)),
+        ("decode_errors", report.map(|r| r.decode_errors as f64)),
+        ("dropped_batches", report.map(|r| r.dropped_batches as f64)),
+        ("gaps", report.filter(|r| r.gaps.interval.is_some()).map(|r| r.gaps.gaps as f64)),
+        ("cpu_percent", run.resources.as_ref().map(|r| r.cpu_percent)),
+        ("rss_peak_mib", run.resources.as_ref().map(|r| r.rss_peak_mb)),
+    ]
+}
+
+fn format_value(value: Option<f64>) -> String {
+    match value {
+        Some(value) if value.fract() == 0.0 => format!("{}", value),
+        Some(value) => format!("{:.1}", value),
+        None => String::new(),
+    }
+}
+
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+/// Header and rows shared by the CSV and Markdown reports
+fn table(report: &SweepReport) -> (Vec<String>, Vec<Vec<String>>) {
+    let mut header = report.axes.clone();
+    if let Some(first) = report.runs.first() {
+        header.extend(metrics(first).iter().map(|(name, _)| name.to_string()));
+    }
+    header.push("error".to_string());
+
+    let rows = report
+        .runs
+        .iter()
+        .map(|run| {
+            let mut row: Vec<String> = run.settings.iter().map(|(_, value)| value.clone()).collect();
+            row.extend(metrics(run).into_iter().map(|(_, value)| format_value(value)));
+            row.push(run.error.clone().unwrap_or_default());
+            row
+        })
+        .collect();
+    (header, rows)
+}
+
+pub fn to_csv(report: &SweepReport) -> String {
+    let (header, rows) = table(report);
+    std::iter::once(header)
+        .chain(rows)
+        .map(|row| row.iter().map(|field| csv_field(field)).collect::<Vec<_>>().join(",") + "\n")
+        .collect()
+}
+
+pub fn to_markdown(report: &SweepReport) -> String {
+    let (header, rows) = table(report);
+    let mut markdown = format!("# Sweep `{}`\n\n{} runs, started at {} (Unix time)\n\n", report.name, report.runs.len(), report.started_at);
+    markdown += &format!("| {} |\n", header.join(" | "));
+    markdown += &format!("|{}\n", "---|".repeat(header.len()));
+    for row in rows {
+        markdown += &format!("| {} |\n", row.iter().map(|field| field.replace('|', "\\|")).collect::<Vec<_>>().join(" | "));
+    }
+    markdown
+}
+
+/// Write `<name>.json`, `<name>.csv` and `<name>.md` into `directory`, returning their paths
+pub fn write(report: &SweepReport, directory: &Path) -> Result<Vec<PathBuf>, String> {
+    std::fs::create_dir_all(directory).map_err(|e| format!("Failed to create {}: {}", directory.display(), e))?;
+    let json = serde_json::to_string_pretty(report).map_err(|e| e.to_string())?;
+    let files = [("json", json), ("csv", to_csv(report)), ("md", to_markdown(report))];
+
+    files
+        .into_iter()
+        .map(|(extension, content)| {
+            let path = directory.join(format!("{}.{}", report.name, extension));
+            std::fs::write(&path, content).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+            Ok(path)
+        })
+        .collect()
+}
@@ -0,0 +1,222 @@
+//! Measurements collected while receiving: timing distributions and gaps in the data
+
+use arrow::array::{Array, Int64Array};
+use arrow::compute::cast;
+use arrow::datatypes::DataType;
+use arrow::record_batch::RecordBatch;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Every sample of one measured quantity, summarized at the end of the run
+#[derive(Debug, Default)]
+pub struct Distribution {
+    samples_us: Vec<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DistributionSummary {
+    pub count: usize,
+    pub mean_us: f64,
+    pub min_us: f64,
+    pub p50_us: f64,
+    pub p90_us: f64,
+    pub p99_us: f64,
+    pub max_us: f64,
+}
+
+impl Distribution {
+    pub fn record(&mut self, duration: Duration) {
+        self.record_us(duration.as_secs_f64() * 1e6);
+    }
+
+    pub fn record_us(&mut self, microseconds: f64) {
+        self.samples_us.push(microseconds);
+    }
+
+    /// Add every sample of `other`, e.g. to summarize many connections together
+    pub fn extend(&mut self, other: &Distribution) {
+        self.samples_us.extend_from_slice(&other.samples_us);
+    }
+
+    pub fn summary(&self) -> DistributionSummary {
+        let mut sorted = self.samples_us.clone();
+        sorted.sort_by(f64::total_cmp);
+        let percentile = |p: f64| match sorted.len() {
+            0 => 0.0,
+            n => sorted[((n - 1) as f64 * p).round() as usize],
+        };
+        DistributionSummary {
+            count: sorted.len(),
+            mean_us: if sorted.is_empty() { 0.0 } else { sorted.iter().sum::<f64>() / sorted.len() as f64 },
+            min_us: percentile(0.0),
+            p50_us: percentile(0.5),
+            p90_us: percentile(0.9),
+            p99_us: percentile(0.99),
+            max_us: percentile(1.0),
+        }
+    }
+}
+
+/// Finds gaps in an increasing timestamp column, across batch boundaries.
+///
+/// The expected spacing is the known one if given, otherwise the smallest positive step of
+/// the whole run; a step more than `factor` times that is a gap. Durations are in the column's
+/// own units.
+#[derive(Debug)]
+pub struct GapTracker {
+    column: String,
+    factor: f64,
+    last: Option<i64>,
+    interval: Option<i64>,
+    steps: BTreeMap<i64, u64>,      // how often each positive step was seen, few distinct ones at a fixed rate
+    out_of_order: u64,
+    batches_without_column: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GapSummary {
+    pub column: String,
+    pub interval: Option<i64>,          // expected spacing between rows
+    pub gaps: u64,
+    pub largest_gap: i64,
+    pub total_gap: i64,                 // time covered by gaps beyond the expected spacing
+    pub out_of_order: u64,              // rows older than the row before them
+    pub batches_without_column: u64,
+}
+
+impl GapTracker {
+    pub fn new(column: &str, factor: f64) -> Self {
+        Self {
+            column: column.to_string(),
+            factor,
+            last: None,
+            interval: None,
+            steps: BTreeMap::new(),
+            out_of_order: 0,
+            batches_without_column: 0,
+        }
+    }
+
+    /// Expect rows this far apart, e.g. from the generator's rate, instead of the smallest step
+    pub fn with_interval(mut self, interval: Option<i64>) -> Self {
+        self.interval = interval.filter(|interval| *interval > 0);
+        self
+    }
+
+    pub fn observe(&mut self, batch: &RecordBatch) {
+        let Some(values) = batch
+            .column_by_name(&self.column)
+            .and_then(|column| cast(column, &DataType::Int64).ok())
+        else {
+            self.batches_without_column += 1;
+            return;
+        };
+        let values = values.as_any().downcast_ref::<Int64Array>().expect("cast to Int64");
+
+        for value in values.iter().flatten() {
+            if let Some(last) = self.last {
+                let step = value - last;
+                if step < 0 {
+                    self.out_of_order += 1;
+                } else if step > 0 {
+                    *self.steps.entry(step).or_default() += 1;
+                }
+            }
+            self.last = Some(value);
+        }
+    }
+
+    pub fn summary(&self) -> GapSummary {
+        let interval = self.interval.or_else(|| self.steps.keys().next().copied());
+        let (mut gaps, mut largest, mut total) = (0, 0, 0);
+        if let Some(interval) = interval {
+            for (&step, &count) in self.steps.iter().filter(|(step, _)| **step as f64 > self.factor * interval as f64) {
+                gaps += count;
+                largest = step;
+                total += (step - interval) * count as i64;
+            }
+        }
+        GapSummary {
+            column: self.column.clone(),
+            interval,
+            gaps,
+            largest_gap: largest,
+            total_gap: total,
+            out_of_order: self.out_of_order,
+            batches_without_column: self.batches_without_column,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{StringArray, UInt64Array};
+    use std::sync::Arc;
+
+    fn timestamps(values: &[u64]) -> RecordBatch {
+        RecordBatch::try_from_iter([("timestamp", Arc::new(UInt64Array::from(values.to_vec())) as _)]).unwrap()
+    }
+
+    #[test]
+    fn summarizes_percentiles_of_the_samples() {
+        let mut distribution = Distribution::default();
+        for sample in (1..=100).rev() {
+            distribution.record_us(sample as f64);
+        }
+        let summary = distribution.summary();
+        assert_eq!(summary.count, 100);
+        assert_eq!(summary.mean_us, 50.5);
+        assert_eq!((summary.min_us, summary.max_us), (1.0, 100.0));
+        // Nearest rank of (count - 1) * p, rounded
+        assert_eq!((summary.p50_us, summary.p90_us, summary.p99_us), (51.0, 90.0, 99.0));
+
+        let mut merged = Distribution::default();
+        merged.record(Duration::from_millis(2));
+        merged.extend(&distribution);
+        assert_eq!((merged.summary().count, merged.summary().max_us), (101, 2000.0));
+    }
+
+    #[test]
+    fn an_empty_distribution_summarizes_to_zero() {
+        let summary = Distribution::default().summary();
+        assert_eq!(summary.count, 0);
+        assert_eq!((summary.mean_us, summary.p50_us, summary.max_us), (0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn finds_gaps_across_batches() {
+        let mut tracker = GapTracker::new("timestamp", 2.0);
+        tracker.observe(&timestamps(&[0, 10, 20, 50]));
+        tracker.observe(&timestamps(&[60, 55, 70, 90]));
+        tracker.observe(&RecordBatch::try_from_iter([("name", Arc::new(StringArray::from(vec!["a"])) as _)]).unwrap());
+        let summary = tracker.summary();
+        assert_eq!(summary.interval, Some(10));
+        // 20 -> 50 is a gap, 70 -> 90 is exactly twice the interval and not one
+        assert_eq!((summary.gaps, summary.largest_gap, summary.total_gap), (1, 30, 20));
+        assert_eq!((summary.out_of_order, summary.batches_without_column), (1, 1));
+    }
+
+    #[test]
+    fn gaps_before_the_smallest_step_still_count() {
+        // The first step is itself a gap, the spacing only shows later
+        let mut tracker = GapTracker::new("timestamp", 2.0);
+        tracker.observe(&timestamps(&[0, 100, 110, 120, 130]));
+        let summary = tracker.summary();
+        assert_eq!(summary.interval, Some(10));
+        assert_eq!((summary.gaps, summary.largest_gap, summary.total_gap), (1, 100, 90));
+    }
+
+    #[test]
+    fn a_known_interval_replaces_the_smallest_step() {
+        let mut tracker = GapTracker::new("timestamp", 2.0).with_interval(Some(20));
+        tracker.observe(&timestamps(&[0, 20, 40, 100, 101]));
+        let summary = tracker.summary();
+        assert_eq!(summary.interval, Some(20));
+        assert_eq!((summary.gaps, summary.largest_gap, summary.total_gap), (1, 60, 40));
+
+        let empty = GapTracker::new("timestamp", 2.0).with_interval(Some(0)).summary();
+        assert_eq!((empty.interval, empty.gaps), (None, 0));
+    }
+}
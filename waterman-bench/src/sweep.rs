@@ -0,0 +1,289 @@
+//! Parameter sweeps: run the benchmark client once for every combination of a
+//! matrix of bridge parameters and connection options, described in YAML
+
+use crate::bridge::{Bridge, BridgeConfig, ResourceMonitor, ResourceUsage};
+use crate::client::{self, BenchOptions, Report};
+use crate::http;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Matrix axes that pick `/ws` query parameters rather than bridge parameters
+const CONNECTION_AXES: [&str; 2] = ["encoding", "layout"];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Scenario {
+    pub name: String,
+    pub bridge: BridgeConfig,
+    pub topic: String,                  // generator topic to measure
+    pub duration_secs: Option<f64>,     // measure this long instead of the parameters' `test_duration`
+    pub settle_secs: f64,               // pause between runs
+    pub parameters: Value,              // overrides applied to every run, laid out like parameters.yaml
+    pub matrix: serde_yaml::Mapping,    // axis name -> values, every combination is run
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Self {
+            name: "sweep".to_string(),
+            bridge: BridgeConfig::default(),
+            topic: "synthetic".to_string(),
+            duration_secs: None,
+            settle_secs: 1.0,
+            parameters: Value::Null,
+            matrix: serde_yaml::Mapping::new(),
+        }
+    }
+}
+
+impl Scenario {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        serde_yaml::from_str(&text).map_err(|e| format!("Failed to parse {}: {}", path.display(), e))
+    }
+
+    /// Axis names and their values as JSON, in the order they were written
+    fn axes(&self) -> Result<Vec<(String, Vec<Value>)>, String> {
+        self.matrix
+            .iter()
+            .map(|(name, values)| {
+                let name = name.as_str().ok_or("Matrix axis names must be strings")?.to_string();
+                let values: Vec<Value> = match serde_json::to_value(values).map_err(|e| e.to_string())? {
+                    Value::Array(values) => values,
+                    value => vec![value],
+                };
+                if values.is_empty() {
+                    return Err(format!("Matrix axis '{}' has no values", name));
+                }
+                Ok((name, values))
+            })
+            .collect()
+    }
+}
+
+/// Every combination of the axes' values, the first axis varying slowest
+fn combinations(axes: &[(String, Vec<Value>)]) -> Vec<Vec<(String, Value)>> {
+    axes.iter().fold(vec![Vec::new()], |combinations, (name, values)| {
+        combinations
+            .into_iter()
+            .flat_map(|combination| {
+                values.iter().map(move |value| {
+                    let mut combination = combination.clone();
+                    combination.push((name.clone(), value.clone()));
+                    combination
+                })
+            })
+            .collect()
+    })
+}
+
+/// Merge `overrides` into `target`, object by object
+pub fn merge(target: &mut Value, overrides: &Value) {
+    match (target, overrides) {
+        (Value::Object(target), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                merge(target.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        },
+        (target, Value::Null) if !target.is_null() => {},
+        (target, overrides) => *target = overrides.clone(),
+    }
+}
+
+/// Set a parameter by its dotted path (`websocket.batch_size`) or plain field name (`batch_size`)
+fn set_parameter(parameters: &mut Value, name: &str, value: Value) -> Result<(), String> {
+    let (section, field) = match name.split_once('.') {
+        Some((section, field)) => (section.to_string(), field),
+        None => {
+            let section = parameters
+                .as_object()
+                .and_then(|sections| sections.iter().find(|(_, fields)| fields.get(name).is_some()))
+                .map(|(section, _)| section.clone())
+                .ok_or_else(|| format!("Unknown parameter '{}'", name))?;
+            (section, name)
+        },
+    };
+    let target = parameters
+        .get_mut(&section)
+        .and_then(|fields| fields.get_mut(field))
+        .ok_or_else(|| format!("Unknown parameter '{}'", name))?;
+    *target = value;
+    Ok(())
+}
+
+/// Seconds a `test_duration` stands for, as in the bridge
+fn test_duration_secs(parameters: &Value) -> Option<f64> {
+    match parameters.pointer("/data_volume/test_duration")?.as_str()? {
+        "Short" => Some(10.0),
+        "Medium" => Some(100.0),
+        "Long" => Some(1000.0),
+        "Hour" => Some(3600.0),
+        _ => None,
+    }
+}
+
+/// Spacing of the generator's millisecond timestamps, if it is at least one millisecond
+fn sample_interval_ms(parameters: &Value) -> Option<i64> {
+    let rate_hz = parameters.pointer("/data_volume/data_rate_hz")?.as_f64()?;
+    let interval_ms = (1000.0 / rate_hz).round();
+    (interval_ms.is_finite() && interval_ms >= 1.0).then_some(interval_ms as i64)
+}
+
+fn value_label(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        value => value.to_string(),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunResult {
+    pub settings: Vec<(String, String)>,    // axis name and value, in matrix order
+    pub report: Option<Report>,
+    pub round_trip: Option<Value>,          // the bridge's round-trip histogram summary
+    pub resources: Option<ResourceUsage>,   // of the bridge
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SweepReport {
+    pub name: String,
+    pub started_at: u64,        // seconds since the Unix epoch
+    pub axes: Vec<String>,
+    pub runs: Vec<RunResult>,
+}
+
+/// Parameters of the topic under test, as the bridge has them now
+pub async fn topic_parameters(bridge: &Bridge, topic: &str) -> Result<Value, String> {
+    let topics = http::get_json(&bridge.http, "/topics").await?;
+    topics
+        .as_array()
+        .and_then(|topics| topics.iter().find(|t| t["name"] == topic))
+        .and_then(|t| t.get("parameters").filter(|p| !p.is_null()))
+        .cloned()
+        .ok_or_else(|| format!("'{}' is not a generator topic", topic))
+}
+
+/// Measure one combination; the bridge keeps its parameters afterwards
+async fn run_combination(scenario: &Scenario, bridge: &Bridge, combination: &[(String, Value)]) -> Result<RunResult, String> {
+    let mut parameters = topic_parameters(bridge, &scenario.topic).await?;
+    merge(&mut parameters, &scenario.parameters);
+
+    let mut query = Vec::new();
+    for (name, value) in combination {
+        if CONNECTION_AXES.contains(&name.as_str()) {
+            query.push(format!("{}={}", name, value_label(value)));
+        } else {
+            set_parameter(&mut parameters, name, value.clone())?;
+        }
+    }
+
+    // An explicit duration replaces the test duration, so the generator must not stop early
+    let duration = match scenario.duration_secs {
+        Some(seconds) => {
+            set_parameter(&mut parameters, "data_volume.test_duration", Value::from("OpenEnded"))?;
+            seconds
+        },
+        None => test_duration_secs(&parameters).ok_or("An OpenEnded test duration needs `duration_secs`")?,
+    };
+    http::put_json(&bridge.http, &format!("/topics/{}/parameters", scenario.topic), &parameters).await?;
+
+    let mut url = format!("{}/ws/{}", bridge.ws, scenario.topic);
+    if !query.is_empty() {
+        url = format!("{}?{}", url, query.join("&"));
+    }
+    let options = BenchOptions {
+        url,
+        duration: Duration::from_secs_f64(duration),
+        progress_interval: None,
+        timestamp_column: "timestamp".to_string(),
+        gap_factor: 2.0,
+        expected_interval: sample_interval_ms(&parameters),
+        echo: true,
+    };
+
+    let monitor = bridge.pid.and_then(ResourceMonitor::start);
+    let report = client::run(&options).await?;
+    let resources = monitor.and_then(ResourceMonitor::finish);
+
+    let round_trip = match report.latency.connection_id {
+        Some(id) => http::get_json(&bridge.http, &format!("/latency/{}", id))
+            .await
+            .ok()
+            .and_then(|latency| latency.get("round_trip").cloned()),
+        None => None,
+    };
+
+    Ok(RunResult {
+        settings: combination.iter().map(|(name, value)| (name.clone(), value_label(value))).collect(),
+        report: Some(report),
+        round_trip,
+        resources,
+        error: None,
+    })
+}
+
+pub async fn run(scenario: &Scenario, output_dir: &Path) -> Result<SweepReport, String> {
+    let axes = scenario.axes()?;
+    let combinations = combinations(&axes);
+    let scratch_dir = output_dir.join(format!("{}-bridge", scenario.name));
+    info!("Sweep '{}': {} combinations", scenario.name, combinations.len());
+
+    // A running bridge gets its parameters back at the end
+    let original = match &scenario.bridge.url {
+        Some(_) => {
+            let bridge = Bridge::start(&scenario.bridge, &scratch_dir, &scratch_dir.join("bridge.log")).await?;
+            Some(topic_parameters(&bridge, &scenario.topic).await?)
+        },
+        None => None,
+    };
+
+    let mut report = SweepReport {
+        name: scenario.name.clone(),
+        started_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        axes: axes.iter().map(|(name, _)| name.clone()).collect(),
+        runs: Vec::new(),
+    };
+
+    for (index, combination) in combinations.iter().enumerate() {
+        let label: Vec<String> = combination.iter().map(|(name, value)| format!("{}={}", name, value_label(value))).collect();
+        eprintln!("[{}/{}] {}", index + 1, combinations.len(), label.join(" "));
+
+        let log_file = scratch_dir.join(format!("bridge-{}.log", index + 1));
+        let result = match Bridge::start(&scenario.bridge, &scratch_dir, &log_file).await {
+            Ok(bridge) => {
+                let result = run_combination(scenario, &bridge, combination).await;
+                bridge.stop().await;
+                result
+            },
+            Err(e) => Err(e),
+        };
+        let result = result.unwrap_or_else(|e| {
+            warn!("Run {} failed: {}", index + 1, e);
+            RunResult {
+                settings: combination.iter().map(|(name, value)| (name.clone(), value_label(value))).collect(),
+                report: None,
+                round_trip: None,
+                resources: None,
+                error: Some(e),
+            }
+        });
+        if let Some(run) = &result.report {
+            match run.latency.one_way.count {
+                0 => eprintln!("        {:.1} msg/s, {:.1} rows/s", run.messages_per_sec, run.rows_per_sec),
+                _ => eprintln!("        {:.1} msg/s, {:.1} rows/s, one way p50 {:.0} us", run.messages_per_sec, run.rows_per_sec, run.latency.one_way.p50_us),
+            }
+        }
+        report.runs.push(result);
+        tokio::time::sleep(Duration::from_secs_f64(scenario.settle_secs)).await;
+    }
+
+    if let Some(original) = original {
+        let bridge = Bridge::start(&scenario.bridge, &scratch_dir, &scratch_dir.join("bridge.log")).await?;
+        http::put_json(&bridge.http, &format!("/topics/{}/parameters", scenario.topic), &original).await?;
+    }
+    Ok(report)
+}
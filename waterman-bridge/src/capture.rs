@@ -0,0 +1,351 @@
+//! Raw WebSocket frame capture and replay.
+//!
+//! A capture file holds the exact bytes of every text and binary frame sent to one client:
+//!
+//! ```text
+//! header:  b"WMCAP\0\x02\0"  u64 LE capture start (unix ms)
+//! record:  u64 LE send time (us since capture start)  u8 frame type (0 binary, 1 text)
+//!          u32 LE length  <length bytes>
+//! ```
+//!
+//! Frames are handed to a blocking writer thread, so the send path never waits on the disk.
+
+use crate::paths::resolve_within;
+use futures::{Sink, SinkExt};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tokio::sync::{mpsc, oneshot};
+use warp::ws::Message;
+
+const CAPTURE_MAGIC: &[u8; 8] = b"WMCAP\0\x02\0";
+
+/// How many frames may queue up for the writer before new ones are dropped
+const CAPTURE_QUEUE: usize = 4096;
+
+const FRAME_BINARY: u8 = 0;
+const FRAME_TEXT: u8 = 1;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CaptureParams {
+    pub directory: String,      // under the server's capture directory; each capture session gets its own subdirectory
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CaptureStatus {
+    pub capturing: bool,
+    pub session_dir: Option<String>,
+    pub frames: u64,
+    pub bytes: u64,
+    pub files: Vec<String>,
+    pub errors: u64,
+}
+
+/// What the send path hands to the writer thread
+enum CaptureEvent {
+    Frame { connection_id: u64, sent: Instant, frame_type: u8, frame: Vec<u8> },
+    Closed { connection_id: u64 },
+}
+
+struct ActiveCapture {
+    feed: mpsc::Sender<CaptureEvent>,
+    done: oneshot::Receiver<()>,
+}
+
+/// Owns the open capture files; runs on a blocking thread since file writes are synchronous
+struct CaptureTask {
+    session_dir: PathBuf,
+    files: HashMap<u64, (BufWriter<File>, Instant)>,
+    status: Arc<Mutex<CaptureStatus>>,
+}
+
+/// Writes every frame sent by `ws_handler` to one capture file per connection while active
+pub struct SessionCapture {
+    root: PathBuf,      // set on the command line; captures are written and replayed only below it
+    active: Mutex<Option<ActiveCapture>>,
+    status: Arc<Mutex<CaptureStatus>>,
+}
+
+pub type CaptureHandle = Arc<SessionCapture>;
+
+impl std::fmt::Debug for SessionCapture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionCapture").field("status", &self.status()).finish()
+    }
+}
+
+fn unix_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+impl CaptureTask {
+    fn write(&mut self, connection_id: u64, sent: Instant, frame_type: u8, frame: &[u8]) -> std::io::Result<()> {
+        if !self.files.contains_key(&connection_id) {
+            let path = self.session_dir.join(format!("conn-{:04}.wmcap", connection_id));
+            let mut file = BufWriter::new(File::create(&path)?);
+            // The first frame marks the start; it may have waited in the queue for a moment
+            let started_ms = unix_ms().saturating_sub(sent.elapsed().as_millis() as u64);
+            file.write_all(CAPTURE_MAGIC)?;
+            file.write_all(&started_ms.to_le_bytes())?;
+            self.files.insert(connection_id, (file, sent));
+            self.status.lock().unwrap().files.push(path.display().to_string());
+        }
+
+        let (file, started) = self.files.get_mut(&connection_id).unwrap();
+        file.write_all(&(sent.duration_since(*started).as_micros() as u64).to_le_bytes())?;
+        file.write_all(&[frame_type])?;
+        file.write_all(&(frame.len() as u32).to_le_bytes())?;
+        file.write_all(frame)
+    }
+
+    fn close(connection_id: u64, mut file: BufWriter<File>) {
+        if let Err(e) = file.flush() {
+            error!("Failed to flush capture for connection {}: {}", connection_id, e);
+        }
+    }
+
+    /// Write frames until `feed` closes, then flush every file
+    fn run(mut self, mut feed: mpsc::Receiver<CaptureEvent>) {
+        while let Some(event) = feed.blocking_recv() {
+            match event {
+                CaptureEvent::Frame { connection_id, sent, frame_type, frame } => {
+                    let result = self.write(connection_id, sent, frame_type, &frame);
+                    let mut status = self.status.lock().unwrap();
+                    match result {
+                        Ok(()) => {
+                            status.frames += 1;
+                            status.bytes += frame.len() as u64;
+                        },
+                        Err(e) => {
+                            error!("Failed to capture frame for connection {}: {}", connection_id, e);
+                            status.errors += 1;
+                        }
+                    }
+                },
+                CaptureEvent::Closed { connection_id } => {
+                    if let Some((file, _)) = self.files.remove(&connection_id) {
+                        Self::close(connection_id, file);
+                    }
+                },
+            }
+        }
+
+        for (connection_id, (file, _)) in self.files.drain() {
+            Self::close(connection_id, file);
+        }
+    }
+}
+
+impl SessionCapture {
+    pub fn new(root: &str) -> Self {
+        Self {
+            root: PathBuf::from(root),
+            active: Mutex::new(None),
+            status: Arc::new(Mutex::new(CaptureStatus::default())),
+        }
+    }
+
+    /// Directory all captures live in
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn status(&self) -> CaptureStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    pub fn start(&self, params: CaptureParams) -> std::result::Result<CaptureStatus, String> {
+        let mut active = self.active.lock().unwrap();
+        if active.is_some() {
+            return Err("A capture is already running".to_string());
+        }
+
+        let Some(directory) = resolve_within(&self.root, &params.directory) else {
+            return Err(format!("Invalid directory '{}': must be relative, without '..'", params.directory));
+        };
+        let session_dir = directory.join(unix_ms().to_string());
+        std::fs::create_dir_all(&session_dir)
+            .map_err(|e| format!("Failed to create {}: {}", session_dir.display(), e))?;
+
+        *self.status.lock().unwrap() = CaptureStatus {
+            capturing: true,
+            session_dir: Some(session_dir.display().to_string()),
+            ..Default::default()
+        };
+        let (feed_tx, feed) = mpsc::channel(CAPTURE_QUEUE);
+        let (done_tx, done_rx) = oneshot::channel();
+        let task = CaptureTask {
+            session_dir,
+            files: HashMap::new(),
+            status: self.status.clone(),
+        };
+        tokio::task::spawn_blocking(move || {
+            task.run(feed);
+            let _ = done_tx.send(());
+        });
+
+        *active = Some(ActiveCapture {
+            feed: feed_tx,
+            done: done_rx,
+        });
+        info!("Frame capture started");
+        Ok(self.status())
+    }
+
+    /// Stop the running capture and wait until every file is flushed
+    pub async fn stop(&self) -> std::result::Result<CaptureStatus, String> {
+        let active = self.active.lock().unwrap().take();
+        let Some(active) = active else {
+            return Err("No capture is running".to_string());
+        };
+
+        drop(active.feed);
+        let _ = active.done.await;
+        self.status.lock().unwrap().capturing = false;
+        info!("Frame capture stopped");
+        Ok(self.status())
+    }
+
+    fn send(&self, event: CaptureEvent) {
+        let active = self.active.lock().unwrap();
+        let Some(capture) = active.as_ref() else { return };
+        if let Err(e) = capture.feed.try_send(event) {
+            if let mpsc::error::TrySendError::Full(CaptureEvent::Frame { connection_id, .. }) = e {
+                error!("Capture queue is full, dropping a frame for connection {}", connection_id);
+            }
+            self.status.lock().unwrap().errors += 1;
+        }
+    }
+
+    /// Queue a frame sent to `connection_id` for its capture file, opened on first use
+    pub fn record(&self, connection_id: u64, message: &Message) {
+        let frame_type = if message.is_text() {
+            FRAME_TEXT
+        } else if message.is_binary() {
+            FRAME_BINARY
+        } else {
+            return;
+        };
+
+        self.send(CaptureEvent::Frame {
+            connection_id,
+            sent: Instant::now(),
+            frame_type,
+            frame: message.as_bytes().to_vec(),
+        });
+    }
+
+    /// Flush and close the capture file of a connection that went away
+    pub fn close_connection(&self, connection_id: u64) {
+        self.send(CaptureEvent::Closed { connection_id });
+    }
+}
+
+/// Re-send the frames of a capture file with their original spacing
+pub async fn replay_capture<S>(path: &Path, sink: &mut S) -> std::result::Result<u64, String>
+where
+    S: Sink<Message> + Unpin,
+    S::Error: std::fmt::Display,
+{
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+
+    let mut header = [0u8; 16];
+    file.read_exact(&mut header).await.map_err(|e| format!("Failed to read header: {}", e))?;
+    if &header[..8] != CAPTURE_MAGIC {
+        return Err(format!("{} is not a capture file", path.display()));
+    }
+
+    let start = tokio::time::Instant::now();
+    let mut frames = 0u64;
+    let mut record_header = [0u8; 13];
+    loop {
+        match file.read_exact(&mut record_header).await {
+            Ok(_) => {},
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(format!("Failed to read record: {}", e)),
+        }
+
+        let send_us = u64::from_le_bytes(record_header[..8].try_into().unwrap());
+        let frame_type = record_header[8];
+        let len = u32::from_le_bytes(record_header[9..].try_into().unwrap()) as usize;
+        let mut frame = vec![0u8; len];
+        file.read_exact(&mut frame).await.map_err(|e| format!("Truncated record: {}", e))?;
+
+        let message = match frame_type {
+            FRAME_TEXT => Message::text(
+                String::from_utf8(frame).map_err(|_| "Text frame is not valid UTF-8".to_string())?,
+            ),
+            FRAME_BINARY => Message::binary(frame),
+            other => return Err(format!("Unknown frame type {}", other)),
+        };
+
+        tokio::time::sleep_until(start + Duration::from_micros(send_us)).await;
+        sink.send(message).await.map_err(|e| format!("Client went away: {}", e))?;
+        frames += 1;
+    }
+
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn frames_replay_as_captured() {
+        let root = std::env::temp_dir().join(format!("wmcap-test-{}-{}", std::process::id(), unix_ms()));
+        let capture = SessionCapture::new(&root.to_string_lossy());
+        assert!(capture.start(CaptureParams { directory: "../elsewhere".to_string() }).is_err());
+        capture.start(CaptureParams::default()).unwrap();
+
+        let sent = vec![
+            Message::binary(vec![0u8, 1, 2, 255]),
+            Message::text(r#"{"type":"lagged","skipped":3}"#),
+            Message::ping(Vec::new()),
+            Message::binary(Vec::new()),
+        ];
+        for message in &sent {
+            capture.record(7, message);
+        }
+        capture.record(8, &sent[0]);
+        capture.close_connection(8);
+        let status = capture.stop().await.unwrap();
+        assert!(!status.capturing);
+        assert_eq!((status.frames, status.errors), (4, 0));
+        assert_eq!(status.files.len(), 2);
+        capture.record(7, &sent[0]);
+        assert_eq!(capture.status().frames, 4);
+
+        let (mut sink, received) = futures::channel::mpsc::unbounded();
+        let frames = replay_capture(Path::new(&status.files[0]), &mut sink).await.unwrap();
+        drop(sink);
+        let received: Vec<Message> = futures::StreamExt::collect(received).await;
+
+        assert_eq!(frames, 3);
+        let expected: Vec<&Message> = sent.iter().filter(|m| !m.is_ping()).collect();
+        assert_eq!(received.len(), expected.len());
+        for (got, want) in received.iter().zip(expected) {
+            assert_eq!(got.is_text(), want.is_text());
+            assert_eq!(got.as_bytes(), want.as_bytes());
+        }
+
+        // Files from before frame types were recorded are refused
+        let old = root.join("old.wmcap");
+        std::fs::write(&old, b"WMCAP\0\x01\0\0\0\0\0\0\0\0\0").unwrap();
+        assert!(replay_capture(&old, &mut futures::sink::drain()).await.is_err());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
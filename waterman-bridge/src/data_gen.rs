@@ -1,13 +1,10 @@
-use arrow::array::{Array, Float32Array, Float64Array, Int32Array, UInt64Array, BooleanArray, ListArray, StructArray};
+use arrow::array::{Array, Float32Array, Float64Array, Int32Array, UInt64Array, BooleanArray, StructArray};
 use arrow::datatypes::{Field, Schema, DataType, Fields};
 use arrow::record_batch::RecordBatch;
 use crate::parameters::{DataType as WsDataType, SignalPattern, Parameters};
 use std::sync::Arc;
-use std::f64::consts::PI;
-use std::collections::HashMap;
 use rand::Rng;
-use log::{debug, error, info};
-use arrow::array::ArrayRef;
+use log::{debug, error};
 use arrow::array::builder::ListBuilder;
 use arrow::array::builder::Float64Builder;
 
@@ -15,22 +12,16 @@ pub struct DataGenerator {
     fields: Vec<Field>,
     pub parameters: Option<Parameters>,
     time_counter: f64,
+    sample_clock: Option<(f64, f64)>,   // Unix ms of the first row and ms between rows, when sampling
 }
 
 impl DataGenerator {
-    pub fn new() -> Self {
-        Self {
-            fields: Self::default_fields(),
-            parameters: None,
-            time_counter: 0.0,
-        }
-    }
-
     pub fn with_parameters(parameters: Parameters) -> Self {
         let mut generator = Self {
             fields: Vec::new(),
             parameters: Some(parameters.clone()),
             time_counter: 0.0,
+            sample_clock: None,
         };
         
         // Configure fields based on parameters
@@ -69,22 +60,6 @@ impl DataGenerator {
         }
     }
     
-    pub fn default_fields() -> Vec<Field> {
-        vec![
-            Field::new("timestamp", DataType::UInt64, false),
-            Field::new("float_value", DataType::Float64, false),
-            Field::new("int_value", DataType::Int32, false),
-        ]
-    }
-
-    pub fn clear_fields(&mut self) {
-        self.fields.clear();
-    }
-
-    pub fn add_field(&mut self, name: &str, data_type: DataType) {
-        self.fields.push(Field::new(name, data_type, false));
-    }
-
     pub fn generate_record_batch(&mut self, num_rows: usize) -> Result<RecordBatch, arrow::error::ArrowError> {
         // If parameters are available, use them to configure fields
         if let Some(params) = &self.parameters {
@@ -131,14 +106,26 @@ impl DataGenerator {
         let result = RecordBatch::try_new(Arc::new(schema), arrays);
         
         match &result {
-            Ok(_) => info!("Successfully generated record batch with {} rows", num_rows),
+            Ok(_) => debug!("Successfully generated record batch with {} rows", num_rows),
             Err(e) => error!("Failed to create record batch: {}", e),
         }
         
         result
     }
     
+    /// Rows sampled at fixed times: row `i` is stamped `first_ms + i * interval_ms`
+    pub fn generate_samples(&mut self, first_ms: f64, interval_ms: f64, num_rows: usize) -> Result<RecordBatch, arrow::error::ArrowError> {
+        self.sample_clock = Some((first_ms, interval_ms));
+        let result = self.generate_record_batch(num_rows);
+        self.sample_clock = None;
+        result
+    }
+    
     fn get_timestamp(&self, row_index: usize) -> u64 {
+        if let Some((first_ms, interval_ms)) = self.sample_clock {
+            return (first_ms + row_index as f64 * interval_ms) as u64;
+        }
+
         // Generate timestamp based on row index
         let base_time = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
@@ -268,12 +255,12 @@ impl DataGenerator {
         (0..num_rows).map(|i| i as u64).collect()
     }
     
-    fn generate_list_array(&self, num_rows: usize, inner_field: &Arc<Field>) -> Result<Arc<dyn Array>, arrow::error::ArrowError> {
+    fn generate_list_array(&self, num_rows: usize, _inner_field: &Arc<Field>) -> Result<Arc<dyn Array>, arrow::error::ArrowError> {
         // Define constant for list size
         const LIST_SIZE: usize = 10;
         
         // Create a builder for the list values (Float64)
-        let mut values_builder = Float64Builder::with_capacity(num_rows * LIST_SIZE);
+        let values_builder = Float64Builder::with_capacity(num_rows * LIST_SIZE);
         
         // Create a builder for the list offsets
         let mut list_builder = ListBuilder::new(values_builder);
@@ -396,7 +383,7 @@ impl DataGenerator {
         
         // Create child data
         let child_data: Vec<(Arc<Field>, Arc<dyn Array>)> = struct_fields.into_iter()
-            .zip(child_arrays.into_iter())
+            .zip(child_arrays)
             .collect();
         
         // Create a struct array from the field arrays
@@ -406,7 +393,7 @@ impl DataGenerator {
     
     // Signal pattern generators
     fn sine_wave_value(&self, index: usize, amplitude: f64) -> f64 {
-        let adj_index = (self.time_counter + index as f64) as f64;
+        let adj_index = self.time_counter + index as f64;
         amplitude * (adj_index * 0.1).sin()
     }
     
@@ -416,7 +403,7 @@ impl DataGenerator {
     }
     
     fn step_function_value(&self, index: usize, amplitude: f64) -> f64 {
-        let adj_index = (self.time_counter + index as f64) as f64;
+        let adj_index = self.time_counter + index as f64;
         if (adj_index / 10.0).floor() % 2.0 == 0.0 {
             amplitude
         } else {
@@ -425,7 +412,7 @@ impl DataGenerator {
     }
     
     fn impulse_response_value(&self, index: usize, amplitude: f64) -> f64 {
-        let adj_index = (self.time_counter + index as f64) as f64;
+        let adj_index = self.time_counter + index as f64;
         let x = adj_index % 100.0;
         if x < 1.0 {
             amplitude
@@ -435,7 +422,7 @@ impl DataGenerator {
     }
     
     fn mixed_pattern_value(&self, index: usize, amplitude: f64) -> f64 {
-        let adj_index = (self.time_counter + index as f64) as f64;
+        let adj_index = self.time_counter + index as f64;
         let sine = (adj_index * 0.1).sin();
         let noise = rand::thread_rng().gen_range(-0.2..0.2);
         amplitude * (sine + noise)
@@ -0,0 +1,398 @@
+//! Alternate wire encodings of a `RecordBatch` (JSON, MessagePack, CBOR) as
+//! baselines for Arrow IPC, and the per-encoding serialization statistics.
+//!
+//! The row-oriented payload is an array of objects, `[{"timestamp": 1, "channel_0": 0.5}, ...]`;
+//! the column-oriented payload is one object of arrays, `{"timestamp": [1, ...], "channel_0": [0.5, ...]}`.
+//! Both are serialized straight from the Arrow arrays, without an intermediate document.
+
+use crate::ipc::encode_ipc_file;
+use arrow::array::{Array, ArrayRef, AsArray};
+use arrow::datatypes::*;
+use arrow::record_batch::RecordBatch;
+use arrow::util::display::{ArrayFormatter, FormatOptions};
+use serde::ser::{SerializeMap, SerializeSeq};
+use serde::{Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Encoding {
+    Arrow,      // Arrow IPC file, binary frames
+    Json,       // text frames
+    Msgpack,    // binary frames
+    Cbor,       // binary frames
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Layout {
+    Rows,       // array of row objects
+    Columns,    // object of column arrays
+}
+
+impl Encoding {
+    pub const ALL: [Encoding; 4] = [Encoding::Arrow, Encoding::Json, Encoding::Msgpack, Encoding::Cbor];
+
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "arrow" => Ok(Encoding::Arrow),
+            "json" => Ok(Encoding::Json),
+            "msgpack" => Ok(Encoding::Msgpack),
+            "cbor" => Ok(Encoding::Cbor),
+            other => Err(format!("Unknown encoding '{}', expected arrow, json, msgpack or cbor", other)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Arrow => "arrow",
+            Encoding::Json => "json",
+            Encoding::Msgpack => "msgpack",
+            Encoding::Cbor => "cbor",
+        }
+    }
+}
+
+impl Layout {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "rows" => Ok(Layout::Rows),
+            "columns" => Ok(Layout::Columns),
+            other => Err(format!("Unknown layout '{}', expected rows or columns", other)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Layout::Rows => "rows",
+            Layout::Columns => "columns",
+        }
+    }
+}
+
+/// A serialized batch, ready to become a WebSocket frame
+#[derive(Debug)]
+pub enum Encoded {
+    Binary(Vec<u8>),
+    Text(String),
+}
+
+impl Encoded {
+    pub fn len(&self) -> usize {
+        match self {
+            Encoded::Binary(bytes) => bytes.len(),
+            Encoded::Text(text) => text.len(),
+        }
+    }
+}
+
+/// One value of `array`
+struct Cell<'a>(&'a dyn Array, usize);
+
+/// All values of `array`, in order
+struct Column<'a>(&'a dyn Array);
+
+struct Rows<'a>(&'a RecordBatch);
+
+struct Columns<'a>(&'a RecordBatch);
+
+/// The fields of row `row` of a struct column
+struct StructRow<'a>(&'a arrow::array::StructArray, usize);
+
+impl Serialize for Cell<'_> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let Cell(array, row) = *self;
+        if array.is_null(row) {
+            return s.serialize_none();
+        }
+        match array.data_type() {
+            DataType::Boolean => s.serialize_bool(array.as_boolean().value(row)),
+            DataType::Int8 => s.serialize_i8(array.as_primitive::<Int8Type>().value(row)),
+            DataType::Int16 => s.serialize_i16(array.as_primitive::<Int16Type>().value(row)),
+            DataType::Int32 => s.serialize_i32(array.as_primitive::<Int32Type>().value(row)),
+            DataType::Int64 => s.serialize_i64(array.as_primitive::<Int64Type>().value(row)),
+            DataType::UInt8 => s.serialize_u8(array.as_primitive::<UInt8Type>().value(row)),
+            DataType::UInt16 => s.serialize_u16(array.as_primitive::<UInt16Type>().value(row)),
+            DataType::UInt32 => s.serialize_u32(array.as_primitive::<UInt32Type>().value(row)),
+            DataType::UInt64 => s.serialize_u64(array.as_primitive::<UInt64Type>().value(row)),
+            DataType::Float32 => s.serialize_f32(array.as_primitive::<Float32Type>().value(row)),
+            DataType::Float64 => s.serialize_f64(array.as_primitive::<Float64Type>().value(row)),
+            DataType::Utf8 => s.serialize_str(array.as_string::<i32>().value(row)),
+            DataType::LargeUtf8 => s.serialize_str(array.as_string::<i64>().value(row)),
+            DataType::List(_) => Column(array.as_list::<i32>().value(row).as_ref()).serialize(s),
+            DataType::LargeList(_) => Column(array.as_list::<i64>().value(row).as_ref()).serialize(s),
+            DataType::FixedSizeList(..) => Column(array.as_fixed_size_list().value(row).as_ref()).serialize(s),
+            DataType::Struct(_) => StructRow(array.as_struct(), row).serialize(s),
+            // Timestamps, decimals, ...: as Arrow displays them
+            _ => {
+                let formatter = ArrayFormatter::try_new(array, &FormatOptions::default()).map_err(serde::ser::Error::custom)?;
+                s.serialize_str(&formatter.value(row).to_string())
+            },
+        }
+    }
+}
+
+impl Serialize for Column<'_> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let mut seq = s.serialize_seq(Some(self.0.len()))?;
+        for row in 0..self.0.len() {
+            seq.serialize_element(&Cell(self.0, row))?;
+        }
+        seq.end()
+    }
+}
+
+impl Serialize for StructRow<'_> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let StructRow(array, row) = *self;
+        let mut map = s.serialize_map(Some(array.num_columns()))?;
+        for (name, column) in array.column_names().into_iter().zip(array.columns()) {
+            map.serialize_entry(name, &Cell(column.as_ref(), row))?;
+        }
+        map.end()
+    }
+}
+
+impl Serialize for Rows<'_> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let batch = self.0;
+        let schema = batch.schema();
+        let mut seq = s.serialize_seq(Some(batch.num_rows()))?;
+        for row in 0..batch.num_rows() {
+            seq.serialize_element(&RowFields(&schema, batch.columns(), row))?;
+        }
+        seq.end()
+    }
+}
+
+/// Row `row` of a batch as an object
+struct RowFields<'a>(&'a Schema, &'a [ArrayRef], usize);
+
+impl Serialize for RowFields<'_> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let RowFields(schema, columns, row) = *self;
+        let mut map = s.serialize_map(Some(columns.len()))?;
+        for (field, column) in schema.fields().iter().zip(columns) {
+            map.serialize_entry(field.name(), &Cell(column.as_ref(), row))?;
+        }
+        map.end()
+    }
+}
+
+impl Serialize for Columns<'_> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let batch = self.0;
+        let mut map = s.serialize_map(Some(batch.num_columns()))?;
+        for (field, column) in batch.schema().fields().iter().zip(batch.columns()) {
+            map.serialize_entry(field.name(), &Column(column.as_ref()))?;
+        }
+        map.end()
+    }
+}
+
+fn serialize_with<T: Serialize>(value: &T, encoding: Encoding) -> Result<Encoded, String> {
+    match encoding {
+        Encoding::Json => serde_json::to_string(value).map(Encoded::Text).map_err(|e| e.to_string()),
+        Encoding::Msgpack => rmp_serde::to_vec_named(value).map(Encoded::Binary).map_err(|e| e.to_string()),
+        Encoding::Cbor => {
+            let mut bytes = Vec::new();
+            ciborium::into_writer(value, &mut bytes).map_err(|e| e.to_string())?;
+            Ok(Encoded::Binary(bytes))
+        },
+        Encoding::Arrow => unreachable!("Arrow batches are written by the IPC writer"),
+    }
+}
+
+/// Serialize `batch`; `layout` does not apply to Arrow, which is always columnar
+pub fn encode(batch: &RecordBatch, encoding: Encoding, layout: Layout) -> Result<Encoded, String> {
+    match (encoding, layout) {
+        (Encoding::Arrow, _) => encode_ipc_file(batch).map(Encoded::Binary),
+        (_, Layout::Rows) => serialize_with(&Rows(batch), encoding),
+        (_, Layout::Columns) => serialize_with(&Columns(batch), encoding),
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Counters {
+    messages: u64,
+    rows: u64,
+    bytes: u64,
+    serialize_time: Duration,
+    max_serialize_time: Duration,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EncodingSummary {
+    pub messages: u64,
+    pub rows: u64,
+    pub bytes: u64,
+    pub bytes_per_row: f64,
+    pub mean_serialize_us: f64,
+    pub max_serialize_us: f64,
+    /// Rows serialized per second of serialization time
+    pub serialize_rows_per_sec: f64,
+}
+
+impl From<&Counters> for EncodingSummary {
+    fn from(c: &Counters) -> Self {
+        let seconds = c.serialize_time.as_secs_f64();
+        Self {
+            messages: c.messages,
+            rows: c.rows,
+            bytes: c.bytes,
+            bytes_per_row: if c.rows > 0 { c.bytes as f64 / c.rows as f64 } else { 0.0 },
+            mean_serialize_us: if c.messages > 0 { seconds * 1e6 / c.messages as f64 } else { 0.0 },
+            max_serialize_us: c.max_serialize_time.as_secs_f64() * 1e6,
+            serialize_rows_per_sec: if seconds > 0.0 { c.rows as f64 / seconds } else { 0.0 },
+        }
+    }
+}
+
+/// Serialization time and size per encoding and layout, across all connections
+#[derive(Debug, Default)]
+pub struct EncodingStats {
+    counters: Mutex<BTreeMap<(Encoding, Layout), Counters>>,
+}
+
+pub type EncodingStatsHandle = Arc<EncodingStats>;
+
+impl EncodingStats {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn record(&self, encoding: Encoding, layout: Layout, rows: usize, bytes: usize, elapsed: Duration) {
+        let layout = if encoding == Encoding::Arrow { Layout::Columns } else { layout };
+        let mut counters = self.counters.lock().unwrap();
+        let entry = counters.entry((encoding, layout)).or_default();
+        entry.messages += 1;
+        entry.rows += rows as u64;
+        entry.bytes += bytes as u64;
+        entry.serialize_time += elapsed;
+        entry.max_serialize_time = entry.max_serialize_time.max(elapsed);
+    }
+
+    /// Summaries keyed `<encoding>/<layout>`, e.g. `json/rows`
+    pub fn summary(&self) -> BTreeMap<String, EncodingSummary> {
+        self.counters
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((encoding, layout), counters)| (format!("{}/{}", encoding.name(), layout.name()), counters.into()))
+            .collect()
+    }
+
+    pub fn reset(&self) {
+        self.counters.lock().unwrap().clear();
+    }
+}
+
+/// Encode `batch` and record how long that took and how big it came out
+pub fn encode_timed(stats: &EncodingStats, batch: &RecordBatch, encoding: Encoding, layout: Layout) -> Result<Encoded, String> {
+    let start = Instant::now();
+    let encoded = encode(batch, encoding, layout)?;
+    stats.record(encoding, layout, batch.num_rows(), encoded.len(), start.elapsed());
+    Ok(encoded)
+}
+
+/// Encode the same batches in every encoding and layout, without touching the shared statistics
+pub fn compare(batches: &[RecordBatch]) -> Result<BTreeMap<String, EncodingSummary>, String> {
+    let stats = EncodingStats::new();
+    for encoding in Encoding::ALL {
+        let layouts: &[Layout] = if encoding == Encoding::Arrow { &[Layout::Columns] } else { &[Layout::Rows, Layout::Columns] };
+        for layout in layouts {
+            for batch in batches {
+                encode_timed(&stats, batch, encoding, *layout)?;
+            }
+        }
+    }
+    Ok(stats.summary())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{BooleanArray, Float32Array, Int64Array, ListArray, StringArray, StructArray, TimestampMillisecondArray};
+    use arrow::ipc::reader::FileReader;
+    use serde_json::{Value, json};
+
+    fn batch() -> RecordBatch {
+        let x: ArrayRef = Arc::new(Float32Array::from(vec![Some(1.5), None]));
+        let channel = StructArray::from(vec![(Arc::new(Field::new("x", DataType::Float32, true)), x)]);
+        let samples = ListArray::from_iter_primitive::<Int64Type, _, _>(vec![Some(vec![Some(1), Some(2)]), None]);
+        RecordBatch::try_from_iter(vec![
+            ("timestamp", Arc::new(TimestampMillisecondArray::from(vec![Some(1_700_000_000_000), None])) as ArrayRef),
+            ("id", Arc::new(Int64Array::from(vec![Some(-1), None])) as ArrayRef),
+            ("label", Arc::new(StringArray::from(vec![Some("a"), None])) as ArrayRef),
+            ("valid", Arc::new(BooleanArray::from(vec![Some(true), None])) as ArrayRef),
+            ("samples", Arc::new(samples) as ArrayRef),
+            ("channel", Arc::new(channel) as ArrayRef),
+        ])
+        .unwrap()
+    }
+
+    fn expected(layout: Layout) -> Value {
+        match layout {
+            Layout::Rows => json!([
+                {
+                    "timestamp": "2023-11-14T22:13:20", "id": -1, "label": "a", "valid": true,
+                    "samples": [1, 2], "channel": { "x": 1.5 }
+                },
+                {
+                    "timestamp": null, "id": null, "label": null, "valid": null,
+                    "samples": null, "channel": { "x": null }
+                },
+            ]),
+            Layout::Columns => json!({
+                "timestamp": ["2023-11-14T22:13:20", null],
+                "id": [-1, null],
+                "label": ["a", null],
+                "valid": [true, null],
+                "samples": [[1, 2], null],
+                "channel": [{ "x": 1.5 }, { "x": null }],
+            }),
+        }
+    }
+
+    fn decode(encoded: Encoded, encoding: Encoding) -> Value {
+        match (encoded, encoding) {
+            (Encoded::Text(text), Encoding::Json) => serde_json::from_str(&text).unwrap(),
+            (Encoded::Binary(bytes), Encoding::Msgpack) => rmp_serde::from_slice(&bytes).unwrap(),
+            (Encoded::Binary(bytes), Encoding::Cbor) => ciborium::from_reader(bytes.as_slice()).unwrap(),
+            (encoded, encoding) => panic!("{:?} came out as {:?}", encoding, encoded),
+        }
+    }
+
+    #[test]
+    fn round_trips_in_every_encoding_and_layout() {
+        for encoding in [Encoding::Json, Encoding::Msgpack, Encoding::Cbor] {
+            for layout in [Layout::Rows, Layout::Columns] {
+                let decoded = decode(encode(&batch(), encoding, layout).unwrap(), encoding);
+                assert_eq!(decoded, expected(layout), "{}/{}", encoding.name(), layout.name());
+            }
+        }
+
+        let Encoded::Binary(ipc) = encode(&batch(), Encoding::Arrow, Layout::Rows).unwrap() else {
+            panic!("Arrow is binary");
+        };
+        let read = FileReader::try_new(std::io::Cursor::new(ipc), None).unwrap().next().unwrap().unwrap();
+        assert_eq!(read, batch());
+    }
+
+    #[test]
+    fn compare_covers_every_encoding_and_layout() {
+        let batches = [batch(), batch()];
+        let summary = compare(&batches).unwrap();
+
+        let keys: Vec<&str> = summary.keys().map(String::as_str).collect();
+        assert_eq!(keys, ["arrow/columns", "cbor/columns", "cbor/rows", "json/columns", "json/rows", "msgpack/columns", "msgpack/rows"]);
+        for (key, encoding) in summary.iter() {
+            assert_eq!((encoding.messages, encoding.rows), (2, 4), "{}", key);
+            assert_eq!(encoding.bytes_per_row, encoding.bytes as f64 / 4.0);
+        }
+        let json_rows = encode(&batch(), Encoding::Json, Layout::Rows).unwrap().len() as u64;
+        assert_eq!(summary["json/rows"].bytes, 2 * json_rows);
+
+        assert!(compare(&[]).unwrap().is_empty());
+    }
+}
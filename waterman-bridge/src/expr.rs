@@ -0,0 +1,148 @@
+//! Row filters written as SQL conditions (`channel_2 > 0.5 AND valid = true`),
+//! planned by DataFusion against the schema of each batch they are applied to.
+
+use crate::sql;
+use arrow::array::AsArray;
+use arrow::compute::filter_record_batch;
+use arrow::datatypes::{DataType, Fields};
+use arrow::record_batch::RecordBatch;
+use datafusion::common::DFSchema;
+use datafusion::physical_expr::PhysicalExpr;
+use datafusion::sql::sqlparser::dialect::GenericDialect;
+use datafusion::sql::sqlparser::parser::Parser;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+pub type Result<T> = std::result::Result<T, String>;
+
+/// A condition planned for one batch layout
+type Compiled = (Fields, Arc<dyn PhysicalExpr>);
+
+/// A parsed condition, planned once per batch layout and cached until the layout changes
+#[derive(Clone)]
+pub struct Filter {
+    text: String,
+    compiled: Arc<Mutex<Option<Compiled>>>,
+}
+
+impl fmt::Debug for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Filter({})", self.text)
+    }
+}
+
+impl Filter {
+    /// Check the syntax of a condition, e.g. the filter of a subscription. Columns are only
+    /// resolved once a batch comes along.
+    pub fn parse(text: &str) -> Result<Self> {
+        Parser::new(&GenericDialect {})
+            .try_with_sql(text)
+            .and_then(|mut parser| parser.parse_expr())
+            .map_err(|e| format!("Invalid expression '{}': {}", text, e))?;
+        Ok(Self { text: text.to_string(), compiled: Arc::new(Mutex::new(None)) })
+    }
+
+    fn compile(&self, batch: &RecordBatch) -> Result<Arc<dyn PhysicalExpr>> {
+        let fields = batch.schema().fields().clone();
+        let mut compiled = self.compiled.lock().unwrap();
+        if let Some((cached, predicate)) = compiled.as_ref()
+            && *cached == fields
+        {
+            return Ok(predicate.clone());
+        }
+
+        let context = sql::session();
+        let schema = DFSchema::try_from(batch.schema().as_ref().clone()).map_err(|e| e.to_string())?;
+        let logical = context.parse_sql_expr(&self.text, &schema).map_err(|e| e.to_string())?;
+        let predicate = context.create_physical_expr(logical, &schema).map_err(|e| e.to_string())?;
+        match predicate.data_type(batch.schema().as_ref()).map_err(|e| e.to_string())? {
+            DataType::Boolean | DataType::Null => {},
+            other => return Err(format!("'{}' is not a condition, it evaluates to {}", self.text, other)),
+        }
+        *compiled = Some((fields, predicate.clone()));
+        Ok(predicate)
+    }
+
+    /// Keep only the rows of `batch` that match; rows where the condition is null do not
+    pub fn apply(&self, batch: &RecordBatch) -> Result<RecordBatch> {
+        let predicate = self.compile(batch)?;
+        let mask = predicate
+            .evaluate(batch)
+            .and_then(|value| value.into_array(batch.num_rows()))
+            .map_err(|e| e.to_string())?;
+        let Some(mask) = mask.as_boolean_opt() else {
+            return Ok(batch.slice(0, 0));
+        };
+        filter_record_batch(batch, mask).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{ArrayRef, BooleanArray, Float64Array, Int64Array, StructArray};
+    use arrow::datatypes::{Field, Schema};
+
+    fn batch() -> RecordBatch {
+        let value: ArrayRef = Arc::new(Float64Array::from(vec![0.1, 0.6, 0.9, 0.4]));
+        let channel = StructArray::from(vec![(Arc::new(Field::new("value", DataType::Float64, false)), value.clone())]);
+        RecordBatch::try_from_iter(vec![
+            ("sequence", Arc::new(Int64Array::from(vec![1, 2, 3, 4])) as ArrayRef),
+            ("channel_2", value),
+            ("valid", Arc::new(BooleanArray::from(vec![Some(true), Some(false), Some(true), None])) as ArrayRef),
+            ("channel_0", Arc::new(channel) as ArrayRef),
+        ])
+        .unwrap()
+    }
+
+    fn sequences(batch: &RecordBatch) -> Vec<i64> {
+        batch.column_by_name("sequence").unwrap().as_primitive::<arrow::datatypes::Int64Type>().values().to_vec()
+    }
+
+    #[test]
+    fn keeps_matching_rows() {
+        let filter = Filter::parse("channel_2 > 0.5 AND valid = true").unwrap();
+        assert_eq!(sequences(&filter.apply(&batch()).unwrap()), vec![3]);
+
+        let filter = Filter::parse("sequence BETWEEN 2 AND 3 OR sequence IN (4)").unwrap();
+        assert_eq!(sequences(&filter.apply(&batch()).unwrap()), vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn null_conditions_do_not_match() {
+        let filter = Filter::parse("NOT valid").unwrap();
+        assert_eq!(sequences(&filter.apply(&batch()).unwrap()), vec![2]);
+
+        let filter = Filter::parse("valid IS NULL").unwrap();
+        assert_eq!(sequences(&filter.apply(&batch()).unwrap()), vec![4]);
+    }
+
+    #[test]
+    fn reads_struct_fields() {
+        let filter = Filter::parse("channel_0.value < 0.5").unwrap();
+        assert_eq!(sequences(&filter.apply(&batch()).unwrap()), vec![1, 4]);
+    }
+
+    #[test]
+    fn replans_when_the_layout_changes() {
+        let filter = Filter::parse("sequence % 2 = 0").unwrap();
+        assert_eq!(sequences(&filter.apply(&batch()).unwrap()), vec![2, 4]);
+
+        let narrower = batch().project(&[0]).unwrap();
+        assert_eq!(sequences(&filter.apply(&narrower).unwrap()), vec![2, 4]);
+
+        let renamed = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![Field::new("other", DataType::Int64, false)])),
+            vec![narrower.column(0).clone()],
+        )
+        .unwrap();
+        assert!(filter.apply(&renamed).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_filters() {
+        assert!(Filter::parse("channel_2 >").is_err());
+        assert!(Filter::parse("no_such_column > 1").unwrap().apply(&batch()).is_err());
+        assert!(Filter::parse("channel_2 + 1").unwrap().apply(&batch()).is_err());
+    }
+}
@@ -0,0 +1,388 @@
+//! Arrow Flight service (feature `flight`) on its own port, serving the same
+//! streams as the WebSocket endpoints to Flight clients such as pyarrow.
+//!
+//! | RPC             | Behaviour                                                        |
+//! |-----------------|------------------------------------------------------------------|
+//! | `ListFlights`   | One flight per topic with a known schema                         |
+//! | `GetFlightInfo` | Schema and ticket of a topic (path descriptor) or query (command) |
+//! | `GetSchema`     | Schema of a topic or query                                       |
+//! | `DoGet`         | A topic live, a time range, or a SQL query, depending on the ticket |
+//! | `DoPut`         | Publish the uploaded batches on the topic named by the descriptor |
+//!
+//! Other RPCs answer `Unimplemented`.
+
+// `tonic::Status` is the error type of every RPC
+#![allow(clippy::result_large_err)]
+
+use crate::history::{self, RangeQuery};
+use crate::sql::{self, SqlRequest};
+use crate::state::StateHandle;
+use crate::topics;
+use arrow::datatypes::SchemaRef;
+use arrow::ipc::writer::IpcWriteOptions;
+use arrow::record_batch::RecordBatch;
+use arrow_flight::decode::FlightRecordBatchStream;
+use arrow_flight::encode::FlightDataEncoderBuilder;
+use arrow_flight::error::FlightError;
+use arrow_flight::flight_descriptor::DescriptorType;
+use arrow_flight::flight_service_server::{FlightService, FlightServiceServer};
+use arrow_flight::{
+    Action, ActionType, Criteria, Empty, FlightData, FlightDescriptor, FlightEndpoint, FlightInfo, HandshakeRequest,
+    HandshakeResponse, PollInfo, PutResult, SchemaAsIpc, SchemaResult, Ticket,
+};
+use futures::{StreamExt, TryStreamExt};
+use log::{error, info, warn};
+use serde::Deserialize;
+use serde_json::json;
+use std::net::SocketAddr;
+use std::path::Path;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tonic::codegen::BoxStream;
+use tonic::{Request, Response, Status, Streaming};
+
+/// What a ticket (or command descriptor) asks for. A plain topic name follows the topic live.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum TicketRequest {
+    Sql(SqlRequest),
+    Range(RangeQuery),
+    Live {
+        stream: String,
+        #[serde(default)]
+        backfill: bool,
+    },
+}
+
+impl TicketRequest {
+    fn parse(bytes: &[u8]) -> Result<Self, Status> {
+        if bytes.first() == Some(&b'{') {
+            return serde_json::from_slice(bytes).map_err(|e| Status::invalid_argument(format!("Invalid ticket: {}", e)));
+        }
+        let stream = std::str::from_utf8(bytes).map_err(|_| Status::invalid_argument("Ticket is not UTF-8"))?;
+        Ok(TicketRequest::Live { stream: stream.to_string(), backfill: false })
+    }
+}
+
+fn topic_of(descriptor: &FlightDescriptor) -> Result<String, Status> {
+    if descriptor.path.is_empty() {
+        return Err(Status::invalid_argument("Descriptor has no path"));
+    }
+    Ok(descriptor.path.join("/"))
+}
+
+/// Stream the items sent on `rx` as a gRPC response stream
+fn receiver_stream<T: Send + 'static>(rx: mpsc::Receiver<Result<T, Status>>) -> BoxStream<T> {
+    Box::pin(futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|item| (item, rx))
+    }))
+}
+
+/// Encode batches as a `FlightData` response, the schema message first
+fn encode<S>(batches: S) -> BoxStream<FlightData>
+where
+    S: futures::Stream<Item = Result<RecordBatch, FlightError>> + Send + 'static,
+{
+    Box::pin(FlightDataEncoderBuilder::new().build(batches).map_err(Status::from))
+}
+
+#[derive(Clone)]
+pub struct BridgeFlightService {
+    state: StateHandle,
+}
+
+impl BridgeFlightService {
+    pub fn new(state: StateHandle) -> Self {
+        Self { state }
+    }
+
+    fn flight_info(&self, topic: &str, schema: &SchemaRef) -> Result<FlightInfo, Status> {
+        let info = FlightInfo::new()
+            .try_with_schema(schema)
+            .map_err(|e| Status::internal(e.to_string()))?
+            .with_descriptor(FlightDescriptor::new_path(vec![topic.to_string()]))
+            .with_endpoint(FlightEndpoint::new().with_ticket(Ticket::new(topic.to_string())))
+            .with_ordered(true);
+        Ok(info)
+    }
+
+    /// Answer a history or SQL request; range reads run off the async runtime
+    async fn run_query(&self, request: TicketRequest) -> Result<Vec<RecordBatch>, Status> {
+        let (hub, recordings_dir) = {
+            let state_guard = self.state.lock().unwrap();
+            (state_guard.hub(), state_guard.recorder().directory())
+        };
+        let result = match request {
+            TicketRequest::Sql(request) => sql::execute(hub, Path::new(&recordings_dir), &request.sql).await,
+            TicketRequest::Range(query) => {
+                let stream = query.stream.clone().unwrap_or_else(|| crate::stream_hub::SYNTHETIC_STREAM.to_string());
+                tokio::task::spawn_blocking(move || history::run_range_query(&hub, Path::new(&recordings_dir), &stream, &query))
+                    .await
+                    .map_err(|e| Status::internal(format!("Query panicked: {}", e)))?
+            },
+            TicketRequest::Live { .. } => Err("Not a query".to_string()),
+        };
+        result.map_err(Status::invalid_argument)
+    }
+
+    /// Schema of what a descriptor refers to
+    async fn descriptor_schema(&self, descriptor: &FlightDescriptor) -> Result<SchemaRef, Status> {
+        if descriptor.r#type() == DescriptorType::Cmd {
+            let batches = self.run_query(TicketRequest::parse(&descriptor.cmd)?).await?;
+            return batches.first().map(|b| b.schema()).ok_or_else(|| Status::not_found("The query returned no data"));
+        }
+        let topic = topic_of(descriptor)?;
+        let state_guard = self.state.lock().unwrap();
+        topics::topic_schema(&state_guard, &topic).ok_or_else(|| Status::not_found(format!("No schema known for '{}'", topic)))
+    }
+}
+
+#[tonic::async_trait]
+impl FlightService for BridgeFlightService {
+    type HandshakeStream = BoxStream<HandshakeResponse>;
+    type ListFlightsStream = BoxStream<FlightInfo>;
+    type DoGetStream = BoxStream<FlightData>;
+    type DoPutStream = BoxStream<PutResult>;
+    type DoExchangeStream = BoxStream<FlightData>;
+    type DoActionStream = BoxStream<arrow_flight::Result>;
+    type ListActionsStream = BoxStream<ActionType>;
+
+    async fn handshake(&self, _request: Request<Streaming<HandshakeRequest>>) -> Result<Response<Self::HandshakeStream>, Status> {
+        Err(Status::unimplemented("No authentication is required"))
+    }
+
+    async fn list_flights(&self, _request: Request<Criteria>) -> Result<Response<Self::ListFlightsStream>, Status> {
+        let flights: Vec<Result<FlightInfo, Status>> = {
+            let state_guard = self.state.lock().unwrap();
+            topics::topic_names(&state_guard)
+                .into_iter()
+                .filter_map(|name| topics::topic_schema(&state_guard, &name).map(|schema| self.flight_info(&name, &schema)))
+                .collect()
+        };
+        Ok(Response::new(Box::pin(futures::stream::iter(flights))))
+    }
+
+    async fn get_flight_info(&self, request: Request<FlightDescriptor>) -> Result<Response<FlightInfo>, Status> {
+        let descriptor = request.into_inner();
+        let schema = self.descriptor_schema(&descriptor).await?;
+        let mut info = if descriptor.r#type() == DescriptorType::Cmd {
+            // The command itself is the ticket
+            let mut info = self.flight_info("", &schema)?;
+            info.endpoint[0].ticket = Some(Ticket::new(descriptor.cmd.clone()));
+            info
+        } else {
+            self.flight_info(&topic_of(&descriptor)?, &schema)?
+        };
+        info.flight_descriptor = Some(descriptor);
+        Ok(Response::new(info))
+    }
+
+    async fn poll_flight_info(&self, _request: Request<FlightDescriptor>) -> Result<Response<PollInfo>, Status> {
+        Err(Status::unimplemented("Use GetFlightInfo"))
+    }
+
+    async fn get_schema(&self, request: Request<FlightDescriptor>) -> Result<Response<SchemaResult>, Status> {
+        let schema = self.descriptor_schema(request.get_ref()).await?;
+        let result = SchemaAsIpc::new(&schema, &IpcWriteOptions::default())
+            .try_into()
+            .map_err(|e: arrow::error::ArrowError| Status::internal(e.to_string()))?;
+        Ok(Response::new(result))
+    }
+
+    async fn do_get(&self, request: Request<Ticket>) -> Result<Response<Self::DoGetStream>, Status> {
+        let (stream, backfill) = match TicketRequest::parse(&request.get_ref().ticket)? {
+            TicketRequest::Live { stream, backfill } => (stream, backfill),
+            query => {
+                let batches = self.run_query(query).await?;
+                return Ok(Response::new(encode(futures::stream::iter(batches.into_iter().map(Ok)))));
+            },
+        };
+
+        let hub = {
+            let state_guard = self.state.lock().unwrap();
+            if !topics::is_topic(&state_guard, &stream) {
+                return Err(Status::not_found(format!("Unknown topic '{}'", stream)));
+            }
+            state_guard.hub()
+        };
+        info!("Flight client following '{}'", stream);
+        let (history, feed) = if backfill {
+            hub.subscribe_with_backfill(&stream)
+        } else {
+            (Vec::new(), hub.subscribe(&stream))
+        };
+        let topic = stream.clone();
+        let live = futures::stream::unfold(feed, move |mut feed| {
+            let topic = topic.clone();
+            async move {
+                loop {
+                    match feed.recv().await {
+                        Ok(batch) => return Some((batch, feed)),
+                        Err(RecvError::Lagged(skipped)) => {
+                            warn!("Flight client on '{}' fell behind, skipped {} batches", topic, skipped);
+                        },
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            }
+        });
+
+        // A Flight stream has exactly one schema; a change ends it
+        let mut fields = None;
+        let batches = futures::stream::iter(history).chain(live).map(move |batch| {
+            let current = batch.schema().fields().clone();
+            match &fields {
+                Some(first) if *first != current => {
+                    info!("Flight stream of '{}' ended by a schema change", stream);
+                    Err(FlightError::Tonic(Status::failed_precondition("The stream's schema changed")))
+                },
+                Some(_) => Ok(batch),
+                None => {
+                    fields = Some(current);
+                    Ok(batch)
+                },
+            }
+        });
+        Ok(Response::new(encode(batches)))
+    }
+
+    async fn do_put(&self, request: Request<Streaming<FlightData>>) -> Result<Response<Self::DoPutStream>, Status> {
+        let mut upload = request.into_inner();
+        let first = upload.message().await?.ok_or_else(|| Status::invalid_argument("Empty upload"))?;
+        let topic = first
+            .flight_descriptor
+            .as_ref()
+            .ok_or_else(|| Status::invalid_argument("The first message needs a descriptor"))
+            .and_then(topic_of)?;
+
+        info!("Flight client uploading to '{}'", topic);
+        let hub = self.state.lock().unwrap().hub();
+        let data = futures::stream::iter([Ok(first)]).chain(upload).map_err(FlightError::from);
+        let mut uploaded = FlightRecordBatchStream::new_from_flight_data(data);
+        let (tx, rx) = mpsc::channel(64);
+        tokio::spawn(async move {
+            let (mut batches, mut rows) = (0u64, 0u64);
+            while let Some(batch) = uploaded.next().await {
+                match batch {
+                    Ok(batch) => {
+                        batches += 1;
+                        rows += batch.num_rows() as u64;
+                        hub.publish(&topic, batch);
+                        let ack = json!({ "batches": batches, "rows": rows }).to_string();
+                        let _ = tx.send(Ok(PutResult { app_metadata: ack.into() })).await;
+                    },
+                    Err(FlightError::Tonic(e)) => {
+                        warn!("Flight upload to '{}' failed: {}", topic, e);
+                        break;
+                    },
+                    Err(e) => {
+                        error!("Invalid Flight upload to '{}': {}", topic, e);
+                        let _ = tx.send(Err(Status::invalid_argument(e.to_string()))).await;
+                        break;
+                    },
+                }
+            }
+            info!("Flight upload to '{}' finished: {} batches, {} rows", topic, batches, rows);
+        });
+        Ok(Response::new(receiver_stream(rx)))
+    }
+
+    async fn do_exchange(&self, _request: Request<Streaming<FlightData>>) -> Result<Response<Self::DoExchangeStream>, Status> {
+        Err(Status::unimplemented("Use DoGet and DoPut"))
+    }
+
+    async fn do_action(&self, _request: Request<Action>) -> Result<Response<Self::DoActionStream>, Status> {
+        Err(Status::unimplemented("No actions are supported"))
+    }
+
+    async fn list_actions(&self, _request: Request<Empty>) -> Result<Response<Self::ListActionsStream>, Status> {
+        Ok(Response::new(Box::pin(futures::stream::empty())))
+    }
+}
+
+/// Serve the Flight service until the process exits
+pub async fn serve(state: StateHandle, addr: SocketAddr) {
+    info!("Arrow Flight server listening on grpc://{}", addr);
+    let result = tonic::transport::Server::builder()
+        .add_service(FlightServiceServer::new(BridgeFlightService::new(state)))
+        .serve(addr)
+        .await;
+    if let Err(e) = result {
+        error!("Arrow Flight server failed: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::WSBridgeState;
+    use arrow::array::{ArrayRef, AsArray, Int64Array};
+    use arrow::datatypes::Int64Type;
+    use arrow_flight::FlightClient;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    async fn connect() -> (StateHandle, FlightClient) {
+        let state = WSBridgeState::new().into_handle();
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        tokio::spawn(serve(state.clone(), ([127, 0, 0, 1], port).into()));
+
+        let endpoint = tonic::transport::Endpoint::from_shared(format!("http://127.0.0.1:{}", port)).unwrap();
+        for _ in 0..50 {
+            if let Ok(channel) = endpoint.connect().await {
+                return (state, FlightClient::new(channel));
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("Flight server did not come up");
+    }
+
+    fn batch(values: Vec<i64>) -> RecordBatch {
+        RecordBatch::try_from_iter(vec![("value", Arc::new(Int64Array::from(values)) as ArrayRef)]).unwrap()
+    }
+
+    fn values(batches: &[RecordBatch]) -> Vec<i64> {
+        batches.iter().flat_map(|b| b.column(0).as_primitive::<Int64Type>().values().to_vec()).collect()
+    }
+
+    #[tokio::test]
+    async fn uploads_and_reads_back() {
+        let (state, mut client) = connect().await;
+
+        let upload = FlightDataEncoderBuilder::new()
+            .with_flight_descriptor(Some(FlightDescriptor::new_path(vec!["uploaded".to_string()])))
+            .build(futures::stream::iter([Ok(batch(vec![1, 2])), Ok(batch(vec![3]))]));
+        let acks: Vec<_> = client.do_put(upload).await.unwrap().try_collect().await.unwrap();
+        assert_eq!(acks.len(), 2);
+        assert_eq!(values(&state.lock().unwrap().hub().history("uploaded")), vec![1, 2, 3]);
+
+        let flights: Vec<_> = client.list_flights(Vec::<u8>::new()).await.unwrap().try_collect().await.unwrap();
+        assert!(flights.iter().any(|info| info.flight_descriptor.as_ref().unwrap().path == ["uploaded"]));
+
+        let ticket = Ticket::new(r#"{"stream": "uploaded", "backfill": true}"#);
+        let live: Vec<_> = client.do_get(ticket).await.unwrap().take(2).try_collect().await.unwrap();
+        assert_eq!(values(&live), vec![1, 2, 3]);
+
+        // Following an unknown topic must not create it
+        assert!(client.do_get(Ticket::new(r#"{"stream": "nothing"}"#)).await.is_err());
+        assert!(!state.lock().unwrap().hub().stream_names().contains(&"nothing".to_string()));
+    }
+
+    #[tokio::test]
+    async fn answers_sql_tickets() {
+        let (state, mut client) = connect().await;
+        state.lock().unwrap().hub().publish("numbers", batch(vec![4, 5, 6]));
+
+        let command = FlightDescriptor::new_cmd(r#"{"sql": "SELECT sum(value) AS total FROM numbers"}"#);
+        let schema = client.get_schema(command.clone()).await.unwrap();
+        assert_eq!(schema.field(0).name(), "total");
+
+        let info = client.get_flight_info(command).await.unwrap();
+        let ticket = info.endpoint[0].ticket.clone().unwrap();
+        let result: Vec<_> = client.do_get(ticket).await.unwrap().try_collect().await.unwrap();
+        assert_eq!(values(&result), vec![15]);
+
+        let bad = Ticket::new(r#"{"sql": "SELECT * FROM missing"}"#);
+        assert!(client.do_get(bad).await.is_err());
+    }
+}
@@ -0,0 +1,436 @@
+//! Foxglove WebSocket protocol (`foxglove.websocket.v1`) on `/foxglove`, so the
+//! bridge's topics can be opened directly in Foxglove Studio.
+//!
+//! Every topic is advertised as a channel with JSON encoding and a JSON schema
+//! derived from its Arrow schema. Each row of a batch becomes one message.
+
+use crate::state::StateHandle;
+use crate::stream_hub::HubHandle;
+use crate::topics;
+use arrow::array::AsArray;
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, Field, Float64Type, Int64Type, Schema, SchemaRef, TimeUnit, TimestampNanosecondType};
+use arrow::json::LineDelimitedWriter;
+use arrow::record_batch::RecordBatch;
+use futures::{SinkExt, StreamExt};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use warp::ws::{Message, WebSocket};
+use warp::{Rejection, Reply};
+type Result<T> = std::result::Result<T, Rejection>;
+
+static NEXT_FOXGLOVE_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// WebSocket subprotocol Foxglove Studio asks for
+pub const SUBPROTOCOL: &str = "foxglove.websocket.v1";
+
+/// Binary opcode of a `messageData` frame
+const MESSAGE_DATA_OPCODE: u8 = 0x01;
+
+/// How often new topics and schema changes are looked for
+const ADVERTISE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Column whose values become the receive time of each message
+const TIME_COLUMN: &str = "timestamp";
+
+/// Levels of a `status` message
+const STATUS_WARNING: u8 = 1;
+const STATUS_ERROR: u8 = 2;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Channel {
+    id: u32,
+    topic: String,
+    encoding: &'static str,
+    schema_name: String,
+    schema: String,
+    schema_encoding: &'static str,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Subscription {
+    id: u32,
+    channel_id: u32,
+}
+
+/// Client operations; anything else is answered with a status message
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "camelCase")]
+enum ClientOp {
+    Subscribe {
+        subscriptions: Vec<Subscription>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Unsubscribe {
+        subscription_ids: Vec<u32>,
+    },
+}
+
+/// JSON schema of one Arrow type
+fn json_schema_type(data_type: &DataType) -> Value {
+    match data_type {
+        DataType::Boolean => json!({ "type": "boolean" }),
+        t if t.is_integer() => json!({ "type": "integer" }),
+        t if t.is_floating() || matches!(t, DataType::Decimal128(..) | DataType::Decimal256(..)) => json!({ "type": "number" }),
+        DataType::Utf8 | DataType::LargeUtf8 | DataType::Utf8View => json!({ "type": "string" }),
+        // Written as ISO 8601 text by the JSON writer
+        DataType::Timestamp(..) | DataType::Date32 | DataType::Date64 => json!({ "type": "string" }),
+        DataType::List(item) | DataType::LargeList(item) | DataType::FixedSizeList(item, _) => {
+            json!({ "type": "array", "items": json_schema_type(item.data_type()) })
+        },
+        DataType::Struct(fields) => json_schema_object(fields.iter().map(|f| f.as_ref())),
+        _ => json!({}),
+    }
+}
+
+fn json_schema_object<'a>(fields: impl Iterator<Item = &'a Field>) -> Value {
+    let properties: serde_json::Map<String, Value> = fields
+        .map(|field| (field.name().clone(), json_schema_type(field.data_type())))
+        .collect();
+    json!({ "type": "object", "properties": properties })
+}
+
+/// JSON schema describing one row of a batch with this Arrow schema
+pub fn json_schema(schema: &Schema) -> Value {
+    json_schema_object(schema.fields().iter().map(|f| f.as_ref()))
+}
+
+fn status(level: u8, message: impl Into<String>) -> Message {
+    Message::text(json!({ "op": "status", "level": level, "message": message.into() }).to_string())
+}
+
+fn now_ns() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0)
+}
+
+/// Receive time of every row of `batch` in ns since the epoch, taken from its time column.
+/// Integer and floating point times are unix milliseconds, as the generator writes them;
+/// without a time column, or for a null time, it is now.
+fn receive_times(batch: &RecordBatch) -> Vec<u64> {
+    let now = now_ns();
+    let Some(column) = batch.column_by_name(TIME_COLUMN) else {
+        return vec![now; batch.num_rows()];
+    };
+
+    let nanos: Vec<Option<i64>> = match column.data_type() {
+        DataType::Timestamp(..) | DataType::Date32 | DataType::Date64 => {
+            match cast(column, &DataType::Timestamp(TimeUnit::Nanosecond, None)) {
+                Ok(nanos) => nanos.as_primitive::<TimestampNanosecondType>().iter().collect(),
+                Err(_) => return vec![now; batch.num_rows()],
+            }
+        },
+        t if t.is_integer() => match cast(column, &DataType::Int64) {
+            Ok(millis) => millis.as_primitive::<Int64Type>().iter().map(|ms| ms.map(|ms| ms.saturating_mul(1_000_000))).collect(),
+            Err(_) => return vec![now; batch.num_rows()],
+        },
+        t if t.is_floating() => match cast(column, &DataType::Float64) {
+            Ok(millis) => millis.as_primitive::<Float64Type>().iter().map(|ms| ms.map(|ms| (ms * 1e6) as i64)).collect(),
+            Err(_) => return vec![now; batch.num_rows()],
+        },
+        _ => return vec![now; batch.num_rows()],
+    };
+    nanos.into_iter().map(|ns| ns.and_then(|ns| u64::try_from(ns).ok()).unwrap_or(now)).collect()
+}
+
+/// One `messageData` frame per row of `batch`
+fn message_data(subscription_id: u32, batch: &RecordBatch) -> std::result::Result<Vec<Message>, String> {
+    let mut writer = LineDelimitedWriter::new(Vec::new());
+    writer.write(batch).map_err(|e| e.to_string())?;
+    writer.finish().map_err(|e| e.to_string())?;
+    let rows = writer.into_inner();
+
+    Ok(rows
+        .split(|b| *b == b'\n')
+        .filter(|row| !row.is_empty())
+        .zip(receive_times(batch))
+        .map(|(row, receive_time)| {
+            let mut frame = Vec::with_capacity(13 + row.len());
+            frame.push(MESSAGE_DATA_OPCODE);
+            frame.extend_from_slice(&subscription_id.to_le_bytes());
+            frame.extend_from_slice(&receive_time.to_le_bytes());
+            frame.extend_from_slice(row);
+            Message::binary(frame)
+        })
+        .collect())
+}
+
+/// Whether a `Sec-WebSocket-Protocol` request header lists the Foxglove subprotocol
+fn offers_subprotocol(protocols: Option<&str>) -> bool {
+    protocols.is_some_and(|protocols| protocols.split(',').any(|p| p.trim() == SUBPROTOCOL))
+}
+
+pub async fn foxglove_handler(ws: warp::ws::Ws, protocols: Option<String>, state: StateHandle) -> Result<impl Reply> {
+    let reply = ws.on_upgrade(|socket| async {
+        foxglove_connect(socket, state).await;
+    });
+    // A client must not be answered with a subprotocol it did not offer
+    if offers_subprotocol(protocols.as_deref()) {
+        Ok(warp::reply::with_header(reply, "sec-websocket-protocol", SUBPROTOCOL).into_response())
+    } else {
+        Ok(reply.into_response())
+    }
+}
+
+pub async fn foxglove_connect(ws: WebSocket, state: StateHandle) {
+    let connection_id = NEXT_FOXGLOVE_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+    info!("New Foxglove connection {}", connection_id);
+
+    let (mut client_ws_sender, mut client_ws_rcv) = ws.split();
+    let hub = state.lock().unwrap().hub();
+    let (tx, mut rx) = mpsc::channel::<Message>(4096);
+
+    tokio::spawn(async move {
+        while let Some(message) = rx.recv().await {
+            if let Err(e) = client_ws_sender.send(message).await {
+                error!("Error sending message: {}", e);
+                break;
+            }
+        }
+    });
+
+    let server_info = json!({
+        "op": "serverInfo",
+        "name": "waterman-bridge",
+        "capabilities": [],
+        "supportedEncodings": [],
+        "metadata": {},
+        "sessionId": connection_id.to_string(),
+    });
+    if tx.send(Message::text(server_info.to_string())).await.is_err() {
+        return;
+    }
+
+    let mut channels: HashMap<String, (Channel, SchemaRef)> = HashMap::new();
+    let mut subscriptions: HashMap<u32, (u32, JoinHandle<()>)> = HashMap::new();   // by id: channel id, relay
+    let mut next_channel_id = 1;
+    let mut advertise_ticker = tokio::time::interval(ADVERTISE_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = advertise_ticker.tick() => {
+                // Advertise new topics; a topic whose schema changed gets a new channel
+                let current: Vec<(String, SchemaRef)> = {
+                    let state_guard = state.lock().unwrap();
+                    topics::topic_names(&state_guard)
+                        .into_iter()
+                        .filter_map(|name| topics::topic_schema(&state_guard, &name).map(|schema| (name, schema)))
+                        .collect()
+                };
+
+                let mut advertised = Vec::new();
+                let mut withdrawn = Vec::new();
+                for (topic, schema) in current {
+                    if let Some((channel, known)) = channels.get(&topic) {
+                        if known.fields() == schema.fields() {
+                            continue;
+                        }
+                        withdrawn.push(channel.id);
+                    }
+                    let channel = Channel {
+                        id: next_channel_id,
+                        topic: topic.clone(),
+                        encoding: "json",
+                        schema_name: topic.clone(),
+                        schema: json_schema(&schema).to_string(),
+                        schema_encoding: "jsonschema",
+                    };
+                    next_channel_id += 1;
+                    advertised.push(channel.clone());
+                    channels.insert(topic, (channel, schema));
+                }
+
+                if !withdrawn.is_empty() {
+                    // Subscriptions end with their channel; the client subscribes to the new one
+                    subscriptions.retain(|_, (channel_id, relay)| {
+                        let keep = !withdrawn.contains(channel_id);
+                        if !keep {
+                            relay.abort();
+                        }
+                        keep
+                    });
+                    let message = json!({ "op": "unadvertise", "channelIds": withdrawn });
+                    if tx.send(Message::text(message.to_string())).await.is_err() {
+                        break;
+                    }
+                }
+                if !advertised.is_empty() {
+                    info!("Foxglove connection {} advertising {} channels", connection_id, advertised.len());
+                    let message = json!({ "op": "advertise", "channels": advertised });
+                    if tx.send(Message::text(message.to_string())).await.is_err() {
+                        break;
+                    }
+                }
+            },
+            message = client_ws_rcv.next() => {
+                let Some(Ok(message)) = message else { break };
+                let Ok(text) = message.to_str() else { continue };
+                let reply = match serde_json::from_str::<ClientOp>(text) {
+                    Ok(ClientOp::Subscribe { subscriptions: requested }) => {
+                        let mut errors = Vec::new();
+                        for subscription in requested {
+                            let Some((channel, _)) = channels.values().find(|(c, _)| c.id == subscription.channel_id) else {
+                                errors.push(format!("Unknown channel {}", subscription.channel_id));
+                                continue;
+                            };
+                            info!("Foxglove connection {} subscribed to '{}'", connection_id, channel.topic);
+                            let relay = tokio::spawn(relay_topic(hub.clone(), channel.topic.clone(), subscription.id, tx.clone()));
+                            if let Some((_, previous)) = subscriptions.insert(subscription.id, (channel.id, relay)) {
+                                previous.abort();
+                            }
+                        }
+                        (!errors.is_empty()).then(|| status(STATUS_ERROR, errors.join(", ")))
+                    },
+                    Ok(ClientOp::Unsubscribe { subscription_ids }) => {
+                        for id in subscription_ids {
+                            if let Some((_, relay)) = subscriptions.remove(&id) {
+                                relay.abort();
+                            }
+                        }
+                        None
+                    },
+                    Err(e) => {
+                        warn!("Ignoring Foxglove message on connection {}: {}", connection_id, e);
+                        Some(status(STATUS_WARNING, format!("Unsupported request: {}", e)))
+                    }
+                };
+                if let Some(reply) = reply
+                    && tx.send(reply).await.is_err()
+                {
+                    break;
+                }
+            },
+        }
+    }
+
+    for (_, relay) in subscriptions.into_values() {
+        relay.abort();
+    }
+    info!("Foxglove connection {} closed", connection_id);
+}
+
+/// Forward a topic to one subscription, a message per row
+async fn relay_topic(hub: HubHandle, topic: String, subscription_id: u32, tx: mpsc::Sender<Message>) {
+    let mut feed = hub.subscribe(&topic);
+    loop {
+        let messages = match feed.recv().await {
+            Ok(batch) => match message_data(subscription_id, &batch) {
+                Ok(messages) => messages,
+                Err(e) => {
+                    error!("Failed to encode '{}' as JSON: {}", topic, e);
+                    continue;
+                }
+            },
+            Err(RecvError::Lagged(skipped)) => {
+                vec![status(STATUS_WARNING, format!("Fell behind on '{}', skipped {} batches", topic, skipped))]
+            },
+            Err(RecvError::Closed) => break,
+        };
+        for message in messages {
+            if tx.send(message).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{ArrayRef, Date32Array, Float64Array, Int32Array, StringArray, TimestampMillisecondArray, UInt64Array};
+    use std::sync::Arc;
+
+    fn frames(batch: &RecordBatch) -> Vec<(u32, u64, Value)> {
+        message_data(7, batch)
+            .unwrap()
+            .iter()
+            .map(|message| {
+                let frame = message.as_bytes();
+                assert_eq!(frame[0], MESSAGE_DATA_OPCODE);
+                let subscription_id = u32::from_le_bytes(frame[1..5].try_into().unwrap());
+                let receive_time = u64::from_le_bytes(frame[5..13].try_into().unwrap());
+                (subscription_id, receive_time, serde_json::from_slice(&frame[13..]).unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn advertises_a_json_schema_matching_the_written_rows() {
+        let channel = Field::new("channel", DataType::Struct(vec![Field::new("value", DataType::Float64, false)].into()), false);
+        let schema = Schema::new(vec![
+            Field::new("timestamp", DataType::UInt64, false),
+            Field::new("at", DataType::Timestamp(TimeUnit::Millisecond, None), false),
+            Field::new("day", DataType::Date32, false),
+            Field::new("count", DataType::Int32, false),
+            Field::new("valid", DataType::Boolean, false),
+            Field::new("label", DataType::Utf8, false),
+            Field::new("samples", DataType::new_list(DataType::Float32, false), false),
+            channel,
+            Field::new("raw", DataType::Binary, false),
+        ]);
+
+        let properties = &json_schema(&schema)["properties"];
+        assert_eq!(properties["timestamp"], json!({ "type": "integer" }));
+        assert_eq!(properties["at"], json!({ "type": "string" }));
+        assert_eq!(properties["day"], json!({ "type": "string" }));
+        assert_eq!(properties["count"], json!({ "type": "integer" }));
+        assert_eq!(properties["valid"], json!({ "type": "boolean" }));
+        assert_eq!(properties["label"], json!({ "type": "string" }));
+        assert_eq!(properties["samples"], json!({ "type": "array", "items": { "type": "number" } }));
+        assert_eq!(properties["channel"], json!({ "type": "object", "properties": { "value": { "type": "number" } } }));
+        assert_eq!(properties["raw"], json!({}));
+
+        let batch = RecordBatch::try_from_iter(vec![
+            ("at", Arc::new(TimestampMillisecondArray::from(vec![1_700_000_000_000])) as ArrayRef),
+            ("day", Arc::new(Date32Array::from(vec![19_000])) as ArrayRef),
+        ])
+        .unwrap();
+        let (_, _, row) = frames(&batch).remove(0);
+        assert!(row["at"].is_string());
+        assert!(row["day"].is_string());
+    }
+
+    #[test]
+    fn frames_carry_each_row_with_its_own_time() {
+        let batch = RecordBatch::try_from_iter(vec![
+            ("timestamp", Arc::new(UInt64Array::from(vec![1_000, 1_010])) as ArrayRef),
+            ("count", Arc::new(Int32Array::from(vec![1, 2])) as ArrayRef),
+            ("label", Arc::new(StringArray::from(vec!["a", "b"])) as ArrayRef),
+        ])
+        .unwrap();
+        assert_eq!(frames(&batch), vec![
+            (7, 1_000_000_000, json!({ "timestamp": 1000, "count": 1, "label": "a" })),
+            (7, 1_010_000_000, json!({ "timestamp": 1010, "count": 2, "label": "b" })),
+        ]);
+
+        let timestamps = Arc::new(TimestampMillisecondArray::from(vec![Some(5), None])) as ArrayRef;
+        let batch = RecordBatch::try_from_iter(vec![("timestamp", timestamps)]).unwrap();
+        let before = now_ns();
+        let times: Vec<u64> = frames(&batch).iter().map(|(_, time, _)| *time).collect();
+        assert_eq!(times[0], 5_000_000);
+        assert!(times[1] >= before);
+
+        let batch = RecordBatch::try_from_iter(vec![("timestamp", Arc::new(Float64Array::from(vec![2.5])) as ArrayRef)]).unwrap();
+        assert_eq!(frames(&batch)[0].1, 2_500_000);
+
+        let batch = RecordBatch::try_from_iter(vec![("count", Arc::new(Int32Array::from(vec![1])) as ArrayRef)]).unwrap();
+        assert!(frames(&batch)[0].1 >= before);
+    }
+
+    #[test]
+    fn subprotocol_is_echoed_only_when_offered() {
+        assert!(offers_subprotocol(Some("foxglove.websocket.v1")));
+        assert!(offers_subprotocol(Some("other, foxglove.websocket.v1")));
+        assert!(!offers_subprotocol(Some("other")));
+        assert!(!offers_subprotocol(Some("foxglove.websocket.v10")));
+        assert!(!offers_subprotocol(None));
+    }
+}
@@ -0,0 +1,320 @@
+//! Time-range queries over what the bridge has retained of a stream: the
+//! in-memory history kept by the hub plus any Parquet recordings of it.
+
+use crate::recorder;
+use crate::stream_hub::StreamHub;
+use arrow::array::{AsArray, Float64Array, UInt32Array};
+use arrow::compute::kernels::cmp::{gt_eq, lt, lt_eq};
+use arrow::compute::{and, cast, concat_batches, filter_record_batch, take_record_batch};
+use arrow::datatypes::{DataType, Float64Type, Schema};
+use arrow::error::ArrowError;
+use arrow::ipc::writer::StreamWriter;
+use arrow::record_batch::RecordBatch;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Media type of an Arrow IPC stream
+pub const ARROW_STREAM_MIME: &str = "application/vnd.apache.arrow.stream";
+
+fn default_time_column() -> String {
+    "timestamp".to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RangeQuery {
+    #[serde(default)]
+    pub stream: Option<String>,         // None = the synthetic stream, or the connection's stream on `/ws`
+    pub t_start: f64,                   // inclusive, in the unit of the time column
+    pub t_end: f64,                     // inclusive
+    #[serde(default)]
+    pub channels: Option<Vec<String>>,  // None = every column
+    #[serde(default)]
+    pub max_points: Option<usize>,      // thin the result to at most this many rows
+    #[serde(default = "default_time_column")]
+    pub time_column: String,
+}
+
+/// Rows of `batch` whose time lies in the query range (and before `before`, if given)
+fn select_range(batch: &RecordBatch, query: &RangeQuery, before: Option<f64>) -> Result<Option<RecordBatch>, ArrowError> {
+    let Ok(index) = batch.schema().index_of(&query.time_column) else {
+        return Ok(None);
+    };
+
+    let times = cast(batch.column(index), &DataType::Float64)?;
+    let mut mask = and(
+        &gt_eq(&times, &Float64Array::new_scalar(query.t_start))?,
+        &lt_eq(&times, &Float64Array::new_scalar(query.t_end))?,
+    )?;
+    if let Some(before) = before {
+        mask = and(&mask, &lt(&times, &Float64Array::new_scalar(before))?)?;
+    }
+
+    let selected = filter_record_batch(batch, &mask)?;
+    Ok((selected.num_rows() > 0).then_some(selected))
+}
+
+/// Keep the time column and the requested channels, dropping schema metadata so
+/// recorded and live batches of the same layout line up
+fn project(batch: &RecordBatch, query: &RangeQuery) -> Result<RecordBatch, ArrowError> {
+    let schema = batch.schema();
+    let indices: Vec<usize> = match &query.channels {
+        Some(channels) => std::iter::once(query.time_column.as_str())
+            .chain(channels.iter().map(String::as_str))
+            .filter_map(|name| schema.index_of(name).ok())
+            .collect(),
+        None => (0..schema.fields().len()).collect(),
+    };
+
+    let projected = batch.project(&indices)?;
+    let schema = Schema::new(projected.schema().fields().clone());
+    RecordBatch::try_new(Arc::new(schema), projected.columns().to_vec())
+}
+
+fn earliest_time(batches: &[RecordBatch], time_column: &str) -> Option<f64> {
+    batches
+        .iter()
+        .filter_map(|batch| {
+            let index = batch.schema().index_of(time_column).ok()?;
+            let times = cast(batch.column(index), &DataType::Float64).ok()?;
+            arrow::compute::min(times.as_primitive::<Float64Type>())
+        })
+        .reduce(f64::min)
+}
+
+fn read_recording(path: &Path, query: &RangeQuery, before: Option<f64>, out: &mut Vec<RecordBatch>) -> Result<(), String> {
+    for batch in recorder::open_recording(path)? {
+        let batch = batch.map_err(|e| e.to_string())?;
+        if let Some(selected) = select_range(&batch, query, before).map_err(|e| e.to_string())? {
+            out.push(selected);
+        }
+    }
+    Ok(())
+}
+
+/// Merge consecutive batches that share a schema
+fn coalesce(batches: Vec<RecordBatch>) -> Result<Vec<RecordBatch>, ArrowError> {
+    let mut merged = Vec::new();
+    let mut run: Vec<RecordBatch> = Vec::new();
+    for batch in batches {
+        if run.first().is_some_and(|first| first.schema() != batch.schema()) {
+            merged.push(concat_batches(&run[0].schema(), &run)?);
+            run.clear();
+        }
+        run.push(batch);
+    }
+    if let Some(first) = run.first() {
+        merged.push(concat_batches(&first.schema(), &run)?);
+    }
+    Ok(merged)
+}
+
+/// Keep every n-th row so the whole result has at most `max_points` rows
+fn decimate(batches: Vec<RecordBatch>, max_points: usize) -> Result<Vec<RecordBatch>, ArrowError> {
+    let total: usize = batches.iter().map(|b| b.num_rows()).sum();
+    if max_points == 0 || total <= max_points {
+        return Ok(batches);
+    }
+
+    let step = total.div_ceil(max_points);
+    let mut offset = 0;
+    let mut thinned = Vec::with_capacity(batches.len());
+    for batch in batches {
+        let first = (step - offset % step) % step;
+        let indices: UInt32Array = (first..batch.num_rows()).step_by(step).map(|i| i as u32).collect();
+        offset += batch.num_rows();
+        if !indices.is_empty() {
+            thinned.push(take_record_batch(&batch, &indices)?);
+        }
+    }
+    Ok(thinned)
+}
+
+/// Answer a range query for `stream`. Reads Parquet files, so call it from a blocking context.
+///
+/// Recordings only contribute rows older than the oldest row still held in memory,
+/// which keeps data that is both recorded and retained from showing up twice.
+pub fn run_range_query(hub: &StreamHub, recordings_dir: &Path, stream: &str, query: &RangeQuery) -> Result<Vec<RecordBatch>, String> {
+    if query.t_end < query.t_start {
+        return Err(format!("t_end ({}) is before t_start ({})", query.t_end, query.t_start));
+    }
+
+    let retained = hub.history(stream);
+    let memory_start = earliest_time(&retained, &query.time_column);
+
+    let mut selected = Vec::new();
+    if memory_start.is_none_or(|start| start > query.t_start) {
+        for path in recorder::recorded_files(recordings_dir, stream) {
+            // The file of a recording still in progress has no footer yet
+            if let Err(e) = read_recording(&path, query, memory_start, &mut selected) {
+                debug!("Skipping {} for range query: {}", path.display(), e);
+            }
+        }
+    }
+    for batch in &retained {
+        if let Some(rows) = select_range(batch, query, None).map_err(|e| e.to_string())? {
+            selected.push(rows);
+        }
+    }
+
+    let projected = selected
+        .iter()
+        .map(|batch| project(batch, query))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+    let mut result = coalesce(projected).map_err(|e| e.to_string())?;
+    if let Some(max_points) = query.max_points {
+        result = decimate(result, max_points).map_err(|e| e.to_string())?;
+    }
+
+    info!(
+        "Range query on '{}' [{}, {}] returned {} rows",
+        stream,
+        query.t_start,
+        query.t_end,
+        result.iter().map(|b| b.num_rows()).sum::<usize>()
+    );
+    Ok(result)
+}
+
+/// Serialize batches that share one schema as a single Arrow IPC stream
+pub fn to_ipc_stream(batches: &[RecordBatch]) -> Result<Vec<u8>, ArrowError> {
+    let mut buffer = Vec::new();
+    if let Some(first) = batches.first() {
+        let mut writer = StreamWriter::try_new(&mut buffer, &first.schema())?;
+        for batch in batches {
+            writer.write(batch)?;
+        }
+        writer.finish()?;
+    }
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{ArrayRef, Int64Array};
+    use arrow::datatypes::Int64Type;
+    use parquet::arrow::ArrowWriter;
+    use std::fs::File;
+
+    fn batch(times: std::ops::Range<i64>) -> RecordBatch {
+        let values: Vec<f64> = times.clone().map(|t| t as f64 / 10.0).collect();
+        RecordBatch::try_from_iter(vec![
+            ("timestamp", Arc::new(Int64Array::from_iter_values(times)) as ArrayRef),
+            ("a", Arc::new(Float64Array::from(values.clone())) as ArrayRef),
+            ("b", Arc::new(Float64Array::from(values)) as ArrayRef),
+        ])
+        .unwrap()
+    }
+
+    fn query(t_start: f64, t_end: f64) -> RangeQuery {
+        RangeQuery {
+            stream: None,
+            t_start,
+            t_end,
+            channels: None,
+            max_points: None,
+            time_column: default_time_column(),
+        }
+    }
+
+    /// A hub whose stream retains one batch per `(start, end)` time range
+    fn hub(parts: &[(i64, i64)]) -> StreamHub {
+        let hub = StreamHub::new();
+        for &(start, end) in parts {
+            hub.publish("stream", batch(start..end));
+        }
+        hub
+    }
+
+    fn times(batches: &[RecordBatch]) -> Vec<i64> {
+        batches
+            .iter()
+            .flat_map(|b| b.column(0).as_primitive::<Int64Type>().values().to_vec())
+            .collect()
+    }
+
+    const NONE: &str = "/nonexistent";
+
+    #[test]
+    fn range_bounds_are_inclusive() {
+        let hub = hub(&[(0, 5), (5, 10)]);
+        let result = run_range_query(&hub, Path::new(NONE), "stream", &query(2.0, 7.0)).unwrap();
+        assert_eq!(times(&result), vec![2, 3, 4, 5, 6, 7]);
+
+        assert!(run_range_query(&hub, Path::new(NONE), "stream", &query(20.0, 30.0)).unwrap().is_empty());
+        assert!(run_range_query(&hub, Path::new(NONE), "stream", &query(7.0, 2.0)).is_err());
+    }
+
+    #[test]
+    fn batches_of_one_layout_are_coalesced() {
+        let hub = hub(&[(0, 3), (3, 6), (6, 9)]);
+        let result = run_range_query(&hub, Path::new(NONE), "stream", &query(0.0, 100.0)).unwrap();
+        assert_eq!(result.len(), 1);
+        assert_eq!(times(&result), (0..9).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn projects_the_time_column_and_requested_channels() {
+        let hub = hub(&[(0, 3)]);
+        let mut range = query(0.0, 10.0);
+        range.channels = Some(vec!["b".to_string(), "missing".to_string()]);
+        let result = run_range_query(&hub, Path::new(NONE), "stream", &range).unwrap();
+
+        let schema = result[0].schema();
+        let names: Vec<&String> = schema.fields().iter().map(|f| f.name()).collect();
+        assert_eq!(names, vec!["timestamp", "b"]);
+        assert!(schema.metadata().is_empty());
+    }
+
+    #[test]
+    fn max_points_thins_evenly_across_batches() {
+        let mut range = query(0.0, 100.0);
+        range.max_points = Some(3);
+        let thinned = decimate(vec![batch(0..3), batch(3..7), batch(7..10)], 3).unwrap();
+        assert_eq!(times(&thinned), vec![0, 4, 8]);
+
+        let hub = hub(&[(0, 10)]);
+        let result = run_range_query(&hub, Path::new(NONE), "stream", &range).unwrap();
+        assert_eq!(times(&result), vec![0, 4, 8]);
+
+        range.max_points = Some(0);
+        assert_eq!(times(&run_range_query(&hub, Path::new(NONE), "stream", &range).unwrap()).len(), 10);
+    }
+
+    #[test]
+    fn a_schema_change_in_range_yields_separate_batches() {
+        let hub = hub(&[(0, 3)]);
+        let narrower = batch(3..6).project(&[0, 1]).unwrap();
+        hub.publish("stream", narrower);
+
+        let result = run_range_query(&hub, Path::new(NONE), "stream", &query(0.0, 10.0)).unwrap();
+        assert_eq!(result.len(), 2);
+
+        let mut range = query(0.0, 10.0);
+        range.channels = Some(vec!["a".to_string()]);
+        assert_eq!(run_range_query(&hub, Path::new(NONE), "stream", &range).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn recordings_fill_in_before_the_retained_history() {
+        let root = std::env::temp_dir().join(format!("wmhistory-test-{}", std::process::id()));
+        let session = root.join("1");
+        std::fs::create_dir_all(&session).unwrap();
+        let recorded = batch(0..10);
+        let file = File::create(session.join("stream-0000.parquet")).unwrap();
+        let mut writer = ArrowWriter::try_new(file, recorded.schema(), None).unwrap();
+        writer.write(&recorded).unwrap();
+        writer.close().unwrap();
+
+        let hub = hub(&[(6, 12)]);
+        let overlapping = run_range_query(&hub, &root, "stream", &query(2.0, 11.0));
+        let in_memory = run_range_query(&hub, &root, "stream", &query(7.0, 11.0));
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(times(&overlapping.unwrap()), (2..12).collect::<Vec<_>>());
+        assert_eq!(times(&in_memory.unwrap()), (7..12).collect::<Vec<_>>());
+    }
+}
@@ -1,9 +1,16 @@
+use crate::capture::CaptureParams;
+use crate::encoding;
+use crate::history::{self, RangeQuery, ARROW_STREAM_MIME};
 use crate::parameters::Parameters;
+use crate::recorder::RecorderParams;
+use crate::runs::{RunParams, RunTrigger};
+use crate::sql::{self, SqlRequest};
 use crate::state::StateHandle;
-use log::{debug, error, info};
+use crate::stream_hub::SYNTHETIC_STREAM;
+use crate::topics;
+use log::{error, info};
 use serde_json::json;
 use warp::{Filter, Rejection, Reply};
-use std::convert::Infallible;
 use std::fs::File;
 use std::io::Write;
 
@@ -11,7 +18,7 @@ use std::io::Write;
 fn save_parameters_to_yaml(params: &Parameters) -> std::io::Result<()> {
     let yaml_content = serde_yaml::to_string(params).map_err(|e| {
         error!("Failed to serialize parameters to YAML: {}", e);
-        std::io::Error::new(std::io::ErrorKind::Other, e)
+        std::io::Error::other(e)
     })?;
     
     let file_path = "config/parameters.yaml";
@@ -62,11 +69,62 @@ pub fn get_routes(
         .and(state_filter.clone())
         .and_then(handle_get_yaml);
 
+    let list_topics = warp::path!("topics")
+        .and(warp::get())
+        .and(state_filter.clone())
+        .and_then(handle_list_topics);
+
+    let get_encodings = warp::path!("encodings")
+        .and(warp::get())
+        .and(state_filter.clone())
+        .and_then(handle_get_encodings);
+
+    let compare_encodings = warp::path!("encodings" / "compare")
+        .and(warp::get())
+        .and(warp::query::<std::collections::HashMap<String, String>>())
+        .and(state_filter.clone())
+        .and_then(handle_compare_encodings);
+
+    let get_latency = warp::path!("latency")
+        .and(warp::get())
+        .and(state_filter.clone())
+        .and_then(handle_get_latency);
+
+    let get_connection_latency = warp::path!("latency" / u64)
+        .and(warp::get())
+        .and(state_filter.clone())
+        .and_then(handle_get_connection_latency);
+
+    let get_connections = warp::path!("connections")
+        .and(warp::get())
+        .and(state_filter.clone())
+        .and_then(handle_get_connections);
+
+    let list_runs = warp::path!("runs")
+        .and(warp::get())
+        .and(state_filter.clone())
+        .and_then(handle_list_runs);
+
+    let get_run = warp::path!("runs" / u64)
+        .and(warp::get())
+        .and(state_filter.clone())
+        .and_then(handle_get_run);
+
     let status = warp::path!("status")
         .and(warp::get())
         .and(state_filter.clone())
         .and_then(handle_status);
 
+    let get_recording = warp::path!("recording")
+        .and(warp::get())
+        .and(state_filter.clone())
+        .and_then(handle_get_recording);
+
+    let get_capture = warp::path!("capture")
+        .and(warp::get())
+        .and(state_filter.clone())
+        .and_then(handle_get_capture);
+
     // PUT routes (need careful body handling)
     let update_parameters = warp::path("parameters")
         .and(warp::put())
@@ -96,6 +154,74 @@ pub fn get_routes(
         .and(state_filter.clone())
         .and_then(handle_update_websocket_params);
     
+    let update_topic_parameters = warp::path!("topics" / String / "parameters")
+        .and(warp::put())
+        .and(warp::body::content_length_limit(1024 * 1024))
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(handle_update_topic_parameters);
+
+    // POST routes for recording control
+    let start_recording = warp::path!("recording" / "start")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024 * 1024))
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(handle_start_recording);
+
+    let stop_recording = warp::path!("recording" / "stop")
+        .and(warp::post())
+        .and(state_filter.clone())
+        .and_then(handle_stop_recording);
+
+    let start_capture = warp::path!("capture" / "start")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024 * 1024))
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(handle_start_capture);
+
+    let stop_capture = warp::path!("capture" / "stop")
+        .and(warp::post())
+        .and(state_filter.clone())
+        .and_then(handle_stop_capture);
+
+    let start_run = warp::path!("runs" / "start")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024 * 1024))
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(handle_start_run);
+
+    let stop_run = warp::path!("runs" / u64 / "stop")
+        .and(warp::post())
+        .and(state_filter.clone())
+        .and_then(handle_stop_run);
+
+    let reset_encodings = warp::path!("encodings" / "reset")
+        .and(warp::post())
+        .and(state_filter.clone())
+        .and_then(handle_reset_encodings);
+
+    let reset_latency = warp::path!("latency" / "reset")
+        .and(warp::post())
+        .and(state_filter.clone())
+        .and_then(handle_reset_latency);
+
+    let query_history = warp::path!("history")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024 * 1024))
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(handle_query_history);
+
+    let query_sql = warp::path!("query")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024 * 1024))
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(handle_query_sql);
+    
     // CORS settings for the API
     let cors = warp::cors()
         .allow_any_origin()
@@ -108,16 +234,39 @@ pub fn get_routes(
         .or(get_data_characteristics)
         .or(get_websocket_params)
         .or(get_yaml)
-        .or(status);
+        .or(status)
+        .or(list_topics)
+        .or(get_encodings)
+        .or(compare_encodings)
+        .or(get_latency)
+        .or(get_connection_latency)
+        .or(get_connections)
+        .or(list_runs)
+        .or(get_run)
+        .or(get_recording)
+        .or(get_capture);
         
     let put_routes = update_data_volume
         .or(update_data_characteristics)
         .or(update_websocket_params)
-        .or(update_parameters);
+.or(update_parameters)
+        .or(update_topic_parameters);
+
+    let post_routes = start_recording
+        .or(stop_recording)
+        .or(start_capture)
+        .or(stop_capture)
+        .or(start_run)
+        .or(stop_run)
+        .or(reset_encodings)
+        .or(reset_latency)
+        .or(query_history)
+        .or(query_sql);
     
     // Combine all routes, GET routes first
     get_routes
         .or(put_routes)
+        .or(post_routes)
         .with(cors)
         .with(warp::log("http_api"))
 }
@@ -239,18 +388,87 @@ async fn handle_update_websocket_params(
 
 async fn handle_status(state: StateHandle) -> Result<impl Reply> {
     let state_guard = state.lock().unwrap();
-    let current_rate = state_guard.parameters.data_volume.data_rate_hz;
+    let current_rate = state_guard.get_send_rate_hz();
     
     let status = json!({
         "time_ms": state_guard.t_ms,
         "last_update_ms": state_guard.last_t_ms,
         "data_rate_hz": current_rate,
+        "streams": state_guard.hub.stream_names(),
+        "history": state_guard.hub.history_stats(),
+        "generators": state_guard.generator_rates.snapshot(),
+        "send_queues": state_guard.send_queues.totals(),
     });
     
     info!("Status requested: current data_rate_hz = {}", current_rate);
     Ok(warp::reply::json(&status))
 }
 
+async fn handle_get_encodings(state: StateHandle) -> Result<impl Reply> {
+    let stats = state.lock().unwrap().encoding_stats();
+    Ok(warp::reply::json(&stats.summary()))
+}
+
+async fn handle_reset_encodings(state: StateHandle) -> Result<impl Reply> {
+    state.lock().unwrap().encoding_stats().reset();
+    info!("Encoding statistics reset");
+    Ok(warp::reply::with_status(warp::reply::reply(), warp::http::StatusCode::NO_CONTENT))
+}
+
+async fn handle_get_latency(state: StateHandle) -> Result<impl Reply> {
+    let stats = state.lock().unwrap().latency_stats();
+    Ok(warp::reply::json(&stats.summary()))
+}
+
+async fn handle_get_connections(state: StateHandle) -> Result<impl Reply> {
+    let queues = state.lock().unwrap().send_queues();
+    Ok(warp::reply::json(&queues.summary()))
+}
+
+async fn handle_get_connection_latency(connection_id: u64, state: StateHandle) -> Result<impl Reply> {
+    let stats = state.lock().unwrap().latency_stats();
+    match stats.connection(connection_id) {
+        Some(summary) => Ok(warp::reply::with_status(warp::reply::json(&summary), warp::http::StatusCode::OK)),
+        None => Ok(warp::reply::with_status(
+            warp::reply::json(&json!({ "error": format!("No latency data for connection {}", connection_id) })),
+            warp::http::StatusCode::NOT_FOUND,
+        )),
+    }
+}
+
+async fn handle_reset_latency(state: StateHandle) -> Result<impl Reply> {
+    state.lock().unwrap().latency_stats().reset();
+    info!("Latency statistics reset");
+    Ok(warp::reply::with_status(warp::reply::reply(), warp::http::StatusCode::NO_CONTENT))
+}
+
+/// Encode a stream's retained history in every encoding, e.g. `/encodings/compare?stream=imu`
+async fn handle_compare_encodings(query: std::collections::HashMap<String, String>, state: StateHandle) -> Result<impl Reply> {
+    let stream = query.get("stream").cloned().unwrap_or_else(|| SYNTHETIC_STREAM.to_string());
+    let batches = state.lock().unwrap().hub().history(&stream);
+    if batches.is_empty() {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&json!({ "error": format!("No history retained for '{}'", stream) })),
+            warp::http::StatusCode::NOT_FOUND,
+        ));
+    }
+
+    let rows: usize = batches.iter().map(|b| b.num_rows()).sum();
+    let result = tokio::task::spawn_blocking(move || encoding::compare(&batches))
+        .await
+        .unwrap_or_else(|e| Err(format!("Comparison panicked: {}", e)));
+    match result {
+        Ok(summary) => Ok(warp::reply::with_status(
+            warp::reply::json(&json!({ "stream": stream, "rows": rows, "encodings": summary })),
+            warp::http::StatusCode::OK,
+        )),
+        Err(e) => Ok(warp::reply::with_status(
+            warp::reply::json(&json!({ "error": e })),
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
 // New handler function to get the parameters as YAML
 async fn handle_get_yaml(state: StateHandle) -> Result<impl Reply> {
     let parameters = state.lock().unwrap().get_parameters();
@@ -276,3 +494,234 @@ async fn handle_get_yaml(state: StateHandle) -> Result<impl Reply> {
         }
     }
 }
+
+async fn handle_list_runs(state: StateHandle) -> Result<impl Reply> {
+    let runs = state.lock().unwrap().runs();
+    Ok(warp::reply::json(&runs.list()))
+}
+
+async fn handle_get_run(id: u64, state: StateHandle) -> Result<impl Reply> {
+    let runs = state.lock().unwrap().runs();
+    match runs.get(id).await {
+        Some(summary) => Ok(warp::reply::with_status(warp::reply::json(&summary), warp::http::StatusCode::OK)),
+        None => Ok(warp::reply::with_status(
+            warp::reply::json(&json!({ "error": format!("Unknown run {}", id) })),
+            warp::http::StatusCode::NOT_FOUND,
+        )),
+    }
+}
+
+async fn handle_start_run(params: RunParams, state: StateHandle) -> Result<impl Reply> {
+    let (runs, parameters) = {
+        let state_guard = state.lock().unwrap();
+        (state_guard.runs(), state_guard.topic_parameters(&params.topic))
+    };
+    let Some(parameters) = parameters else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&json!({ "error": format!("'{}' is not a generator topic", params.topic) })),
+            warp::http::StatusCode::NOT_FOUND,
+        ));
+    };
+    match runs.start(params, RunTrigger::Api, parameters) {
+        Ok(summary) => Ok(warp::reply::with_status(warp::reply::json(&summary), warp::http::StatusCode::OK)),
+        Err(e) => {
+            error!("Failed to start a run: {}", e);
+            Ok(warp::reply::with_status(
+                warp::reply::json(&json!({ "error": e })),
+                warp::http::StatusCode::CONFLICT,
+            ))
+        }
+    }
+}
+
+async fn handle_stop_run(id: u64, state: StateHandle) -> Result<impl Reply> {
+    let runs = state.lock().unwrap().runs();
+    match runs.stop(id) {
+        Ok(summary) => Ok(warp::reply::with_status(warp::reply::json(&summary), warp::http::StatusCode::OK)),
+        Err(e) => Ok(warp::reply::with_status(
+            warp::reply::json(&json!({ "error": e })),
+            warp::http::StatusCode::NOT_FOUND,
+        )),
+    }
+}
+
+async fn handle_get_recording(state: StateHandle) -> Result<impl Reply> {
+    let recorder = state.lock().unwrap().recorder();
+    Ok(warp::reply::json(&recorder.status()))
+}
+
+async fn handle_start_recording(params: RecorderParams, state: StateHandle) -> Result<impl Reply> {
+    let recorder = state.lock().unwrap().recorder();
+    match recorder.start(params) {
+        Ok(status) => {
+            info!("Recording started via API");
+            Ok(warp::reply::with_status(warp::reply::json(&status), warp::http::StatusCode::OK))
+        },
+        Err(e) => {
+            error!("Failed to start recording: {}", e);
+            Ok(warp::reply::with_status(
+                warp::reply::json(&json!({ "error": e })),
+                warp::http::StatusCode::CONFLICT,
+            ))
+        }
+    }
+}
+
+async fn handle_stop_recording(state: StateHandle) -> Result<impl Reply> {
+    let recorder = state.lock().unwrap().recorder();
+    match recorder.stop().await {
+        Ok(status) => {
+            info!("Recording stopped via API");
+            Ok(warp::reply::with_status(warp::reply::json(&status), warp::http::StatusCode::OK))
+        },
+        Err(e) => Ok(warp::reply::with_status(
+            warp::reply::json(&json!({ "error": e })),
+            warp::http::StatusCode::CONFLICT,
+        )),
+    }
+}
+
+async fn handle_get_capture(state: StateHandle) -> Result<impl Reply> {
+    let capture = state.lock().unwrap().capture();
+    Ok(warp::reply::json(&capture.status()))
+}
+
+async fn handle_start_capture(params: CaptureParams, state: StateHandle) -> Result<impl Reply> {
+    let capture = state.lock().unwrap().capture();
+    match capture.start(params) {
+        Ok(status) => {
+            info!("Frame capture started via API");
+            Ok(warp::reply::with_status(warp::reply::json(&status), warp::http::StatusCode::OK))
+        },
+        Err(e) => {
+            error!("Failed to start frame capture: {}", e);
+            Ok(warp::reply::with_status(
+                warp::reply::json(&json!({ "error": e })),
+                warp::http::StatusCode::CONFLICT,
+            ))
+        }
+    }
+}
+
+async fn handle_stop_capture(state: StateHandle) -> Result<impl Reply> {
+    let capture = state.lock().unwrap().capture();
+    match capture.stop().await {
+        Ok(status) => {
+            info!("Frame capture stopped via API");
+            Ok(warp::reply::with_status(warp::reply::json(&status), warp::http::StatusCode::OK))
+        },
+        Err(e) => Ok(warp::reply::with_status(
+            warp::reply::json(&json!({ "error": e })),
+            warp::http::StatusCode::CONFLICT,
+        )),
+    }
+}
+
+async fn handle_query_history(query: RangeQuery, state: StateHandle) -> Result<warp::reply::Response> {
+    let (hub, recordings_dir) = {
+        let state_guard = state.lock().unwrap();
+        (state_guard.hub(), state_guard.recorder().directory())
+    };
+    let stream = query.stream.clone().unwrap_or_else(|| SYNTHETIC_STREAM.to_string());
+
+    let result = tokio::task::spawn_blocking(move || {
+        history::run_range_query(&hub, std::path::Path::new(&recordings_dir), &stream, &query)
+    })
+    .await
+    .unwrap_or_else(|e| Err(format!("Range query panicked: {}", e)));
+
+    let error = |message: String, status| {
+        warp::reply::with_status(warp::reply::json(&json!({ "error": message })), status).into_response()
+    };
+    match result {
+        Ok(batches) if batches.is_empty() => Ok(warp::http::StatusCode::NO_CONTENT.into_response()),
+        // One IPC stream carries one schema, which a range spanning a parameter change doesn't have
+        Ok(batches) if batches.len() > 1 => Ok(error(
+            "The data in this range changes schema; narrow the range or select channels".to_string(),
+            warp::http::StatusCode::CONFLICT,
+        )),
+        Ok(batches) => match history::to_ipc_stream(&batches) {
+            Ok(buffer) => Ok(warp::reply::with_header(buffer, "content-type", ARROW_STREAM_MIME).into_response()),
+            Err(e) => Ok(error(e.to_string(), warp::http::StatusCode::INTERNAL_SERVER_ERROR)),
+        },
+        Err(e) => Ok(error(e, warp::http::StatusCode::BAD_REQUEST)),
+    }
+}
+
+async fn handle_query_sql(request: SqlRequest, state: StateHandle) -> Result<warp::reply::Response> {
+    let (hub, recordings_dir) = {
+        let state_guard = state.lock().unwrap();
+        (state_guard.hub(), state_guard.recorder().directory())
+    };
+
+    let result = sql::execute(hub, std::path::Path::new(&recordings_dir), &request.sql).await;
+    let result = result.and_then(|batches| history::to_ipc_stream(&batches).map_err(|e| e.to_string()));
+    match result {
+        Ok(buffer) => Ok(warp::reply::with_header(buffer, "content-type", ARROW_STREAM_MIME).into_response()),
+        Err(e) => Ok(warp::reply::with_status(
+            warp::reply::json(&json!({ "error": e })),
+            warp::http::StatusCode::BAD_REQUEST,
+        ).into_response()),
+    }
+}
+
+async fn handle_list_topics(state: StateHandle) -> Result<impl Reply> {
+    Ok(warp::reply::json(&topics::list_topics(&state)))
+}
+
+async fn handle_update_topic_parameters(topic: String, params: Parameters, state: StateHandle) -> Result<impl Reply> {
+    let result = state.lock().unwrap().set_topic_parameters(&topic, params.clone());
+    match result {
+        Ok(()) => {
+            info!("Parameters of topic '{}' updated via API", topic);
+            Ok(warp::reply::with_status(warp::reply::json(&params), warp::http::StatusCode::OK))
+        },
+        Err(e) => Ok(warp::reply::with_status(
+            warp::reply::json(&json!({ "error": e })),
+            warp::http::StatusCode::NOT_FOUND,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recorder::Recorder;
+    use crate::state::WSBridgeState;
+    use arrow::array::{ArrayRef, Float64Array, Int64Array};
+    use arrow::record_batch::RecordBatch;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn range_queries_across_a_schema_change_conflict() {
+        let mut state = WSBridgeState::new();
+        state.recorder = Arc::new(Recorder::new(state.hub(), "/nonexistent"));
+        let hub = state.hub();
+        let state = state.into_handle();
+
+        let times = || Arc::new(Int64Array::from(vec![1, 2])) as ArrayRef;
+        let values = || Arc::new(Float64Array::from(vec![0.5, 1.5])) as ArrayRef;
+        hub.publish(SYNTHETIC_STREAM, RecordBatch::try_from_iter(vec![("timestamp", times()), ("a", values())]).unwrap());
+        hub.publish(SYNTHETIC_STREAM, RecordBatch::try_from_iter(vec![("timestamp", times()), ("b", values())]).unwrap());
+
+        let query = |channels: Option<Vec<String>>| RangeQuery {
+            stream: None,
+            t_start: 0.0,
+            t_end: 10.0,
+            channels,
+            max_points: None,
+            time_column: "timestamp".to_string(),
+        };
+        let response = handle_query_history(query(None), state.clone()).await.unwrap();
+        assert_eq!(response.status(), warp::http::StatusCode::CONFLICT);
+
+        let response = handle_query_history(query(Some(Vec::new())), state.clone()).await.unwrap();
+        assert_eq!(response.status(), warp::http::StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], ARROW_STREAM_MIME);
+
+        let mut empty = query(None);
+        empty.t_start = 5.0;
+        let response = handle_query_history(empty, state).await.unwrap();
+        assert_eq!(response.status(), warp::http::StatusCode::NO_CONTENT);
+    }
+}
@@ -0,0 +1,323 @@
+//! Simulated network link between `/ws` and its clients, to test them against poor
+//! radio links.
+//!
+//! A frame enters the link when the token bucket capping its bandwidth has room,
+//! and arrives after the latency plus a jitter drawn from the configured
+//! distribution. The link keeps sending while earlier frames are still on their
+//! way, so latency does not cost throughput. Frames are never reordered though, as
+//! on the TCP connection underneath, so one drawn with a long delay holds back
+//! those behind it.
+//!
+//! Outages start at random, `outage_interval_secs` apart on average, and stop the
+//! link for `outage_duration_ms`: nothing enters it, and frames due to arrive in
+//! the meantime arrive together when it ends.
+//!
+//! With `disconnect_interval_secs`, each connection is dropped that long after it
+//! opened, without a close frame, or after the link was enabled or the interval
+//! changed, if that was later. The timer is per connection and fires once: when the
+//! next drop happens depends on when the client reconnects, so clients reconnecting
+//! at different speeds are not dropped in step.
+
+use crate::parameters::{ImpairmentParams, JitterDistribution};
+use rand::Rng;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+/// Most frames on their way at once; the send queue holds the rest
+pub const MAX_IN_FLIGHT: usize = 4096;
+
+/// Longest a frame is delayed, however far out the jitter distribution reaches
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// A frame leaving this soon after the bucket refilled was waiting for it
+const THROTTLE_SLACK: Duration = Duration::from_millis(5);
+
+/// Shape of the Pareto jitter; lower has a longer tail
+const PARETO_SHAPE: f64 = 2.5;
+
+/// What the link did to a connection's frames, for `/connections`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LinkCounters {
+    pub enabled: bool,
+    pub in_flight: usize,           // frames sent but not arrived yet
+    pub frames: u64,                // arrived
+    pub mean_delay_ms: f64,         // from entering the link to arriving
+    pub max_delay_ms: f64,
+    pub throttled: u64,             // frames that waited for bandwidth
+    pub outages: u64,
+    pub outage_ms: f64,             // time spent in outages
+    pub disconnected: bool,         // dropped by a scheduled disconnect
+}
+
+pub struct Link {
+    params: ImpairmentParams,
+    disconnect_from: Instant,               // opened, or the disconnect was last enabled or changed
+    tokens: f64,                            // bytes; below 0 after a frame bigger than the bucket
+    refilled: Instant,
+    last_arrival: Instant,                  // of the latest frame, which the next may not overtake
+    outage: Option<(Instant, Instant)>,     // the current or next outage
+    total_delay_ms: f64,
+    pub counters: LinkCounters,
+}
+
+impl Link {
+    pub fn new(params: &ImpairmentParams) -> Self {
+        let now = Instant::now();
+        let mut link = Self {
+            params: ImpairmentParams::default(),
+            disconnect_from: now,
+            tokens: 0.0,
+            refilled: now,
+            last_arrival: now,
+            outage: None,
+            total_delay_ms: 0.0,
+            counters: LinkCounters::default(),
+        };
+        link.set_params(params);
+        link
+    }
+
+    /// Take on changed parameters; the bucket starts full, outages are scheduled afresh and a
+    /// newly enabled or changed disconnect counts from now
+    pub fn set_params(&mut self, params: &ImpairmentParams) {
+        if *params == self.params {
+            return;
+        }
+        if params.outage_interval_secs != self.params.outage_interval_secs || !params.enabled {
+            self.outage = None;
+        }
+        if params.enabled != self.params.enabled || params.disconnect_interval_secs != self.params.disconnect_interval_secs {
+            self.disconnect_from = Instant::now();
+        }
+        self.params = params.clone();
+        self.tokens = params.burst_bytes as f64;
+        self.refilled = Instant::now();
+        self.counters.enabled = params.enabled;
+    }
+
+    /// When the link drops the connection, if it does
+    pub fn disconnect_at(&self) -> Option<Instant> {
+        (self.params.enabled && self.params.disconnect_interval_secs > 0.0)
+            .then(|| self.disconnect_from + Duration::from_secs_f64(self.params.disconnect_interval_secs))
+    }
+
+    /// End of the outage the link is in at `now`, if any
+    pub fn outage_end(&mut self, now: Instant) -> Option<Instant> {
+        let mean = self.params.outage_interval_secs;
+        if !self.params.enabled || mean <= 0.0 {
+            return None;
+        }
+        let duration = Duration::from_secs_f64(self.params.outage_duration_ms.max(0.0) / 1000.0);
+        loop {
+            let (start, end) = *self.outage.get_or_insert_with(|| {
+                let start = now + exponential(mean);
+                (start, start + duration)
+            });
+            if now < start {
+                return None;
+            }
+            if now < end {
+                return Some(end);
+            }
+            // Outages follow one another at random, counted once they are over
+            self.counters.outages += 1;
+            self.counters.outage_ms += (end - start).as_secs_f64() * 1000.0;
+            let next = end + exponential(mean);
+            self.outage = Some((next, next + duration));
+        }
+    }
+
+    /// When the next frame may enter the link: after any outage, once the bucket has refilled
+    pub fn ready_at(&mut self, now: Instant) -> Instant {
+        if !self.params.enabled {
+            return now;
+        }
+        let ready = self.outage_end(now).unwrap_or(now);
+        let rate = self.params.bandwidth_bytes_per_sec as f64;
+        let tokens = self.tokens_at(ready);
+        if rate > 0.0 && tokens < 0.0 {
+            ready + Duration::from_secs_f64(-tokens / rate)
+        } else {
+            ready
+        }
+    }
+
+    fn tokens_at(&self, time: Instant) -> f64 {
+        let rate = self.params.bandwidth_bytes_per_sec as f64;
+        let refill = time.saturating_duration_since(self.refilled).as_secs_f64() * rate;
+        (self.tokens + refill).min(self.params.burst_bytes.max(1) as f64)
+    }
+
+    /// Send a frame of `bytes` into the link at `now`, returning when it arrives
+    pub fn send(&mut self, now: Instant, bytes: usize) -> Instant {
+        if !self.params.enabled {
+            self.last_arrival = self.last_arrival.max(now);
+            return self.last_arrival;
+        }
+        let departure = self.outage_end(now).unwrap_or(now);
+        let rate = self.params.bandwidth_bytes_per_sec as f64;
+        if rate > 0.0 {
+            // The last frame left the bucket empty, and this one left as soon as it had refilled
+            let refilled_at = self.refilled + Duration::from_secs_f64(-self.tokens.min(0.0) / rate);
+            if self.tokens < 0.0 && departure <= refilled_at + THROTTLE_SLACK {
+                self.counters.throttled += 1;
+            }
+            self.tokens = self.tokens_at(departure) - bytes as f64;
+            self.refilled = departure;
+        }
+        let delay_ms = (self.params.latency_ms + self.jitter_ms()).max(0.0);
+        let delay = Duration::from_secs_f64(delay_ms / 1000.0).min(MAX_DELAY);
+        self.last_arrival = self.last_arrival.max(departure + delay);
+        self.last_arrival
+    }
+
+    fn jitter_ms(&self) -> f64 {
+        let jitter = self.params.jitter_ms;
+        if jitter <= 0.0 {
+            return 0.0;
+        }
+        let mut rng = rand::thread_rng();
+        match self.params.jitter_distribution {
+            JitterDistribution::Uniform => rng.gen_range(-jitter..=jitter),
+            JitterDistribution::Normal => {
+                // Box-Muller
+                let u: f64 = 1.0 - rng.gen_range(0.0..1.0);
+                let v: f64 = rng.gen_range(0.0..1.0);
+                jitter * (-2.0 * u.ln()).sqrt() * (2.0 * std::f64::consts::PI * v).cos()
+            },
+            JitterDistribution::Pareto => {
+                // Lomax, scaled so the mean is the jitter
+                let u: f64 = 1.0 - rng.gen_range(0.0..1.0);
+                jitter * (PARETO_SHAPE - 1.0) * (u.powf(-1.0 / PARETO_SHAPE) - 1.0)
+            },
+        }
+    }
+
+    /// Count a frame that arrived after `delay` in the link
+    pub fn record_arrival(&mut self, delay: Duration) {
+        let delay_ms = delay.as_secs_f64() * 1000.0;
+        self.counters.frames += 1;
+        self.total_delay_ms += delay_ms;
+        self.counters.mean_delay_ms = self.total_delay_ms / self.counters.frames as f64;
+        self.counters.max_delay_ms = self.counters.max_delay_ms.max(delay_ms);
+    }
+}
+
+/// Time to the next of events happening `mean_secs` apart on average
+fn exponential(mean_secs: f64) -> Duration {
+    let u: f64 = 1.0 - rand::thread_rng().gen_range(0.0..1.0);
+    Duration::from_secs_f64(-u.ln() * mean_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params() -> ImpairmentParams {
+        ImpairmentParams { enabled: true, latency_ms: 100.0, jitter_ms: 0.0, ..Default::default() }
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn disabled_link_passes_frames_through() {
+        let mut link = Link::new(&ImpairmentParams { enabled: false, ..params() });
+        let now = Instant::now();
+        assert_eq!(link.ready_at(now), now);
+        assert_eq!(link.send(now, 1 << 20), now);
+        assert_eq!(link.disconnect_at(), None);
+    }
+
+    #[test]
+    fn latency_does_not_cost_throughput() {
+        let mut link = Link::new(&params());
+        let start = Instant::now();
+        for i in 0..10 {
+            let now = start + ms(i);
+            assert_eq!(link.ready_at(now), now);
+            assert_eq!(link.send(now, 100), now + ms(100));
+        }
+    }
+
+    #[test]
+    fn bandwidth_limits_when_frames_enter() {
+        let mut link = Link::new(&ImpairmentParams { bandwidth_bytes_per_sec: 1000, burst_bytes: 1000, ..params() });
+        let start = Instant::now();
+
+        // The full bucket lets the first frame through, the next waits for it to refill
+        assert_eq!(link.ready_at(start), start);
+        assert_eq!(link.send(start, 1500), start + ms(100));
+        let ready = link.ready_at(start);
+        assert_eq!(ready, start + ms(500));
+        assert_eq!(link.send(ready, 200), ready + ms(100));
+        assert_eq!(link.counters.throttled, 1);
+
+        // Within the burst, once refilled, nothing waits
+        let later = ready + Duration::from_secs(2);
+        assert_eq!(link.ready_at(later), later);
+        assert_eq!(link.send(later, 500), later + ms(100));
+        assert_eq!(link.counters.throttled, 1);
+    }
+
+    #[test]
+    fn frames_are_never_reordered() {
+        let mut link = Link::new(&ImpairmentParams { jitter_ms: 80.0, jitter_distribution: JitterDistribution::Pareto, ..params() });
+        let start = Instant::now();
+        let mut last = start;
+        for i in 0..1000 {
+            let arrival = link.send(start + ms(i), 100);
+            assert!(arrival >= last);
+            assert!(arrival <= start + ms(i) + MAX_DELAY);
+            last = arrival;
+        }
+    }
+
+    #[test]
+    fn outages_hold_frames_until_they_end() {
+        let mut link = Link::new(&ImpairmentParams { outage_interval_secs: 60.0, outage_duration_ms: 1000.0, ..params() });
+        let start = Instant::now();
+        link.outage = Some((start + ms(1000), start + ms(2000)));
+
+        assert_eq!(link.outage_end(start), None);
+        assert_eq!(link.send(start, 100), start + ms(100));
+
+        let during = start + ms(1500);
+        assert_eq!(link.outage_end(during), Some(start + ms(2000)));
+        assert_eq!(link.ready_at(during), start + ms(2000));
+        assert_eq!(link.send(during, 100), start + ms(2100));
+
+        // Counted once over, with the next one scheduled after it
+        assert_eq!(link.outage_end(start + ms(2000)), None);
+        assert_eq!(link.counters.outages, 1);
+        assert_eq!(link.counters.outage_ms, 1000.0);
+        let (next, _) = link.outage.unwrap();
+        assert!(next >= start + ms(2000));
+
+        // Disabling the link clears them
+        link.set_params(&ImpairmentParams { enabled: false, ..params() });
+        assert_eq!(link.outage, None);
+    }
+
+    #[test]
+    fn disconnects_count_from_when_they_were_enabled() {
+        let enabled = ImpairmentParams { disconnect_interval_secs: 10.0, ..params() };
+        let mut link = Link::new(&ImpairmentParams { enabled: false, ..enabled.clone() });
+        assert_eq!(link.disconnect_at(), None);
+
+        // Enabled long after the connection opened, the link does not drop it right away
+        link.disconnect_from -= Duration::from_secs(60);
+        let before = Instant::now();
+        link.set_params(&enabled);
+        assert!(link.disconnect_at().unwrap() >= before + Duration::from_secs(10));
+
+        // Other changes keep the deadline
+        let deadline = link.disconnect_at();
+        link.set_params(&ImpairmentParams { latency_ms: 5.0, ..enabled.clone() });
+        assert_eq!(link.disconnect_at(), deadline);
+
+        link.set_params(&ImpairmentParams { disconnect_interval_secs: 20.0, ..enabled });
+        assert!(link.disconnect_at().unwrap() >= before + Duration::from_secs(20));
+    }
+}
@@ -0,0 +1,47 @@
+//! Arrow IPC framing of the batches sent to clients, and the schema metadata keys
+//! stamped on them along the way.
+
+use arrow::ipc::writer::FileWriter;
+use arrow::record_batch::RecordBatch;
+use std::sync::Arc;
+
+/// Schema metadata key on live batches: when the batch was published to the hub,
+/// i.e. right after it was generated or ingested, in microseconds since the Unix epoch.
+/// Only subscribers see it; the history and taps keep the batch as published.
+pub const GENERATED_AT_METADATA_KEY: &str = "waterman.generated_at_us";
+
+/// Schema metadata key set on batches replayed from the stream history
+pub const BACKFILL_METADATA_KEY: &str = "waterman.backfill";
+
+/// Schema metadata key carrying the `id` of the query a batch answers
+pub const QUERY_ID_METADATA_KEY: &str = "waterman.query_id";
+
+/// Schema metadata key numbering the batches sent on a connection, starting at 0
+pub const SEQUENCE_METADATA_KEY: &str = "waterman.sequence";
+
+/// Schema metadata key with the time a batch was handed to the serializer, in
+/// microseconds since the Unix epoch
+pub const SENT_AT_METADATA_KEY: &str = "waterman.sent_at_us";
+
+/// Schema metadata key with the id of the connection, which `/latency/{id}` reports on
+pub const CONNECTION_METADATA_KEY: &str = "waterman.connection";
+
+/// Tag a batch via the schema metadata that travels with every IPC frame,
+/// e.g. to mark it as history rather than live data
+pub fn tag_batch(batch: RecordBatch, key: &str, value: &str) -> RecordBatch {
+    let mut metadata = batch.schema().metadata().clone();
+    metadata.insert(key.to_string(), value.to_string());
+    let schema = Arc::new(batch.schema().as_ref().clone().with_metadata(metadata));
+    batch.with_schema(schema).expect("metadata change keeps the schema compatible")
+}
+
+/// Serialize one batch as a self-contained Arrow IPC file, the payload of every binary frame
+pub fn encode_ipc_file(batch: &RecordBatch) -> Result<Vec<u8>, String> {
+    let mut buffer = Vec::new();
+    let mut writer = FileWriter::try_new(&mut buffer, &batch.schema())
+        .map_err(|e| format!("Failed to create IPC writer: {}", e))?;
+    writer.write(batch).map_err(|e| format!("Failed to write record batch: {}", e))?;
+    writer.finish().map_err(|e| format!("Failed to finish IPC writer: {}", e))?;
+    drop(writer);
+    Ok(buffer)
+}
@@ -0,0 +1,316 @@
+//! Per-connection latency histograms, fed by the `echo` messages clients send back
+//! for the batches they receive.
+//!
+//! Every batch on `/ws` carries its send time (and, for live data, its generation
+//! time) in the IPC schema metadata. A client echoes those stamps together with its
+//! own receive time, which gives:
+//!
+//! - round trip: bridge send until the echo arrives back, on the bridge's clock only
+//! - one way: bridge send until client receive, which assumes synchronized clocks
+//! - end to end: generation until client receive, including queueing in the bridge
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Closed connections kept around for reporting, beyond which the oldest are dropped
+const MAX_CLOSED_CONNECTIONS: usize = 256;
+
+/// Every power of two microseconds is split into this many equal buckets (12.5% resolution)
+const SUB_BUCKETS: u64 = 8;
+
+/// Enough buckets for latencies up to 2^31 us (~36 minutes); longer ones land in the last
+const BUCKETS: usize = (SUB_BUCKETS + (31 - SUB_BUCKETS.ilog2() as u64) * SUB_BUCKETS) as usize;
+
+pub fn unix_time_us() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or(0)
+}
+
+/// Bucket holding `value`: exact below `SUB_BUCKETS`, then `SUB_BUCKETS` per power of two
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS {
+        return value as usize;
+    }
+    let shift = value.ilog2() - SUB_BUCKETS.ilog2();
+    let index = SUB_BUCKETS * (shift as u64 + 1) + ((value >> shift) - SUB_BUCKETS);
+    (index as usize).min(BUCKETS - 1)
+}
+
+/// Exclusive upper bound of a bucket
+fn bucket_upper_bound(index: usize) -> u64 {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return index + 1;
+    }
+    let shift = index / SUB_BUCKETS - 1;
+    (SUB_BUCKETS + index % SUB_BUCKETS + 1) << shift
+}
+
+/// Log-linear histogram of latencies in microseconds
+#[derive(Debug, Clone)]
+pub struct LatencyHistogram {
+    buckets: Vec<u64>,
+    count: u64,
+    sum_us: u64,
+    min_us: u64,
+    max_us: u64,
+    negative: u64,      // samples below zero, i.e. clocks out of sync
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HistogramBucket {
+    pub le_us: u64,     // upper bound, exclusive
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub mean_us: f64,
+    pub min_us: u64,
+    pub p50_us: u64,    // percentiles are bucket upper bounds
+    pub p90_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+    pub negative: u64,
+    pub buckets: Vec<HistogramBucket>,      // non-empty buckets only
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: vec![0; BUCKETS],
+            count: 0,
+            sum_us: 0,
+            min_us: 0,
+            max_us: 0,
+            negative: 0,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, latency_us: i64) {
+        if latency_us < 0 {
+            self.negative += 1;
+            return;
+        }
+        let value = latency_us as u64;
+        self.buckets[bucket_index(value)] += 1;
+        self.min_us = if self.count == 0 { value } else { self.min_us.min(value) };
+        self.max_us = self.max_us.max(value);
+        self.count += 1;
+        self.sum_us += value;
+    }
+
+    fn percentile(&self, p: f64) -> u64 {
+        let rank = (self.count as f64 * p).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (index, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return bucket_upper_bound(index).min(self.max_us);
+            }
+        }
+        self.max_us
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        LatencySummary {
+            count: self.count,
+            mean_us: if self.count == 0 { 0.0 } else { self.sum_us as f64 / self.count as f64 },
+            min_us: self.min_us,
+            p50_us: self.percentile(0.5),
+            p90_us: self.percentile(0.9),
+            p99_us: self.percentile(0.99),
+            max_us: self.max_us,
+            negative: self.negative,
+            buckets: self
+                .buckets
+                .iter()
+                .enumerate()
+                .filter(|(_, count)| **count > 0)
+                .map(|(index, count)| HistogramBucket { le_us: bucket_upper_bound(index), count: *count })
+                .collect(),
+        }
+    }
+}
+
+/// What a client reports back for one received batch
+#[derive(Debug, Clone, Copy)]
+pub struct Echo {
+    pub sequence: u64,
+    pub sent_at_us: u64,
+    pub generated_at_us: Option<u64>,
+    pub received_at_us: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+struct ConnectionLatency {
+    stream: String,
+    open: bool,
+    echoes: u64,
+    last_sequence: Option<u64>,
+    round_trip: LatencyHistogram,
+    one_way: LatencyHistogram,
+    end_to_end: LatencyHistogram,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionLatencySummary {
+    pub stream: String,
+    pub open: bool,
+    pub echoes: u64,
+    pub last_sequence: Option<u64>,
+    pub round_trip: LatencySummary,
+    pub one_way: LatencySummary,
+    pub end_to_end: LatencySummary,
+}
+
+impl From<&ConnectionLatency> for ConnectionLatencySummary {
+    fn from(latency: &ConnectionLatency) -> Self {
+        Self {
+            stream: latency.stream.clone(),
+            open: latency.open,
+            echoes: latency.echoes,
+            last_sequence: latency.last_sequence,
+            round_trip: latency.round_trip.summary(),
+            one_way: latency.one_way.summary(),
+            end_to_end: latency.end_to_end.summary(),
+        }
+    }
+}
+
+/// Latency histograms of every `/ws` connection, keyed by connection id
+#[derive(Debug, Default)]
+pub struct LatencyStats {
+    connections: Mutex<BTreeMap<u64, ConnectionLatency>>,
+}
+
+pub type LatencyStatsHandle = Arc<LatencyStats>;
+
+impl LatencyStats {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn open_connection(&self, connection_id: u64, stream: &str) {
+        let latency = ConnectionLatency { stream: stream.to_string(), open: true, ..Default::default() };
+        self.connections.lock().unwrap().insert(connection_id, latency);
+    }
+
+    pub fn close_connection(&self, connection_id: u64) {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(latency) = connections.get_mut(&connection_id) {
+            latency.open = false;
+        }
+        let closed: Vec<u64> = connections.iter().filter(|(_, l)| !l.open).map(|(id, _)| *id).collect();
+        for id in closed.iter().take(closed.len().saturating_sub(MAX_CLOSED_CONNECTIONS)) {
+            connections.remove(id);
+        }
+    }
+
+    /// Record an echo as it arrives back at the bridge
+    pub fn record(&self, connection_id: u64, echo: Echo) {
+        let now = unix_time_us() as i64;
+        let mut connections = self.connections.lock().unwrap();
+        let Some(latency) = connections.get_mut(&connection_id) else { return };
+        latency.echoes += 1;
+        latency.last_sequence = Some(latency.last_sequence.map_or(echo.sequence, |last| last.max(echo.sequence)));
+        latency.round_trip.record(now - echo.sent_at_us as i64);
+        latency.one_way.record(echo.received_at_us as i64 - echo.sent_at_us as i64);
+        if let Some(generated_at) = echo.generated_at_us {
+            latency.end_to_end.record(echo.received_at_us as i64 - generated_at as i64);
+        }
+    }
+
+    pub fn connection(&self, connection_id: u64) -> Option<ConnectionLatencySummary> {
+        self.connections.lock().unwrap().get(&connection_id).map(Into::into)
+    }
+
+    pub fn summary(&self) -> BTreeMap<u64, ConnectionLatencySummary> {
+        self.connections.lock().unwrap().iter().map(|(id, latency)| (*id, latency.into())).collect()
+    }
+
+    /// Clear the histograms, keeping the open connections
+    pub fn reset(&self) {
+        let mut connections = self.connections.lock().unwrap();
+        connections.retain(|_, latency| latency.open);
+        for latency in connections.values_mut() {
+            *latency = ConnectionLatency { stream: latency.stream.clone(), open: true, ..Default::default() };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bucket_lower_bound(index: usize) -> u64 {
+        if index == 0 { 0 } else { bucket_upper_bound(index - 1) }
+    }
+
+    #[test]
+    fn values_fall_inside_their_bucket() {
+        let powers = (0..31).flat_map(|bit| {
+            let power = 1u64 << bit;
+            [power - 1, power, power + 1, power + power / 3]
+        });
+        for value in (0..10_000).chain(powers) {
+            let index = bucket_index(value);
+            assert!(index < BUCKETS, "{} has no bucket", value);
+            assert!(bucket_lower_bound(index) <= value, "{} below bucket {}", value, index);
+            assert!(value < bucket_upper_bound(index), "{} above bucket {}", value, index);
+        }
+
+        // Buckets are contiguous, and no wider than the resolution
+        for index in 1..BUCKETS {
+            let (lower, upper) = (bucket_lower_bound(index), bucket_upper_bound(index));
+            assert!(lower < upper);
+            assert!((upper - lower) as f64 <= (lower as f64 / SUB_BUCKETS as f64).max(1.0));
+        }
+        assert_eq!(bucket_upper_bound(BUCKETS - 1), 1 << 31);
+    }
+
+    #[test]
+    fn longer_latencies_land_in_the_last_bucket() {
+        assert_eq!(bucket_index(1 << 31), BUCKETS - 1);
+        assert_eq!(bucket_index(u64::MAX), BUCKETS - 1);
+    }
+
+    #[test]
+    fn percentiles_are_within_a_bucket_of_the_exact_value() {
+        let mut histogram = LatencyHistogram::default();
+        for value in 1..=1000 {
+            histogram.record(value);
+        }
+        histogram.record(-5);
+
+        let summary = histogram.summary();
+        assert_eq!(summary.count, 1000);
+        assert_eq!(summary.negative, 1);
+        assert_eq!((summary.min_us, summary.max_us), (1, 1000));
+        assert_eq!(summary.mean_us, 500.5);
+        for (reported, exact) in [(summary.p50_us, 500), (summary.p90_us, 900), (summary.p99_us, 990)] {
+            assert!(reported >= exact, "{} below {}", reported, exact);
+            assert!(reported as f64 <= exact as f64 * (1.0 + 1.0 / SUB_BUCKETS as f64), "{} too far above {}", reported, exact);
+        }
+        assert_eq!(summary.buckets.iter().map(|b| b.count).sum::<u64>(), 1000);
+
+        // Percentiles never exceed the largest sample
+        let mut single = LatencyHistogram::default();
+        single.record(1001);
+        let summary = single.summary();
+        assert_eq!((summary.p50_us, summary.p99_us), (1001, 1001));
+    }
+
+    #[test]
+    fn empty_histogram() {
+        let summary = LatencyHistogram::default().summary();
+        assert_eq!(summary.count, 0);
+        assert_eq!(summary.mean_us, 0.0);
+        assert_eq!(summary.p99_us, 0);
+        assert!(summary.buckets.is_empty());
+    }
+}
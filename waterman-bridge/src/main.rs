@@ -1,14 +1,34 @@
 use warp::Filter;
-use warp::{ Rejection};
 use clap::Parser;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
 
+mod capture;
 mod data_gen;
+mod encoding;
+mod expr;
+mod history;
+mod impairment;
+mod ipc;
+mod latency;
 mod ws_handler;
+mod mux;
+mod foxglove;
+#[cfg(feature = "flight")]
+mod flight;
 mod http_handler;
 mod state;
 mod parameters;
+mod paths;
+mod recorder;
+mod runs;
+mod schedule;
+mod send_queue;
+mod sql;
+mod sources;
+mod stream_hub;
+mod topics;
 
 // Import std::path for handling file paths
 use std::path::Path;
@@ -67,6 +87,47 @@ pub struct WSBridgeArgs {
     /// Data send rate in Hz
     #[clap(short, long, default_value_t = 100.0)]
     pub rate: f64,
+
+    /// YAML file describing live ingestion sources (UDP, ...)
+    #[clap(long, default_value = "config/sources.yaml")]
+    pub sources: String,
+
+    /// YAML file declaring additional topics (generators with their own parameters, replays)
+    #[clap(long, default_value = "config/topics.yaml")]
+    pub topics: String,
+
+    /// Read an Arrow IPC stream from stdin and publish it under this stream name
+    #[clap(long)]
+    pub ipc_stdin: Option<String>,
+
+    /// Seconds of recent data each stream keeps for late joiners (0 disables)
+    #[clap(long, default_value_t = 30.0)]
+    pub history_seconds: f64,
+
+    /// Memory budget per stream for that history, in MiB (0 disables)
+    #[clap(long, default_value_t = 64)]
+    pub history_mb: usize,
+
+    /// Replay this frame capture to every `/ws` client instead of the synthetic stream
+    #[clap(long)]
+    pub replay: Option<String>,
+
+    /// Directory recordings are written to; `/recording/start` may only pick subdirectories of it
+    #[clap(long, default_value = "recordings")]
+    pub recordings_dir: String,
+
+    /// Directory frame captures are written to and replayed from
+    #[clap(long, default_value = "captures")]
+    pub captures_dir: String,
+
+    /// Directory run summaries are written to
+    #[clap(long, default_value = "runs")]
+    pub runs_dir: String,
+
+    /// Port of the Arrow Flight server
+    #[cfg(feature = "flight")]
+    #[clap(long, default_value_t = 3033)]
+    pub flight_port: u16,
 }
 
 #[tokio::main]
@@ -87,16 +148,80 @@ async fn main() {
         state.set_send_rate_hz(args.rate);
         info!("Using default parameters with rate {} Hz", args.rate);
     }
+
+    if let Some(replay) = &args.replay {
+        info!("Replay mode: /ws clients will receive {}", replay);
+        state.replay_file = Some(replay.clone());
+    }
     
-    let state = state.as_handle();
+    state.recorder = std::sync::Arc::new(recorder::Recorder::new(state.hub(), &args.recordings_dir));
+
+    state.capture = std::sync::Arc::new(capture::SessionCapture::new(&args.captures_dir));
+
+    state.runs = std::sync::Arc::new(runs::RunManager::new(&args.runs_dir));
+
+    let topics = topics::TopicsConfig::load_from_yaml(&args.topics).unwrap_or_default();
+    state.topics = topics.topics;
+
+    state.hub().set_history_limits(stream_hub::HistoryLimits {
+        max_age: std::time::Duration::from_secs_f64(args.history_seconds.max(0.0)),
+        max_bytes: args.history_mb * 1024 * 1024,
+    });
+
+    let state = state.into_handle();
+
+    // Shared synthetic generator and the declared topics, plus any live sources from the sources file
+    tokio::spawn(ws_handler::run_generator(state.clone(), stream_hub::SYNTHETIC_STREAM.to_string()));
+    let declared = state.lock().unwrap().topics.clone();
+    for topic in declared {
+        match topic.source {
+            topics::TopicSource::Generator => {
+                info!("Starting generator topic '{}'", topic.name);
+                tokio::spawn(ws_handler::run_generator(state.clone(), topic.name));
+            },
+            topics::TopicSource::Replay => match topic.replay {
+                Some(replay) => {
+                    tokio::spawn(topics::run_replay(topic.name, replay, state.lock().unwrap().hub()));
+                },
+                None => warn!("Replay topic '{}' has no replay settings, skipping", topic.name),
+            },
+            topics::TopicSource::Ingest => {},
+        }
+    }
+    let mut sources = sources::SourcesConfig::load_from_yaml(&args.sources).unwrap_or_default();
+    if let Some(name) = &args.ipc_stdin {
+        sources.ipc.push(sources::ipc::IpcSourceConfig::stdin(name));
+    }
+    sources::spawn_sources(sources, state.lock().unwrap().hub());
+
+    #[cfg(feature = "flight")]
+    tokio::spawn(flight::serve(state.clone(), ([0, 0, 0, 0], args.flight_port).into()));
     
-    // WebSocket route
+    // WebSocket route, `/ws` or `/ws/{topic}`
     let ws_route = warp::path("ws")
+        .and(warp::path::tail())
         // The `ws()` filter will prepare the Websocket handshake.
         .and(warp::ws())
+        .and(warp::query::<HashMap<String, String>>())
         .and(with_state(state.clone())) 
         .and_then(ws_handler::ws_handler);
 
+    // Multiplexed WebSocket route, many topics over one socket
+    let mux_route = warp::path("mux")
+        .and(warp::path::end())
+        .and(warp::ws())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_state(state.clone()))
+        .and_then(mux::mux_handler);
+
+    // Foxglove WebSocket protocol, for Foxglove Studio
+    let foxglove_route = warp::path("foxglove")
+        .and(warp::path::end())
+        .and(warp::ws())
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .and(with_state(state.clone()))
+        .and_then(foxglove::foxglove_handler);
+
     // HTTP API routes for parameter adjustment
     let http_routes = http_handler::get_routes(state.clone());
     
@@ -110,6 +235,8 @@ async fn main() {
     
     // Combine all routes (WebSocket, HTTP API, and static files) into a single server
     let combined_routes = ws_route
+        .or(mux_route)
+        .or(foxglove_route)
         .or(http_routes)
         .or(index_route)
         .or(static_route);
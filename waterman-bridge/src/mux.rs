@@ -0,0 +1,417 @@
+//! Multiplexed WebSocket connections (`/mux`): one socket carries any number of
+//! topics, which the client subscribes to and unsubscribes from at runtime.
+//!
+//! Every binary frame starts with a fixed 16-byte header, all integers little endian:
+//!
+//! | Bytes  | Field                                               |
+//! |--------|-----------------------------------------------------|
+//! | 0..4   | topic id (`u32`), assigned by the `subscribed` reply |
+//! | 4..12  | sequence number (`u64`), per topic                  |
+//! | 12     | kind ([`FrameKind`])                                |
+//! | 13..16 | reserved, zero                                      |
+//!
+//! The header length keeps the payload 8-byte aligned, so clients can decode
+//! the Arrow IPC payload in place. Frames go through the same [`SendQueue`] as
+//! on `/ws`, and take its `encoding`, `layout` and queue query parameters.
+//!
+//! [`SendQueue`]: crate::send_queue::SendQueue
+
+use crate::encoding::{self, Encoded};
+use crate::expr::Filter;
+use crate::ipc::{tag_batch, BACKFILL_METADATA_KEY, CONNECTION_METADATA_KEY, SENT_AT_METADATA_KEY, SEQUENCE_METADATA_KEY};
+use crate::latency::{self, Echo};
+use crate::send_queue::{QueueLimits, SendQueueHandle};
+use crate::state::StateHandle;
+use crate::stream_hub::HubHandle;
+use crate::topics;
+use crate::ws_handler::{next_connection_id, ConnectionOptions, WSMessage};
+use arrow::record_batch::RecordBatch;
+use futures::{SinkExt, StreamExt};
+use log::{error, info, warn};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+use warp::ws::{Message, WebSocket};
+use warp::{Rejection, Reply};
+type Result<T> = std::result::Result<T, Rejection>;
+
+/// Length of the header in front of every binary frame
+pub const FRAME_HEADER_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameKind {
+    Data = 0,       // payload: one batch in the connection's encoding
+    Backfill = 1,   // same as Data, from the topic's history
+    Lagged = 2,     // payload: number of batches skipped (`u64`) because the client fell behind
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameHeader {
+    pub topic_id: u32,
+    pub sequence: u64,
+    pub kind: FrameKind,
+}
+
+impl FrameHeader {
+    /// Header followed by `payload`, ready to send
+    pub fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+        frame.extend_from_slice(&self.topic_id.to_le_bytes());
+        frame.extend_from_slice(&self.sequence.to_le_bytes());
+        frame.push(self.kind as u8);
+        frame.extend_from_slice(&[0; 3]);
+        frame.extend_from_slice(payload);
+        frame
+    }
+}
+
+/// Requests a client may send as text frames
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum MuxRequest {
+    Subscribe {
+        topic: String,
+        #[serde(default)]
+        backfill: bool,             // start with the topic's recent history
+        #[serde(default)]
+        filter: Option<String>,     // only rows matching this expression are sent
+    },
+    Unsubscribe {
+        topic: String,
+    },
+    /// Stamps of a received batch sent back for latency measurement; not answered
+    Echo {
+        sequence: u64,
+        sent_at_us: u64,
+        #[serde(default)]
+        generated_at_us: Option<u64>,
+        received_at_us: u64,
+    },
+}
+
+struct Subscription {
+    topic_id: u32,
+    relay: JoinHandle<()>,
+}
+
+pub async fn mux_handler(ws: warp::ws::Ws, query: HashMap<String, String>, state: StateHandle) -> Result<warp::reply::Response> {
+    let options = match ConnectionOptions::from_query(&query) {
+        Ok(options) => options,
+        Err(e) => {
+            warn!("Rejecting multiplexed WebSocket connection: {}", e);
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({ "error": e })),
+                warp::http::StatusCode::BAD_REQUEST,
+            ).into_response());
+        }
+    };
+    Ok(ws.on_upgrade(|socket| async {
+        mux_connect(socket, options, state).await;
+    }).into_response())
+}
+
+pub async fn mux_connect(ws: WebSocket, options: ConnectionOptions, state: StateHandle) {
+    let connection_id = next_connection_id();
+    info!("New multiplexed WebSocket connection {} ({} encoding)", connection_id, options.encoding.name());
+
+    let (mut client_ws_sender, mut client_ws_rcv) = ws.split();
+    let (hub, frame_capture, encoding_stats, latency_stats, runs, send_queues, mut limits) = {
+        let state_guard = state.lock().unwrap();
+        (
+            state_guard.hub(),
+            state_guard.capture(),
+            state_guard.encoding_stats(),
+            state_guard.latency_stats(),
+            state_guard.runs(),
+            state_guard.send_queues(),
+            QueueLimits::from_parameters(&state_guard.get_parameters()),
+        )
+    };
+    limits.policy = options.overflow.unwrap_or(limits.policy);
+    limits.max_messages = options.queue_messages.map_or(limits.max_messages, |n| n.max(1));
+    limits.max_bytes = options.queue_bytes.unwrap_or(limits.max_bytes);
+    let queue = send_queues.open_connection(connection_id, "mux", limits);
+    latency_stats.open_connection(connection_id, "mux");
+
+    // Topic `n` is `topic_names[n - 1]`; ids stay the same for the lifetime of the connection,
+    // also across re-subscribes
+    let topic_names: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
+
+    let sender_queue = queue.clone();
+    let sender_topic_names = topic_names.clone();
+    let sender_latency_stats = latency_stats.clone();
+    let (encoding, layout) = (options.encoding, options.layout);
+    tokio::spawn(async move {
+        let mut sent = 0u64;
+        let mut sequence = 0u64;
+        let mut reported_lagged = 0u64;
+        while let Some(message) = sender_queue.pop().await {
+            let mut frames = Vec::new();
+            // Batches the send queue dropped, whichever topic they were of; what a topic skipped
+            // behind its stream is told by its lagged frames
+            let lagged_total = sender_queue.dropped_batches();
+            if lagged_total > reported_lagged {
+                let notice = json!({ "type": "lagged", "skipped": lagged_total - reported_lagged, "total": lagged_total });
+                frames.push((Message::text(notice.to_string()), None));
+                reported_lagged = lagged_total;
+            }
+            match message {
+                WSMessage::Topic(header, batch) => {
+                    // Stamped for latency measurement like on `/ws`, with one sequence across topics
+                    let batch = tag_batch(batch, SEQUENCE_METADATA_KEY, &sequence.to_string());
+                    let batch = tag_batch(batch, SENT_AT_METADATA_KEY, &latency::unix_time_us().to_string());
+                    sequence += 1;
+                    let batch = tag_batch(batch, CONNECTION_METADATA_KEY, &connection_id.to_string());
+                    let topic = sender_topic_names.lock().unwrap().get(header.topic_id as usize - 1).cloned();
+                    match encoding::encode_timed(&encoding_stats, &batch, encoding, layout) {
+                        Ok(Encoded::Binary(payload)) => frames.push((Message::binary(header.encode(&payload)), topic)),
+                        Ok(Encoded::Text(payload)) => frames.push((Message::binary(header.encode(payload.as_bytes())), topic)),
+                        Err(e) => error!("Error serializing message: {}", e),
+                    }
+                },
+                WSMessage::Binary(bytes) => frames.push((Message::binary(bytes), None)),
+                WSMessage::Text(text) => frames.push((Message::text(text), None)),
+                WSMessage::Batch(_) => unreachable!("multiplexed batches always carry a topic"),
+            }
+
+            for (frame, topic) in frames {
+                let frame_len = frame.as_bytes().len();
+                frame_capture.record(connection_id, &frame);
+                if let Err(e) = client_ws_sender.send(frame).await {
+                    error!("Error sending message: {}", e);
+                    if let Some(topic) = &topic {
+                        runs.record_send_error(topic);
+                    }
+                    sender_queue.close();
+                    break;
+                }
+                if let Some(topic) = &topic {
+                    runs.record_sent(topic, connection_id, frame_len);
+                }
+                sent += 1;
+            }
+        }
+
+        if sender_queue.overflowed() {
+            warn!("Multiplexed connection {} could not keep up, closing it", connection_id);
+            let _ = client_ws_sender.send(Message::close_with(1008u16, "send queue full")).await;
+        }
+        send_queues.close_connection(connection_id);
+        frame_capture.close_connection(connection_id);
+        sender_latency_stats.close_connection(connection_id);
+        info!("Multiplexed connection {} sender ending after {} messages", connection_id, sent);
+    });
+
+    let mut subscriptions: HashMap<String, Subscription> = HashMap::new();
+    loop {
+        // Stop reading once the sender has finished, so the connection is dropped
+        let message = tokio::select! {
+            message = client_ws_rcv.next() => message,
+            _ = queue.closed() => break,
+        };
+        let Some(Ok(message)) = message else { break };
+        let Ok(text) = message.to_str() else { continue };
+        let reply = match serde_json::from_str::<MuxRequest>(text) {
+            Ok(MuxRequest::Subscribe { topic, .. }) if !topics::is_topic(&state.lock().unwrap(), &topic) => {
+                json!({ "type": "subscribe_error", "topic": topic, "error": format!("Unknown topic '{}'", topic) })
+            },
+            Ok(MuxRequest::Subscribe { topic, backfill, filter }) => {
+                match filter.as_deref().map(Filter::parse).transpose() {
+                    Ok(filter) => {
+                        let topic_id = {
+                            let mut names = topic_names.lock().unwrap();
+                            match names.iter().position(|name| *name == topic) {
+                                Some(index) => index as u32 + 1,
+                                None => {
+                                    names.push(topic.clone());
+                                    names.len() as u32
+                                }
+                            }
+                        };
+                        let relay = tokio::spawn(relay_topic(hub.clone(), topic.clone(), topic_id, backfill, filter, queue.clone()));
+                        if let Some(previous) = subscriptions.insert(topic.clone(), Subscription { topic_id, relay }) {
+                            previous.relay.abort();
+                        }
+                        info!("Connection {} subscribed to '{}' as topic {}", connection_id, topic, topic_id);
+                        json!({ "type": "subscribed", "topic": topic, "topic_id": topic_id })
+                    },
+                    Err(e) => json!({ "type": "subscribe_error", "topic": topic, "error": e }),
+                }
+            },
+            Ok(MuxRequest::Unsubscribe { topic }) => match subscriptions.remove(&topic) {
+                Some(subscription) => {
+                    subscription.relay.abort();
+                    info!("Connection {} unsubscribed from '{}'", connection_id, topic);
+                    json!({ "type": "unsubscribed", "topic": topic, "topic_id": subscription.topic_id })
+                },
+                None => json!({ "type": "error", "error": format!("Not subscribed to '{}'", topic) }),
+            },
+            Ok(MuxRequest::Echo { sequence, sent_at_us, generated_at_us, received_at_us }) => {
+                latency_stats.record(connection_id, Echo { sequence, sent_at_us, generated_at_us, received_at_us });
+                continue;
+            },
+            Err(e) => {
+                warn!("Ignoring client message on multiplexed connection {}: {}", connection_id, e);
+                json!({ "type": "error", "error": e.to_string() })
+            }
+        };
+        if queue.push(WSMessage::Text(reply.to_string())).await.is_err() {
+            break;
+        }
+    }
+
+    for subscription in subscriptions.into_values() {
+        subscription.relay.abort();
+    }
+    // The client has gone
+    queue.close();
+    info!("Multiplexed WebSocket connection {} closed", connection_id);
+}
+
+/// Forward one topic into the connection's send queue, numbering its frames
+async fn relay_topic(
+    hub: HubHandle,
+    topic: String,
+    topic_id: u32,
+    backfill: bool,
+    filter: Option<Filter>,
+    queue: SendQueueHandle,
+) {
+    let (history, mut feed) = if backfill {
+        hub.subscribe_with_backfill(&topic)
+    } else {
+        (Vec::new(), hub.subscribe(&topic))
+    };
+    let mut sequence = 0u64;
+    let mut next_header = |kind| {
+        let header = FrameHeader { topic_id, sequence, kind };
+        sequence += 1;
+        header
+    };
+
+    let matching = |batch: RecordBatch| match &filter {
+        Some(predicate) => match predicate.apply(&batch) {
+            Ok(rows) => (rows.num_rows() > 0).then_some(rows),
+            Err(e) => {
+                warn!("Filter failed on topic '{}': {}", topic, e);
+                None
+            }
+        },
+        None => Some(batch),
+    };
+
+    for batch in history {
+        if let Some(batch) = matching(batch) {
+            let batch = tag_batch(batch, BACKFILL_METADATA_KEY, "true");
+            if queue.push(WSMessage::Topic(next_header(FrameKind::Backfill), batch)).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    loop {
+        let message = match feed.recv().await {
+            Ok(batch) => match matching(batch) {
+                Some(batch) => WSMessage::Topic(next_header(FrameKind::Data), batch),
+                None => continue,
+            },
+            Err(RecvError::Lagged(skipped)) => {
+                queue.record_lagged(skipped);
+                WSMessage::Binary(next_header(FrameKind::Lagged).encode(&skipped.to_le_bytes()))
+            },
+            Err(RecvError::Closed) => break,
+        };
+        if queue.push(message).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::WSBridgeState;
+    use arrow::array::{ArrayRef, AsArray, Int64Array};
+    use arrow::datatypes::Int64Type;
+    use arrow::ipc::reader::FileReader;
+    use std::time::Duration;
+    use warp::Filter as _;
+
+    fn decode(frame: &[u8]) -> (FrameHeader, &[u8]) {
+        let kind = match frame[12] {
+            0 => FrameKind::Data,
+            1 => FrameKind::Backfill,
+            2 => FrameKind::Lagged,
+            other => panic!("unknown frame kind {}", other),
+        };
+        assert_eq!(frame[13..FRAME_HEADER_LEN], [0, 0, 0]);
+        let header = FrameHeader {
+            topic_id: u32::from_le_bytes(frame[0..4].try_into().unwrap()),
+            sequence: u64::from_le_bytes(frame[4..12].try_into().unwrap()),
+            kind,
+        };
+        (header, &frame[FRAME_HEADER_LEN..])
+    }
+
+    #[test]
+    fn header_round_trips() {
+        let header = FrameHeader { topic_id: 0x0102_0304, sequence: u64::MAX - 1, kind: FrameKind::Lagged };
+        let frame = header.encode(&7u64.to_le_bytes());
+        assert_eq!(frame.len(), FRAME_HEADER_LEN + 8);
+        assert_eq!(frame[..4], [4, 3, 2, 1]);
+
+        let (decoded, payload) = decode(&frame);
+        assert_eq!(decoded, header);
+        assert_eq!(u64::from_le_bytes(payload.try_into().unwrap()), 7);
+
+        let empty = FrameHeader { topic_id: 1, sequence: 0, kind: FrameKind::Backfill }.encode(&[]);
+        assert_eq!(decode(&empty), (FrameHeader { topic_id: 1, sequence: 0, kind: FrameKind::Backfill }, &[][..]));
+    }
+
+    async fn next_text(client: &mut warp::test::WsClient) -> serde_json::Value {
+        let message = tokio::time::timeout(Duration::from_secs(5), client.recv()).await.unwrap().unwrap();
+        serde_json::from_str(message.to_str().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn relays_topics_through_the_send_queue() {
+        let state = WSBridgeState::new().into_handle();
+        let hub = state.lock().unwrap().hub();
+        let batch = |values: Vec<i64>| RecordBatch::try_from_iter(vec![("value", Arc::new(Int64Array::from(values)) as ArrayRef)]).unwrap();
+        hub.publish("numbers", batch(vec![1, 2]));
+
+        let with_state = state.clone();
+        let route = warp::path("mux")
+            .and(warp::ws())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(warp::any().map(move || with_state.clone()))
+            .and_then(mux_handler);
+        let mut client = warp::test::ws().path("/mux").handshake(route).await.unwrap();
+
+        client.send_text(r#"{"type": "subscribe", "topic": "nothing"}"#).await;
+        assert_eq!(next_text(&mut client).await["type"], "subscribe_error");
+        assert!(!hub.stream_names().contains(&"nothing".to_string()));
+
+        client.send_text(r#"{"type": "subscribe", "topic": "numbers", "backfill": true}"#).await;
+        assert_eq!(next_text(&mut client).await["topic_id"], 1);
+
+        let frame = tokio::time::timeout(Duration::from_secs(5), client.recv()).await.unwrap().unwrap();
+        let (header, payload) = decode(frame.as_bytes());
+        assert_eq!(header, FrameHeader { topic_id: 1, sequence: 0, kind: FrameKind::Backfill });
+        let received = FileReader::try_new(std::io::Cursor::new(payload.to_vec()), None).unwrap().next().unwrap().unwrap();
+        assert_eq!(received.column(0).as_primitive::<Int64Type>().values().to_vec(), vec![1, 2]);
+        assert!(received.schema().metadata().contains_key(SENT_AT_METADATA_KEY));
+
+        // Published only once the relay is subscribed, which the backfill frame above shows
+        hub.publish("numbers", batch(vec![3]));
+        let frame = tokio::time::timeout(Duration::from_secs(5), client.recv()).await.unwrap().unwrap();
+        assert_eq!(decode(frame.as_bytes()).0, FrameHeader { topic_id: 1, sequence: 1, kind: FrameKind::Data });
+
+        let queues = state.lock().unwrap().send_queues().summary();
+        assert!(queues.values().any(|queue| queue.stream == "mux" && queue.counters.sent >= 4));
+    }
+}
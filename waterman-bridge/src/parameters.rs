@@ -1,5 +1,4 @@
 use serde::{Deserialize, Serialize};
-use std::sync::{Arc, Mutex};
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum DataType {
@@ -28,15 +27,64 @@ pub enum TestDuration {
     OpenEnded,  // Runs until stopped
 }
 
+/// What the generator does with ticks that are already overdue when it gets to them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum CatchUpPolicy {
+    #[default]
+    Burst,      // generate a batch for each of them, back to back
+    Skip,       // drop them
+    Coalesce,   // generate one batch holding the rows of all of them
+}
+
+/// What a client's send queue does with a batch that does not fit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum OverflowPolicy {
+    #[default]
+    Wait,           // stop taking batches until there is room; the stream skips what the relay then misses
+    DropOldest,     // drop queued batches, oldest first, to make room
+    DropNewest,     // drop the batch
+    Coalesce,       // merge it into the newest queued batch
+    Disconnect,     // close the connection
+}
+
+impl OverflowPolicy {
+    /// The snake case name, as in a `/ws` query string
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "wait" => Ok(Self::Wait),
+            "drop_oldest" => Ok(Self::DropOldest),
+            "drop_newest" => Ok(Self::DropNewest),
+            "coalesce" => Ok(Self::Coalesce),
+            "disconnect" => Ok(Self::Disconnect),
+            other => Err(format!(
+                "Unknown overflow policy '{}', expected wait, drop_oldest, drop_newest, coalesce or disconnect",
+                other,
+            )),
+        }
+    }
+}
+
+/// How the jitter added to the latency of each frame is distributed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum JitterDistribution {
+    #[default]
+    Uniform,    // anywhere up to jitter_ms either side of the latency
+    Normal,     // jitter_ms is the standard deviation
+    Pareto,     // only ever later, by jitter_ms on average, with a long tail
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct DataVolumeParams {
     pub num_channels: usize,            // 1, 10, 100, 1000
     pub float_precision: f64,           // 1e4, 1e8, 1e16, 1e32
     pub data_rate_hz: f64,              // 50Hz, 500Hz, 50kHz
     pub test_duration: TestDuration,    // 10s, 100s, 1000s, 3600s, open-ended
+    pub catch_up: CatchUpPolicy,        // when the generator falls behind data_rate_hz
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct DataCharacteristicsParams {
     pub data_type: DataType,
     pub include_missing_data: bool,
@@ -46,25 +94,40 @@ pub struct DataCharacteristicsParams {
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct WebSocketParams {
     pub batch_size: usize,                  // points per message
     pub message_size: usize,                // bytes (0 = auto)
     pub message_frequency: f64,             // may differ from data_rate
-    pub simulate_disconnects: bool,
-    pub simulate_latency: bool,
-    pub latency_ms: u64,                    // when simulating latency
-    pub disconnection_probability: f64,     // 0.0-1.0
+    pub impairment: ImpairmentParams,       // of the simulated network link
+    pub overflow_policy: OverflowPolicy,    // when a client's send queue is full
+    pub queue_max_messages: usize,          // per client
+    pub queue_max_bytes: usize,             // per client, in memory (0 = no limit)
 }
 
+/// The network link `/ws` clients are served over, see [`impairment`](crate::impairment)
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImpairmentParams {
+    pub enabled: bool,
+    pub latency_ms: f64,                    // one way
+    pub jitter_ms: f64,
+    pub jitter_distribution: JitterDistribution,
+    pub bandwidth_bytes_per_sec: u64,       // 0 = no limit
+    pub burst_bytes: u64,                   // sent at once before the bandwidth limit applies
+    pub outage_interval_secs: f64,          // mean time between outages (0 = none)
+    pub outage_duration_ms: f64,
+    pub disconnect_interval_secs: f64,      // each connection is dropped once, this long after opening or enabling it (0 = never)
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Parameters {
     pub data_volume: DataVolumeParams,
     pub data_characteristics: DataCharacteristicsParams,
     pub websocket: WebSocketParams,
 }
 
-pub type ParametersHandle = Arc<Mutex<Parameters>>;
-
 impl Default for DataVolumeParams {
     fn default() -> Self {
         Self {
@@ -72,6 +135,7 @@ impl Default for DataVolumeParams {
             float_precision: 1e8,
             data_rate_hz: 100.0,
             test_duration: TestDuration::Medium,
+            catch_up: CatchUpPolicy::Burst,
         }
     }
 }
@@ -94,33 +158,31 @@ impl Default for WebSocketParams {
             batch_size: 10,
             message_size: 0,  // Auto
             message_frequency: 100.0,
-            simulate_disconnects: false,
-            simulate_latency: false,
-            latency_ms: 0,
-            disconnection_probability: 0.0,
+            impairment: ImpairmentParams::default(),
+            overflow_policy: OverflowPolicy::Wait,
+            queue_max_messages: 512,
+            queue_max_bytes: 0,
         }
     }
 }
 
-impl Default for Parameters {
+impl Default for ImpairmentParams {
     fn default() -> Self {
         Self {
-            data_volume: DataVolumeParams::default(),
-            data_characteristics: DataCharacteristicsParams::default(),
-            websocket: WebSocketParams::default(),
+            enabled: false,
+            latency_ms: 0.0,
+            jitter_ms: 0.0,
+            jitter_distribution: JitterDistribution::Uniform,
+            bandwidth_bytes_per_sec: 0,
+            burst_bytes: 64 * 1024,
+            outage_interval_secs: 0.0,
+            outage_duration_ms: 2000.0,
+            disconnect_interval_secs: 0.0,
         }
     }
 }
 
 impl Parameters {
-    pub fn new() -> Self {
-        Default::default()
-    }
-
-    pub fn as_handle(self) -> ParametersHandle {
-        Arc::new(Mutex::new(self))
-    }
-    
     // Utility methods to help update parameters
     pub fn get_data_rate_hz(&self) -> f64 {
         self.data_volume.data_rate_hz
@@ -135,8 +197,4 @@ impl Parameters {
             TestDuration::OpenEnded => None,
         }
     }
-    
-    pub fn should_simulate_network_issues(&self) -> bool {
-        self.websocket.simulate_disconnects || self.websocket.simulate_latency
-    }
 }    
\ No newline at end of file
@@ -0,0 +1,29 @@
+//! Confinement of client-supplied paths to the directories set on the command line.
+
+use std::path::{Component, Path, PathBuf};
+
+/// Resolve a client-supplied path below `root`, refusing anything that could leave it:
+/// absolute paths, `..`, and `.` components
+pub fn resolve_within(root: &Path, relative: &str) -> Option<PathBuf> {
+    let relative = Path::new(relative);
+    if relative.components().any(|c| !matches!(c, Component::Normal(_))) {
+        return None;
+    }
+    Some(root.join(relative))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_paths_outside_the_root() {
+        let root = Path::new("/srv/captures");
+        assert_eq!(resolve_within(root, "1/conn-0001.wmcap"), Some(root.join("1/conn-0001.wmcap")));
+        assert_eq!(resolve_within(root, ""), Some(root.join("")));
+        assert_eq!(resolve_within(root, "../secret"), None);
+        assert_eq!(resolve_within(root, "a/../../secret"), None);
+        assert_eq!(resolve_within(root, "/etc/passwd"), None);
+        assert_eq!(resolve_within(root, "./a"), None);
+    }
+}
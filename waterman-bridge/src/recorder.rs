@@ -0,0 +1,406 @@
+use crate::paths::resolve_within;
+use crate::stream_hub::{HubHandle, TappedBatch};
+use arrow::datatypes::SchemaRef;
+use arrow::record_batch::RecordBatch;
+use log::{error, info};
+use parquet::arrow::ArrowWriter;
+use parquet::arrow::arrow_reader::{ParquetRecordBatchReader, ParquetRecordBatchReaderBuilder};
+use parquet::basic::{Compression, GzipLevel, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+
+/// How many batches may queue up for the recorder before new ones are dropped
+const RECORDER_QUEUE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum RecordingCompression {
+    Uncompressed,
+    Snappy,
+    Gzip,
+    Lz4,
+    Zstd,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecorderParams {
+    pub directory: String,                  // under the server's recordings directory; each recording gets its own subdirectory
+    pub compression: RecordingCompression,
+    pub max_row_group_size: usize,          // rows per Parquet row group
+    pub rotate_max_bytes: Option<u64>,      // start a new file after this many bytes
+    pub rotate_interval_s: Option<u64>,     // start a new file after this many seconds
+    pub streams: Option<Vec<String>>,       // None = record every stream
+}
+
+impl Default for RecorderParams {
+    fn default() -> Self {
+        Self {
+            directory: String::new(),
+            compression: RecordingCompression::Zstd,
+            max_row_group_size: 65536,
+            rotate_max_bytes: Some(256 * 1024 * 1024),
+            rotate_interval_s: Some(600),
+            streams: None,
+        }
+    }
+}
+
+impl RecorderParams {
+    fn writer_properties(&self) -> WriterProperties {
+        let compression = match self.compression {
+            RecordingCompression::Uncompressed => Compression::UNCOMPRESSED,
+            RecordingCompression::Snappy => Compression::SNAPPY,
+            RecordingCompression::Gzip => Compression::GZIP(GzipLevel::default()),
+            RecordingCompression::Lz4 => Compression::LZ4_RAW,
+            RecordingCompression::Zstd => Compression::ZSTD(ZstdLevel::default()),
+        };
+
+        WriterProperties::builder()
+            .set_compression(compression)
+            .set_max_row_group_size(self.max_row_group_size.max(1))
+            .build()
+    }
+
+    fn records(&self, stream: &str) -> bool {
+        self.streams.as_ref().is_none_or(|streams| streams.iter().any(|s| s == stream))
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RecordingStatus {
+    pub recording: bool,
+    pub session_dir: Option<String>,
+    pub started_ms: Option<u64>,
+    pub batches: u64,
+    pub rows: u64,
+    pub files: Vec<String>,
+    pub errors: u64,
+    pub params: Option<RecorderParams>,
+}
+
+/// One open Parquet file for one stream
+struct StreamFile {
+    writer: ArrowWriter<File>,
+    schema: SchemaRef,
+    opened: Instant,
+}
+
+struct ActiveRecording {
+    stop: oneshot::Sender<()>,
+    done: oneshot::Receiver<()>,
+}
+
+/// Tees every batch published on the hub into rotating Parquet files while a recording is active
+pub struct Recorder {
+    hub: HubHandle,
+    root: PathBuf,      // set on the command line; clients only pick directories below it
+    active: Mutex<Option<ActiveRecording>>,
+    status: Arc<Mutex<RecordingStatus>>,
+}
+
+pub type RecorderHandle = Arc<Recorder>;
+
+impl std::fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Recorder").field("status", &self.status()).finish()
+    }
+}
+
+fn unix_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// Stream names may contain `/` (e.g. `mavlink/ATTITUDE`), keep file names flat
+fn file_stem(stream: &str) -> String {
+    stream
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
+
+/// Parquet files recorded for `stream` in every session under `directory`, oldest first
+pub fn recorded_files(directory: &Path, stream: &str) -> Vec<PathBuf> {
+    let prefix = format!("{}-", file_stem(stream));
+    let mut files = Vec::new();
+    let Ok(sessions) = std::fs::read_dir(directory) else {
+        return files;
+    };
+
+    for session in sessions.flatten() {
+        let Ok(entries) = std::fs::read_dir(session.path()) else { continue };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let is_stream_file = name
+                .strip_prefix(&prefix)
+                .and_then(|rest| rest.strip_suffix(".parquet"))
+                .is_some_and(|index| index.chars().all(|c| c.is_ascii_digit()));
+            if is_stream_file {
+                files.push(entry.path());
+            }
+        }
+    }
+
+    // Session directories and file indices are both fixed-width, so this is chronological
+    files.sort();
+    files
+}
+
+/// Read back a finished recording file
+pub fn open_recording(path: &Path) -> std::result::Result<ParquetRecordBatchReader, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    ParquetRecordBatchReaderBuilder::try_new(file)
+        .and_then(|builder| builder.build())
+        .map_err(|e| e.to_string())
+}
+
+struct RecordingTask {
+    params: RecorderParams,
+    properties: WriterProperties,
+    session_dir: PathBuf,
+    files: HashMap<String, StreamFile>,
+    file_counts: HashMap<String, usize>,
+    status: Arc<Mutex<RecordingStatus>>,
+}
+
+impl RecordingTask {
+    fn open(&mut self, stream: &str, schema: SchemaRef) -> parquet::errors::Result<StreamFile> {
+        let index = self.file_counts.entry(stream.to_string()).or_insert(0);
+        let path = self.session_dir.join(format!("{}-{:04}.parquet", file_stem(stream), index));
+        *index += 1;
+
+        let file = File::create(&path)?;
+        let writer = ArrowWriter::try_new(file, schema.clone(), Some(self.properties.clone()))?;
+        info!("Recording '{}' to {}", stream, path.display());
+        self.status.lock().unwrap().files.push(path.display().to_string());
+
+        Ok(StreamFile {
+            writer,
+            schema,
+            opened: Instant::now(),
+        })
+    }
+
+    fn close(stream: &str, file: StreamFile) {
+        if let Err(e) = file.writer.close() {
+            error!("Failed to finish Parquet file for '{}': {}", stream, e);
+        }
+    }
+
+    fn needs_rotation(&self, file: &StreamFile) -> bool {
+        let size = (file.writer.bytes_written() + file.writer.in_progress_size()) as u64;
+        self.params.rotate_max_bytes.is_some_and(|max| size >= max)
+            || self.params.rotate_interval_s.is_some_and(|s| file.opened.elapsed() >= Duration::from_secs(s))
+    }
+
+    fn write(&mut self, stream: &str, batch: &RecordBatch) -> parquet::errors::Result<()> {
+        // A schema change (e.g. new parameters) or a full file starts a new file
+        if let Some(file) = self.files.get(stream)
+            && (file.schema != batch.schema() || self.needs_rotation(file))
+        {
+            let file = self.files.remove(stream).unwrap();
+            Self::close(stream, file);
+        }
+
+        if !self.files.contains_key(stream) {
+            let file = self.open(stream, batch.schema())?;
+            self.files.insert(stream.to_string(), file);
+        }
+
+        self.files.get_mut(stream).unwrap().writer.write(batch)
+    }
+
+    /// Write batches until `feed` closes, on a blocking thread since Parquet writes are synchronous
+    fn run(mut self, mut feed: mpsc::Receiver<TappedBatch>) {
+        while let Some((stream, batch)) = feed.blocking_recv() {
+            if !self.params.records(&stream) {
+                continue;
+            }
+
+            let result = self.write(&stream, &batch);
+            let mut status = self.status.lock().unwrap();
+            match result {
+                Ok(()) => {
+                    status.batches += 1;
+                    status.rows += batch.num_rows() as u64;
+                },
+                Err(e) => {
+                    error!("Failed to record batch from '{}': {}", stream, e);
+                    status.errors += 1;
+                }
+            }
+        }
+
+        for (stream, file) in self.files.drain() {
+            Self::close(&stream, file);
+        }
+    }
+}
+
+impl Recorder {
+    pub fn new(hub: HubHandle, root: &str) -> Self {
+        Self {
+            hub,
+            root: PathBuf::from(root),
+            active: Mutex::new(None),
+            status: Arc::new(Mutex::new(RecordingStatus::default())),
+        }
+    }
+
+    pub fn status(&self) -> RecordingStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Directory the current or most recent recording was written to
+    pub fn directory(&self) -> String {
+        let status = self.status.lock().unwrap();
+        let directory = status.params.as_ref().map_or("", |params| &params.directory);
+        resolve_within(&self.root, directory)
+            .unwrap_or_else(|| self.root.clone())
+            .display()
+            .to_string()
+    }
+
+    /// Start a new recording session, failing if one is already running
+    pub fn start(&self, params: RecorderParams) -> std::result::Result<RecordingStatus, String> {
+        let mut active = self.active.lock().unwrap();
+        if active.is_some() {
+            return Err("A recording is already running".to_string());
+        }
+
+        let Some(directory) = resolve_within(&self.root, &params.directory) else {
+            return Err(format!("Invalid directory '{}': must be relative, without '..'", params.directory));
+        };
+        let started_ms = unix_ms();
+        let session_dir = directory.join(started_ms.to_string());
+        std::fs::create_dir_all(&session_dir)
+            .map_err(|e| format!("Failed to create {}: {}", session_dir.display(), e))?;
+
+        *self.status.lock().unwrap() = RecordingStatus {
+            recording: true,
+            session_dir: Some(session_dir.display().to_string()),
+            started_ms: Some(started_ms),
+            params: Some(params.clone()),
+            ..Default::default()
+        };
+
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let (done_tx, done_rx) = oneshot::channel();
+        let task = RecordingTask {
+            properties: params.writer_properties(),
+            params,
+            session_dir,
+            files: HashMap::new(),
+            file_counts: HashMap::new(),
+            status: self.status.clone(),
+        };
+        // Batches go from the hub to the writer thread until the recording is stopped
+        let mut tap = self.hub.tap(RECORDER_QUEUE);
+        let (feed_tx, feed) = mpsc::channel(RECORDER_QUEUE);
+        tokio::spawn(async move {
+            let mut stop = stop_rx;
+            loop {
+                tokio::select! {
+                    tapped = tap.recv() => {
+                        let Some(tapped) = tapped else { break };
+                        if feed_tx.send(tapped).await.is_err() {
+                            break;
+                        }
+                    },
+                    _ = &mut stop => break,
+                }
+            }
+        });
+        tokio::task::spawn_blocking(move || {
+            task.run(feed);
+            let _ = done_tx.send(());
+        });
+
+        *active = Some(ActiveRecording {
+            stop: stop_tx,
+            done: done_rx,
+        });
+        info!("Recording started");
+        Ok(self.status())
+    }
+
+    /// Stop the running recording and wait until every file is finalized
+    pub async fn stop(&self) -> std::result::Result<RecordingStatus, String> {
+        let active = self.active.lock().unwrap().take();
+        let Some(active) = active else {
+            return Err("No recording is running".to_string());
+        };
+
+        let _ = active.stop.send(());
+        let _ = active.done.await;
+        self.status.lock().unwrap().recording = false;
+        info!("Recording stopped");
+        Ok(self.status())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream_hub::StreamHub;
+    use arrow::array::{ArrayRef, AsArray, Int64Array};
+    use arrow::datatypes::Int64Type;
+
+    fn batch(sequence: Vec<i64>) -> RecordBatch {
+        RecordBatch::try_from_iter(vec![("sequence", Arc::new(Int64Array::from(sequence)) as ArrayRef)]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn rotates_and_reads_back() {
+        let root = std::env::temp_dir().join(format!("wmrecorder-test-{}", std::process::id()));
+        let hub = Arc::new(StreamHub::new());
+        let recorder = Recorder::new(hub.clone(), &root.display().to_string());
+        let params = RecorderParams {
+            directory: "rotation".to_string(),
+            rotate_max_bytes: Some(1),
+            streams: Some(vec!["synthetic".to_string()]),
+            ..Default::default()
+        };
+        assert!(recorder.start(RecorderParams { directory: "../outside".to_string(), ..params.clone() }).is_err());
+        recorder.start(params.clone()).unwrap();
+        assert!(recorder.start(params).is_err());
+
+        for start in [1, 3, 5] {
+            hub.publish("synthetic", batch(vec![start, start + 1]));
+            hub.publish("other", batch(vec![start]));
+        }
+        for _ in 0..100 {
+            if recorder.status().batches == 3 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let status = recorder.stop().await.unwrap();
+        assert!(!status.recording);
+        assert_eq!((status.batches, status.rows, status.errors), (3, 6, 0));
+
+        let directory = root.join("rotation");
+        let files = recorded_files(&directory, "synthetic");
+        let mut sequences = Vec::new();
+        for file in &files {
+            for batch in open_recording(file).unwrap() {
+                sequences.extend(batch.unwrap().column(0).as_primitive::<Int64Type>().values().iter().copied());
+            }
+        }
+        let others = recorded_files(&directory, "other");
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(files.len(), 3);
+        assert_eq!(status.files.len(), 3);
+        assert_eq!(sequences, vec![1, 2, 3, 4, 5, 6]);
+        assert!(others.is_empty());
+    }
+}
@@ -0,0 +1,449 @@
+//! Benchmark runs: a server-wide measurement of one generator topic, from start
+//! until its duration elapses or it is stopped.
+//!
+//! A run starts over HTTP (`POST /runs/start`), or by itself when the first client
+//! subscribes to a generator topic whose `test_duration` is not `OpenEnded`. While
+//! it lasts, the generator and every `/ws` connection on the topic report what
+//! they produce and send; at the end `/ws` clients get a `run_ended` message, and
+//! the summary is kept for `/runs/{id}` and written to `<runs dir>/<directory>/<id>.json`.
+
+use crate::paths::resolve_within;
+use crate::latency::{LatencyHistogram, LatencySummary};
+use crate::parameters::Parameters;
+use crate::stream_hub::SYNTHETIC_STREAM;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+/// Finished runs kept in memory; older ones are only on disk
+const MAX_FINISHED_RUNS: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RunParams {
+    pub topic: String,
+    pub duration_secs: Option<f64>,     // None = the topic's test duration; OpenEnded runs until stopped
+    pub directory: String,              // under the server's runs directory, where the summary is written
+}
+
+impl Default for RunParams {
+    fn default() -> Self {
+        Self {
+            topic: SYNTHETIC_STREAM.to_string(),
+            duration_secs: None,
+            directory: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunTrigger {
+    Api,        // `POST /runs/start`
+    Clients,    // the first client on a topic with a test duration
+}
+
+/// What the generator needs to know about a topic's active run
+#[derive(Debug, Clone, Copy)]
+pub struct ActiveRunInfo {
+    pub trigger: RunTrigger,
+    pub deadline: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RunSummary {
+    pub id: u64,                        // start time in Unix milliseconds
+    pub topic: String,
+    pub trigger: RunTrigger,
+    pub running: bool,
+    pub end_reason: Option<String>,     // `duration elapsed`, `stopped`, `clients left`, ...
+    pub started_at_ms: u64,
+    pub ended_at_ms: Option<u64>,
+    pub duration_secs: f64,
+    pub target_duration_secs: Option<f64>,
+    pub batches: u64,                   // messages generated
+    pub rows: u64,                      // samples, one row each
+    pub samples_skipped: u64,
+    pub messages_coalesced: u64,        // overdue messages merged into the next one
+    pub target_rate_hz: f64,            // samples per second the parameters asked for at the start
+    pub achieved_rate_hz: f64,
+    pub target_message_rate_hz: f64,    // 0 = a message whenever samples are taken
+    pub message_rate_hz: f64,
+    pub rows_per_sec: f64,
+    pub lateness: LatencySummary,       // how long after its scheduled time each message was generated
+    pub connections: usize,             // `/ws` connections that were sent data
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+    pub generation_errors: u64,
+    pub send_errors: u64,
+    pub parameters: Parameters,         // of the topic at the start
+    pub summary_file: Option<String>,   // written in the background once the run has ended
+}
+
+/// A run start or end, forwarded as a text message to the `/ws` clients of the topic
+#[derive(Debug, Clone)]
+pub struct RunEvent {
+    pub topic: String,
+    pub message: String,
+}
+
+struct ActiveRun {
+    id: u64,
+    topic: String,
+    trigger: RunTrigger,
+    started: Instant,
+    started_at_ms: u64,
+    target_duration: Option<Duration>,
+    directory: PathBuf,
+    parameters: Parameters,
+    batches: u64,
+    rows: u64,
+    samples_skipped: u64,
+    messages_coalesced: u64,
+    lateness: LatencyHistogram,
+    connections: HashSet<u64>,
+    messages_sent: u64,
+    bytes_sent: u64,
+    generation_errors: u64,
+    send_errors: u64,
+}
+
+impl ActiveRun {
+    fn summary(&self, end: Option<(&str, u64)>) -> RunSummary {
+        let seconds = self.started.elapsed().as_secs_f64();
+        let per_second = |count: u64| if seconds > 0.0 { count as f64 / seconds } else { 0.0 };
+        RunSummary {
+            id: self.id,
+            topic: self.topic.clone(),
+            trigger: self.trigger,
+            running: end.is_none(),
+            end_reason: end.map(|(reason, _)| reason.to_string()),
+            started_at_ms: self.started_at_ms,
+            ended_at_ms: end.map(|(_, ended_at_ms)| ended_at_ms),
+            duration_secs: seconds,
+            target_duration_secs: self.target_duration.map(|d| d.as_secs_f64()),
+            batches: self.batches,
+            rows: self.rows,
+            samples_skipped: self.samples_skipped,
+            messages_coalesced: self.messages_coalesced,
+            target_rate_hz: self.parameters.data_volume.data_rate_hz,
+            achieved_rate_hz: per_second(self.rows),
+            target_message_rate_hz: self.parameters.websocket.message_frequency.max(0.0),
+            message_rate_hz: per_second(self.batches),
+            rows_per_sec: per_second(self.rows),
+            lateness: self.lateness.summary(),
+            connections: self.connections.len(),
+            messages_sent: self.messages_sent,
+            bytes_sent: self.bytes_sent,
+            generation_errors: self.generation_errors,
+            send_errors: self.send_errors,
+            parameters: self.parameters.clone(),
+            summary_file: None,
+        }
+    }
+}
+
+fn unix_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// Active runs by topic, at most one each, and the summaries of finished ones
+pub struct RunManager {
+    active: Mutex<HashMap<String, ActiveRun>>,
+    finished: Mutex<VecDeque<RunSummary>>,
+    root: PathBuf,                  // set on the command line; clients only pick directories below it
+    directory: Mutex<PathBuf>,      // of the latest run, where `/runs/{id}` looks for older ones
+    last_id: Mutex<u64>,
+    events: broadcast::Sender<RunEvent>,
+}
+
+pub type RunManagerHandle = Arc<RunManager>;
+
+impl std::fmt::Debug for RunManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let topics: Vec<String> = self.active.lock().unwrap().keys().cloned().collect();
+        f.debug_struct("RunManager").field("active", &topics).finish()
+    }
+}
+
+impl RunManager {
+    pub fn new(root: &str) -> Self {
+        Self {
+            active: Mutex::new(HashMap::new()),
+            finished: Mutex::new(VecDeque::new()),
+            root: PathBuf::from(root),
+            directory: Mutex::new(PathBuf::from(root)),
+            last_id: Mutex::new(0),
+            events: broadcast::channel(64).0,
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<RunEvent> {
+        self.events.subscribe()
+    }
+
+    fn emit(&self, topic: &str, message: serde_json::Value) {
+        // No receivers just means no `/ws` clients
+        let _ = self.events.send(RunEvent { topic: topic.to_string(), message: message.to_string() });
+    }
+
+    /// Start a run on `params.topic` with the topic's current `parameters`. A run started
+    /// over HTTP replaces one the clients started; otherwise only one run per topic is active.
+    pub fn start(&self, params: RunParams, trigger: RunTrigger, parameters: Parameters) -> Result<RunSummary, String> {
+        let target_duration = match params.duration_secs {
+            Some(seconds) if seconds > 0.0 => Some(Duration::from_secs_f64(seconds)),
+            Some(seconds) => return Err(format!("Invalid run duration {}s", seconds)),
+            None => parameters.get_test_duration_seconds().map(Duration::from_secs),
+        };
+        let Some(directory) = resolve_within(&self.root, &params.directory) else {
+            return Err(format!("Invalid directory '{}': must be relative, without '..'", params.directory));
+        };
+
+        let existing = self.active.lock().unwrap().get(&params.topic).map(|run| (run.id, run.trigger));
+        match existing {
+            Some((_, RunTrigger::Clients)) if trigger == RunTrigger::Api => {
+                self.finish(&params.topic, "superseded");
+            },
+            Some((id, _)) => return Err(format!("Run {} is already active on '{}'", id, params.topic)),
+            None => {},
+        }
+
+        let id = {
+            let mut last_id = self.last_id.lock().unwrap();
+            *last_id = unix_ms().max(*last_id + 1);
+            *last_id
+        };
+        let run = ActiveRun {
+            id,
+            topic: params.topic.clone(),
+            trigger,
+            started: Instant::now(),
+            started_at_ms: unix_ms(),
+            target_duration,
+            directory: directory.clone(),
+            parameters,
+            batches: 0,
+            rows: 0,
+            samples_skipped: 0,
+            messages_coalesced: 0,
+            lateness: LatencyHistogram::default(),
+            connections: HashSet::new(),
+            messages_sent: 0,
+            bytes_sent: 0,
+            generation_errors: 0,
+            send_errors: 0,
+        };
+        let summary = run.summary(None);
+        *self.directory.lock().unwrap() = directory;
+        self.active.lock().unwrap().insert(params.topic.clone(), run);
+
+        info!("Run {} started on '{}' ({:?})", id, params.topic, trigger);
+        self.emit(&params.topic, json!({
+            "type": "run_started",
+            "id": id,
+            "topic": params.topic,
+            "duration_secs": summary.target_duration_secs,
+        }));
+        Ok(summary)
+    }
+
+    pub fn active(&self, topic: &str) -> Option<ActiveRunInfo> {
+        self.active.lock().unwrap().get(topic).map(|run| ActiveRunInfo {
+            trigger: run.trigger,
+            deadline: run.target_duration.map(|d| run.started + d),
+        })
+    }
+
+    /// A generated message and how late it was, in microseconds
+    pub fn record_batch(&self, topic: &str, rows: usize, lateness_us: i64) {
+        if let Some(run) = self.active.lock().unwrap().get_mut(topic) {
+            run.batches += 1;
+            run.rows += rows as u64;
+            run.lateness.record(lateness_us);
+        }
+    }
+
+    /// Samples the generator dropped, and overdue messages it merged, instead of catching up
+    pub fn record_catch_up(&self, topic: &str, samples_skipped: u64, messages_coalesced: u64) {
+        if let Some(run) = self.active.lock().unwrap().get_mut(topic) {
+            run.samples_skipped += samples_skipped;
+            run.messages_coalesced += messages_coalesced;
+        }
+    }
+
+    pub fn record_generation_error(&self, topic: &str) {
+        if let Some(run) = self.active.lock().unwrap().get_mut(topic) {
+            run.generation_errors += 1;
+        }
+    }
+
+    /// A frame sent on a `/ws` connection following `topic`
+    pub fn record_sent(&self, topic: &str, connection_id: u64, bytes: usize) {
+        if let Some(run) = self.active.lock().unwrap().get_mut(topic) {
+            run.connections.insert(connection_id);
+            run.messages_sent += 1;
+            run.bytes_sent += bytes as u64;
+        }
+    }
+
+    pub fn record_send_error(&self, topic: &str) {
+        if let Some(run) = self.active.lock().unwrap().get_mut(topic) {
+            run.send_errors += 1;
+        }
+    }
+
+    /// End the topic's active run: summarize it, write the summary and tell the clients
+    pub fn finish(&self, topic: &str, reason: &str) -> Option<RunSummary> {
+        let run = self.active.lock().unwrap().remove(topic)?;
+        let mut summary = run.summary(Some((reason, unix_ms())));
+
+        let path = run.directory.join(format!("{}.json", run.id));
+        summary.summary_file = Some(path.display().to_string());
+        match serde_json::to_string_pretty(&summary) {
+            Ok(json) => {
+                let id = run.id;
+                tokio::spawn(async move {
+                    if let Err(e) = write_summary(&path, json).await {
+                        error!("Failed to write the summary of run {} to {}: {}", id, path.display(), e);
+                    }
+                });
+            },
+            Err(e) => error!("Failed to serialize the summary of run {}: {}", run.id, e),
+        }
+        info!(
+            "Run {} on '{}' ended ({}): {} rows in {} messages in {:.1}s, {:.1} of {} Hz",
+            run.id, topic, reason, summary.rows, summary.batches, summary.duration_secs, summary.achieved_rate_hz, summary.target_rate_hz,
+        );
+
+        self.emit(topic, json!({ "type": "run_ended", "id": run.id, "reason": reason, "summary": summary }));
+        let mut finished = self.finished.lock().unwrap();
+        finished.push_back(summary.clone());
+        while finished.len() > MAX_FINISHED_RUNS {
+            finished.pop_front();
+        }
+        Some(summary)
+    }
+
+    pub fn stop(&self, id: u64) -> Result<RunSummary, String> {
+        let topic = self
+            .active
+            .lock()
+            .unwrap()
+            .values()
+            .find(|run| run.id == id)
+            .map(|run| run.topic.clone())
+            .ok_or_else(|| format!("Run {} is not active", id))?;
+        self.finish(&topic, "stopped").ok_or_else(|| format!("Run {} is not active", id))
+    }
+
+    /// Summary of a run, so far if it is still active; older runs are read back from disk
+    pub async fn get(&self, id: u64) -> Option<serde_json::Value> {
+        if let Some(run) = self.active.lock().unwrap().values().find(|run| run.id == id) {
+            return serde_json::to_value(run.summary(None)).ok();
+        }
+        if let Some(summary) = self.finished.lock().unwrap().iter().find(|s| s.id == id) {
+            return serde_json::to_value(summary).ok();
+        }
+        let path = self.directory.lock().unwrap().join(format!("{}.json", id));
+        let text = tokio::fs::read_to_string(path).await.ok()?;
+        serde_json::from_str(&text).ok()
+    }
+
+    /// Active runs, then finished ones, newest first
+    pub fn list(&self) -> Vec<RunSummary> {
+        let mut runs: Vec<RunSummary> = self.active.lock().unwrap().values().map(|run| run.summary(None)).collect();
+        runs.extend(self.finished.lock().unwrap().iter().rev().cloned());
+        runs
+    }
+}
+
+async fn write_summary(path: &Path, json: String) -> std::io::Result<()> {
+    if let Some(directory) = path.parent() {
+        tokio::fs::create_dir_all(directory).await?;
+    }
+    tokio::fs::write(path, json).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(topic: &str, duration_secs: Option<f64>) -> RunParams {
+        RunParams { topic: topic.to_string(), duration_secs, directory: String::new() }
+    }
+
+    #[tokio::test]
+    async fn starts_one_run_per_topic() {
+        let runs = RunManager::new(&std::env::temp_dir().join(format!("wmruns-start-{}", std::process::id())).display().to_string());
+        let mut events = runs.subscribe();
+
+        let started = runs.start(params("a", None), RunTrigger::Clients, Parameters::default()).unwrap();
+        assert!(started.running);
+        assert_eq!(started.target_duration_secs, Some(100.0));
+        assert!(runs.active("a").unwrap().deadline.is_some());
+        let event: serde_json::Value = serde_json::from_str(&events.try_recv().unwrap().message).unwrap();
+        assert_eq!((event["type"].as_str(), event["id"].as_u64()), (Some("run_started"), Some(started.id)));
+
+        assert!(runs.start(params("a", None), RunTrigger::Clients, Parameters::default()).is_err());
+        assert!(runs.start(params("a", Some(0.0)), RunTrigger::Api, Parameters::default()).is_err());
+        assert!(runs.start(RunParams { directory: "../out".to_string(), ..params("a", None) }, RunTrigger::Api, Parameters::default()).is_err());
+
+        // A run started over HTTP replaces the one the clients started
+        let replacing = runs.start(params("a", Some(5.0)), RunTrigger::Api, Parameters::default()).unwrap();
+        assert!(replacing.id > started.id);
+        assert_eq!(runs.get(started.id).await.unwrap()["end_reason"], "superseded");
+        assert!(runs.start(params("a", None), RunTrigger::Api, Parameters::default()).is_err());
+        assert!(runs.start(params("b", None), RunTrigger::Api, Parameters::default()).is_ok());
+        assert_eq!(runs.list().iter().filter(|run| run.running).count(), 2);
+    }
+
+    #[tokio::test]
+    async fn stops_and_summarizes_a_run() {
+        let root = std::env::temp_dir().join(format!("wmruns-stop-{}", std::process::id()));
+        let runs = RunManager::new(&root.display().to_string());
+        let run = runs.start(RunParams { directory: "nightly".to_string(), ..params("a", None) }, RunTrigger::Api, Parameters::default()).unwrap();
+
+        runs.record_batch("a", 10, 100);
+        runs.record_batch("a", 10, 300);
+        runs.record_batch("other", 10, 0);
+        runs.record_catch_up("a", 4, 1);
+        runs.record_sent("a", 1, 100);
+        runs.record_sent("a", 2, 100);
+        runs.record_sent("a", 2, 50);
+        runs.record_send_error("a");
+        let so_far = runs.get(run.id).await.unwrap();
+        assert_eq!((so_far["running"].as_bool(), so_far["rows"].as_u64()), (Some(true), Some(20)));
+
+        let mut events = runs.subscribe();
+        let summary = runs.stop(run.id).unwrap();
+        assert!(runs.stop(run.id).is_err());
+        assert!(runs.active("a").is_none());
+        assert_eq!((summary.running, summary.end_reason.as_deref()), (false, Some("stopped")));
+        assert_eq!((summary.batches, summary.rows, summary.samples_skipped, summary.messages_coalesced), (2, 20, 4, 1));
+        assert_eq!((summary.connections, summary.messages_sent, summary.bytes_sent, summary.send_errors), (2, 3, 250, 1));
+        assert_eq!(summary.lateness.count, 2);
+        let event: serde_json::Value = serde_json::from_str(&events.try_recv().unwrap().message).unwrap();
+        assert_eq!((event["type"].as_str(), event["summary"]["rows"].as_u64()), (Some("run_ended"), Some(20)));
+
+        // The summary is written in the background, then read back by a fresh manager
+        let path = root.join("nightly").join(format!("{}.json", run.id));
+        assert_eq!(summary.summary_file, Some(path.display().to_string()));
+        for _ in 0..100 {
+            if std::fs::read_to_string(&path).is_ok_and(|text| serde_json::from_str::<serde_json::Value>(&text).is_ok()) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let restarted = RunManager::new(&root.join("nightly").display().to_string());
+        let read_back = restarted.get(run.id).await.unwrap();
+        assert_eq!((read_back["rows"].as_u64(), read_back["end_reason"].as_str()), (Some(20), Some("stopped")));
+        assert!(restarted.get(run.id + 1).await.is_none());
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
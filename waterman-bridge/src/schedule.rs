@@ -0,0 +1,489 @@
+//! Generator scheduling: absolute-deadline tickers, the buffer that turns samples
+//! into messages, and the achieved rates.
+//!
+//! A generator samples on one ticker, at `data_rate_hz`, and sends on another, at
+//! `message_frequency`. Tick `n` of a ticker is due at `origin + n * interval`,
+//! worked out from the tick count instead of adding up sleeps, so neither rounding
+//! nor timer slack accumulates. Tokio timers resolve to a millisecond, so above
+//! 1 kHz the ticks arrive in groups; those due within `TIMER_SLACK` are on time,
+//! and older ones are handled by the topic's `CatchUpPolicy`.
+
+use crate::parameters::CatchUpPolicy;
+use crate::ipc::{encode_ipc_file, CONNECTION_METADATA_KEY, GENERATED_AT_METADATA_KEY, SENT_AT_METADATA_KEY, SEQUENCE_METADATA_KEY};
+use arrow::compute::concat_batches;
+use arrow::datatypes::SchemaRef;
+use arrow::record_batch::RecordBatch;
+use log::error;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Ticks due this recently count as on time rather than missed
+const TIMER_SLACK: Duration = Duration::from_millis(2);
+
+/// Ticks further behind than this are dropped whatever the policy, so a stalled
+/// generator does not spend the next minute catching up
+const MAX_CATCH_UP: Duration = Duration::from_secs(1);
+
+/// Rows the size of a message is estimated from
+const MEASURED_ROWS: usize = 1024;
+
+/// Longest single wait, so the generator still notices parameter changes and
+/// run deadlines at very low rates
+const MAX_WAIT: Duration = Duration::from_millis(50);
+
+/// Ticks that became due since the last `Ticker::due`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DueTicks {
+    pub on_time: u64,
+    pub late: u64,              // overdue by more than the timer slack
+    pub dropped: u64,           // overdue by more than `MAX_CATCH_UP`
+    pub lateness: Duration,     // of the oldest tick handed out
+}
+
+impl DueTicks {
+    /// Rows to sample for these ticks under `policy`, and the samples skipped
+    pub fn samples(&self, policy: CatchUpPolicy) -> (u64, u64) {
+        let skipped = self.dropped + if policy == CatchUpPolicy::Skip { self.late } else { 0 };
+        (self.on_time + self.late + self.dropped - skipped, skipped)
+    }
+
+    /// Messages to send for these ticks under `policy`, and the overdue ones merged into others
+    pub fn messages(&self, policy: CatchUpPolicy) -> (u64, u64) {
+        let overdue = self.late + self.dropped;
+        let merged = if policy == CatchUpPolicy::Burst { 0 } else { overdue.saturating_sub(1) };
+        (self.on_time + overdue - merged, merged)
+    }
+}
+
+pub struct Ticker {
+    interval: f64,      // seconds
+    origin: Instant,    // when tick 0 was due
+    next: u64,          // first tick not yet handed out
+}
+
+impl Ticker {
+    /// A ticker whose first tick is due now
+    pub fn new(rate_hz: f64) -> Self {
+        Self { interval: 1.0 / rate_hz.max(0.001), origin: Instant::now(), next: 0 }
+    }
+
+    /// Change the rate from the next tick on, which stays due when it was
+    pub fn set_rate(&mut self, rate_hz: f64) {
+        let interval = 1.0 / rate_hz.max(0.001);
+        if interval != self.interval {
+            self.origin = self.deadline(self.next);
+            self.next = 0;
+            self.interval = interval;
+        }
+    }
+
+    fn deadline(&self, tick: u64) -> Instant {
+        self.origin + Duration::from_secs_f64(self.interval * tick as f64)
+    }
+
+    /// The first tick due at or after `time`
+    fn first_tick_since(&self, time: Option<Instant>) -> u64 {
+        time.map_or(0.0, |t| t.saturating_duration_since(self.origin).as_secs_f64() / self.interval).ceil() as u64
+    }
+
+    pub fn next_deadline(&self) -> Instant {
+        self.deadline(self.next)
+    }
+
+    /// Hand out every tick due by `now`
+    pub fn due(&mut self, now: Instant) -> DueTicks {
+        if now < self.deadline(self.next) {
+            return DueTicks::default();
+        }
+        let due = (((now - self.origin).as_secs_f64() / self.interval) as u64 + 1).max(self.next + 1);
+        let on_time_from = self.first_tick_since(now.checked_sub(TIMER_SLACK));
+        let keep_from = self.first_tick_since(now.checked_sub(MAX_CATCH_UP));
+
+        let first = self.next.max(keep_from).min(due - 1);
+        let on_time_from = on_time_from.clamp(first, due);
+        let ticks = DueTicks {
+            on_time: due - on_time_from,
+            late: on_time_from - first,
+            dropped: first - self.next,
+            lateness: now.saturating_duration_since(self.deadline(first)),
+        };
+        self.next = due;
+        ticks
+    }
+}
+
+/// Sleep until `deadline`, or for `MAX_WAIT` if it is further away
+pub async fn sleep_until(deadline: Instant) {
+    tokio::time::sleep_until(deadline.min(Instant::now() + MAX_WAIT).into()).await;
+}
+
+/// Rows sampled but not sent yet, cut into messages by row count, size or the message ticker
+#[derive(Default)]
+pub struct MessageBuffer {
+    pending: Vec<RecordBatch>,
+    pending_rows: usize,
+    size: Option<(SchemaRef, f64, f64)>,    // Arrow IPC bytes of a message: fixed, and per row
+}
+
+impl MessageBuffer {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add sampled rows; rows with another schema first go out on their own, into `out`
+    pub fn push(&mut self, batch: RecordBatch, out: &mut Vec<RecordBatch>) {
+        if self.pending.first().is_some_and(|first| first.schema() != batch.schema()) {
+            out.extend(self.take_all(1));
+        }
+        if self.size.as_ref().is_none_or(|(schema, _, _)| *schema != batch.schema()) {
+            self.size = measure(&batch).map(|(fixed, per_row)| (batch.schema(), fixed, per_row));
+        }
+        self.pending_rows += batch.num_rows();
+        self.pending.push(batch);
+    }
+
+    pub fn clear(&mut self) {
+        self.pending.clear();
+        self.pending_rows = 0;
+    }
+
+    /// Rows per message allowed by `max_rows` and `max_bytes`, either 0 for no limit
+    fn message_rows(&self, max_rows: usize, max_bytes: usize) -> usize {
+        let by_size = match &self.size {
+            Some((_, fixed, per_row)) if max_bytes > 0 && *per_row > 0.0 => {
+                ((max_bytes as f64 - fixed) / per_row).max(1.0) as usize
+            },
+            _ => usize::MAX,
+        };
+        let by_rows = if max_rows > 0 { max_rows } else { usize::MAX };
+        by_rows.min(by_size)
+    }
+
+    /// Messages that are full already, by row count or size; the rest stays pending
+    pub fn take_full(&mut self, max_rows: usize, max_bytes: usize) -> Vec<RecordBatch> {
+        let limit = self.message_rows(max_rows, max_bytes);
+        if self.pending_rows < limit {
+            return Vec::new();
+        }
+        let Some(merged) = self.take_merged() else { return Vec::new() };
+        let mut messages = Vec::new();
+        let mut offset = 0;
+        while merged.num_rows() - offset >= limit {
+            messages.push(merged.slice(offset, limit));
+            offset += limit;
+        }
+        if offset < merged.num_rows() {
+            self.pending_rows = merged.num_rows() - offset;
+            self.pending.push(merged.slice(offset, self.pending_rows));
+        }
+        messages
+    }
+
+    /// Everything pending, in `parts` messages of nearly the same number of rows
+    pub fn take_all(&mut self, parts: u64) -> Vec<RecordBatch> {
+        let Some(merged) = self.take_merged() else { return Vec::new() };
+        let parts = (parts as usize).clamp(1, merged.num_rows());
+        let mut offset = 0;
+        (0..parts)
+            .map(|part| {
+                let end = merged.num_rows() * (part + 1) / parts;
+                let message = merged.slice(offset, end - offset);
+                offset = end;
+                message
+            })
+            .collect()
+    }
+
+    fn take_merged(&mut self) -> Option<RecordBatch> {
+        let first = self.pending.first()?;
+        let merged = concat_batches(&first.schema(), &self.pending);
+        self.clear();
+        match merged {
+            Ok(batch) if batch.num_rows() > 0 => Some(batch),
+            Ok(_) => None,
+            Err(e) => {
+                error!("Failed to merge sampled rows: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// Arrow IPC size of a `/ws` frame with rows like those of `batch`: fixed bytes, and bytes
+/// per row, measured on enough copies of them that buffer padding does not count
+fn measure(batch: &RecordBatch) -> Option<(f64, f64)> {
+    if batch.num_rows() == 0 {
+        return None;
+    }
+    // The stamps the hub and `/ws` add to every frame, at their widest
+    let mut metadata = batch.schema().metadata().clone();
+    for key in [GENERATED_AT_METADATA_KEY, SEQUENCE_METADATA_KEY, SENT_AT_METADATA_KEY, CONNECTION_METADATA_KEY] {
+        metadata.insert(key.to_string(), u64::MAX.to_string());
+    }
+    let schema = Arc::new(batch.schema().as_ref().clone().with_metadata(metadata));
+
+    // A single row pads every buffer nearly as much as it can be, so estimates stay on the safe side
+    let copies = MEASURED_ROWS.div_ceil(batch.num_rows());
+    let sample = concat_batches(&schema, std::iter::repeat_n(batch, copies)).ok()?;
+    let one = encode_ipc_file(&sample.slice(0, 1)).ok()?.len() as f64;
+    let full = encode_ipc_file(&sample).ok()?.len() as f64;
+    let per_row = (full - one) / (sample.num_rows() - 1).max(1) as f64;
+    Some((one - per_row, per_row))
+}
+
+/// How a generator topic keeps up with its requested rates, for `/status`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct GeneratorRate {
+    pub running: bool,
+    pub requested_hz: f64,              // samples per second
+    pub achieved_hz: f64,               // over the last second or so
+    pub requested_message_hz: f64,      // 0 = a message whenever samples are taken
+    pub message_hz: f64,
+    pub catch_up: CatchUpPolicy,
+    pub samples: u64,
+    pub messages: u64,
+    pub skipped_samples: u64,           // dropped by `Skip`, or for being too far behind
+    pub coalesced_messages: u64,        // overdue messages merged into the next one
+}
+
+/// Counts samples and messages for one generator and works out its achieved rates
+pub struct RateMeter {
+    pub rate: GeneratorRate,
+    window_start: Instant,
+    window_samples: u64,
+    window_messages: u64,
+}
+
+impl Default for RateMeter {
+    fn default() -> Self {
+        Self { rate: GeneratorRate::default(), window_start: Instant::now(), window_samples: 0, window_messages: 0 }
+    }
+}
+
+impl RateMeter {
+    pub fn record(&mut self, samples: u64, messages: u64, skipped: u64, coalesced: u64) {
+        self.rate.samples += samples;
+        self.rate.messages += messages;
+        self.rate.skipped_samples += skipped;
+        self.rate.coalesced_messages += coalesced;
+        self.window_samples += samples;
+        self.window_messages += messages;
+    }
+
+    /// Close the window once it has lasted a second; true when it did
+    pub fn roll(&mut self) -> bool {
+        let seconds = self.window_start.elapsed().as_secs_f64();
+        if seconds < 1.0 {
+            return false;
+        }
+        self.rate.achieved_hz = self.window_samples as f64 / seconds;
+        self.rate.message_hz = self.window_messages as f64 / seconds;
+        self.window_start = Instant::now();
+        self.window_samples = 0;
+        self.window_messages = 0;
+        true
+    }
+
+    /// Start measuring afresh, after the generator was paused
+    pub fn restart(&mut self) {
+        self.rate.achieved_hz = 0.0;
+        self.rate.message_hz = 0.0;
+        self.window_start = Instant::now();
+        self.window_samples = 0;
+        self.window_messages = 0;
+    }
+}
+
+/// The latest `GeneratorRate` of every generator topic
+#[derive(Debug, Default)]
+pub struct GeneratorRates {
+    topics: Mutex<BTreeMap<String, GeneratorRate>>,
+}
+
+pub type GeneratorRatesHandle = Arc<GeneratorRates>;
+
+impl GeneratorRates {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn update(&self, topic: &str, rate: &GeneratorRate) {
+        self.topics.lock().unwrap().insert(topic.to_string(), rate.clone());
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, GeneratorRate> {
+        self.topics.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{Float64Array, Int64Array};
+    use arrow::datatypes::{DataType, Field, Schema};
+
+    // 64 Hz, so every deadline is exact in floating point
+    const INTERVAL: Duration = Duration::from_micros(15_625);
+
+    fn ticker() -> (Ticker, Instant) {
+        let ticker = Ticker::new(64.0);
+        let origin = ticker.next_deadline();
+        (ticker, origin)
+    }
+
+    #[test]
+    fn ticks_are_handed_out_once() {
+        let (mut ticker, origin) = ticker();
+        assert_eq!(ticker.due(origin).on_time, 1);
+        assert_eq!(ticker.due(origin).on_time, 0);
+        assert_eq!(ticker.due(origin + INTERVAL / 2).on_time, 0);
+
+        let ticks = ticker.due(origin + INTERVAL);
+        assert_eq!((ticks.on_time, ticks.late, ticks.dropped), (1, 0, 0));
+        assert_eq!(ticks.lateness, Duration::ZERO);
+        assert_eq!(ticker.next_deadline(), origin + INTERVAL * 2);
+    }
+
+    #[test]
+    fn overdue_ticks_are_late() {
+        let (mut ticker, origin) = ticker();
+        ticker.due(origin);
+
+        // Ticks 1 to 5 are due; only the last within the timer slack
+        let ticks = ticker.due(origin + INTERVAL * 5);
+        assert_eq!((ticks.on_time, ticks.late, ticks.dropped), (1, 4, 0));
+        assert_eq!(ticks.lateness, INTERVAL * 4);
+
+        // Several ticks can fall within the slack at high rates
+        let mut fast = Ticker::new(4096.0);
+        let start = fast.next_deadline();
+        let ticks = fast.due(start + Duration::from_millis(1));
+        assert_eq!((ticks.on_time, ticks.late, ticks.dropped), (5, 0, 0));
+    }
+
+    #[test]
+    fn ticks_beyond_the_catch_up_limit_are_dropped() {
+        let (mut ticker, origin) = ticker();
+        ticker.due(origin);
+
+        // Four seconds behind: ticks 1 to 191, more than a second overdue, are dropped
+        let ticks = ticker.due(origin + INTERVAL * 192 + MAX_CATCH_UP);
+        assert_eq!(ticks.dropped, 191);
+        assert_eq!(ticks.on_time + ticks.late, 65);
+        assert_eq!(ticks.lateness, MAX_CATCH_UP);
+        assert_eq!(ticker.next_deadline(), origin + INTERVAL * 257);
+    }
+
+    #[test]
+    fn rate_changes_keep_the_next_deadline() {
+        let (mut ticker, origin) = ticker();
+        ticker.due(origin);
+        ticker.set_rate(32.0);
+        assert_eq!(ticker.next_deadline(), origin + INTERVAL);
+        ticker.due(origin + INTERVAL);
+        assert_eq!(ticker.next_deadline(), origin + INTERVAL * 3);
+    }
+
+    #[test]
+    fn catch_up_policies() {
+        let ticks = DueTicks { on_time: 1, late: 4, dropped: 2, lateness: Duration::ZERO };
+
+        // Dropped samples are never generated; `Skip` drops the late ones too
+        assert_eq!(ticks.samples(CatchUpPolicy::Burst), (5, 2));
+        assert_eq!(ticks.samples(CatchUpPolicy::Coalesce), (5, 2));
+        assert_eq!(ticks.samples(CatchUpPolicy::Skip), (1, 6));
+
+        // `Burst` sends a message per tick; the others merge the overdue ones into one
+        assert_eq!(ticks.messages(CatchUpPolicy::Burst), (7, 0));
+        assert_eq!(ticks.messages(CatchUpPolicy::Coalesce), (2, 5));
+        assert_eq!(ticks.messages(CatchUpPolicy::Skip), (2, 5));
+
+        let on_time = DueTicks { on_time: 3, ..Default::default() };
+        for policy in [CatchUpPolicy::Burst, CatchUpPolicy::Skip, CatchUpPolicy::Coalesce] {
+            assert_eq!(on_time.samples(policy), (3, 0));
+            assert_eq!(on_time.messages(policy), (3, 0));
+            assert_eq!(DueTicks::default().messages(policy), (0, 0));
+        }
+    }
+
+    fn rows(range: std::ops::Range<i64>) -> RecordBatch {
+        let schema = Arc::new(Schema::new(vec![Field::new("timestamp", DataType::Int64, false)]));
+        RecordBatch::try_new(schema, vec![Arc::new(Int64Array::from_iter_values(range))]).unwrap()
+    }
+
+    fn row_counts(messages: &[RecordBatch]) -> Vec<usize> {
+        messages.iter().map(|m| m.num_rows()).collect()
+    }
+
+    #[test]
+    fn full_messages_by_row_count() {
+        let mut buffer = MessageBuffer::new();
+        let mut out = Vec::new();
+        buffer.push(rows(0..3), &mut out);
+        assert!(buffer.take_full(4, 0).is_empty());
+        buffer.push(rows(3..10), &mut out);
+        assert!(out.is_empty());
+
+        let full = buffer.take_full(4, 0);
+        assert_eq!(row_counts(&full), [4, 4]);
+        let values = full[1].column(0).as_any().downcast_ref::<Int64Array>().unwrap();
+        assert_eq!(values.values(), &[4, 5, 6, 7]);
+
+        // The remainder waits for the message ticker
+        assert!(buffer.take_full(4, 0).is_empty());
+        assert_eq!(row_counts(&buffer.take_all(1)), [2]);
+        assert!(buffer.take_all(1).is_empty());
+    }
+
+    #[test]
+    fn full_messages_by_size() {
+        let mut buffer = MessageBuffer::new();
+        let mut out = Vec::new();
+        buffer.push(rows(0..10_000), &mut out);
+
+        let max_bytes = 16 * 1024;
+        let full = buffer.take_full(0, max_bytes);
+        assert!(full.len() > 1);
+        for message in &full {
+            assert!(encode_ipc_file(message).unwrap().len() <= max_bytes);
+        }
+        let rest: usize = row_counts(&buffer.take_all(1)).iter().sum();
+        assert_eq!(row_counts(&full).iter().sum::<usize>() + rest, 10_000);
+
+        // With both limits the lower one applies
+        buffer.push(rows(0..10_000), &mut out);
+        assert!(buffer.take_full(100, max_bytes).iter().all(|m| m.num_rows() == 100));
+    }
+
+    #[test]
+    fn take_all_splits_evenly() {
+        let mut buffer = MessageBuffer::new();
+        let mut out = Vec::new();
+        buffer.push(rows(0..10), &mut out);
+        assert_eq!(row_counts(&buffer.take_all(3)), [3, 3, 4]);
+
+        // Never more messages than rows
+        buffer.push(rows(0..2), &mut out);
+        assert_eq!(row_counts(&buffer.take_all(5)), [1, 1]);
+
+        assert!(MessageBuffer::new().take_all(3).is_empty());
+    }
+
+    #[test]
+    fn schema_changes_flush_pending_rows() {
+        let mut buffer = MessageBuffer::new();
+        let mut out = Vec::new();
+        buffer.push(rows(0..3), &mut out);
+
+        let schema = Arc::new(Schema::new(vec![Field::new("value", DataType::Float64, false)]));
+        let other = RecordBatch::try_new(schema.clone(), vec![Arc::new(Float64Array::from(vec![1.0, 2.0]))]).unwrap();
+        buffer.push(other, &mut out);
+        assert_eq!(row_counts(&out), [3]);
+
+        let rest = buffer.take_all(1);
+        assert_eq!(row_counts(&rest), [2]);
+        assert_eq!(rest[0].schema(), schema);
+    }
+}
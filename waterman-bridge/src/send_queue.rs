@@ -0,0 +1,564 @@
+//! Per-connection send queues of `/ws` and `/mux`: what waits between the stream relay and the
+//! task writing to the socket, and what happens when a client reads slower than its
+//! stream produces.
+//!
+//! A queue holds at most `max_messages` batches and, unless it is 0, `max_bytes` of
+//! them in memory. A batch that does not fit is handled by the connection's
+//! [`OverflowPolicy`]. Control messages and query results are never dropped: control
+//! messages skip the limits, and query results wait for room.
+
+use crate::impairment::LinkCounters;
+use crate::parameters::{OverflowPolicy, Parameters};
+use crate::ws_handler::WSMessage;
+use arrow::compute::concat_batches;
+use arrow::record_batch::RecordBatch;
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// Closed connections kept around for reporting, beyond which the oldest are dropped
+const MAX_CLOSED_CONNECTIONS: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct QueueLimits {
+    pub policy: OverflowPolicy,
+    pub max_messages: usize,
+    pub max_bytes: usize,       // 0 = no limit
+}
+
+impl QueueLimits {
+    pub fn from_parameters(params: &Parameters) -> Self {
+        Self {
+            policy: params.websocket.overflow_policy,
+            max_messages: params.websocket.queue_max_messages.max(1),
+            max_bytes: params.websocket.queue_max_bytes,
+        }
+    }
+}
+
+/// Memory held by a batch, counting only the rows of sliced ones
+fn batch_bytes(batch: &RecordBatch) -> usize {
+    batch
+        .columns()
+        .iter()
+        .map(|column| column.to_data().get_slice_memory_size().unwrap_or_else(|_| column.get_array_memory_size()))
+        .sum()
+}
+
+fn message_bytes(message: &WSMessage) -> usize {
+    match message {
+        WSMessage::Batch(batch) | WSMessage::Topic(_, batch) => batch_bytes(batch),
+        WSMessage::Text(text) => text.len(),
+        WSMessage::Binary(bytes) => bytes.len(),
+    }
+}
+
+/// A batch from a stream, as opposed to a control message
+fn is_batch(message: &WSMessage) -> bool {
+    matches!(message, WSMessage::Batch(_) | WSMessage::Topic(..))
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct QueueCounters {
+    pub queued: usize,              // messages waiting now
+    pub queued_bytes: usize,
+    pub peak_queued: usize,
+    pub peak_queued_bytes: usize,
+    pub enqueued: u64,
+    pub sent: u64,                  // taken off the queue by the sender
+    pub dropped_batches: u64,       // by `DropOldest` and `DropNewest`
+    pub dropped_bytes: u64,
+    pub coalesced_batches: u64,     // merged into the batch before them
+    pub waited: u64,                // times a batch had to wait for room
+    pub lagged_batches: u64,        // skipped by the stream while the relay was waiting
+    pub overflowed: bool,           // closed by `Disconnect`
+}
+
+struct Inner {
+    messages: VecDeque<(WSMessage, usize)>,
+    counters: QueueCounters,
+    closed: bool,
+}
+
+pub struct SendQueue {
+    pub stream: String,
+    pub limits: QueueLimits,
+    inner: Mutex<Inner>,
+    changed: Notify,
+    link: Mutex<LinkCounters>,      // of the simulated link the sender writes through
+}
+
+pub type SendQueueHandle = Arc<SendQueue>;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct QueueClosed;
+
+impl SendQueue {
+    pub fn new(stream: &str, limits: QueueLimits) -> Self {
+        Self {
+            stream: stream.to_string(),
+            limits,
+            inner: Mutex::new(Inner { messages: VecDeque::new(), counters: QueueCounters::default(), closed: false }),
+            changed: Notify::new(),
+            link: Mutex::new(LinkCounters::default()),
+        }
+    }
+
+    fn fits(&self, inner: &Inner, bytes: usize) -> bool {
+        let counters = &inner.counters;
+        counters.queued < self.limits.max_messages
+            && (self.limits.max_bytes == 0 || counters.queued == 0 || counters.queued_bytes + bytes <= self.limits.max_bytes)
+    }
+
+    fn enqueue(&self, inner: &mut Inner, message: WSMessage, bytes: usize) {
+        inner.messages.push_back((message, bytes));
+        let counters = &mut inner.counters;
+        counters.enqueued += 1;
+        counters.queued += 1;
+        counters.queued_bytes += bytes;
+        counters.peak_queued = counters.peak_queued.max(counters.queued);
+        counters.peak_queued_bytes = counters.peak_queued_bytes.max(counters.queued_bytes);
+        self.changed.notify_waiters();
+    }
+
+    fn drop_oldest_batch(inner: &mut Inner) -> bool {
+        let Some(index) = inner.messages.iter().position(|(m, _)| is_batch(m)) else {
+            return false;
+        };
+        let (_, bytes) = inner.messages.remove(index).expect("index is in range");
+        let counters = &mut inner.counters;
+        counters.queued -= 1;
+        counters.queued_bytes -= bytes;
+        counters.dropped_batches += 1;
+        counters.dropped_bytes += bytes as u64;
+        true
+    }
+
+    /// Merge `batch` into the newest queued batch, if there is one of the same topic with the
+    /// same fields; the merged batch keeps the metadata and header of the older one
+    fn coalesce(inner: &mut Inner, topic_id: Option<u32>, batch: &RecordBatch) -> bool {
+        let Some((newest, bytes)) = inner.messages.back_mut() else {
+            return false;
+        };
+        let (newest_topic_id, newest) = match newest {
+            WSMessage::Batch(newest) => (None, newest),
+            WSMessage::Topic(header, newest) => (Some(header.topic_id), newest),
+            WSMessage::Text(_) | WSMessage::Binary(_) => return false,
+        };
+        if newest_topic_id != topic_id || newest.schema().fields() != batch.schema().fields() {
+            return false;
+        }
+        let Ok(merged) = concat_batches(&newest.schema(), [&*newest, batch]) else {
+            return false;
+        };
+        let merged_bytes = batch_bytes(&merged);
+        inner.counters.queued_bytes = inner.counters.queued_bytes - *bytes + merged_bytes;
+        inner.counters.peak_queued_bytes = inner.counters.peak_queued_bytes.max(inner.counters.queued_bytes);
+        inner.counters.coalesced_batches += 1;
+        *newest = merged;
+        *bytes = merged_bytes;
+        true
+    }
+
+    /// Queue a message from the stream; a batch that does not fit is handled by the policy
+    pub async fn push(&self, message: WSMessage) -> Result<(), QueueClosed> {
+        let bytes = message_bytes(&message);
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.closed {
+                return Err(QueueClosed);
+            }
+            let (topic_id, batch) = match &message {
+                WSMessage::Batch(batch) => (None, batch),
+                WSMessage::Topic(header, batch) => (Some(header.topic_id), batch),
+                WSMessage::Text(_) | WSMessage::Binary(_) => {
+                    self.enqueue(&mut inner, message, bytes);
+                    return Ok(());
+                },
+            };
+            if !self.fits(&inner, bytes) {
+                match self.limits.policy {
+                    OverflowPolicy::Wait => {},
+                    OverflowPolicy::DropOldest => {
+                        while !self.fits(&inner, bytes) && Self::drop_oldest_batch(&mut inner) {}
+                    },
+                    OverflowPolicy::DropNewest => {
+                        inner.counters.dropped_batches += 1;
+                        inner.counters.dropped_bytes += bytes as u64;
+                        return Ok(());
+                    },
+                    OverflowPolicy::Coalesce => {
+                        // Merging saves messages, not bytes: past the byte limit the oldest go
+                        if Self::coalesce(&mut inner, topic_id, batch) {
+                            while self.limits.max_bytes > 0
+                                && inner.messages.iter().filter(|(m, _)| is_batch(m)).count() > 1
+                                && inner.counters.queued_bytes > self.limits.max_bytes
+                                && Self::drop_oldest_batch(&mut inner)
+                            {}
+                            self.changed.notify_waiters();
+                            return Ok(());
+                        }
+                        while !self.fits(&inner, bytes) && Self::drop_oldest_batch(&mut inner) {}
+                    },
+                    OverflowPolicy::Disconnect => {
+                        inner.counters.overflowed = true;
+                        self.shut(&mut inner);
+                        return Err(QueueClosed);
+                    },
+                }
+            }
+            if self.fits(&inner, bytes) {
+                self.enqueue(&mut inner, message, bytes);
+                return Ok(());
+            }
+            inner.counters.waited += 1;
+        }
+        self.push_waiting(message).await
+    }
+
+    /// Queue a message once there is room for it, whatever the policy
+    pub async fn push_waiting(&self, message: WSMessage) -> Result<(), QueueClosed> {
+        let bytes = message_bytes(&message);
+        loop {
+            let notified = self.changed.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            {
+                let mut inner = self.inner.lock().unwrap();
+                if inner.closed {
+                    return Err(QueueClosed);
+                }
+                if self.fits(&inner, bytes) {
+                    self.enqueue(&mut inner, message, bytes);
+                    return Ok(());
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// The next message to send; `None` once the queue is closed
+    pub async fn pop(&self) -> Option<WSMessage> {
+        loop {
+            let notified = self.changed.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            {
+                let mut inner = self.inner.lock().unwrap();
+                if inner.closed {
+                    return None;
+                }
+                if let Some((message, bytes)) = inner.messages.pop_front() {
+                    inner.counters.queued -= 1;
+                    inner.counters.queued_bytes -= bytes;
+                    inner.counters.sent += 1;
+                    self.changed.notify_waiters();
+                    return Some(message);
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// Stop taking and handing out messages, e.g. when the client has gone
+    pub fn close(&self) {
+        self.shut(&mut self.inner.lock().unwrap());
+    }
+
+    fn shut(&self, inner: &mut Inner) {
+        inner.closed = true;
+        inner.messages.clear();
+        inner.counters.queued = 0;
+        inner.counters.queued_bytes = 0;
+        self.changed.notify_waiters();
+    }
+
+    pub async fn closed(&self) {
+        loop {
+            let notified = self.changed.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if self.inner.lock().unwrap().closed {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Batches the stream skipped because the relay fell behind it
+    pub fn record_lagged(&self, batches: u64) {
+        self.inner.lock().unwrap().counters.lagged_batches += batches;
+    }
+
+    /// Batches dropped so far, by the queue or the stream, to tell the client about
+    pub fn skipped_batches(&self) -> u64 {
+        let counters = &self.inner.lock().unwrap().counters;
+        counters.dropped_batches + counters.lagged_batches
+    }
+
+    /// Batches dropped by the overflow policy so far
+    pub fn dropped_batches(&self) -> u64 {
+        self.inner.lock().unwrap().counters.dropped_batches
+    }
+
+    pub fn overflowed(&self) -> bool {
+        self.inner.lock().unwrap().counters.overflowed
+    }
+
+    fn counters(&self) -> QueueCounters {
+        self.inner.lock().unwrap().counters.clone()
+    }
+
+    pub fn set_link_counters(&self, counters: &LinkCounters) {
+        self.link.lock().unwrap().clone_from(counters);
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionQueueSummary {
+    pub stream: String,
+    pub open: bool,
+    pub limits: QueueLimits,
+    #[serde(flatten)]
+    pub counters: QueueCounters,
+    pub link: LinkCounters,
+}
+
+/// Totals over every connection, for `/status`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct QueueTotals {
+    pub open_connections: usize,
+    pub queued: usize,
+    pub queued_bytes: usize,
+    pub dropped_batches: u64,
+    pub dropped_bytes: u64,
+    pub coalesced_batches: u64,
+    pub lagged_batches: u64,
+    pub disconnected: usize,    // by `Disconnect`
+}
+
+/// The send queue of every `/ws` connection, keyed by connection id
+#[derive(Default)]
+pub struct SendQueues {
+    connections: Mutex<BTreeMap<u64, (SendQueueHandle, bool)>>,    // queue, open
+}
+
+pub type SendQueuesHandle = Arc<SendQueues>;
+
+impl std::fmt::Debug for SendQueues {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SendQueues").field("connections", &self.connections.lock().unwrap().len()).finish()
+    }
+}
+
+impl SendQueues {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn open_connection(&self, connection_id: u64, stream: &str, limits: QueueLimits) -> SendQueueHandle {
+        let queue = Arc::new(SendQueue::new(stream, limits));
+        self.connections.lock().unwrap().insert(connection_id, (queue.clone(), true));
+        queue
+    }
+
+    pub fn close_connection(&self, connection_id: u64) {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some((queue, open)) = connections.get_mut(&connection_id) {
+            queue.close();
+            *open = false;
+        }
+        let closed: Vec<u64> = connections.iter().filter(|(_, (_, open))| !open).map(|(id, _)| *id).collect();
+        for id in closed.iter().take(closed.len().saturating_sub(MAX_CLOSED_CONNECTIONS)) {
+            connections.remove(id);
+        }
+    }
+
+    pub fn summary(&self) -> BTreeMap<u64, ConnectionQueueSummary> {
+        self.connections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, (queue, open))| {
+                let summary = ConnectionQueueSummary {
+                    stream: queue.stream.clone(),
+                    open: *open,
+                    limits: queue.limits,
+                    counters: queue.counters(),
+                    link: queue.link.lock().unwrap().clone(),
+                };
+                (*id, summary)
+            })
+            .collect()
+    }
+
+    pub fn totals(&self) -> QueueTotals {
+        let mut totals = QueueTotals::default();
+        for (queue, open) in self.connections.lock().unwrap().values() {
+            let counters = queue.counters();
+            totals.open_connections += *open as usize;
+            totals.queued += counters.queued;
+            totals.queued_bytes += counters.queued_bytes;
+            totals.dropped_batches += counters.dropped_batches;
+            totals.dropped_bytes += counters.dropped_bytes;
+            totals.coalesced_batches += counters.coalesced_batches;
+            totals.lagged_batches += counters.lagged_batches;
+            totals.disconnected += counters.overflowed as usize;
+        }
+        totals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mux::{FrameHeader, FrameKind};
+    use arrow::array::Int64Array;
+    use arrow::datatypes::{DataType, Field, Schema};
+
+    fn batch(rows: i64) -> RecordBatch {
+        let schema = Arc::new(Schema::new(vec![Field::new("timestamp", DataType::Int64, false)]));
+        RecordBatch::try_new(schema, vec![Arc::new(Int64Array::from_iter_values(0..rows))]).unwrap()
+    }
+
+    fn queue(policy: OverflowPolicy, max_messages: usize, max_bytes: usize) -> SendQueue {
+        SendQueue::new("test", QueueLimits { policy, max_messages, max_bytes })
+    }
+
+    /// Rows of each queued message, texts as 0, emptying the queue
+    async fn drain(queue: &SendQueue) -> Vec<usize> {
+        let mut rows = Vec::new();
+        while queue.counters().queued > 0 {
+            match queue.pop().await.unwrap() {
+                WSMessage::Batch(batch) | WSMessage::Topic(_, batch) => rows.push(batch.num_rows()),
+                WSMessage::Text(_) | WSMessage::Binary(_) => rows.push(0),
+            }
+        }
+        rows
+    }
+
+    #[tokio::test]
+    async fn wait_holds_the_batch_until_there_is_room() {
+        let queue = Arc::new(queue(OverflowPolicy::Wait, 2, 0));
+        queue.push(WSMessage::Batch(batch(1))).await.unwrap();
+        queue.push(WSMessage::Batch(batch(2))).await.unwrap();
+
+        let pushing = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.push(WSMessage::Batch(batch(3))).await }
+        });
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert!(!pushing.is_finished());
+        assert_eq!(queue.counters().waited, 1);
+
+        queue.pop().await.unwrap();
+        pushing.await.unwrap().unwrap();
+        assert_eq!(drain(&queue).await, [2, 3]);
+        assert_eq!(queue.counters().dropped_batches, 0);
+
+        // What the stream skips meanwhile is reported with the drops
+        queue.record_lagged(5);
+        assert_eq!(queue.skipped_batches(), 5);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_makes_room() {
+        let queue = queue(OverflowPolicy::DropOldest, 2, 0);
+        queue.push(WSMessage::Text("{}".to_string())).await.unwrap();
+        for rows in 1..=3 {
+            queue.push(WSMessage::Batch(batch(rows))).await.unwrap();
+        }
+        // Control messages are neither limited nor dropped
+        assert_eq!(drain(&queue).await, [0, 3]);
+        let counters = queue.counters();
+        assert_eq!(counters.dropped_batches, 2);
+        assert_eq!(counters.dropped_bytes as usize, batch_bytes(&batch(1)) + batch_bytes(&batch(2)));
+        assert_eq!(queue.skipped_batches(), 2);
+    }
+
+    #[tokio::test]
+    async fn drop_newest_drops_the_batch() {
+        let queue = queue(OverflowPolicy::DropNewest, 2, 0);
+        for rows in 1..=3 {
+            queue.push(WSMessage::Batch(batch(rows))).await.unwrap();
+        }
+        assert_eq!(drain(&queue).await, [1, 2]);
+        let counters = queue.counters();
+        assert_eq!(counters.dropped_batches, 1);
+        assert_eq!(counters.dropped_bytes as usize, batch_bytes(&batch(3)));
+        assert_eq!(counters.peak_queued, 2);
+    }
+
+    #[tokio::test]
+    async fn coalesce_merges_into_the_newest_batch() {
+        let queue = queue(OverflowPolicy::Coalesce, 2, 0);
+        for rows in 1..=4 {
+            queue.push(WSMessage::Batch(batch(rows))).await.unwrap();
+        }
+        assert_eq!(drain(&queue).await, [1, 9]);
+        let counters = queue.counters();
+        assert_eq!(counters.coalesced_batches, 2);
+        assert_eq!(counters.dropped_batches, 0);
+    }
+
+    #[tokio::test]
+    async fn disconnect_closes_the_queue() {
+        let queue = queue(OverflowPolicy::Disconnect, 1, 0);
+        queue.push(WSMessage::Batch(batch(1))).await.unwrap();
+        assert_eq!(queue.push(WSMessage::Batch(batch(1))).await, Err(QueueClosed));
+        assert!(queue.overflowed());
+        assert!(queue.pop().await.is_none());
+        assert_eq!(queue.push(WSMessage::Text("{}".to_string())).await, Err(QueueClosed));
+    }
+
+    #[tokio::test]
+    async fn byte_limit() {
+        let max_bytes = batch_bytes(&batch(10));
+        let queue = queue(OverflowPolicy::DropNewest, 100, max_bytes);
+        queue.push(WSMessage::Batch(batch(5))).await.unwrap();
+        queue.push(WSMessage::Batch(batch(5))).await.unwrap();
+        queue.push(WSMessage::Batch(batch(1))).await.unwrap();
+        assert_eq!(queue.counters().queued_bytes, max_bytes);
+        assert_eq!(drain(&queue).await, [5, 5]);
+        assert_eq!(queue.counters().dropped_batches, 1);
+
+        // A batch bigger than the limit still goes into an empty queue
+        queue.push(WSMessage::Batch(batch(20))).await.unwrap();
+        assert_eq!(drain(&queue).await, [20]);
+        assert_eq!(queue.counters().peak_queued_bytes, batch_bytes(&batch(20)));
+    }
+
+    #[tokio::test]
+    async fn coalesce_drops_the_oldest_past_the_byte_limit() {
+        let max_bytes = batch_bytes(&batch(10));
+        let queue = queue(OverflowPolicy::Coalesce, 100, max_bytes);
+        for _ in 0..3 {
+            queue.push(WSMessage::Batch(batch(5))).await.unwrap();
+        }
+        assert_eq!(drain(&queue).await, [10]);
+        let counters = queue.counters();
+        assert_eq!(counters.coalesced_batches, 1);
+        assert_eq!(counters.dropped_batches, 1);
+    }
+
+    #[tokio::test]
+    async fn coalesce_keeps_mux_topics_apart() {
+        let topic = |topic_id, rows| {
+            let header = FrameHeader { topic_id, sequence: 0, kind: FrameKind::Data };
+            WSMessage::Topic(header, batch(rows))
+        };
+        let queue = queue(OverflowPolicy::Coalesce, 2, 0);
+        queue.push(topic(1, 1)).await.unwrap();
+        queue.push(topic(2, 2)).await.unwrap();
+        queue.push(topic(2, 3)).await.unwrap();
+        queue.push(topic(1, 4)).await.unwrap();
+
+        // The last batch follows one of another topic, so it pushes out the oldest instead
+        assert_eq!(drain(&queue).await, [5, 4]);
+        assert_eq!(queue.counters().coalesced_batches, 1);
+        assert_eq!(queue.counters().dropped_batches, 1);
+    }
+}
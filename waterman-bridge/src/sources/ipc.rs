@@ -0,0 +1,320 @@
+use super::BatchingParams;
+use crate::stream_hub::HubHandle;
+use arrow::compute::concat_batches;
+use arrow::ipc::reader::StreamReader;
+use arrow::record_batch::RecordBatch;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum IpcInput {
+    Stdin,
+    UnixSocket,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IpcSourceConfig {
+    pub name: String,                       // stream the batches are published under
+    pub input: IpcInput,
+    #[serde(default)]
+    pub path: Option<String>,               // socket path for UnixSocket
+    #[serde(default)]
+    pub rebatch: Option<BatchingParams>,    // None = forward batches unchanged
+}
+
+impl IpcSourceConfig {
+    pub fn stdin(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            input: IpcInput::Stdin,
+            path: None,
+            rebatch: None,
+        }
+    }
+}
+
+/// Read one Arrow IPC stream to completion on a blocking thread, forwarding every batch
+fn read_ipc_stream<R: Read>(reader: R, label: &str, tx: &mpsc::Sender<RecordBatch>) {
+    let reader = match StreamReader::try_new(reader, None) {
+        Ok(reader) => reader,
+        Err(e) => {
+            error!("IPC source '{}': not an Arrow IPC stream: {}", label, e);
+            return;
+        }
+    };
+
+    info!("IPC source '{}': stream opened with schema {:?}", label, reader.schema());
+    let mut batch_count = 0u64;
+    for batch in reader {
+        match batch {
+            Ok(batch) => {
+                batch_count += 1;
+                if tx.blocking_send(batch).is_err() {
+                    break;
+                }
+            },
+            Err(e) => {
+                error!("IPC source '{}': failed to read record batch: {}", label, e);
+                break;
+            }
+        }
+    }
+    info!("IPC source '{}': stream closed after {} batches", label, batch_count);
+}
+
+#[cfg(unix)]
+async fn accept_unix_streams(config: IpcSourceConfig, tx: mpsc::Sender<RecordBatch>) {
+    let Some(path) = config.path.clone() else {
+        error!("IPC source '{}': UnixSocket input requires a path", config.name);
+        return;
+    };
+
+    // A socket file left behind by a previous run would make bind fail
+    let _ = std::fs::remove_file(&path);
+    let listener = match tokio::net::UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("IPC source '{}' failed to bind {}: {}", config.name, path, e);
+            return;
+        }
+    };
+    info!("IPC source '{}' listening on {}", config.name, path);
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!("IPC source '{}' accept error: {}", config.name, e);
+                break;
+            }
+        };
+
+        // StreamReader is synchronous, so hand the connection to a blocking thread
+        let std_stream = match stream.into_std().and_then(|s| s.set_nonblocking(false).map(|_| s)) {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("IPC source '{}': could not take over connection: {}", config.name, e);
+                continue;
+            }
+        };
+        let tx = tx.clone();
+        let label = config.name.clone();
+        tokio::task::spawn_blocking(move || read_ipc_stream(std_stream, &label, &tx));
+    }
+}
+
+#[cfg(not(unix))]
+async fn accept_unix_streams(config: IpcSourceConfig, _tx: mpsc::Sender<RecordBatch>) {
+    error!("IPC source '{}': Unix domain sockets are not supported on this platform", config.name);
+}
+
+/// Concatenates incoming batches into batches of `max_rows`, flushing early after `max_latency_ms`
+struct Rebatcher {
+    params: BatchingParams,
+    pending: Vec<RecordBatch>,
+    pending_rows: usize,
+}
+
+impl Rebatcher {
+    fn new(params: BatchingParams) -> Self {
+        // Zero rows per batch would never fill a batch, and slicing by zero never ends
+        let max_rows = params.max_rows.max(1);
+        Self {
+            params: BatchingParams { max_rows, ..params },
+            pending: Vec::new(),
+            pending_rows: 0,
+        }
+    }
+
+    fn push(&mut self, batch: RecordBatch, out: &mut Vec<RecordBatch>) {
+        // Batches with different schemas cannot be merged, so cut at schema changes
+        if self.pending.first().is_some_and(|first| first.schema() != batch.schema()) {
+            self.flush(out);
+        }
+
+        self.pending_rows += batch.num_rows();
+        self.pending.push(batch);
+
+        if self.pending_rows >= self.params.max_rows {
+            let Some(merged) = self.take_merged() else { return };
+            let mut offset = 0;
+            while merged.num_rows() - offset >= self.params.max_rows {
+                out.push(merged.slice(offset, self.params.max_rows));
+                offset += self.params.max_rows;
+            }
+            if offset < merged.num_rows() {
+                self.pending_rows = merged.num_rows() - offset;
+                self.pending.push(merged.slice(offset, self.pending_rows));
+            }
+        }
+    }
+
+    fn flush(&mut self, out: &mut Vec<RecordBatch>) {
+        if let Some(merged) = self.take_merged() {
+            out.push(merged);
+        }
+    }
+
+    fn take_merged(&mut self) -> Option<RecordBatch> {
+        let first = self.pending.first()?;
+        let schema = first.schema();
+        let merged = concat_batches(&schema, &self.pending);
+        self.pending.clear();
+        self.pending_rows = 0;
+
+        match merged {
+            Ok(batch) => Some(batch),
+            Err(e) => {
+                error!("Failed to re-batch IPC data: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// Publish everything read from the configured IPC input to `config.name`
+pub async fn run_ipc_source(config: IpcSourceConfig, hub: HubHandle) {
+    let (tx, mut rx) = mpsc::channel::<RecordBatch>(512);
+
+    match config.input {
+        IpcInput::Stdin => {
+            let label = config.name.clone();
+            tokio::task::spawn_blocking(move || read_ipc_stream(std::io::stdin().lock(), &label, &tx));
+        },
+        IpcInput::UnixSocket => {
+            tokio::spawn(accept_unix_streams(config.clone(), tx));
+        },
+    }
+
+    let Some(params) = config.rebatch.clone() else {
+        while let Some(batch) = rx.recv().await {
+            hub.publish(&config.name, batch);
+        }
+        info!("IPC source '{}' ending", config.name);
+        return;
+    };
+
+    let mut flush_timer = tokio::time::interval(Duration::from_millis(params.max_latency_ms.max(1)));
+    flush_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut rebatcher = Rebatcher::new(params);
+    let mut ready = Vec::new();
+
+    loop {
+        tokio::select! {
+            batch = rx.recv() => match batch {
+                Some(batch) => rebatcher.push(batch, &mut ready),
+                None => {
+                    rebatcher.flush(&mut ready);
+                    for batch in ready.drain(..) {
+                        hub.publish(&config.name, batch);
+                    }
+                    break;
+                }
+            },
+            _ = flush_timer.tick() => rebatcher.flush(&mut ready),
+        }
+
+        for batch in ready.drain(..) {
+            hub.publish(&config.name, batch);
+        }
+    }
+
+    info!("IPC source '{}' ending", config.name);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream_hub::StreamHub;
+    use arrow::array::{ArrayRef, AsArray, Int64Array};
+    use arrow::datatypes::Int64Type;
+    use std::sync::Arc;
+
+    fn batch(values: std::ops::Range<i64>) -> RecordBatch {
+        RecordBatch::try_from_iter(vec![("value", Arc::new(Int64Array::from_iter_values(values)) as ArrayRef)]).unwrap()
+    }
+
+    fn values(batches: &[RecordBatch]) -> Vec<Vec<i64>> {
+        batches.iter().map(|b| b.column(0).as_primitive::<Int64Type>().values().to_vec()).collect()
+    }
+
+    fn rebatcher(max_rows: usize) -> Rebatcher {
+        Rebatcher::new(BatchingParams { max_rows, max_latency_ms: 50 })
+    }
+
+    #[test]
+    fn splits_and_merges_to_max_rows() {
+        let mut rebatcher = rebatcher(4);
+        let mut out = Vec::new();
+        rebatcher.push(batch(0..3), &mut out);
+        assert!(out.is_empty());
+        rebatcher.push(batch(3..13), &mut out);
+        assert_eq!(values(&out), vec![vec![0, 1, 2, 3], vec![4, 5, 6, 7], vec![8, 9, 10, 11]]);
+
+        out.clear();
+        rebatcher.flush(&mut out);
+        assert_eq!(values(&out), vec![vec![12]]);
+        rebatcher.flush(&mut out);
+        assert_eq!(out.len(), 1);
+    }
+
+    #[test]
+    fn cuts_at_schema_changes() {
+        let mut rebatcher = rebatcher(10);
+        let mut out = Vec::new();
+        rebatcher.push(batch(0..2), &mut out);
+        let other = RecordBatch::try_from_iter(vec![("other", Arc::new(Int64Array::from(vec![9])) as ArrayRef)]).unwrap();
+        rebatcher.push(other, &mut out);
+        assert_eq!(values(&out), vec![vec![0, 1]]);
+    }
+
+    #[test]
+    fn zero_max_rows_passes_rows_one_by_one() {
+        let mut rebatcher = rebatcher(0);
+        let mut out = Vec::new();
+        rebatcher.push(batch(0..3), &mut out);
+        assert_eq!(values(&out), vec![vec![0], vec![1], vec![2]]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn flushes_partial_batches_after_max_latency() {
+        use arrow::ipc::writer::StreamWriter;
+
+        let path = std::env::temp_dir().join(format!("wmipc-test-{}.sock", std::process::id()));
+        let config = IpcSourceConfig {
+            name: "ipc".to_string(),
+            input: IpcInput::UnixSocket,
+            path: Some(path.to_string_lossy().to_string()),
+            rebatch: Some(BatchingParams { max_rows: 100, max_latency_ms: 20 }),
+        };
+        let hub = Arc::new(StreamHub::new());
+        tokio::spawn(run_ipc_source(config, hub.clone()));
+
+        let mut connection = None;
+        for _ in 0..50 {
+            if let Ok(stream) = std::os::unix::net::UnixStream::connect(&path) {
+                connection = Some(stream);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let data = batch(0..3);
+        let mut writer = StreamWriter::try_new(connection.expect("IPC source did not listen"), &data.schema()).unwrap();
+        writer.write(&data).unwrap();
+        writer.flush().unwrap();
+
+        // Far fewer than max_rows, and the stream stays open: only the latency bound sends it
+        for _ in 0..100 {
+            if !hub.history("ipc").is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(values(&hub.history("ipc")), vec![vec![0, 1, 2]]);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,481 @@
+//! MAVLink telemetry over UDP.
+//!
+//! Frames are decoded by a small built-in parser that understands MAVLink v1 and v2
+//! framing and the common telemetry messages below. Every message type is published
+//! as its own stream, `<stream_prefix>/<MESSAGE_NAME>`.
+
+use super::{BatchingParams, FieldSpec, FieldType, RowAccumulator};
+use crate::stream_hub::HubHandle;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+const MAVLINK_V1_STX: u8 = 0xFE;
+const MAVLINK_V2_STX: u8 = 0xFD;
+const MAVLINK_IFLAG_SIGNED: u8 = 0x01;
+const SIGNATURE_LEN: usize = 13;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MavlinkSourceConfig {
+    pub bind: String,                       // e.g. 0.0.0.0:14550
+    #[serde(default = "default_stream_prefix")]
+    pub stream_prefix: String,
+    #[serde(default)]
+    pub messages: Option<Vec<String>>,      // None = every supported message
+    #[serde(default)]
+    pub batching: BatchingParams,
+}
+
+fn default_stream_prefix() -> String {
+    "mavlink".to_string()
+}
+
+/// Wire types used by the supported messages
+#[derive(Debug, Clone, Copy)]
+enum Wire {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    F32,
+}
+
+impl Wire {
+    fn width(self) -> usize {
+        match self {
+            Wire::U8 | Wire::I8 => 1,
+            Wire::U16 | Wire::I16 => 2,
+            Wire::U32 | Wire::I32 | Wire::F32 => 4,
+            Wire::U64 => 8,
+        }
+    }
+
+    fn field_type(self) -> FieldType {
+        match self {
+            Wire::U8 | Wire::U16 | Wire::U32 => FieldType::UInt32,
+            Wire::I8 | Wire::I16 | Wire::I32 => FieldType::Int32,
+            Wire::U64 => FieldType::UInt64,
+            Wire::F32 => FieldType::Float32,
+        }
+    }
+
+    fn decode(self, bytes: &[u8]) -> Value {
+        match self {
+            Wire::U8 => Value::from(bytes[0]),
+            Wire::I8 => Value::from(bytes[0] as i8),
+            Wire::U16 => Value::from(u16::from_le_bytes([bytes[0], bytes[1]])),
+            Wire::I16 => Value::from(i16::from_le_bytes([bytes[0], bytes[1]])),
+            Wire::U32 => Value::from(u32::from_le_bytes(bytes[..4].try_into().unwrap())),
+            Wire::I32 => Value::from(i32::from_le_bytes(bytes[..4].try_into().unwrap())),
+            Wire::U64 => Value::from(u64::from_le_bytes(bytes[..8].try_into().unwrap())),
+            Wire::F32 => Value::from(f32::from_le_bytes(bytes[..4].try_into().unwrap())),
+        }
+    }
+}
+
+/// A message layout in wire order (MAVLink sorts fields by size, extensions are not decoded)
+struct MessageDef {
+    id: u32,
+    name: &'static str,
+    crc_extra: u8,
+    fields: &'static [(&'static str, Wire)],
+}
+
+impl MessageDef {
+    fn payload_len(&self) -> usize {
+        self.fields.iter().map(|(_, wire)| wire.width()).sum()
+    }
+}
+
+const MESSAGES: &[MessageDef] = &[
+    MessageDef {
+        id: 0,
+        name: "HEARTBEAT",
+        crc_extra: 50,
+        fields: &[
+            ("custom_mode", Wire::U32),
+            ("type", Wire::U8),
+            ("autopilot", Wire::U8),
+            ("base_mode", Wire::U8),
+            ("system_status", Wire::U8),
+            ("mavlink_version", Wire::U8),
+        ],
+    },
+    MessageDef {
+        id: 1,
+        name: "SYS_STATUS",
+        crc_extra: 124,
+        fields: &[
+            ("onboard_control_sensors_present", Wire::U32),
+            ("onboard_control_sensors_enabled", Wire::U32),
+            ("onboard_control_sensors_health", Wire::U32),
+            ("load", Wire::U16),
+            ("voltage_battery", Wire::U16),
+            ("current_battery", Wire::I16),
+            ("drop_rate_comm", Wire::U16),
+            ("errors_comm", Wire::U16),
+            ("errors_count1", Wire::U16),
+            ("errors_count2", Wire::U16),
+            ("errors_count3", Wire::U16),
+            ("errors_count4", Wire::U16),
+            ("battery_remaining", Wire::I8),
+        ],
+    },
+    MessageDef {
+        id: 24,
+        name: "GPS_RAW_INT",
+        crc_extra: 24,
+        fields: &[
+            ("time_usec", Wire::U64),
+            ("lat", Wire::I32),
+            ("lon", Wire::I32),
+            ("alt", Wire::I32),
+            ("eph", Wire::U16),
+            ("epv", Wire::U16),
+            ("vel", Wire::U16),
+            ("cog", Wire::U16),
+            ("fix_type", Wire::U8),
+            ("satellites_visible", Wire::U8),
+        ],
+    },
+    MessageDef {
+        id: 30,
+        name: "ATTITUDE",
+        crc_extra: 39,
+        fields: &[
+            ("time_boot_ms", Wire::U32),
+            ("roll", Wire::F32),
+            ("pitch", Wire::F32),
+            ("yaw", Wire::F32),
+            ("rollspeed", Wire::F32),
+            ("pitchspeed", Wire::F32),
+            ("yawspeed", Wire::F32),
+        ],
+    },
+    MessageDef {
+        id: 33,
+        name: "GLOBAL_POSITION_INT",
+        crc_extra: 104,
+        fields: &[
+            ("time_boot_ms", Wire::U32),
+            ("lat", Wire::I32),
+            ("lon", Wire::I32),
+            ("alt", Wire::I32),
+            ("relative_alt", Wire::I32),
+            ("vx", Wire::I16),
+            ("vy", Wire::I16),
+            ("vz", Wire::I16),
+            ("hdg", Wire::U16),
+        ],
+    },
+    MessageDef {
+        id: 74,
+        name: "VFR_HUD",
+        crc_extra: 20,
+        fields: &[
+            ("airspeed", Wire::F32),
+            ("groundspeed", Wire::F32),
+            ("alt", Wire::F32),
+            ("climb", Wire::F32),
+            ("heading", Wire::I16),
+            ("throttle", Wire::U16),
+        ],
+    },
+];
+
+/// CRC-16/MCRF4XX as used by MAVLink
+fn crc_accumulate(crc: u16, byte: u8) -> u16 {
+    let mut tmp = byte ^ (crc & 0xFF) as u8;
+    tmp ^= tmp << 4;
+    let tmp = tmp as u16;
+    (crc >> 8) ^ (tmp << 8) ^ (tmp << 3) ^ (tmp >> 4)
+}
+
+fn frame_crc(bytes: &[u8], crc_extra: u8) -> u16 {
+    let crc = bytes.iter().fold(0xFFFF, |crc, &b| crc_accumulate(crc, b));
+    crc_accumulate(crc, crc_extra)
+}
+
+struct Frame<'a> {
+    system_id: u8,
+    component_id: u8,
+    message_id: u32,
+    payload: &'a [u8],
+}
+
+/// Outcome of trying to parse a frame at the start of a buffer
+enum Parsed<'a> {
+    Frame(Frame<'a>, usize),    // frame and total bytes consumed
+    Unknown(usize),             // well-formed frame of an unsupported message
+    Invalid,                    // bad CRC or not a frame, resync one byte later
+    Incomplete,
+}
+
+fn parse_frame(buf: &[u8]) -> Parsed<'_> {
+    let (header_len, payload_len, message_id, system_id, component_id, signed) = match buf.first() {
+        Some(&MAVLINK_V2_STX) if buf.len() >= 10 => {
+            let message_id = u32::from_le_bytes([buf[7], buf[8], buf[9], 0]);
+            (10, buf[1] as usize, message_id, buf[5], buf[6], buf[2] & MAVLINK_IFLAG_SIGNED != 0)
+        },
+        Some(&MAVLINK_V1_STX) if buf.len() >= 6 => (6, buf[1] as usize, buf[5] as u32, buf[3], buf[4], false),
+        Some(&MAVLINK_V2_STX) | Some(&MAVLINK_V1_STX) => return Parsed::Incomplete,
+        _ => return Parsed::Invalid,
+    };
+
+    let frame_len = header_len + payload_len + 2 + if signed { SIGNATURE_LEN } else { 0 };
+    if buf.len() < frame_len {
+        return Parsed::Incomplete;
+    }
+
+    // Without the message's CRC_EXTRA the checksum can't be verified, so only trust the
+    // length when the frame ends the buffer or another one starts right after it
+    let Some(def) = MESSAGES.iter().find(|def| def.id == message_id) else {
+        return match buf.get(frame_len) {
+            None | Some(&MAVLINK_V2_STX) | Some(&MAVLINK_V1_STX) => Parsed::Unknown(frame_len),
+            Some(_) => Parsed::Invalid,
+        };
+    };
+
+    let crc_end = header_len + payload_len;
+    let expected = u16::from_le_bytes([buf[crc_end], buf[crc_end + 1]]);
+    if frame_crc(&buf[1..crc_end], def.crc_extra) != expected {
+        return Parsed::Invalid;
+    }
+
+    Parsed::Frame(
+        Frame {
+            system_id,
+            component_id,
+            message_id,
+            payload: &buf[header_len..crc_end],
+        },
+        frame_len,
+    )
+}
+
+/// Offset of the next start-of-frame marker after the first byte, or the end of `buf`
+fn next_frame_start(buf: &[u8]) -> usize {
+    1 + buf[1..]
+        .iter()
+        .position(|&b| b == MAVLINK_V2_STX || b == MAVLINK_V1_STX)
+        .unwrap_or(buf.len() - 1)
+}
+
+fn message_fields(def: &MessageDef) -> Vec<FieldSpec> {
+    let mut fields = vec![
+        FieldSpec { name: "received_ms".to_string(), data_type: FieldType::UInt64 },
+        FieldSpec { name: "system_id".to_string(), data_type: FieldType::UInt32 },
+        FieldSpec { name: "component_id".to_string(), data_type: FieldType::UInt32 },
+    ];
+    fields.extend(def.fields.iter().map(|(name, wire)| FieldSpec {
+        name: name.to_string(),
+        data_type: wire.field_type(),
+    }));
+    fields
+}
+
+fn decode_row(def: &MessageDef, frame: &Frame) -> Vec<Value> {
+    // MAVLink v2 trims trailing zero bytes from the payload
+    let mut payload = frame.payload.to_vec();
+    payload.resize(payload.len().max(def.payload_len()), 0);
+
+    let received_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+
+    let mut row = vec![
+        Value::from(received_ms),
+        Value::from(frame.system_id),
+        Value::from(frame.component_id),
+    ];
+    let mut offset = 0;
+    for (_, wire) in def.fields {
+        row.push(wire.decode(&payload[offset..offset + wire.width()]));
+        offset += wire.width();
+    }
+    row
+}
+
+struct MessageStream {
+    name: String,
+    accumulator: RowAccumulator,
+    last_flush: Instant,
+}
+
+/// Listen for MAVLink datagrams and publish one stream per message type
+pub async fn run_mavlink_source(config: MavlinkSourceConfig, hub: HubHandle) {
+    let socket = match UdpSocket::bind(&config.bind).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("MAVLink source failed to bind {}: {}", config.bind, e);
+            return;
+        }
+    };
+
+    let enabled: Vec<&MessageDef> = MESSAGES
+        .iter()
+        .filter(|def| config.messages.as_ref().is_none_or(|names| names.iter().any(|n| n == def.name)))
+        .collect();
+    let mut streams: HashMap<u32, MessageStream> = enabled
+        .iter()
+        .map(|def| {
+            (def.id, MessageStream {
+                name: format!("{}/{}", config.stream_prefix, def.name),
+                accumulator: RowAccumulator::new(&message_fields(def)),
+                last_flush: Instant::now(),
+            })
+        })
+        .collect();
+
+    let max_latency = Duration::from_millis(config.batching.max_latency_ms);
+    let mut buffer = vec![0u8; 65536];
+    let mut flush_timer = tokio::time::interval(max_latency.max(Duration::from_millis(1)));
+    flush_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    let mut frame_count = 0u64;
+    let mut error_count = 0u64;
+    let mut last_log_time = Instant::now();
+
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buffer) => {
+                let len = match received {
+                    Ok((len, _)) => len,
+                    Err(e) => {
+                        error!("MAVLink source receive error: {}", e);
+                        break;
+                    }
+                };
+
+                // A datagram usually holds one frame but may carry several
+                let mut offset = 0;
+                while offset < len {
+                    match parse_frame(&buffer[offset..len]) {
+                        Parsed::Frame(frame, consumed) => {
+                            frame_count += 1;
+                            offset += consumed;
+                            let Some(def) = enabled.iter().find(|def| def.id == frame.message_id) else { continue };
+                            let row = decode_row(def, &frame);
+                            if let Some(stream) = streams.get_mut(&frame.message_id) {
+                                stream.accumulator.push_row(&row);
+                            }
+                        },
+                        Parsed::Unknown(consumed) => {
+                            frame_count += 1;
+                            offset += consumed;
+                        },
+                        Parsed::Invalid => {
+                            error_count += 1;
+                            offset += next_frame_start(&buffer[offset..len]);
+                        },
+                        Parsed::Incomplete => {
+                            // Truncated, or its length byte is corrupt and frames follow it
+                            warn!("MAVLink source dropped truncated frame ({} bytes)", len - offset);
+                            error_count += 1;
+                            offset += next_frame_start(&buffer[offset..len]);
+                        },
+                    }
+                }
+            },
+            _ = flush_timer.tick() => {},
+        }
+
+        for stream in streams.values_mut() {
+            let full = stream.accumulator.len() >= config.batching.max_rows;
+            let stale = stream.last_flush.elapsed() >= max_latency;
+            if stream.accumulator.is_empty() || !(full || stale) {
+                continue;
+            }
+
+            match stream.accumulator.finish() {
+                Ok(batch) => {
+                    hub.publish(&stream.name, batch);
+                },
+                Err(e) => error!("MAVLink stream '{}' failed to build record batch: {}", stream.name, e),
+            }
+            stream.last_flush = Instant::now();
+        }
+
+        if last_log_time.elapsed() > Duration::from_secs(5) {
+            info!("MAVLink source {} stats - Frames: {}, Errors: {}", config.bind, frame_count, error_count);
+            last_log_time = Instant::now();
+        }
+    }
+
+    info!("MAVLink source {} ending", config.bind);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A MAVLink v2 frame with a valid checksum for `crc_extra`
+    fn v2_frame(message_id: u32, payload: &[u8], crc_extra: u8) -> Vec<u8> {
+        let id = message_id.to_le_bytes();
+        let mut frame = vec![MAVLINK_V2_STX, payload.len() as u8, 0, 0, 7, 1, 1, id[0], id[1], id[2]];
+        frame.extend_from_slice(payload);
+        let crc = frame_crc(&frame[1..], crc_extra);
+        frame.extend_from_slice(&crc.to_le_bytes());
+        frame
+    }
+
+    fn heartbeat() -> Vec<u8> {
+        v2_frame(0, &[1, 0, 0, 0, 2, 3, 81, 4, 3], 50)
+    }
+
+    #[test]
+    fn parses_a_known_frame() {
+        let buf = heartbeat();
+        let Parsed::Frame(frame, consumed) = parse_frame(&buf) else { panic!("expected a frame") };
+        assert_eq!(consumed, buf.len());
+        assert_eq!((frame.system_id, frame.component_id, frame.message_id), (1, 1, 0));
+
+        let row = decode_row(&MESSAGES[0], &frame);
+        assert_eq!(row[3], Value::from(1u32));      // custom_mode
+        assert_eq!(row[8], Value::from(3u8));       // mavlink_version
+    }
+
+    #[test]
+    fn rejects_a_bad_crc() {
+        let mut buf = heartbeat();
+        let last = buf.len() - 1;
+        buf[last] ^= 0xFF;
+        assert!(matches!(parse_frame(&buf), Parsed::Invalid));
+    }
+
+    #[test]
+    fn reports_a_truncated_frame() {
+        let buf = heartbeat();
+        assert!(matches!(parse_frame(&buf[..buf.len() - 3]), Parsed::Incomplete));
+        assert!(matches!(parse_frame(&buf[..4]), Parsed::Incomplete));
+        assert!(matches!(parse_frame(&[0x00, 0x01]), Parsed::Invalid));
+    }
+
+    #[test]
+    fn skips_unknown_messages_followed_by_a_frame() {
+        let mut buf = v2_frame(9999, &[1, 2, 3], 0);
+        let unknown_len = buf.len();
+        buf.extend(heartbeat());
+        assert!(matches!(parse_frame(&buf), Parsed::Unknown(n) if n == unknown_len));
+        assert!(matches!(parse_frame(&buf[unknown_len..]), Parsed::Frame(_, _)));
+    }
+
+    #[test]
+    fn does_not_trust_a_corrupt_length_of_an_unknown_message() {
+        // The length byte claims one more payload byte than there is, so the frame
+        // would swallow the first byte of the heartbeat behind it
+        let mut buf = v2_frame(9999, &[1, 2, 3], 0);
+        buf[1] += 1;
+        let heartbeat_at = buf.len();
+        buf.extend(heartbeat());
+        assert!(matches!(parse_frame(&buf), Parsed::Invalid));
+        assert_eq!(next_frame_start(&buf), heartbeat_at);
+    }
+}
@@ -0,0 +1,271 @@
+//! Live data sources that feed named streams on the [`StreamHub`](crate::stream_hub::StreamHub).
+//!
+//! Sources are declared in a YAML file (see `config/sources.example.yaml`) and each one
+//! publishes the `RecordBatch`es it builds under its own stream name.
+
+pub mod ipc;
+#[cfg(feature = "mavlink")]
+pub mod mavlink;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod udp;
+
+use crate::stream_hub::HubHandle;
+use arrow::array::builder::{
+    BooleanBuilder, Float32Builder, Float64Builder, Int32Builder, Int64Builder, StringBuilder,
+    UInt32Builder, UInt64Builder,
+};
+use arrow::array::ArrayRef;
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::error::ArrowError;
+use arrow::record_batch::RecordBatch;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Column types that can be declared for ingested data
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum FieldType {
+    Boolean,
+    Int32,
+    Int64,
+    UInt32,
+    UInt64,
+    Float32,
+    Float64,
+    Utf8,
+}
+
+impl FieldType {
+    pub fn arrow_type(&self) -> DataType {
+        match self {
+            FieldType::Boolean => DataType::Boolean,
+            FieldType::Int32 => DataType::Int32,
+            FieldType::Int64 => DataType::Int64,
+            FieldType::UInt32 => DataType::UInt32,
+            FieldType::UInt64 => DataType::UInt64,
+            FieldType::Float32 => DataType::Float32,
+            FieldType::Float64 => DataType::Float64,
+            FieldType::Utf8 => DataType::Utf8,
+        }
+    }
+
+    /// Width in bytes when packed in a fixed binary layout, `None` for variable-width types
+    pub fn packed_width(&self) -> Option<usize> {
+        match self {
+            FieldType::Boolean => Some(1),
+            FieldType::Int32 | FieldType::UInt32 | FieldType::Float32 => Some(4),
+            FieldType::Int64 | FieldType::UInt64 | FieldType::Float64 => Some(8),
+            FieldType::Utf8 => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldSpec {
+    pub name: String,
+    pub data_type: FieldType,
+}
+
+/// When to cut a batch: whichever of the two limits is hit first
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BatchingParams {
+    pub max_rows: usize,        // flush once this many rows are buffered
+    pub max_latency_ms: u64,    // flush at least this often while rows are pending
+}
+
+impl Default for BatchingParams {
+    fn default() -> Self {
+        Self {
+            max_rows: 100,
+            max_latency_ms: 50,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct SourcesConfig {
+    #[serde(default)]
+    pub udp: Vec<udp::UdpSourceConfig>,
+    #[serde(default)]
+    pub ipc: Vec<ipc::IpcSourceConfig>,
+    #[cfg(feature = "mqtt")]
+    #[serde(default)]
+    pub mqtt: Vec<mqtt::MqttSourceConfig>,
+    #[cfg(feature = "mavlink")]
+    #[serde(default)]
+    pub mavlink: Vec<mavlink::MavlinkSourceConfig>,
+}
+
+impl SourcesConfig {
+    pub fn load_from_yaml(file_path: &str) -> Option<Self> {
+        if !Path::new(file_path).exists() {
+            info!("Sources file {} does not exist. No live sources configured.", file_path);
+            return None;
+        }
+
+        let contents = match std::fs::read_to_string(file_path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                warn!("Failed to read sources file: {}", e);
+                return None;
+            }
+        };
+
+        match serde_yaml::from_str(&contents) {
+            Ok(config) => {
+                info!("Sources loaded from {}", file_path);
+                Some(config)
+            },
+            Err(e) => {
+                warn!("Failed to parse sources from YAML: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// Start a task for every configured source
+pub fn spawn_sources(config: SourcesConfig, hub: HubHandle) {
+    for source in config.udp {
+        info!("Starting UDP source '{}' on {}", source.name, source.bind);
+        tokio::spawn(udp::run_udp_source(source, hub.clone()));
+    }
+
+    for source in config.ipc {
+        info!("Starting Arrow IPC source '{}' on {:?}", source.name, source.input);
+        tokio::spawn(ipc::run_ipc_source(source, hub.clone()));
+    }
+
+    #[cfg(feature = "mqtt")]
+    for source in config.mqtt {
+        info!("Starting MQTT source for {}:{}", source.broker, source.port);
+        tokio::spawn(mqtt::run_mqtt_source(source, hub.clone()));
+    }
+
+    #[cfg(feature = "mavlink")]
+    for source in config.mavlink {
+        info!("Starting MAVLink source on {}", source.bind);
+        tokio::spawn(mavlink::run_mavlink_source(source, hub.clone()));
+    }
+}
+
+enum ColumnBuilder {
+    Boolean(BooleanBuilder),
+    Int32(Int32Builder),
+    Int64(Int64Builder),
+    UInt32(UInt32Builder),
+    UInt64(UInt64Builder),
+    Float32(Float32Builder),
+    Float64(Float64Builder),
+    Utf8(StringBuilder),
+}
+
+impl ColumnBuilder {
+    fn new(data_type: FieldType) -> Self {
+        match data_type {
+            FieldType::Boolean => ColumnBuilder::Boolean(BooleanBuilder::new()),
+            FieldType::Int32 => ColumnBuilder::Int32(Int32Builder::new()),
+            FieldType::Int64 => ColumnBuilder::Int64(Int64Builder::new()),
+            FieldType::UInt32 => ColumnBuilder::UInt32(UInt32Builder::new()),
+            FieldType::UInt64 => ColumnBuilder::UInt64(UInt64Builder::new()),
+            FieldType::Float32 => ColumnBuilder::Float32(Float32Builder::new()),
+            FieldType::Float64 => ColumnBuilder::Float64(Float64Builder::new()),
+            FieldType::Utf8 => ColumnBuilder::Utf8(StringBuilder::new()),
+        }
+    }
+
+    /// Append a JSON value, storing a null when it is missing or has the wrong shape
+    fn append(&mut self, value: Option<&Value>) {
+        match self {
+            ColumnBuilder::Boolean(b) => b.append_option(value.and_then(Value::as_bool)),
+            ColumnBuilder::Int32(b) => {
+                b.append_option(value.and_then(Value::as_i64).and_then(|v| i32::try_from(v).ok()))
+            },
+            ColumnBuilder::Int64(b) => b.append_option(value.and_then(Value::as_i64)),
+            ColumnBuilder::UInt32(b) => {
+                b.append_option(value.and_then(Value::as_u64).and_then(|v| u32::try_from(v).ok()))
+            },
+            ColumnBuilder::UInt64(b) => b.append_option(value.and_then(Value::as_u64)),
+            ColumnBuilder::Float32(b) => b.append_option(value.and_then(Value::as_f64).map(|v| v as f32)),
+            ColumnBuilder::Float64(b) => b.append_option(value.and_then(Value::as_f64)),
+            ColumnBuilder::Utf8(b) => match value {
+                Some(Value::String(s)) => b.append_value(s),
+                Some(Value::Null) | None => b.append_null(),
+                Some(other) => b.append_value(other.to_string()),
+            },
+        }
+    }
+
+    fn finish(&mut self) -> ArrayRef {
+        match self {
+            ColumnBuilder::Boolean(b) => Arc::new(b.finish()),
+            ColumnBuilder::Int32(b) => Arc::new(b.finish()),
+            ColumnBuilder::Int64(b) => Arc::new(b.finish()),
+            ColumnBuilder::UInt32(b) => Arc::new(b.finish()),
+            ColumnBuilder::UInt64(b) => Arc::new(b.finish()),
+            ColumnBuilder::Float32(b) => Arc::new(b.finish()),
+            ColumnBuilder::Float64(b) => Arc::new(b.finish()),
+            ColumnBuilder::Utf8(b) => Arc::new(b.finish()),
+        }
+    }
+}
+
+/// Buffers decoded rows column-wise until they are cut into a `RecordBatch`
+pub struct RowAccumulator {
+    schema: SchemaRef,
+    fields: Vec<FieldSpec>,
+    columns: Vec<ColumnBuilder>,
+    rows: usize,
+}
+
+impl RowAccumulator {
+    pub fn new(fields: &[FieldSpec]) -> Self {
+        let schema = Schema::new(
+            fields
+                .iter()
+                .map(|f| Field::new(&f.name, f.data_type.arrow_type(), true))
+                .collect::<Vec<_>>(),
+        );
+
+        Self {
+            schema: Arc::new(schema),
+            fields: fields.to_vec(),
+            columns: fields.iter().map(|f| ColumnBuilder::new(f.data_type)).collect(),
+            rows: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows == 0
+    }
+
+    /// Append a row given as a JSON object keyed by field name
+    pub fn push_object(&mut self, object: &Map<String, Value>) {
+        for (spec, column) in self.fields.iter().zip(self.columns.iter_mut()) {
+            column.append(object.get(&spec.name));
+        }
+        self.rows += 1;
+    }
+
+    /// Append a row given as values in field order
+    pub fn push_row(&mut self, values: &[Value]) {
+        for (i, column) in self.columns.iter_mut().enumerate() {
+            column.append(values.get(i));
+        }
+        self.rows += 1;
+    }
+
+    /// Cut everything buffered so far into a batch and start over
+    pub fn finish(&mut self) -> Result<RecordBatch, ArrowError> {
+        let arrays: Vec<ArrayRef> = self.columns.iter_mut().map(ColumnBuilder::finish).collect();
+        self.rows = 0;
+        RecordBatch::try_new(self.schema.clone(), arrays)
+    }
+}
@@ -0,0 +1,286 @@
+use super::{BatchingParams, FieldSpec, FieldType, RowAccumulator};
+use crate::stream_hub::HubHandle;
+use log::{error, info, warn};
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MqttTopicConfig {
+    pub topic: String,                      // subscription filter, wildcards allowed
+    #[serde(default)]
+    pub stream: Option<String>,             // defaults to the topic filter itself
+    #[serde(default)]
+    pub fields: Option<Vec<FieldSpec>>,     // None = infer from the first payload
+    #[serde(default)]
+    pub batching: BatchingParams,
+}
+
+impl MqttTopicConfig {
+    fn stream_name(&self) -> &str {
+        self.stream.as_deref().unwrap_or(&self.topic)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MqttSourceConfig {
+    pub broker: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+    pub topics: Vec<MqttTopicConfig>,
+}
+
+fn default_port() -> u16 {
+    1883
+}
+
+fn default_client_id() -> String {
+    "waterman-bridge".to_string()
+}
+
+/// MQTT topic filter matching with `+` (one level) and `#` (all remaining levels)
+fn topic_matches(filter: &str, topic: &str) -> bool {
+    // Wildcards at the first level don't match the broker's `$SYS/...` topics
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+    let mut topic_levels = topic.split('/');
+    for filter_level in filter.split('/') {
+        match (filter_level, topic_levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {},
+            (expected, Some(level)) if expected == level => {},
+            _ => return false,
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+/// Pick a column type for every key of the first object seen on a topic. Numbers are
+/// always `Float64`: a first value of `20` says nothing about whether `20.5` follows.
+fn infer_fields(object: &Map<String, Value>) -> Vec<FieldSpec> {
+    object
+        .iter()
+        .map(|(name, value)| {
+            let data_type = match value {
+                Value::Bool(_) => FieldType::Boolean,
+                Value::Number(_) => FieldType::Float64,
+                _ => FieldType::Utf8,
+            };
+            FieldSpec {
+                name: name.clone(),
+                data_type,
+            }
+        })
+        .collect()
+}
+
+/// Per-subscription batching state
+struct TopicState {
+    config: MqttTopicConfig,
+    accumulator: Option<RowAccumulator>,
+    last_flush: Instant,
+}
+
+impl TopicState {
+    fn push_payload(&mut self, payload: &[u8]) -> std::result::Result<(), String> {
+        let rows = match serde_json::from_slice::<Value>(payload) {
+            Ok(Value::Object(object)) => vec![object],
+            Ok(Value::Array(items)) => items
+                .into_iter()
+                .filter_map(|item| match item {
+                    Value::Object(object) => Some(object),
+                    _ => None,
+                })
+                .collect(),
+            Ok(other) => return Err(format!("expected a JSON object or array, got {}", other)),
+            Err(e) => return Err(format!("invalid JSON: {}", e)),
+        };
+
+        for row in rows {
+            let accumulator = self.accumulator.get_or_insert_with(|| {
+                let fields = self.config.fields.clone().unwrap_or_else(|| infer_fields(&row));
+                info!("MQTT stream '{}' using schema {:?}", self.config.stream_name(), fields);
+                RowAccumulator::new(&fields)
+            });
+            accumulator.push_object(&row);
+        }
+        Ok(())
+    }
+
+    fn flush_if_due(&mut self, hub: &HubHandle, force_check_latency: bool) {
+        let Some(accumulator) = self.accumulator.as_mut() else { return };
+        if accumulator.is_empty() {
+            self.last_flush = Instant::now();
+            return;
+        }
+
+        let full = accumulator.len() >= self.config.batching.max_rows;
+        let stale = force_check_latency
+            && self.last_flush.elapsed() >= Duration::from_millis(self.config.batching.max_latency_ms);
+        if !full && !stale {
+            return;
+        }
+
+        match accumulator.finish() {
+            Ok(batch) => {
+                hub.publish(self.config.stream_name(), batch);
+            },
+            Err(e) => error!("MQTT stream '{}' failed to build record batch: {}", self.config.stream_name(), e),
+        }
+        self.last_flush = Instant::now();
+    }
+}
+
+/// Drive the MQTT event loop, forwarding publishes and re-subscribing after every (re)connect
+async fn poll_broker(config: MqttSourceConfig, tx: mpsc::Sender<(String, Vec<u8>)>) {
+    let mut options = MqttOptions::new(&config.client_id, &config.broker, config.port);
+    options.set_keep_alive(Duration::from_secs(5));
+    let (client, mut event_loop) = AsyncClient::new(options, 64);
+
+    loop {
+        match event_loop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("MQTT connected to {}:{}", config.broker, config.port);
+                for topic in &config.topics {
+                    if let Err(e) = client.try_subscribe(&topic.topic, QoS::AtMostOnce) {
+                        error!("MQTT failed to subscribe to '{}': {}", topic.topic, e);
+                    }
+                }
+            },
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                if tx.send((publish.topic, publish.payload.to_vec())).await.is_err() {
+                    break;
+                }
+            },
+            Ok(_) => {},
+            Err(e) => {
+                // The event loop reconnects on the next poll
+                warn!("MQTT connection to {}:{} failed: {}", config.broker, config.port, e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+/// Subscribe to every configured topic and publish one stream per subscription
+pub async fn run_mqtt_source(config: MqttSourceConfig, hub: HubHandle) {
+    let (tx, mut rx) = mpsc::channel::<(String, Vec<u8>)>(1024);
+    tokio::spawn(poll_broker(config.clone(), tx));
+
+    let mut topics: Vec<TopicState> = config
+        .topics
+        .iter()
+        .map(|topic| TopicState {
+            config: topic.clone(),
+            accumulator: topic.fields.as_ref().map(|fields| RowAccumulator::new(fields)),
+            last_flush: Instant::now(),
+        })
+        .collect();
+
+    let tick_ms = config.topics.iter().map(|t| t.batching.max_latency_ms).min().unwrap_or(50).max(1);
+    let mut flush_timer = tokio::time::interval(Duration::from_millis(tick_ms));
+    flush_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    let mut message_count = 0u64;
+    let mut error_count = 0u64;
+    let mut last_log_time = Instant::now();
+
+    loop {
+        tokio::select! {
+            message = rx.recv() => {
+                let Some((topic_name, payload)) = message else { break };
+                message_count += 1;
+
+                // First matching subscription wins, like the order in the config file
+                let Some(topic) = topics.iter_mut().find(|t| topic_matches(&t.config.topic, &topic_name)) else {
+                    continue;
+                };
+                if let Err(e) = topic.push_payload(&payload) {
+                    warn!("MQTT dropped message on '{}': {}", topic_name, e);
+                    error_count += 1;
+                }
+                topic.flush_if_due(&hub, false);
+            },
+            _ = flush_timer.tick() => {
+                for topic in topics.iter_mut() {
+                    topic.flush_if_due(&hub, true);
+                }
+            },
+        }
+
+        if last_log_time.elapsed() > Duration::from_secs(5) {
+            info!("MQTT source {}:{} stats - Messages: {}, Errors: {}",
+                  config.broker, config.port, message_count, error_count);
+            last_log_time = Instant::now();
+        }
+    }
+
+    info!("MQTT source {}:{} ending", config.broker, config.port);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{Array, AsArray};
+
+    #[test]
+    fn matches_exact_topics() {
+        assert!(topic_matches("vehicle/imu", "vehicle/imu"));
+        assert!(!topic_matches("vehicle/imu", "vehicle/gps"));
+        assert!(!topic_matches("vehicle/imu", "vehicle/imu/raw"));
+        assert!(!topic_matches("vehicle/imu/raw", "vehicle/imu"));
+    }
+
+    #[test]
+    fn plus_matches_exactly_one_level() {
+        assert!(topic_matches("vehicle/+/temperature", "vehicle/motor1/temperature"));
+        assert!(topic_matches("+/imu", "vehicle/imu"));
+        assert!(topic_matches("vehicle/+", "vehicle/"));
+        assert!(!topic_matches("vehicle/+", "vehicle"));
+        assert!(!topic_matches("vehicle/+", "vehicle/motor1/temperature"));
+    }
+
+    #[test]
+    fn hash_matches_the_remaining_levels() {
+        assert!(topic_matches("#", "vehicle/imu"));
+        assert!(topic_matches("vehicle/#", "vehicle/imu/raw"));
+        assert!(topic_matches("vehicle/#", "vehicle"));
+        assert!(topic_matches("vehicle/+/#", "vehicle/motor1/temperature/max"));
+        assert!(!topic_matches("vehicle/#", "base/imu"));
+    }
+
+    #[test]
+    fn inferred_numbers_keep_their_fractions() {
+        let mut state = TopicState {
+            config: MqttTopicConfig {
+                topic: "vehicle/temperature".to_string(),
+                stream: None,
+                fields: None,
+                batching: BatchingParams::default(),
+            },
+            accumulator: None,
+            last_flush: Instant::now(),
+        };
+        state.push_payload(br#"{"celsius": 20, "sensor": "motor", "ok": true}"#).unwrap();
+        state.push_payload(br#"[{"celsius": 20.5, "sensor": 3, "ok": false}, {"celsius": -4}]"#).unwrap();
+
+        let batch = state.accumulator.as_mut().unwrap().finish().unwrap();
+        let celsius = batch.column_by_name("celsius").unwrap().as_primitive::<arrow::datatypes::Float64Type>();
+        assert_eq!(celsius.values().to_vec(), vec![20.0, 20.5, -4.0]);
+        assert_eq!(celsius.null_count(), 0);
+        assert_eq!(batch.column_by_name("ok").unwrap().data_type(), &arrow::datatypes::DataType::Boolean);
+        assert_eq!(batch.column_by_name("sensor").unwrap().data_type(), &arrow::datatypes::DataType::Utf8);
+    }
+
+    #[test]
+    fn leading_wildcards_skip_system_topics() {
+        assert!(!topic_matches("#", "$SYS/broker/uptime"));
+        assert!(!topic_matches("+/broker/uptime", "$SYS/broker/uptime"));
+        assert!(topic_matches("$SYS/#", "$SYS/broker/uptime"));
+    }
+}
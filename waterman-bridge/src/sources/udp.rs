@@ -0,0 +1,276 @@
+use super::{BatchingParams, FieldSpec, FieldType, RowAccumulator};
+use crate::stream_hub::HubHandle;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PacketFormat {
+    JsonLines,  // one JSON object per line, any number of lines per datagram
+    Binary,     // fields packed back to back in declaration order
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum ByteOrder {
+    #[default]
+    Little,
+    Big,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UdpSourceConfig {
+    pub name: String,                   // stream the batches are published under
+    pub bind: String,                   // e.g. 0.0.0.0:14600
+    pub format: PacketFormat,
+    #[serde(default)]
+    pub byte_order: ByteOrder,          // only used by the binary format
+    pub fields: Vec<FieldSpec>,
+    #[serde(default)]
+    pub batching: BatchingParams,
+}
+
+/// Size of one record in the binary layout, or `None` if a field cannot be packed
+fn record_size(fields: &[FieldSpec]) -> Option<usize> {
+    fields.iter().map(|f| f.data_type.packed_width()).sum()
+}
+
+fn decode_value(bytes: &[u8], data_type: FieldType, byte_order: ByteOrder) -> Value {
+    macro_rules! read {
+        ($ty:ty) => {{
+            let raw: [u8; std::mem::size_of::<$ty>()] = bytes.try_into().unwrap();
+            match byte_order {
+                ByteOrder::Little => <$ty>::from_le_bytes(raw),
+                ByteOrder::Big => <$ty>::from_be_bytes(raw),
+            }
+        }};
+    }
+
+    match data_type {
+        FieldType::Boolean => Value::from(bytes[0] != 0),
+        FieldType::Int32 => Value::from(read!(i32)),
+        FieldType::Int64 => Value::from(read!(i64)),
+        FieldType::UInt32 => Value::from(read!(u32)),
+        FieldType::UInt64 => Value::from(read!(u64)),
+        FieldType::Float32 => Value::from(read!(f32)),
+        FieldType::Float64 => Value::from(read!(f64)),
+        FieldType::Utf8 => Value::Null,
+    }
+}
+
+/// Decode a datagram into the accumulator, returning the number of rows added
+fn decode_packet(
+    packet: &[u8],
+    config: &UdpSourceConfig,
+    accumulator: &mut RowAccumulator,
+) -> std::result::Result<usize, String> {
+    match config.format {
+        PacketFormat::JsonLines => {
+            let text = std::str::from_utf8(packet).map_err(|e| format!("invalid UTF-8: {}", e))?;
+            // Parse every line before taking any, so a bad line drops the whole datagram
+            let mut objects = Vec::new();
+            for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+                match serde_json::from_str::<Value>(line) {
+                    Ok(Value::Object(object)) => objects.push(object),
+                    Ok(other) => return Err(format!("expected a JSON object, got {}", other)),
+                    Err(e) => return Err(format!("invalid JSON: {}", e)),
+                }
+            }
+            for object in &objects {
+                accumulator.push_object(object);
+            }
+            Ok(objects.len())
+        },
+        PacketFormat::Binary => {
+            let size = record_size(&config.fields).ok_or("binary layout contains a Utf8 field")?;
+            if size == 0 || !packet.len().is_multiple_of(size) {
+                return Err(format!(
+                    "packet of {} bytes is not a multiple of the {} byte record size",
+                    packet.len(), size
+                ));
+            }
+
+            for record in packet.chunks_exact(size) {
+                let mut offset = 0;
+                let values: Vec<Value> = config.fields.iter().map(|field| {
+                    let width = field.data_type.packed_width().unwrap_or(0);
+                    let value = decode_value(&record[offset..offset + width], field.data_type, config.byte_order);
+                    offset += width;
+                    value
+                }).collect();
+                accumulator.push_row(&values);
+            }
+            Ok(packet.len() / size)
+        },
+    }
+}
+
+fn flush(config: &UdpSourceConfig, accumulator: &mut RowAccumulator, hub: &HubHandle) {
+    if accumulator.is_empty() {
+        return;
+    }
+
+    match accumulator.finish() {
+        Ok(batch) => {
+            hub.publish(&config.name, batch);
+        },
+        Err(e) => error!("UDP source '{}' failed to build record batch: {}", config.name, e),
+    }
+}
+
+/// Receive datagrams until the socket fails, publishing batches to `config.name`
+pub async fn run_udp_source(config: UdpSourceConfig, hub: HubHandle) {
+    if config.format == PacketFormat::Binary && record_size(&config.fields).is_none() {
+        error!("UDP source '{}': Utf8 fields are not supported in the binary format", config.name);
+        return;
+    }
+
+    let socket = match UdpSocket::bind(&config.bind).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("UDP source '{}' failed to bind {}: {}", config.name, config.bind, e);
+            return;
+        }
+    };
+
+    let mut accumulator = RowAccumulator::new(&config.fields);
+    let mut buffer = vec![0u8; 65536];
+    let mut flush_timer = tokio::time::interval(Duration::from_millis(config.batching.max_latency_ms.max(1)));
+    flush_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    let mut packet_count = 0u64;
+    let mut error_count = 0u64;
+    let mut last_log_time = Instant::now();
+
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buffer) => {
+                let (len, peer) = match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        error!("UDP source '{}' receive error: {}", config.name, e);
+                        break;
+                    }
+                };
+
+                packet_count += 1;
+                if let Err(e) = decode_packet(&buffer[..len], &config, &mut accumulator) {
+                    warn!("UDP source '{}' dropped packet from {}: {}", config.name, peer, e);
+                    error_count += 1;
+                }
+
+                if accumulator.len() >= config.batching.max_rows {
+                    flush(&config, &mut accumulator, &hub);
+                }
+            },
+            _ = flush_timer.tick() => {
+                flush(&config, &mut accumulator, &hub);
+            },
+        }
+
+        if last_log_time.elapsed() > Duration::from_secs(5) {
+            info!("UDP source '{}' stats - Packets: {}, Errors: {}", config.name, packet_count, error_count);
+            last_log_time = Instant::now();
+        }
+    }
+
+    info!("UDP source '{}' ending", config.name);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{Float64Array, UInt32Array};
+
+    fn config(format: PacketFormat, byte_order: ByteOrder) -> UdpSourceConfig {
+        UdpSourceConfig {
+            name: "vehicle".to_string(),
+            bind: "127.0.0.1:0".to_string(),
+            format,
+            byte_order,
+            fields: vec![
+                FieldSpec { name: "seq".to_string(), data_type: FieldType::UInt32 },
+                FieldSpec { name: "altitude".to_string(), data_type: FieldType::Float64 },
+            ],
+            batching: BatchingParams::default(),
+        }
+    }
+
+    fn columns(accumulator: &mut RowAccumulator) -> (Vec<u32>, Vec<f64>) {
+        let batch = accumulator.finish().unwrap();
+        let seq = batch.column(0).as_any().downcast_ref::<UInt32Array>().unwrap();
+        let altitude = batch.column(1).as_any().downcast_ref::<Float64Array>().unwrap();
+        (seq.values().to_vec(), altitude.values().to_vec())
+    }
+
+    #[test]
+    fn decodes_json_lines() {
+        let config = config(PacketFormat::JsonLines, ByteOrder::Little);
+        let mut accumulator = RowAccumulator::new(&config.fields);
+        let packet = b"{\"seq\": 1, \"altitude\": 10.5}\n\n{\"seq\": 2, \"altitude\": 11.0, \"extra\": true}\n";
+        assert_eq!(decode_packet(packet, &config, &mut accumulator), Ok(2));
+        assert_eq!(columns(&mut accumulator), (vec![1, 2], vec![10.5, 11.0]));
+    }
+
+    #[test]
+    fn rejects_bad_json_lines() {
+        let config = config(PacketFormat::JsonLines, ByteOrder::Little);
+        let mut accumulator = RowAccumulator::new(&config.fields);
+        assert!(decode_packet(b"{\"seq\": ", &config, &mut accumulator).unwrap_err().starts_with("invalid JSON"));
+        assert!(decode_packet(b"[1, 2]", &config, &mut accumulator).unwrap_err().starts_with("expected a JSON object"));
+        assert!(decode_packet(&[0xFF, 0xFE], &config, &mut accumulator).unwrap_err().starts_with("invalid UTF-8"));
+    }
+
+    #[test]
+    fn bad_line_drops_the_whole_datagram() {
+        let config = config(PacketFormat::JsonLines, ByteOrder::Little);
+        let mut accumulator = RowAccumulator::new(&config.fields);
+        let packet = b"{\"seq\": 1, \"altitude\": 10.5}\n{\"seq\": \n{\"seq\": 3, \"altitude\": 12.0}\n";
+        assert!(decode_packet(packet, &config, &mut accumulator).unwrap_err().starts_with("invalid JSON"));
+        assert!(accumulator.is_empty());
+
+        assert_eq!(decode_packet(b"{\"seq\": 4, \"altitude\": 1.0}", &config, &mut accumulator), Ok(1));
+        assert_eq!(columns(&mut accumulator), (vec![4], vec![1.0]));
+    }
+
+    #[test]
+    fn decodes_binary_records_in_either_byte_order() {
+        for byte_order in [ByteOrder::Little, ByteOrder::Big] {
+            let config = config(PacketFormat::Binary, byte_order);
+            let mut packet = Vec::new();
+            for (seq, altitude) in [(7u32, 1.25f64), (8, -3.5)] {
+                match byte_order {
+                    ByteOrder::Little => {
+                        packet.extend(seq.to_le_bytes());
+                        packet.extend(altitude.to_le_bytes());
+                    },
+                    ByteOrder::Big => {
+                        packet.extend(seq.to_be_bytes());
+                        packet.extend(altitude.to_be_bytes());
+                    },
+                }
+            }
+            let mut accumulator = RowAccumulator::new(&config.fields);
+            assert_eq!(decode_packet(&packet, &config, &mut accumulator), Ok(2));
+            assert_eq!(columns(&mut accumulator), (vec![7, 8], vec![1.25, -3.5]));
+        }
+    }
+
+    #[test]
+    fn rejects_partial_binary_records() {
+        let config = config(PacketFormat::Binary, ByteOrder::Little);
+        let mut accumulator = RowAccumulator::new(&config.fields);
+        let error = decode_packet(&[0; 13], &config, &mut accumulator).unwrap_err();
+        assert!(error.contains("not a multiple of the 12 byte record size"), "{}", error);
+        assert!(accumulator.is_empty());
+    }
+
+    #[test]
+    fn binary_layout_cannot_hold_strings() {
+        let mut config = config(PacketFormat::Binary, ByteOrder::Little);
+        config.fields.push(FieldSpec { name: "mode".to_string(), data_type: FieldType::Utf8 });
+        let mut accumulator = RowAccumulator::new(&config.fields);
+        assert!(decode_packet(&[0; 12], &config, &mut accumulator).is_err());
+    }
+}
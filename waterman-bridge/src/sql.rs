@@ -0,0 +1,279 @@
+//! Ad hoc SQL over what the bridge streams, answered by DataFusion. Every stream
+//! is a table: `synthetic` (or `live.synthetic`) is its in-memory history,
+//! `recorded.synthetic` its Parquet recordings. Queries are read-only; DDL, DML
+//! and `SET`-style statements are refused.
+
+use crate::recorder;
+use crate::stream_hub::HubHandle;
+use arrow::datatypes::{Schema, SchemaRef};
+use arrow::record_batch::{RecordBatch, RecordBatchReader};
+use async_trait::async_trait;
+use datafusion::catalog::{SchemaProvider, TableProvider};
+use datafusion::datasource::MemTable;
+use datafusion::datasource::file_format::parquet::ParquetFormat;
+use datafusion::datasource::listing::{ListingOptions, ListingTable, ListingTableConfig, ListingTableUrl};
+use datafusion::error::DataFusionError;
+use datafusion::execution::context::{SQLOptions, SessionConfig, SessionContext};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub type Result<T> = std::result::Result<T, String>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SqlRequest {
+    pub sql: String,
+}
+
+/// Schema without metadata; the bridge stamps per-batch keys that would keep batches from concatenating
+fn plain(schema: &SchemaRef) -> SchemaRef {
+    Arc::new(Schema::new(schema.fields().clone()))
+}
+
+/// A DataFusion session without tables. Identifiers are case-sensitive, as stream and column names are.
+pub fn session() -> SessionContext {
+    let config = SessionConfig::new().set_bool("datafusion.sql_parser.enable_ident_normalization", false);
+    SessionContext::new_with_config(config)
+}
+
+/// The in-memory history of every hub stream
+#[derive(Debug)]
+struct LiveSchema {
+    hub: HubHandle,
+}
+
+#[async_trait]
+impl SchemaProvider for LiveSchema {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn table_names(&self) -> Vec<String> {
+        self.hub.stream_names()
+    }
+
+    async fn table(&self, name: &str) -> std::result::Result<Option<Arc<dyn TableProvider>>, DataFusionError> {
+        if !self.table_exist(name) {
+            return Ok(None);
+        }
+        let history = self.hub.history(name);
+        let Some(latest) = history.last() else {
+            return Err(DataFusionError::Plan(format!("Table '{}' has no data yet", name)));
+        };
+
+        // A table has the stream's current layout; data from before a schema change is left out
+        let schema = plain(&latest.schema());
+        let batches = history
+            .iter()
+            .filter(|batch| batch.schema().fields() == schema.fields())
+            .map(|batch| batch.clone().with_schema(schema.clone()))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(Some(Arc::new(MemTable::try_new(schema, vec![batches])?)))
+    }
+
+    fn table_exist(&self, name: &str) -> bool {
+        self.hub.stream_names().iter().any(|stream| stream == name)
+    }
+}
+
+/// The Parquet recordings of every stream under the recordings directory
+#[derive(Debug)]
+struct RecordedSchema {
+    directory: PathBuf,
+}
+
+/// Readable recordings of `stream` with the schema of the latest one
+fn current_recordings(directory: &Path, stream: &str) -> Option<(SchemaRef, Vec<PathBuf>)> {
+    let mut files = Vec::new();
+    for path in recorder::recorded_files(directory, stream) {
+        // The file of a recording still in progress has no footer yet
+        match recorder::open_recording(&path) {
+            Ok(reader) => files.push((plain(&reader.schema()), path)),
+            Err(e) => debug!("Skipping {} for SQL query: {}", path.display(), e),
+        }
+    }
+
+    let schema = files.last()?.0.clone();
+    let paths = files.into_iter().filter(|(s, _)| *s == schema).map(|(_, path)| path).collect();
+    Some((schema, paths))
+}
+
+#[async_trait]
+impl SchemaProvider for RecordedSchema {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn table_names(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    async fn table(&self, name: &str) -> std::result::Result<Option<Arc<dyn TableProvider>>, DataFusionError> {
+        let (directory, stream) = (self.directory.clone(), name.to_string());
+        let found = tokio::task::spawn_blocking(move || current_recordings(&directory, &stream))
+            .await
+            .map_err(|e| DataFusionError::External(Box::new(e)))?;
+        let Some((schema, paths)) = found else {
+            return Ok(None);
+        };
+
+        let urls = paths
+            .iter()
+            .map(|path| ListingTableUrl::parse(path.to_string_lossy()))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let config = ListingTableConfig::new_with_multi_paths(urls)
+            .with_schema(schema)
+            .with_listing_options(ListingOptions::new(Arc::new(ParquetFormat::default())));
+        Ok(Some(Arc::new(ListingTable::try_new(config)?)))
+    }
+
+    fn table_exist(&self, name: &str) -> bool {
+        !recorder::recorded_files(&self.directory, name).is_empty()
+    }
+}
+
+/// Run one read-only SQL statement. The result has at least one batch, so it always carries the schema.
+pub async fn execute(hub: HubHandle, recordings_dir: &Path, sql: &str) -> Result<Vec<RecordBatch>> {
+    let context = session();
+    let catalog = context.catalog("datafusion").ok_or("Missing default catalog")?;
+    let live = Arc::new(LiveSchema { hub });
+    let recorded = Arc::new(RecordedSchema { directory: recordings_dir.to_path_buf() });
+    for (name, schema) in [("public", live.clone() as Arc<dyn SchemaProvider>), ("live", live), ("recorded", recorded)] {
+        catalog.register_schema(name, schema).map_err(|e| e.to_string())?;
+    }
+
+    let options = SQLOptions::new().with_allow_ddl(false).with_allow_dml(false).with_allow_statements(false);
+    let frame = context.sql_with_options(sql, options).await.map_err(|e| e.to_string())?;
+    let schema: SchemaRef = Arc::new(frame.schema().as_arrow().clone());
+    let mut batches: Vec<RecordBatch> = frame
+        .collect()
+        .await
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .filter(|batch| batch.num_rows() > 0)
+        .collect();
+    if batches.is_empty() {
+        batches.push(RecordBatch::new_empty(schema));
+    }
+
+    let rows: usize = batches.iter().map(|b| b.num_rows()).sum();
+    info!("SQL query returned {} rows: {}", rows, sql);
+    Ok(batches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream_hub::StreamHub;
+    use arrow::array::{ArrayRef, AsArray, Float64Array, Int64Array};
+    use arrow::datatypes::{Float64Type, Int64Type};
+    use parquet::arrow::ArrowWriter;
+    use std::fs::File;
+
+    fn batch(sequence: Vec<i64>, value: Vec<f64>) -> RecordBatch {
+        RecordBatch::try_from_iter(vec![
+            ("sequence", Arc::new(Int64Array::from(sequence)) as ArrayRef),
+            ("value", Arc::new(Float64Array::from(value)) as ArrayRef),
+        ])
+        .unwrap()
+    }
+
+    fn hub() -> HubHandle {
+        let hub = Arc::new(StreamHub::new());
+        hub.publish("synthetic", batch(vec![1, 2, 3], vec![0.5, -1.0, 2.0]));
+        hub.publish("synthetic", batch(vec![4, 5], vec![3.0, -0.5]));
+        hub
+    }
+
+    async fn query(hub: HubHandle, directory: &Path, sql: &str) -> Result<RecordBatch> {
+        let batches = execute(hub, directory, sql).await?;
+        Ok(arrow::compute::concat_batches(&batches[0].schema(), &batches).unwrap())
+    }
+
+    fn int64(batch: &RecordBatch, column: usize) -> Vec<i64> {
+        batch.column(column).as_primitive::<Int64Type>().values().to_vec()
+    }
+
+    #[tokio::test]
+    async fn queries_live_history() {
+        let none = Path::new("/nonexistent");
+        let result = query(hub(), none, "SELECT sequence FROM synthetic WHERE value > 0 ORDER BY sequence DESC").await.unwrap();
+        assert_eq!(int64(&result, 0), vec![4, 3, 1]);
+
+        let result = query(hub(), none, "SELECT sequence FROM live.synthetic ORDER BY sequence LIMIT 2 OFFSET 1").await.unwrap();
+        assert_eq!(int64(&result, 0), vec![2, 3]);
+
+        let sql = "SELECT value > 0 AS positive, count(*) AS n, sum(value) AS total FROM synthetic GROUP BY value > 0 ORDER BY positive";
+        let result = query(hub(), none, sql).await.unwrap();
+        assert_eq!(int64(&result, 1), vec![2, 3]);
+        assert_eq!(result.column(2).as_primitive::<Float64Type>().values().to_vec(), vec![-1.5, 5.5]);
+    }
+
+    #[tokio::test]
+    async fn live_tables_keep_the_latest_schema() {
+        let hub = hub();
+        let changed = RecordBatch::try_from_iter(vec![("sequence", Arc::new(Int64Array::from(vec![6])) as ArrayRef)]).unwrap();
+        hub.publish("synthetic", changed);
+
+        let result = query(hub, Path::new("/nonexistent"), "SELECT * FROM synthetic").await.unwrap();
+        assert_eq!(result.num_columns(), 1);
+        assert_eq!(int64(&result, 0), vec![6]);
+    }
+
+    #[tokio::test]
+    async fn empty_results_carry_the_schema() {
+        let batches = execute(hub(), Path::new("/nonexistent"), "SELECT sequence FROM synthetic WHERE value > 100").await.unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].num_rows(), 0);
+        assert_eq!(batches[0].schema().field(0).name(), "sequence");
+    }
+
+    #[tokio::test]
+    async fn queries_recordings() {
+        let root = std::env::temp_dir().join(format!("wmsql-test-{}", std::process::id()));
+        let session = root.join("20260101-000000");
+        std::fs::create_dir_all(&session).unwrap();
+        for (index, part) in [batch(vec![1, 2], vec![1.0, 2.0]), batch(vec![3], vec![3.0])].iter().enumerate() {
+            let file = File::create(session.join(format!("synthetic-{:04}.parquet", index))).unwrap();
+            let mut writer = ArrowWriter::try_new(file, part.schema(), None).unwrap();
+            writer.write(part).unwrap();
+            writer.close().unwrap();
+        }
+        // A recording still in progress has no footer and is skipped
+        std::fs::write(session.join("synthetic-0002.parquet"), b"PAR1").unwrap();
+
+        let result = query(hub(), &root, "SELECT count(*), max(sequence) FROM recorded.synthetic").await;
+        std::fs::remove_dir_all(&root).unwrap();
+        let result = result.unwrap();
+        assert_eq!(int64(&result, 0), vec![3]);
+        assert_eq!(int64(&result, 1), vec![3]);
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_tables_and_bad_syntax() {
+        let none = Path::new("/nonexistent");
+        assert!(query(hub(), none, "SELECT * FROM nothing").await.is_err());
+        assert!(query(hub(), none, "SELECT * FROM recorded.synthetic").await.is_err());
+        assert!(query(hub(), none, "SELECT * FROM Synthetic").await.is_err());
+        assert!(query(hub(), none, "SELEC * FROM synthetic").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn refuses_writes() {
+        let root = std::env::temp_dir().join(format!("wmsql-copy-{}", std::process::id()));
+        let target = root.join("out.csv");
+        let statements = [
+            format!("COPY (SELECT * FROM synthetic) TO '{}'", target.display()),
+            format!("CREATE EXTERNAL TABLE t STORED AS CSV LOCATION '{}'", root.display()),
+            "CREATE TABLE t AS SELECT * FROM synthetic".to_string(),
+            "INSERT INTO synthetic VALUES (9, 9.0)".to_string(),
+            "SET datafusion.execution.batch_size = 1".to_string(),
+        ];
+        for sql in &statements {
+            assert!(query(hub(), &root, sql).await.is_err(), "{} was allowed", sql);
+        }
+        assert!(!target.exists());
+    }
+}
@@ -1,21 +1,50 @@
-use std::collections::BTreeMap;
-use crate::parameters::{Parameters, ParametersHandle};
+use crate::capture::{CaptureHandle, SessionCapture};
+use crate::encoding::{EncodingStats, EncodingStatsHandle};
+use crate::latency::{LatencyStats, LatencyStatsHandle};
+use crate::parameters::Parameters;
+use crate::recorder::{Recorder, RecorderHandle};
+use crate::runs::{RunManager, RunManagerHandle};
+use crate::schedule::{GeneratorRates, GeneratorRatesHandle};
+use crate::send_queue::{SendQueues, SendQueuesHandle};
+use crate::stream_hub::{HubHandle, StreamHub, SYNTHETIC_STREAM};
+use crate::topics::{TopicConfig, TopicSource};
 
-#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[derive(Debug, Clone)]
 pub struct WSBridgeState{
     pub t_ms: u64,
     pub last_t_ms: Option<u64>,
     pub parameters: Parameters,
+    pub hub: HubHandle,
+    pub recorder: RecorderHandle,
+    pub capture: CaptureHandle,
+    pub encoding_stats: EncodingStatsHandle,
+    pub latency_stats: LatencyStatsHandle,
+    pub runs: RunManagerHandle,
+    pub generator_rates: GeneratorRatesHandle,
+    pub send_queues: SendQueuesHandle,
+    pub replay_file: Option<String>,
+    pub topics: Vec<TopicConfig>,       // declared in topics.yaml, besides the synthetic topic
 }
 
 pub type StateHandle = std::sync::Arc<std::sync::Mutex<WSBridgeState>>;
 
 impl Default for WSBridgeState{
     fn default() -> Self{
+        let hub = std::sync::Arc::new(StreamHub::new());
         Self{
             t_ms: 0,
             last_t_ms: None,
             parameters: Parameters::default(),
+            recorder: std::sync::Arc::new(Recorder::new(hub.clone(), "recordings")),
+            capture: std::sync::Arc::new(SessionCapture::new("captures")),
+            encoding_stats: std::sync::Arc::new(EncodingStats::new()),
+            latency_stats: std::sync::Arc::new(LatencyStats::new()),
+            runs: std::sync::Arc::new(RunManager::new("runs")),
+            generator_rates: std::sync::Arc::new(GeneratorRates::new()),
+            send_queues: std::sync::Arc::new(SendQueues::new()),
+            replay_file: None,
+            topics: Vec::new(),
+            hub,
         }
     }
 }
@@ -25,7 +54,7 @@ impl WSBridgeState{
         Default::default()
     }
 
-    pub fn as_handle(self) -> StateHandle{
+    pub fn into_handle(self) -> StateHandle{
         std::sync::Arc::new(std::sync::Mutex::new(self))
     }
 
@@ -36,20 +65,78 @@ impl WSBridgeState{
     pub fn update_last_t(&mut self){
         self.last_t_ms = Some(self.t_ms);
     }
-    
+
     pub fn get_send_rate_hz(&self) -> f64 {
         self.parameters.get_data_rate_hz()
     }
-    
+
     pub fn set_send_rate_hz(&mut self, rate: f64) {
         self.parameters.data_volume.data_rate_hz = rate;
     }
-    
+
     pub fn get_parameters(&self) -> Parameters {
         self.parameters.clone()
     }
-    
+
     pub fn update_parameters(&mut self, params: Parameters) {
         self.parameters = params;
     }
+
+    /// Parameters of a generator topic; the synthetic topic uses the global parameters
+    pub fn topic_parameters(&self, topic: &str) -> Option<Parameters> {
+        if topic == SYNTHETIC_STREAM {
+            return Some(self.parameters.clone());
+        }
+        self.topics
+            .iter()
+            .find(|t| t.name == topic && t.source == TopicSource::Generator)
+            .map(|t| t.parameters.clone())
+    }
+
+    pub fn set_topic_parameters(&mut self, topic: &str, params: Parameters) -> Result<(), String> {
+        if topic == SYNTHETIC_STREAM {
+            self.parameters = params;
+            return Ok(());
+        }
+        match self.topics.iter_mut().find(|t| t.name == topic) {
+            Some(config) if config.source == TopicSource::Generator => {
+                config.parameters = params;
+                Ok(())
+            },
+            Some(_) => Err(format!("Topic '{}' is not a generator", topic)),
+            None => Err(format!("Unknown topic '{}'", topic)),
+        }
+    }
+
+    pub fn hub(&self) -> HubHandle {
+        self.hub.clone()
+    }
+
+    pub fn recorder(&self) -> RecorderHandle {
+        self.recorder.clone()
+    }
+
+    pub fn capture(&self) -> CaptureHandle {
+        self.capture.clone()
+    }
+
+    pub fn encoding_stats(&self) -> EncodingStatsHandle {
+        self.encoding_stats.clone()
+    }
+
+    pub fn latency_stats(&self) -> LatencyStatsHandle {
+        self.latency_stats.clone()
+    }
+
+    pub fn runs(&self) -> RunManagerHandle {
+        self.runs.clone()
+    }
+
+    pub fn generator_rates(&self) -> GeneratorRatesHandle {
+        self.generator_rates.clone()
+    }
+
+    pub fn send_queues(&self) -> SendQueuesHandle {
+        self.send_queues.clone()
+    }
 }
\ No newline at end of file
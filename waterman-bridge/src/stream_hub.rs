@@ -0,0 +1,296 @@
+use crate::ipc::{tag_batch, GENERATED_AT_METADATA_KEY};
+use crate::latency;
+use arrow::datatypes::SchemaRef;
+use arrow::record_batch::RecordBatch;
+use log::warn;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc};
+
+/// Name of the stream fed by the built-in synthetic data generator.
+/// This is what `/ws` serves when no `stream` query parameter is given.
+pub const SYNTHETIC_STREAM: &str = "synthetic";
+
+/// Number of batches a slow subscriber may fall behind before it starts
+/// losing the oldest ones.
+const STREAM_CAPACITY: usize = 512;
+
+/// A batch as seen by a tap: the stream it was published on and the batch itself
+pub type TappedBatch = (String, RecordBatch);
+
+/// How much recent data every stream keeps for late joiners.
+/// Whichever limit is hit first evicts the oldest batches; zero disables history.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HistoryLimits {
+    pub max_age: Duration,
+    pub max_bytes: usize,
+}
+
+impl Default for HistoryLimits {
+    fn default() -> Self {
+        Self {
+            max_age: Duration::from_secs(30),
+            max_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub published: Instant,
+    pub batch: RecordBatch,
+    bytes: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryStats {
+    pub batches: usize,
+    pub rows: usize,
+    pub bytes: usize,
+    pub span_ms: u64,
+}
+
+#[derive(Debug)]
+struct StreamEntry {
+    sender: broadcast::Sender<RecordBatch>,
+    schema: Option<SchemaRef>,      // of the most recently published batch
+    history: VecDeque<HistoryEntry>,
+    history_bytes: usize,
+}
+
+impl StreamEntry {
+    fn new() -> Self {
+        Self {
+            sender: broadcast::channel(STREAM_CAPACITY).0,
+            schema: None,
+            history: VecDeque::new(),
+            history_bytes: 0,
+        }
+    }
+
+    fn remember(&mut self, batch: &RecordBatch, limits: HistoryLimits) {
+        if limits.max_age.is_zero() || limits.max_bytes == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let bytes = batch.get_array_memory_size();
+        self.history.push_back(HistoryEntry {
+            published: now,
+            batch: batch.clone(),
+            bytes,
+        });
+        self.history_bytes += bytes;
+
+        while let Some(oldest) = self.history.front() {
+            let expired = now.duration_since(oldest.published) > limits.max_age;
+            if !expired && self.history_bytes <= limits.max_bytes {
+                break;
+            }
+            self.history_bytes -= oldest.bytes;
+            self.history.pop_front();
+        }
+    }
+}
+
+/// Fan-out point between data sources (generator, UDP ingest, ...) and
+/// WebSocket clients. Every stream is a named broadcast channel that is
+/// created lazily by whichever side touches it first, and keeps a ring
+/// buffer of its most recent batches for late joiners.
+///
+/// Taps see every batch on every stream, e.g. to record it. They do not count
+/// as subscribers, so they never keep the synthetic generator running.
+#[derive(Debug, Default)]
+pub struct StreamHub {
+    streams: Mutex<HashMap<String, StreamEntry>>,
+    taps: Mutex<Vec<mpsc::Sender<TappedBatch>>>,
+    history_limits: Mutex<HistoryLimits>,
+}
+
+pub type HubHandle = Arc<StreamHub>;
+
+impl StreamHub {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn set_history_limits(&self, limits: HistoryLimits) {
+        *self.history_limits.lock().unwrap() = limits;
+    }
+
+    /// Publish a batch to every current subscriber of `name`.
+    /// Returns the number of subscribers that received it.
+    pub fn publish(&self, name: &str, batch: RecordBatch) -> usize {
+        self.taps.lock().unwrap().retain(|tap| {
+            match tap.try_send((name.to_string(), batch.clone())) {
+                Ok(()) => true,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    warn!("Tap is falling behind, dropped a batch from '{}'", name);
+                    true
+                },
+                Err(mpsc::error::TrySendError::Closed(_)) => false,
+            }
+        });
+
+        let limits = *self.history_limits.lock().unwrap();
+        let mut streams = self.streams.lock().unwrap();
+        let entry = streams.entry(name.to_string()).or_insert_with(StreamEntry::new);
+        entry.remember(&batch, limits);
+        entry.schema = Some(batch.schema());
+        if entry.sender.receiver_count() == 0 {
+            return 0;
+        }
+
+        // A send error only means nobody is listening right now
+        let stamped = tag_batch(batch, GENERATED_AT_METADATA_KEY, &latency::unix_time_us().to_string());
+        entry.sender.send(stamped).unwrap_or(0)
+    }
+
+    /// Receive a copy of every batch published from now on, until the receiver is dropped
+    pub fn tap(&self, capacity: usize) -> mpsc::Receiver<TappedBatch> {
+        let (tx, rx) = mpsc::channel(capacity);
+        self.taps.lock().unwrap().push(tx);
+        rx
+    }
+
+    pub fn subscribe(&self, name: &str) -> broadcast::Receiver<RecordBatch> {
+        let mut streams = self.streams.lock().unwrap();
+        streams.entry(name.to_string()).or_insert_with(StreamEntry::new).sender.subscribe()
+    }
+
+    /// Subscribe and snapshot the stream's history in one step, so the
+    /// backfill ends exactly where the live feed begins
+    pub fn subscribe_with_backfill(&self, name: &str) -> (Vec<RecordBatch>, broadcast::Receiver<RecordBatch>) {
+        let mut streams = self.streams.lock().unwrap();
+        let entry = streams.entry(name.to_string()).or_insert_with(StreamEntry::new);
+        let backfill = entry.history.iter().map(|h| h.batch.clone()).collect();
+        (backfill, entry.sender.subscribe())
+    }
+
+    /// Batches currently retained for `name`, oldest first
+    pub fn history(&self, name: &str) -> Vec<RecordBatch> {
+        self.streams
+            .lock()
+            .unwrap()
+            .get(name)
+            .map(|entry| entry.history.iter().map(|h| h.batch.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn history_stats(&self) -> HashMap<String, HistoryStats> {
+        self.streams
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, entry)| {
+                let span_ms = match (entry.history.front(), entry.history.back()) {
+                    (Some(first), Some(last)) => last.published.duration_since(first.published).as_millis() as u64,
+                    _ => 0,
+                };
+                let stats = HistoryStats {
+                    batches: entry.history.len(),
+                    rows: entry.history.iter().map(|h| h.batch.num_rows()).sum(),
+                    bytes: entry.history_bytes,
+                    span_ms,
+                };
+                (name.clone(), stats)
+            })
+            .collect()
+    }
+
+    /// Schema of the last batch published on `name`, if any
+    pub fn schema(&self, name: &str) -> Option<SchemaRef> {
+        self.streams.lock().unwrap().get(name).and_then(|entry| entry.schema.clone())
+    }
+
+    pub fn receiver_count(&self, name: &str) -> usize {
+        self.streams
+            .lock()
+            .unwrap()
+            .get(name)
+            .map(|entry| entry.sender.receiver_count())
+            .unwrap_or(0)
+    }
+
+    pub fn stream_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.streams.lock().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{ArrayRef, AsArray, Int64Array};
+    use arrow::datatypes::Int64Type;
+
+    fn batch(sequence: i64) -> RecordBatch {
+        RecordBatch::try_from_iter(vec![("sequence", Arc::new(Int64Array::from(vec![sequence; 100])) as ArrayRef)]).unwrap()
+    }
+
+    fn sequences(batches: &[RecordBatch]) -> Vec<i64> {
+        batches.iter().map(|b| b.column(0).as_primitive::<Int64Type>().value(0)).collect()
+    }
+
+    #[test]
+    fn history_keeps_the_newest_batches_within_the_byte_limit() {
+        let hub = StreamHub::new();
+        let bytes = batch(0).get_array_memory_size();
+        hub.set_history_limits(HistoryLimits { max_age: Duration::from_secs(60), max_bytes: bytes * 3 });
+        for sequence in 0..10 {
+            hub.publish("stream", batch(sequence));
+        }
+
+        assert_eq!(sequences(&hub.history("stream")), vec![7, 8, 9]);
+        let stats = &hub.history_stats()["stream"];
+        assert_eq!((stats.batches, stats.rows, stats.bytes), (3, 300, bytes * 3));
+    }
+
+    #[test]
+    fn history_drops_batches_older_than_the_age_limit() {
+        let hub = StreamHub::new();
+        hub.set_history_limits(HistoryLimits { max_age: Duration::from_millis(50), max_bytes: usize::MAX });
+        hub.publish("stream", batch(0));
+        hub.publish("stream", batch(1));
+        std::thread::sleep(Duration::from_millis(80));
+        hub.publish("stream", batch(2));
+
+        assert_eq!(sequences(&hub.history("stream")), vec![2]);
+    }
+
+    #[test]
+    fn zero_limits_disable_history() {
+        let hub = StreamHub::new();
+        hub.set_history_limits(HistoryLimits { max_age: Duration::ZERO, max_bytes: usize::MAX });
+        hub.publish("stream", batch(0));
+        hub.set_history_limits(HistoryLimits { max_age: Duration::from_secs(60), max_bytes: 0 });
+        hub.publish("stream", batch(1));
+
+        assert!(hub.history("stream").is_empty());
+        assert_eq!(hub.history_stats()["stream"].bytes, 0);
+        assert!(hub.schema("stream").is_some());
+    }
+
+    #[test]
+    fn backfill_ends_where_the_live_feed_begins() {
+        let hub = StreamHub::new();
+        for sequence in 0..3 {
+            hub.publish("stream", batch(sequence));
+        }
+        let (backfill, mut live) = hub.subscribe_with_backfill("stream");
+        for sequence in 3..5 {
+            assert_eq!(hub.publish("stream", batch(sequence)), 1);
+        }
+
+        assert_eq!(sequences(&backfill), vec![0, 1, 2]);
+        let received = vec![live.try_recv().unwrap(), live.try_recv().unwrap()];
+        assert_eq!(sequences(&received), vec![3, 4]);
+        assert!(live.try_recv().is_err());
+        // Only the live feed carries the generation stamp
+        assert!(received[0].schema().metadata().contains_key(GENERATED_AT_METADATA_KEY));
+        assert!(!backfill[0].schema().metadata().contains_key(GENERATED_AT_METADATA_KEY));
+    }
+}
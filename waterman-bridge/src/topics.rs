@@ -0,0 +1,310 @@
+//! Named topics: streams the bridge produces itself (generators with their own
+//! `Parameters`, Parquet replays) next to the streams of the ingestion sources.
+
+use crate::data_gen::DataGenerator;
+use crate::parameters::Parameters;
+use crate::state::{StateHandle, WSBridgeState};
+use crate::stream_hub::{HubHandle, SYNTHETIC_STREAM};
+use arrow::datatypes::SchemaRef;
+use arrow::record_batch::RecordBatch;
+use log::{error, info, warn};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TopicSource {
+    Generator,  // synthetic data shaped by the topic's `parameters`
+    Replay,     // batches read back from a Parquet recording
+    Ingest,     // fed by one of the sources in sources.yaml
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReplayParams {
+    pub file: String,               // Parquet file, e.g. one written by the recorder
+    pub rows_per_batch: usize,
+    pub rate_hz: f64,               // batches per second
+    pub looped: bool,               // start over at the end of the file
+}
+
+impl Default for ReplayParams {
+    fn default() -> Self {
+        Self {
+            file: String::new(),
+            rows_per_batch: 100,
+            rate_hz: 10.0,
+            looped: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TopicConfig {
+    pub name: String,
+    pub source: TopicSource,
+    #[serde(default)]
+    pub parameters: Parameters,         // Generator topics only
+    #[serde(default)]
+    pub replay: Option<ReplayParams>,   // Replay topics only
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct TopicsConfig {
+    #[serde(default)]
+    pub topics: Vec<TopicConfig>,
+}
+
+impl TopicsConfig {
+    pub fn load_from_yaml(file_path: &str) -> Option<Self> {
+        if !Path::new(file_path).exists() {
+            info!("Topics file {} does not exist. Only the synthetic topic is served.", file_path);
+            return None;
+        }
+
+        let contents = match std::fs::read_to_string(file_path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                warn!("Failed to read topics file: {}", e);
+                return None;
+            }
+        };
+
+        match serde_yaml::from_str(&contents) {
+            Ok(config) => {
+                info!("Topics loaded from {}", file_path);
+                Some(config)
+            },
+            Err(e) => {
+                warn!("Failed to parse topics from YAML: {}", e);
+                None
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldInfo {
+    pub name: String,
+    pub data_type: String,
+    pub nullable: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TopicInfo {
+    pub name: String,
+    pub source: TopicSource,
+    pub parameters: Option<Parameters>,
+    pub replay: Option<ReplayParams>,
+    pub schema: Option<Vec<FieldInfo>>,     // None until the topic has produced data
+    pub subscribers: usize,
+}
+
+fn describe_schema(schema: &SchemaRef) -> Vec<FieldInfo> {
+    schema
+        .fields()
+        .iter()
+        .map(|field| FieldInfo {
+            name: field.name().clone(),
+            data_type: field.data_type().to_string(),
+            nullable: field.is_nullable(),
+        })
+        .collect()
+}
+
+/// Every declared topic plus every stream seen on the hub, sorted by name
+pub fn topic_names(state: &WSBridgeState) -> Vec<String> {
+    let mut names = state.hub().stream_names();
+    names.push(SYNTHETIC_STREAM.to_string());
+    names.extend(state.topics.iter().map(|t| t.name.clone()));
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Whether clients may subscribe to `name`
+pub fn is_topic(state: &WSBridgeState, name: &str) -> bool {
+    topic_names(state).iter().any(|topic| topic == name)
+}
+
+/// Schema of the last batch published on `name`. A generator's schema is known before it has run.
+pub fn topic_schema(state: &WSBridgeState, name: &str) -> Option<SchemaRef> {
+    state.hub().schema(name).or_else(|| {
+        let parameters = state.topic_parameters(name)?;
+        DataGenerator::with_parameters(parameters).generate_record_batch(0).ok().map(|b| b.schema())
+    })
+}
+
+pub fn list_topics(state: &StateHandle) -> Vec<TopicInfo> {
+    let state_guard = state.lock().unwrap();
+    let hub = state_guard.hub();
+
+    topic_names(&state_guard)
+        .into_iter()
+        .map(|name| {
+            let config = state_guard.topics.iter().find(|t| t.name == name);
+            let parameters = state_guard.topic_parameters(&name);
+            let source = match config {
+                Some(config) => config.source,
+                None if parameters.is_some() => TopicSource::Generator,
+                None => TopicSource::Ingest,
+            };
+
+            TopicInfo {
+                subscribers: hub.receiver_count(&name),
+                schema: topic_schema(&state_guard, &name).as_ref().map(describe_schema),
+                replay: config.and_then(|c| c.replay.clone()),
+                parameters,
+                source,
+                name,
+            }
+        })
+        .collect()
+}
+
+/// Read `params.file` on a blocking thread, looping if asked to. Returns once the
+/// receiver is gone or the file is done; a looped file without any rows is an error.
+fn read_replay_file(params: &ReplayParams, tx: mpsc::Sender<RecordBatch>) -> Result<(), String> {
+    loop {
+        let reader = File::open(&params.file)
+            .map_err(|e| e.to_string())
+            .and_then(|file| {
+                ParquetRecordBatchReaderBuilder::try_new(file)
+                    .and_then(|builder| builder.with_batch_size(params.rows_per_batch.max(1)).build())
+                    .map_err(|e| e.to_string())
+            })
+            .map_err(|e| format!("Failed to open replay file {}: {}", params.file, e))?;
+
+        let mut batches = 0;
+        for batch in reader {
+            let batch = batch.map_err(|e| format!("Failed to read replay file {}: {}", params.file, e))?;
+            if batch.num_rows() == 0 {
+                continue;
+            }
+            if tx.blocking_send(batch).is_err() {
+                return Ok(());
+            }
+            batches += 1;
+        }
+
+        if !params.looped {
+            info!("Replay of {} finished", params.file);
+            return Ok(());
+        }
+        if batches == 0 {
+            return Err(format!("Replay file {} has no rows to loop over", params.file));
+        }
+    }
+}
+
+/// Publish the batches of a Parquet file on `topic` at a fixed rate while anyone is subscribed
+pub async fn run_replay(topic: String, params: ReplayParams, hub: HubHandle) {
+    let (tx, mut rx) = mpsc::channel::<RecordBatch>(4);
+    let interval = Duration::from_secs_f64(1.0 / params.rate_hz.max(0.001));
+    info!("Replaying {} on topic '{}' at {} Hz", params.file, topic, params.rate_hz);
+    std::thread::spawn(move || {
+        if let Err(e) = read_replay_file(&params, tx) {
+            error!("{}", e);
+        }
+    });
+
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        if hub.receiver_count(&topic) == 0 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            continue;
+        }
+
+        ticker.tick().await;
+        let Some(batch) = rx.recv().await else { break };
+        hub.publish(&topic, batch);
+    }
+
+    info!("Replay task for topic '{}' ending", topic);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream_hub::StreamHub;
+    use arrow::array::{ArrayRef, AsArray, Int64Array};
+    use arrow::datatypes::Int64Type;
+    use parquet::arrow::ArrowWriter;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    fn write_parquet(name: &str, values: Vec<i64>) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("wmreplay-{}-{}.parquet", name, std::process::id()));
+        let batch = RecordBatch::try_from_iter(vec![("value", Arc::new(Int64Array::from(values)) as ArrayRef)]).unwrap();
+        let mut writer = ArrowWriter::try_new(File::create(&path).unwrap(), batch.schema(), None).unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+        path
+    }
+
+    fn params(path: &Path, looped: bool) -> ReplayParams {
+        ReplayParams {
+            file: path.display().to_string(),
+            rows_per_batch: 2,
+            rate_hz: 1000.0,
+            looped,
+        }
+    }
+
+    fn values(batch: &RecordBatch) -> Vec<i64> {
+        batch.column(0).as_primitive::<Int64Type>().values().to_vec()
+    }
+
+    #[test]
+    fn replays_a_file_in_batches_and_loops() {
+        let path = write_parquet("loop", vec![1, 2, 3]);
+
+        let (tx, mut rx) = mpsc::channel(8);
+        read_replay_file(&params(&path, false), tx).unwrap();
+        let once: Vec<Vec<i64>> = std::iter::from_fn(|| rx.blocking_recv()).map(|b| values(&b)).collect();
+        assert_eq!(once, vec![vec![1, 2], vec![3]]);
+
+        let (tx, mut rx) = mpsc::channel(1);
+        let reader = std::thread::spawn({
+            let params = params(&path, true);
+            move || read_replay_file(&params, tx)
+        });
+        let looped: Vec<Vec<i64>> = (0..4).map(|_| values(&rx.blocking_recv().unwrap())).collect();
+        drop(rx);
+        assert!(reader.join().unwrap().is_ok());
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(looped, vec![vec![1, 2], vec![3], vec![1, 2], vec![3]]);
+    }
+
+    #[test]
+    fn a_looped_empty_file_is_an_error() {
+        let path = write_parquet("empty", Vec::new());
+        let (tx, _rx) = mpsc::channel(1);
+        let result = read_replay_file(&params(&path, true), tx);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(result.is_err());
+        let (tx, _rx) = mpsc::channel(1);
+        assert!(read_replay_file(&params(Path::new("/nonexistent.parquet"), true), tx).is_err());
+    }
+
+    #[tokio::test]
+    async fn replay_topics_publish_while_subscribed() {
+        let path = write_parquet("topic", vec![1, 2, 3]);
+        let hub: HubHandle = Arc::new(StreamHub::new());
+        let mut feed = hub.subscribe("replayed");
+        let task = tokio::spawn(run_replay("replayed".to_string(), params(&path, false), hub.clone()));
+
+        let first = tokio::time::timeout(Duration::from_secs(5), feed.recv()).await.unwrap().unwrap();
+        let second = tokio::time::timeout(Duration::from_secs(5), feed.recv()).await.unwrap().unwrap();
+        tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!((values(&first), values(&second)), (vec![1, 2], vec![3]));
+    }
+}
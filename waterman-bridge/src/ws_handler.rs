@@ -1,201 +1,732 @@
+use crate::capture;
+use crate::paths;
 use crate::data_gen::DataGenerator;
+use crate::encoding::{self, Encoded, Encoding, Layout};
+use crate::expr::Filter;
+use crate::history::{self, RangeQuery};
+use crate::impairment::{Link, MAX_IN_FLIGHT};
+use crate::ipc::{tag_batch, BACKFILL_METADATA_KEY, CONNECTION_METADATA_KEY, QUERY_ID_METADATA_KEY, SENT_AT_METADATA_KEY, SEQUENCE_METADATA_KEY};
+use crate::latency::{self, Echo};
+use crate::mux::FrameHeader;
+use crate::parameters::OverflowPolicy;
+use crate::runs::{RunParams, RunTrigger};
+use crate::schedule::{self, MessageBuffer, RateMeter, Ticker};
+use crate::send_queue::QueueLimits;
+use crate::sql;
 use crate::state::StateHandle;
-use arrow::ipc::writer::FileWriter;
-use log::{debug, error, info, warn};
-use parquet::arrow::ArrowWriter;
-use std::collections::BTreeMap;
+use crate::stream_hub::SYNTHETIC_STREAM;
+use crate::topics;
+use log::{error, info, warn};
+use std::collections::{HashMap, VecDeque};
+use serde::Deserialize;
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
 use warp::ws::{Message, WebSocket};
 use warp::{Rejection, Reply};
 type Result<T> = std::result::Result<T, Rejection>;
-use futures::{FutureExt, SinkExt, StreamExt};
-use arrow::error::ArrowError;
+use futures::{SinkExt, StreamExt};
 use arrow::record_batch::RecordBatch;
 use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
 
-pub async fn ws_handler(ws: warp::ws::Ws, state: StateHandle) -> Result<impl Reply> {
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Id of a new `/ws` or `/mux` connection; they share the per-connection statistics
+pub fn next_connection_id() -> u64 {
+    NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+pub async fn ws_handler(
+    topic: warp::path::Tail,
+    ws: warp::ws::Ws,
+    mut query: HashMap<String, String>,
+    state: StateHandle,
+) -> Result<impl Reply> {
+    // `/ws/{topic}` is the same as `/ws?stream={topic}`
+    if !topic.as_str().is_empty() {
+        query.insert("stream".to_string(), topic.as_str().to_string());
+    }
+
+    // `?replay=<session>/<file>` plays back a capture from the capture directory;
+    // `--replay` makes it the default for connections that don't pick a stream
+    let replay = match query.get("replay") {
+        Some(relative) => match paths::resolve_within(state.lock().unwrap().capture().root(), relative) {
+            Some(path) => Some(path),
+            None => return Err(warp::reject::not_found()),
+        },
+        None if !query.contains_key("stream") => state.lock().unwrap().replay_file.clone().map(PathBuf::from),
+        None => None,
+    };
+
+    if let Some(path) = replay {
+        return Ok(ws.on_upgrade(|socket| async move {
+            ws_replay(socket, path).await;
+        }).into_response());
+    }
+
+    let options = match ConnectionOptions::from_query(&query) {
+        Ok(options) => options,
+        Err(e) => {
+            warn!("Rejecting WebSocket connection: {}", e);
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({ "error": e })),
+                warp::http::StatusCode::BAD_REQUEST,
+            ).into_response());
+        }
+    };
+    // Subscribing would create the stream on the hub, so unknown names are refused up front
+    if options.live && !topics::is_topic(&state.lock().unwrap(), &options.stream) {
+        warn!("Rejecting WebSocket connection to unknown topic '{}'", options.stream);
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&json!({ "error": format!("Unknown topic '{}'", options.stream) })),
+            warp::http::StatusCode::NOT_FOUND,
+        ).into_response());
+    }
     Ok(ws.on_upgrade(|socket| async {
-        ws_connect(socket, state).await;
-    }))
+        ws_connect(socket, options, state).await;
+    }).into_response())
 }
 
-pub type WSMessage = RecordBatch;
+/// Per-connection choices a client makes through the `/ws` query string
+#[derive(Debug, Clone)]
+pub struct ConnectionOptions {
+    pub stream: String,     // hub stream to follow
+    pub backfill: bool,     // start with the stream's recent history
+    pub live: bool,         // follow the stream; false = only answer queries
+    pub filter: Option<Filter>, // only rows matching this expression are sent
+    pub encoding: Encoding,     // how batches are serialized
+    pub layout: Layout,         // row- or column-wise, for the non-Arrow encodings
+    pub overflow: Option<OverflowPolicy>,   // send queue policy; the stream's parameters otherwise
+    pub queue_messages: Option<usize>,
+    pub queue_bytes: Option<usize>,
+}
 
-pub async fn ws_connect(ws: WebSocket, state: StateHandle) {
-    info!("New WebSocket connection");
+impl ConnectionOptions {
+    pub fn from_query(query: &HashMap<String, String>) -> std::result::Result<Self, String> {
+        Ok(Self {
+            stream: query
+                .get("stream")
+                .cloned()
+                .unwrap_or_else(|| SYNTHETIC_STREAM.to_string()),
+            backfill: query
+                .get("backfill")
+                .is_some_and(|v| is_true(v)),
+            live: query.get("live").is_none_or(|v| is_true(v)),
+            filter: query.get("filter").map(|f| Filter::parse(f)).transpose()?,
+            encoding: query.get("encoding").map(|e| Encoding::parse(e)).transpose()?.unwrap_or(Encoding::Arrow),
+            layout: query.get("layout").map(|l| Layout::parse(l)).transpose()?.unwrap_or(Layout::Rows),
+            overflow: query.get("overflow").map(|p| OverflowPolicy::parse(p)).transpose()?,
+            queue_messages: query.get("queue_messages").map(|n| parse_count("queue_messages", n)).transpose()?,
+            queue_bytes: query.get("queue_bytes").map(|n| parse_count("queue_bytes", n)).transpose()?,
+        })
+    }
+}
+
+fn is_true(value: &str) -> bool {
+    value.is_empty() || value == "true" || value == "1"
+}
+
+fn parse_count(name: &str, value: &str) -> std::result::Result<usize, String> {
+    value.parse().map_err(|_| format!("Invalid {} '{}'", name, value))
+}
+
+/// Send a captured session to a new client, frame for frame, with its original timing
+pub async fn ws_replay(ws: WebSocket, path: PathBuf) {
+    info!("New WebSocket connection replaying {}", path.display());
 
     let (mut client_ws_sender, _client_ws_rcv) = ws.split();
+    match capture::replay_capture(&path, &mut client_ws_sender).await {
+        Ok(frames) => info!("Replay of {} finished after {} frames", path.display(), frames),
+        Err(e) => warn!("Replay of {} ended: {}", path.display(), e),
+    }
+    let _ = client_ws_sender.close().await;
+}
+
+/// What a connection's sender task puts on the wire
+#[derive(Debug)]
+pub enum WSMessage {
+    Batch(RecordBatch),                 // binary Arrow IPC frame
+    Text(String),                       // JSON control message
+    Topic(FrameHeader, RecordBatch),    // batch of one `/mux` topic, sent behind its header
+    Binary(Vec<u8>),                    // binary control frame, e.g. a `/mux` lagged notice
+}
+
+/// A frame on its way through a connection's simulated link
+struct InFlight {
+    entered: Instant,
+    arrival: Instant,
+    message: Message,
+    batch: bool,        // a batch from the stream, rather than a control message
+}
+
+/// Requests a client may send as text frames
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientRequest {
+    Query {
+        #[serde(default)]
+        id: u64,
+        #[serde(flatten)]
+        query: RangeQuery,
+    },
+    Sql {
+        #[serde(default)]
+        id: u64,
+        sql: String,
+    },
+    SetFilter {
+        filter: Option<String>,     // None clears the filter
+    },
+    /// Stamps of a received batch sent back for latency measurement; not answered
+    Echo {
+        sequence: u64,
+        sent_at_us: u64,
+        #[serde(default)]
+        generated_at_us: Option<u64>,
+        received_at_us: u64,
+    },
+}
+
+/// Run a history or SQL query off the async runtime, returning the request id with the result
+async fn answer_query(state: &StateHandle, default_stream: &str, request: ClientRequest) -> (u64, std::result::Result<Vec<RecordBatch>, String>) {
+    let (hub, recordings_dir) = {
+        let state_guard = state.lock().unwrap();
+        (state_guard.hub(), state_guard.recorder().directory())
+    };
+    let default_stream = default_stream.to_string();
+
+    let (id, task) = match request {
+        ClientRequest::Query { id, query } => (id, tokio::task::spawn_blocking(move || {
+            let stream = query.stream.clone().unwrap_or(default_stream);
+            history::run_range_query(&hub, Path::new(&recordings_dir), &stream, &query)
+        })),
+        ClientRequest::Sql { id, sql } => (id, tokio::spawn(async move {
+            sql::execute(hub, Path::new(&recordings_dir), &sql).await
+        })),
+        ClientRequest::SetFilter { .. } | ClientRequest::Echo { .. } => return (0, Err("Not a query".to_string())),
+    };
+    (id, task.await.unwrap_or_else(|e| Err(format!("Query panicked: {}", e))))
+}
+
+pub async fn ws_connect(ws: WebSocket, options: ConnectionOptions, state: StateHandle) {
+    let connection_id = next_connection_id();
+    let stream = options.stream;
+    info!("New WebSocket connection {} on stream '{}' ({} encoding)", connection_id, stream, options.encoding.name());
+
+    let (mut client_ws_sender, mut client_ws_rcv) = ws.split();
 
     let state_clone = state.clone();
-    let start_time = std::time::Instant::now();
+    let hub = state.lock().unwrap().hub();
+    let (backfill, feed) = match (options.live, options.backfill) {
+        (false, _) => (Vec::new(), None),
+        (true, true) => {
+            let (backfill, feed) = hub.subscribe_with_backfill(&stream);
+            (backfill, Some(feed))
+        },
+        (true, false) => (Vec::new(), Some(hub.subscribe(&stream))),
+    };
+    let frame_capture = state.lock().unwrap().capture();
+    let encoding_stats = state.lock().unwrap().encoding_stats();
+    let latency_stats = state.lock().unwrap().latency_stats();
+    latency_stats.open_connection(connection_id, &stream);
+    let runs = state.lock().unwrap().runs();
+    let mut run_events = runs.subscribe();
+    let sender_latency_stats = latency_stats.clone();
+    let (encoding, layout) = (options.encoding, options.layout);
+    let filter = Arc::new(Mutex::new(options.filter));
 
-    let (tx, mut rx) = tokio::sync::mpsc::channel::<WSMessage>(512);
+    // The client's send queue, sized and with the overflow policy of its stream unless it asked otherwise
+    let mut limits = {
+        let state_guard = state.lock().unwrap();
+        QueueLimits::from_parameters(&state_guard.topic_parameters(&stream).unwrap_or_else(|| state_guard.get_parameters()))
+    };
+    limits.policy = options.overflow.unwrap_or(limits.policy);
+    limits.max_messages = options.queue_messages.map_or(limits.max_messages, |n| n.max(1));
+    limits.max_bytes = options.queue_bytes.unwrap_or(limits.max_bytes);
+    let send_queues = state.lock().unwrap().send_queues();
+    let queue = send_queues.open_connection(connection_id, &stream, limits);
+    let sender_queue = queue.clone();
+    let params_topic = stream.clone();
 
     tokio::spawn(async move {
         let mut error_count = 0;
         let mut success_count = 0;
         let mut last_log_time = Instant::now();
-        
-        while let Some(message) = rx.recv().await {
-            let schema = message.schema();
-        
-            // Create buffer to write IPC format
-            let mut buffer = Vec::new();
-            
-            // Try to serialize the message to Arrow IPC format
-            let serialization_result = (|| -> std::result::Result<(), String> {
-                let mut writer = match FileWriter::try_new(&mut buffer, &schema) {
-                    Ok(w) => w,
-                    Err(e) => return Err(format!("Failed to create IPC writer: {}", e)),
-                };
-                
-                if let Err(e) = writer.write(&message) {
-                    return Err(format!("Failed to write record batch: {}", e));
-                }
-                
-                if let Err(e) = writer.finish() {
-                    return Err(format!("Failed to finish IPC writer: {}", e));
-                }
-                
-                Ok(())
-            })();
-            
-            // Handle serialization errors
-            if let Err(e) = serialization_result {
-                error!("Error serializing message: {}", e);
-                error_count += 1;
-                continue;
-            }
-            
-            // Simulate network latency if configured
-            let params = state_clone.lock().unwrap().get_parameters();
-            if params.websocket.simulate_latency && params.websocket.latency_ms > 0 {
-                tokio::time::sleep(std::time::Duration::from_millis(params.websocket.latency_ms)).await;
-            }
-            
-            // Simulate random disconnects if configured
-            if params.websocket.simulate_disconnects {
-                let disconnect_probability = params.websocket.disconnection_probability;
-                if disconnect_probability > 0.0 {
-                    let mut rng = rand::thread_rng();
-                    if rand::Rng::gen_range(&mut rng, 0.0..1.0) < disconnect_probability {
-                        warn!("Simulating random disconnect");
-                        break;
+        let mut sequence = 0u64;
+        let mut reported_lagged = 0u64;
+
+        // Frames reach the client through a simulated link, per topic for generator topics
+        let impairment = |state: &StateHandle| {
+            let state_guard = state.lock().unwrap();
+            state_guard.topic_parameters(&params_topic).unwrap_or_else(|| state_guard.get_parameters()).websocket.impairment
+        };
+        let mut link = Link::new(&impairment(&state_clone));
+        let mut in_flight: VecDeque<InFlight> = VecDeque::new();
+
+        loop {
+            link.counters.in_flight = in_flight.len();
+            sender_queue.set_link_counters(&link.counters);
+
+            let now = Instant::now();
+            let never = now + Duration::from_secs(3600);
+            let outage_end = link.outage_end(now);
+            let arrival = in_flight.front().map(|frame| outage_end.map_or(frame.arrival, |end| end.max(frame.arrival)));
+            let accept_at = (in_flight.len() < MAX_IN_FLIGHT).then(|| link.ready_at(now));
+            let disconnect_at = link.disconnect_at();
+
+            tokio::select! {
+                biased;
+                _ = tokio::time::sleep_until(disconnect_at.unwrap_or(never).into()), if disconnect_at.is_some() => {
+                    warn!("Simulating a disconnect of connection {}", connection_id);
+                    link.counters.disconnected = true;
+                    break;
+                },
+                _ = tokio::time::sleep_until(arrival.unwrap_or(never).into()), if arrival.is_some() => {
+                    // An outage may have started since; the frame then arrives when it ends
+                    if link.outage_end(Instant::now()).is_some() {
+                        continue;
                     }
-                }
-            }
-            
-            // Send the message over WebSocket
-            let result = client_ws_sender.send(Message::binary(buffer)).await;
-            
-            match result {
-                Ok(_) => {
-                    success_count += 1;
-                    // Log stats every few seconds
-                    if last_log_time.elapsed() > Duration::from_secs(5) {
-                        info!("WebSocket stats - Success: {}, Errors: {}", success_count, error_count);
-                        last_log_time = Instant::now();
+                    let frame = in_flight.pop_front().expect("a frame is in flight");
+                    link.record_arrival(frame.entered.elapsed());
+                    let frame_len = frame.message.as_bytes().len();
+                    frame_capture.record(connection_id, &frame.message);
+                    match client_ws_sender.send(frame.message).await {
+                        Ok(_) if frame.batch => {
+                            success_count += 1;
+                            runs.record_sent(&params_topic, connection_id, frame_len);
+                            // Log stats every few seconds
+                            if last_log_time.elapsed() > Duration::from_secs(5) {
+                                info!("WebSocket stats - Success: {}, Errors: {}", success_count, error_count);
+                                last_log_time = Instant::now();
+                            }
+                        },
+                        Ok(_) => {},
+                        Err(e) => {
+                            error!("Error sending message: {}", e);
+                            if frame.batch {
+                                error_count += 1;
+                                runs.record_send_error(&params_topic);
+                            }
+                            break;
+                        }
+                    }
+                },
+                message = async {
+                    tokio::time::sleep_until(accept_at.unwrap_or(never).into()).await;
+                    sender_queue.pop().await
+                }, if accept_at.is_some() => {
+                    let Some(message) = message else { break };
+                    link.set_params(&impairment(&state_clone));
+
+                    let mut frames = Vec::new();
+                    // Reported by the sender so the notice doesn't queue behind the batches
+                    let lagged_total = sender_queue.skipped_batches();
+                    if lagged_total > reported_lagged {
+                        let notice = json!({ "type": "lagged", "skipped": lagged_total - reported_lagged, "total": lagged_total });
+                        frames.push((Message::text(notice.to_string()), false));
+                        reported_lagged = lagged_total;
+                    }
+                    match message {
+                        WSMessage::Batch(batch) | WSMessage::Topic(_, batch) => {
+                            // Stamp the batch for latency measurement, see `latency`
+                            let batch = tag_batch(batch, SEQUENCE_METADATA_KEY, &sequence.to_string());
+                            let batch = tag_batch(batch, SENT_AT_METADATA_KEY, &latency::unix_time_us().to_string());
+                            sequence += 1;
+                            let batch = tag_batch(batch, CONNECTION_METADATA_KEY, &connection_id.to_string());
+
+                            // Serialize the message in the connection's encoding
+                            match encoding::encode_timed(&encoding_stats, &batch, encoding, layout) {
+                                Ok(Encoded::Binary(buffer)) => frames.push((Message::binary(buffer), true)),
+                                Ok(Encoded::Text(text)) => frames.push((Message::text(text), true)),
+                                Err(e) => {
+                                    error!("Error serializing message: {}", e);
+                                    error_count += 1;
+                                }
+                            }
+                        },
+                        WSMessage::Text(text) => frames.push((Message::text(text), false)),
+                        WSMessage::Binary(bytes) => frames.push((Message::binary(bytes), false)),
+                    }
+
+                    let now = Instant::now();
+                    for (message, batch) in frames {
+                        let arrival = link.send(now, message.as_bytes().len());
+                        in_flight.push_back(InFlight { entered: now, arrival, message, batch });
                     }
                 },
-                Err(e) => {
-                    error!("Error sending message: {}", e);
-                    error_count += 1;
-                    break;
-                }
             }
         }
-        
+
+        link.counters.in_flight = in_flight.len();
+        sender_queue.set_link_counters(&link.counters);
+        if sender_queue.overflowed() {
+            warn!("Connection {} could not keep up, closing it", connection_id);
+            let _ = client_ws_sender.send(Message::close_with(1008u16, "send queue full")).await;
+        }
+        send_queues.close_connection(connection_id);
+        frame_capture.close_connection(connection_id);
+        sender_latency_stats.close_connection(connection_id);
         info!("WebSocket sender task ending. Total messages sent: {}, errors: {}", 
               success_count, error_count);
     });
 
+    // Answer history and SQL queries in-band, between the live batches
+    let query_queue = queue.clone();
+    let query_stream = stream.clone();
+    let query_filter = filter.clone();
     tokio::spawn(async move {
-        let mut last_send_time = 0;
-        let mut data_generator = DataGenerator::new();
-        let mut consecutive_errors = 0;
-        
         loop {
-            let loop_start = std::time::Instant::now();
-            let current_time = start_time.elapsed().as_millis() as u64;
-            
-            // Get current parameters for this iteration
-            let params = {
-                let state_guard = state.lock().unwrap();
-                state_guard.get_parameters()
+            // Stop reading once the sender has finished, so the connection is dropped
+            let message = tokio::select! {
+                message = client_ws_rcv.next() => message,
+                _ = query_queue.closed() => break,
             };
-            
-            // Update the data generator with current parameters
-            data_generator.parameters = Some(params.clone());
-            
-            // Calculate send interval based on current parameters
-            let data_rate = params.data_volume.data_rate_hz;
-            let send_interval = (1.0 / data_rate) * 1000.0;
-            
-            // Log current data rate periodically
-            if current_time % 5000 < 10 {  // Log approximately every 5 seconds
-                info!("Current data rate: {} Hz, Send interval: {} ms", data_rate, send_interval);
-            }
-            
-            // Check for test duration limits
-            if let Some(duration_seconds) = params.get_test_duration_seconds() {
-                if current_time >= duration_seconds * 1000 {
-                    info!("Test duration reached, stopping data generation");
-                    break;
+            let Some(Ok(message)) = message else { break };
+            let Ok(text) = message.to_str() else { continue };
+            let reply = match serde_json::from_str::<ClientRequest>(text) {
+                Ok(ClientRequest::Echo { sequence, sent_at_us, generated_at_us, received_at_us }) => {
+                    latency_stats.record(connection_id, Echo { sequence, sent_at_us, generated_at_us, received_at_us });
+                    continue;
+                },
+                Ok(ClientRequest::SetFilter { filter }) => {
+                    match filter.as_deref().map(Filter::parse).transpose() {
+                        Ok(parsed) => {
+                            info!("Connection {} filter set to {:?}", connection_id, filter);
+                            *query_filter.lock().unwrap() = parsed;
+                            json!({ "type": "filter_set", "filter": filter })
+                        },
+                        Err(e) => json!({ "type": "filter_error", "error": e }),
+                    }
+                },
+                Ok(request) => {
+                    let (id, result) = answer_query(&state, &query_stream, request).await;
+                    match result {
+                        Ok(batches) => {
+                            let rows: usize = batches.iter().map(|b| b.num_rows()).sum();
+                            let count = batches.len();
+                            for batch in batches {
+                                let batch = tag_batch(batch, QUERY_ID_METADATA_KEY, &id.to_string());
+                                if query_queue.push_waiting(WSMessage::Batch(batch)).await.is_err() {
+                                    return;
+                                }
+                            }
+                            json!({ "type": "query_complete", "id": id, "batches": count, "rows": rows })
+                        },
+                        Err(e) => json!({ "type": "query_error", "id": id, "error": e }),
+                    }
+                },
+                Err(e) => {
+                    warn!("Ignoring client message on connection {}: {}", connection_id, e);
+                    json!({ "type": "error", "error": e.to_string() })
                 }
+            };
+            if query_queue.push(WSMessage::Text(reply.to_string())).await.is_err() {
+                return;
             }
-            
-            if current_time - last_send_time >= send_interval as u64 {
-                last_send_time = current_time;
-                
-                // Generate data with current batch size using updated generator
-                let message = data_generator.generate_record_batch(params.websocket.batch_size);
-                
-                // Handle the Result returned by generate_record_batch
-                let message = match message {
-                    Ok(batch) => {
-                        consecutive_errors = 0;
-                        batch
-                    },
-                    Err(e) => {
-                        error!("Error generating record batch: {}", e);
-                        consecutive_errors += 1;
-                        
-                        if consecutive_errors >= 5 {
-                            error!("Too many consecutive errors generating data, stopping generator");
-                            break;
+        }
+        // The client has gone
+        query_queue.close();
+    });
+
+    // Tell the client when a benchmark run on its stream starts and ends
+    let events_queue = queue.clone();
+    let events_stream = stream.clone();
+    tokio::spawn(async move {
+        loop {
+            let event = tokio::select! {
+                event = run_events.recv() => event,
+                _ = events_queue.closed() => break,
+            };
+            match event {
+                Ok(event) if event.topic == events_stream => {
+                    if events_queue.push(WSMessage::Text(event.message)).await.is_err() {
+                        break;
+                    }
+                },
+                Ok(_) | Err(RecvError::Lagged(_)) => {},
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+
+    let Some(mut feed) = feed else { return };
+
+    // Relay batches from the hub into this client's send queue, history first
+    tokio::spawn(async move {
+        let mut lagged_batches = 0u64;
+        let mut filtered_rows = 0u64;
+        let mut last_filter_error: Option<String> = None;
+
+        // Apply the subscription filter; batches without a matching row are not sent at all
+        let mut apply_filter = |batch: RecordBatch| -> std::result::Result<Option<RecordBatch>, String> {
+            let Some(predicate) = filter.lock().unwrap().clone() else {
+                return Ok(Some(batch));
+            };
+            let matching = predicate.apply(&batch)?;
+            filtered_rows += (batch.num_rows() - matching.num_rows()) as u64;
+            Ok((matching.num_rows() > 0).then_some(matching))
+        };
+
+        if !backfill.is_empty() {
+            info!("Backfilling {} batches on stream '{}'", backfill.len(), stream);
+        }
+        let backfill = backfill.into_iter().map(|b| (true, Ok(b)));
+        let live = futures::stream::unfold(&mut feed, |feed| async move {
+            Some((feed.recv().await, feed))
+        });
+        let mut messages = futures::stream::iter(backfill).chain(live.map(|m| (false, m))).boxed();
+
+        while let Some((is_backfill, message)) = messages.next().await {
+            match message {
+                Ok(message) => {
+                    let message = match apply_filter(message) {
+                        Ok(Some(matching)) => matching,
+                        Ok(None) => continue,
+                        Err(e) => {
+                            // Report each distinct error once rather than for every batch
+                            if last_filter_error.as_ref() != Some(&e) {
+                                warn!("Filter failed on stream '{}': {}", stream, e);
+                                let reply = json!({ "type": "filter_error", "error": e });
+                                let _ = queue.push(WSMessage::Text(reply.to_string())).await;
+                                last_filter_error = Some(e);
+                            }
+                            continue;
                         }
-                        
-                        // Skip this iteration if there was an error
-                        continue;
+                    };
+                    let message = if is_backfill {
+                        tag_batch(message, BACKFILL_METADATA_KEY, "true")
+                    } else {
+                        message
+                    };
+                    if queue.push(WSMessage::Batch(message)).await.is_err() {
+                        break;
+                    }
+                },
+                Err(RecvError::Lagged(skipped)) => {
+                    lagged_batches += skipped;
+                    warn!("Client on stream '{}' fell behind, skipped {} batches ({} total)",
+                          stream, skipped, lagged_batches);
+                    queue.record_lagged(skipped);
+                },
+                Err(RecvError::Closed) => break,
+            }
+        }
+
+        info!("Stream relay task ending for '{}', {} rows filtered out", stream, filtered_rows);
+    });
+}
+
+/// Drives the shared data generator of a generator topic for the lifetime of the server.
+///
+/// The generator runs while at least one client is subscribed to `topic`, or
+/// while a run started over HTTP is active on it. With a test duration, the
+/// first client to arrive starts a run (see [`runs`](crate::runs)); once it
+/// ends, generation stops until everyone has left. Only [`SYNTHETIC_STREAM`]
+/// advances the server clock reported by `/status`.
+pub async fn run_generator(state: StateHandle, topic: String) {
+    let hub = state.lock().unwrap().hub();
+    let runs = state.lock().unwrap().runs();
+    let rates = state.lock().unwrap().generator_rates();
+    let Some(initial) = state.lock().unwrap().topic_parameters(&topic) else {
+        error!("Topic '{}' is not a generator topic", topic);
+        return;
+    };
+    let mut data_generator = DataGenerator::with_parameters(initial);
+    let mut consecutive_errors = 0;
+    let mut sampler: Option<Ticker> = None;
+    let mut sender: Option<Ticker> = None;     // none when every sample is sent right away
+    let mut buffer = MessageBuffer::new();
+    let mut meter = RateMeter::default();
+    let mut windows = 0u64;
+    let mut clock_ms = 0.0;     // of the synthetic stream, not yet added to `t_ms`
+    let mut duration_reached = false;
+
+    loop {
+        let listening = hub.receiver_count(&topic) > 0;
+        let mut run = runs.active(&topic);
+
+        // A run the clients started ends when they have all left; one started over HTTP keeps going
+        if !listening && run.is_some_and(|run| run.trigger == RunTrigger::Clients) {
+            runs.finish(&topic, "clients left");
+            run = None;
+        }
+        if !listening && run.is_none() {
+            if sampler.take().is_some() {
+                info!("No clients left on '{}', pausing data generation", topic);
+                meter.rate.running = false;
+                rates.update(&topic, &meter.rate);
+            }
+            sender = None;
+            buffer.clear();
+            duration_reached = false;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            continue;
+        }
+
+        // Get current parameters for this iteration
+        let Some(params) = state.lock().unwrap().topic_parameters(&topic) else {
+            break;
+        };
+
+        // Update the data generator with current parameters
+        data_generator.parameters = Some(params.clone());
+        let data_rate = params.data_volume.data_rate_hz.max(0.001);
+        let message_frequency = params.websocket.message_frequency;
+        let catch_up = params.data_volume.catch_up;
+
+        // A test duration is a run, started by the first client
+        if run.is_none() && !duration_reached && params.get_test_duration_seconds().is_some() {
+            let run_params = RunParams { topic: topic.clone(), ..Default::default() };
+            match runs.start(run_params, RunTrigger::Clients, params.clone()) {
+                Ok(_) => run = runs.active(&topic),
+                Err(e) => warn!("Failed to start a run on '{}': {}", topic, e),
+            }
+        }
+        if let Some(active) = run
+            && active.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            info!("Test duration reached, stopping data generation");
+            runs.finish(&topic, "duration elapsed");
+            duration_reached = true;
+            continue;
+        }
+        if run.is_none() && duration_reached {
+            if sampler.take().is_some() {
+                meter.rate.running = false;
+                rates.update(&topic, &meter.rate);
+            }
+            sender = None;
+            buffer.clear();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            continue;
+        }
+
+        // Sampling and sending run on tickers of their own
+        let sampler = sampler.get_or_insert_with(|| {
+            meter.restart();
+            Ticker::new(data_rate)
+        });
+        sampler.set_rate(data_rate);
+        if message_frequency > 0.0 {
+            sender.get_or_insert_with(|| Ticker::new(message_frequency)).set_rate(message_frequency);
+        } else {
+            sender = None;
+        }
+        let wake = sender.as_ref().map_or(sampler.next_deadline(), |s| s.next_deadline().min(sampler.next_deadline()));
+        schedule::sleep_until(wake).await;
+        let now = Instant::now();
+
+        // Sample: one row per tick, stamped with the time it was due
+        let samples = sampler.due(now);
+        let interval_ms = 1000.0 / data_rate;
+        let (rows, skipped) = samples.samples(catch_up);
+        let mut messages = Vec::new();
+        if rows > 0 {
+            let first_ms = latency::unix_time_us() as f64 / 1000.0 - samples.lateness.as_secs_f64() * 1000.0
+                + (skipped - samples.dropped) as f64 * interval_ms;
+            match data_generator.generate_samples(first_ms, interval_ms, rows as usize) {
+                Ok(batch) => {
+                    consecutive_errors = 0;
+                    buffer.push(batch, &mut messages);
+                },
+                Err(e) => {
+                    error!("Error generating record batch: {}", e);
+                    runs.record_generation_error(&topic);
+                    consecutive_errors += 1;
+                    if consecutive_errors >= 5 {
+                        error!("Too many consecutive errors generating data, stopping generator");
+                        break;
                     }
-                };
-                
-                {
-                    let mut state_guard = state.lock().unwrap();
-                    state_guard.tick(send_interval as u64);
-                    state_guard.update_last_t();
-                }
-                
-                if let Err(e) = tx.send(message).await {
-                    error!("Error sending to channel: {}", e);
-                    break;
                 }
             }
-            
-            let elapsed = loop_start.elapsed().as_millis() as u64;
-            let sleep_time = if elapsed < send_interval as u64 {
-                send_interval as u64 - elapsed
-            } else {
-                // If we're running behind schedule, log it but don't sleep
-                if elapsed > send_interval as u64 * 2 {
-                    warn!("Data generation falling behind - iteration took {}ms, interval is {}ms", 
-                           elapsed, send_interval as u64);
+
+            if topic == SYNTHETIC_STREAM {
+                // Whole milliseconds only, so rates above 1 kHz still move the clock
+                clock_ms += rows as f64 * interval_ms;
+                let whole = clock_ms.floor();
+                clock_ms -= whole;
+                let mut state_guard = state.lock().unwrap();
+                state_guard.tick(whole as u64);
+                state_guard.update_last_t();
+            }
+        }
+
+        // Send: full messages right away, the rest when the message ticker says so
+        messages.extend(buffer.take_full(params.websocket.batch_size, params.websocket.message_size));
+        let mut coalesced = 0;
+        let newest = (samples.on_time + samples.late).saturating_sub(1);
+        let mut lateness = samples.lateness.saturating_sub(Duration::from_secs_f64(newest as f64 * interval_ms / 1000.0));
+        match sender.as_mut() {
+            Some(sender) => {
+                let sends = sender.due(now);
+                let parts;
+                (parts, coalesced) = sends.messages(catch_up);
+                if parts > 0 {
+                    lateness = sends.lateness;
+                    messages.extend(buffer.take_all(parts));
                 }
-                0
-            };
-            
-            tokio::time::sleep(std::time::Duration::from_millis(sleep_time)).await;
+            },
+            None => messages.extend(buffer.take_all(1)),
         }
-        
-        info!("Data generation task ending");
-    });
+
+        if skipped + coalesced > 0 {
+            runs.record_catch_up(&topic, skipped, coalesced);
+        }
+        meter.record(rows, messages.len() as u64, skipped, coalesced);
+        for message in messages {
+            runs.record_batch(&topic, message.num_rows(), lateness.as_micros() as i64);
+            hub.publish(&topic, message);
+        }
+
+        meter.rate.running = true;
+        meter.rate.requested_hz = data_rate;
+        meter.rate.requested_message_hz = message_frequency.max(0.0);
+        meter.rate.catch_up = catch_up;
+        if meter.roll() {
+            rates.update(&topic, &meter.rate);
+            windows += 1;
+            if windows.is_multiple_of(5) {
+                info!(
+                    "Generator '{}': {:.1} of {} Hz sampled, {:.1} messages/s ({:?}), {} samples skipped, {} messages coalesced",
+                    topic, meter.rate.achieved_hz, data_rate, meter.rate.message_hz, catch_up,
+                    meter.rate.skipped_samples, meter.rate.coalesced_messages,
+                );
+            }
+        }
+        if rows > 1 {
+            // Give the connections a turn between bursts
+            tokio::task::yield_now().await;
+        }
+    }
+
+    info!("Data generation task for '{}' ending", topic);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::WSBridgeState;
+    use warp::Filter as _;
+
+    #[tokio::test]
+    async fn unknown_topics_are_refused_without_creating_a_stream() {
+        let state = WSBridgeState::new().into_handle();
+        let with_state = state.clone();
+        let route = warp::path("ws")
+            .and(warp::path::tail())
+            .and(warp::ws())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(warp::any().map(move || with_state.clone()))
+            .and_then(ws_handler);
+
+        assert!(warp::test::ws().path("/ws/nothing").handshake(route.clone()).await.is_err());
+        assert!(warp::test::ws().path("/ws?stream=nothing").handshake(route.clone()).await.is_err());
+        assert!(state.lock().unwrap().hub().stream_names().is_empty());
+
+        // Answering queries only subscribes to nothing, so any name is fine
+        assert!(warp::test::ws().path("/ws/nothing?live=false").handshake(route.clone()).await.is_ok());
+        assert!(warp::test::ws().path(&format!("/ws/{}", SYNTHETIC_STREAM)).handshake(route).await.is_ok());
+    }
 }
@@ -2,7 +2,7 @@ import { useEffect } from 'react';
 import { NumberInput, Select, Stack, Button, Group, Paper, Title, Divider, LoadingOverlay, Tooltip, Text } from '@mantine/core';
 import { IconInfoCircle } from '@tabler/icons-react';
 import { useForm } from '@mantine/form';
-import { DataVolumeParams, TestDuration } from '../../types/parameters';
+import { CatchUpPolicy, DataVolumeParams, TestDuration } from '../../types/parameters';
 
 interface DataVolumeSettingsProps {
   params: DataVolumeParams | null;
@@ -17,6 +17,7 @@ export function DataVolumeSettings({ params, loading, onUpdate }: DataVolumeSett
       float_precision: 1e8,
       data_rate_hz: 100,
       test_duration: TestDuration.Medium,
+      catch_up: CatchUpPolicy.Burst,
     },
     validate: {
       num_channels: (value: number) => (value < 1 ? 'Number of channels must be at least 1' : null),
@@ -120,6 +121,22 @@ export function DataVolumeSettings({ params, loading, onUpdate }: DataVolumeSett
             {...form.getInputProps('test_duration')}
           />
 
+          <Select
+            label={
+              <LabelWithTooltip 
+                label="Catch-up Policy" 
+                tooltip="What the generator does with ticks that are already overdue when it falls behind the data rate."
+              />
+            }
+            description="How to handle overdue ticks"
+            data={[
+              { value: CatchUpPolicy.Burst, label: 'Burst (a batch per tick)' },
+              { value: CatchUpPolicy.Skip, label: 'Skip (drop them)' },
+              { value: CatchUpPolicy.Coalesce, label: 'Coalesce (one bigger batch)' },
+            ]}
+            {...form.getInputProps('catch_up')}
+          />
+
           <Group justify="flex-end" mt="md">
             <Tooltip label="Save the current settings to the server" position="top">
               <Button type="submit" color="blue">
@@ -1,8 +1,8 @@
 import { useEffect } from 'react';
-import { NumberInput, Switch, Stack, Button, Group, Paper, Title, Divider, LoadingOverlay, Tooltip, Text } from '@mantine/core';
+import { NumberInput, Select, Switch, Stack, Button, Group, Paper, Title, Divider, LoadingOverlay, Tooltip, Text } from '@mantine/core';
 import { IconInfoCircle } from '@tabler/icons-react';
 import { useForm } from '@mantine/form';
-import { WebSocketParams } from '../../types/parameters';
+import { JitterDistribution, OverflowPolicy, WebSocketParams } from '../../types/parameters';
 
 interface WebSocketSettingsProps {
   params: WebSocketParams | null;
@@ -14,21 +14,37 @@ export function WebSocketSettings({ params, loading, onUpdate }: WebSocketSettin
   const form = useForm<WebSocketParams>({
     initialValues: {
       batch_size: 10,
-      message_size: 0, // auto
+      message_size: 0, // no limit
       message_frequency: 100,
-      simulate_disconnects: false,
-      simulate_latency: false,
-      latency_ms: 0,
-      disconnection_probability: 0,
+      overflow_policy: OverflowPolicy.Wait,
+      queue_max_messages: 512,
+      queue_max_bytes: 0, // no limit
+      impairment: {
+        enabled: false,
+        latency_ms: 0,
+        jitter_ms: 0,
+        jitter_distribution: JitterDistribution.Uniform,
+        bandwidth_bytes_per_sec: 0, // no limit
+        burst_bytes: 65536,
+        outage_interval_secs: 0, // no outages
+        outage_duration_ms: 2000,
+        disconnect_interval_secs: 0, // never
+      },
     },
     validate: {
-      batch_size: (value: number) => (value < 1 ? 'Batch size must be at least 1' : null),
+      batch_size: (value: number) => (value < 0 ? 'Batch size cannot be negative' : null),
       message_size: (value: number) => (value < 0 ? 'Message size cannot be negative' : null),
-      message_frequency: (value: number) => (value <= 0 ? 'Message frequency must be positive' : null),
-      latency_ms: (value: number) => (value < 0 ? 'Latency cannot be negative' : null),
-      disconnection_probability: (value: number) => (
-        value < 0 || value > 1 ? 'Probability must be between 0 and 1' : null
-      ),
+      message_frequency: (value: number) => (value < 0 ? 'Message frequency cannot be negative' : null),
+      impairment: {
+        latency_ms: (value: number) => (value < 0 ? 'Latency cannot be negative' : null),
+        jitter_ms: (value: number) => (value < 0 ? 'Jitter cannot be negative' : null),
+        bandwidth_bytes_per_sec: (value: number) => (value < 0 ? 'Bandwidth cannot be negative' : null),
+        outage_interval_secs: (value: number) => (value < 0 ? 'Outage interval cannot be negative' : null),
+        outage_duration_ms: (value: number) => (value < 0 ? 'Outage duration cannot be negative' : null),
+        disconnect_interval_secs: (value: number) => (value < 0 ? 'Disconnect interval cannot be negative' : null),
+      },
+      queue_max_messages: (value: number) => (value < 1 ? 'Queue must hold at least one message' : null),
+      queue_max_bytes: (value: number) => (value < 0 ? 'Queue size cannot be negative' : null),
     },
   });
 
@@ -72,11 +88,11 @@ export function WebSocketSettings({ params, loading, onUpdate }: WebSocketSettin
             label={
               <LabelWithTooltip 
                 label="Batch Size" 
-                tooltip="Defines how many data points are sent in a single WebSocket message. Higher values reduce overhead but increase message size."
+                tooltip="The most data points sent in a single WebSocket message; a full message is sent right away. Set to 0 for no limit."
               />
             }
-            description="Number of data points per message"
-            min={1}
+            description="Most data points per message (0 = no limit)"
+            min={0}
             max={10000}
             step={1}
             {...form.getInputProps('batch_size')}
@@ -86,10 +102,10 @@ export function WebSocketSettings({ params, loading, onUpdate }: WebSocketSettin
             label={
               <LabelWithTooltip 
                 label="Message Size (bytes)" 
-                tooltip="Controls the size of each WebSocket message. Set to 0 for automatic sizing based on data content."
+                tooltip="The most bytes in a WebSocket message; a message that would grow bigger is sent right away. Set to 0 for no limit."
               />
             }
-            description="Size of each message in bytes (0 = auto)"
+            description="Most bytes per message (0 = no limit)"
             min={0}
             step={100}
             {...form.getInputProps('message_size')}
@@ -99,68 +115,182 @@ export function WebSocketSettings({ params, loading, onUpdate }: WebSocketSettin
             label={
               <LabelWithTooltip 
                 label="Message Frequency (Hz)" 
-                tooltip="Determines how many messages are sent per second, which may differ from the actual data sampling rate."
+                tooltip="Determines how many messages are sent per second, independently of the data rate; points sampled in between are sent together."
               />
             }
-            description="How often messages are sent (may differ from data rate)"
-            min={0.1}
+            description="How often messages are sent (0 = with every sample)"
+            min={0}
             max={10000}
             step={1}
             {...form.getInputProps('message_frequency')}
           />
 
-          <Switch
+          <Select
             label={
               <LabelWithTooltip 
-                label="Simulate Network Disconnects" 
-                tooltip="When enabled, the system will randomly disconnect the WebSocket connection to test reconnection handling."
+                label="Overflow Policy" 
+                tooltip="What a client's send queue does with a message that does not fit, when the client reads more slowly than data is produced."
               />
             }
-            description="Randomly disconnect the WebSocket connection"
-            {...form.getInputProps('simulate_disconnects', { type: 'checkbox' })}
+            description="How to handle a full send queue"
+            data={[
+              { value: OverflowPolicy.Wait, label: 'Wait (stream skips what is missed)' },
+              { value: OverflowPolicy.DropOldest, label: 'Drop oldest' },
+              { value: OverflowPolicy.DropNewest, label: 'Drop newest' },
+              { value: OverflowPolicy.Coalesce, label: 'Coalesce (merge into queued)' },
+              { value: OverflowPolicy.Disconnect, label: 'Disconnect the client' },
+            ]}
+            {...form.getInputProps('overflow_policy')}
           />
 
-          {form.values.simulate_disconnects && (
-            <NumberInput
-              label={
-                <LabelWithTooltip 
-                  label="Disconnection Probability" 
-                  tooltip="The probability (between 0-1) that the connection will be dropped on any given message. Higher values cause more frequent disconnections."
-                />
-              }
-              description="Probability of disconnection per message (0-1)"
-              min={0}
-              max={1}
-              step={0.01}
-              {...form.getInputProps('disconnection_probability')}
-            />
-          )}
+          <NumberInput
+            label={
+              <LabelWithTooltip 
+                label="Queue Length (messages)" 
+                tooltip="The most messages waiting to be sent to a single client."
+              />
+            }
+            description="Most messages queued per client"
+            min={1}
+            step={16}
+            {...form.getInputProps('queue_max_messages')}
+          />
+
+          <NumberInput
+            label={
+              <LabelWithTooltip 
+                label="Queue Size (bytes)" 
+                tooltip="The most bytes of messages waiting to be sent to a single client. Set to 0 for no limit."
+              />
+            }
+            description="Most bytes queued per client (0 = no limit)"
+            min={0}
+            step={100000}
+            {...form.getInputProps('queue_max_bytes')}
+          />
 
           <Switch
             label={
               <LabelWithTooltip 
-                label="Simulate Network Latency" 
-                tooltip="When enabled, the system will add artificial delay to WebSocket messages to simulate network lag."
+                label="Simulate a Poor Network" 
+                tooltip="When enabled, messages reach clients through a simulated link with latency, jitter, limited bandwidth, outages and disconnects."
               />
             }
-            description="Add artificial delay to messages"
-            {...form.getInputProps('simulate_latency', { type: 'checkbox' })}
+            description="Send messages through a simulated network link"
+            {...form.getInputProps('impairment.enabled', { type: 'checkbox' })}
           />
 
-          {form.values.simulate_latency && (
-            <NumberInput
-              label={
-                <LabelWithTooltip 
-                  label="Latency (ms)" 
-                  tooltip="The amount of artificial delay in milliseconds to add to each message, simulating network latency conditions."
-                />
-              }
-              description="Artificial latency to add to messages"
-              min={0}
-              max={10000}
-              step={10}
-              {...form.getInputProps('latency_ms')}
-            />
+          {form.values.impairment.enabled && (
+            <>
+              <NumberInput
+                label={
+                  <LabelWithTooltip 
+                    label="Latency (ms)" 
+                    tooltip="One-way delay of every message. Messages keep being sent while earlier ones are on their way, so latency does not lower throughput."
+                  />
+                }
+                description="Delay of every message"
+                min={0}
+                max={30000}
+                step={10}
+                {...form.getInputProps('impairment.latency_ms')}
+              />
+
+              <NumberInput
+                label={
+                  <LabelWithTooltip 
+                    label="Jitter (ms)" 
+                    tooltip="Random variation of the latency of each message. Messages are never reordered, so a late one holds back those behind it."
+                  />
+                }
+                description="Variation of the latency"
+                min={0}
+                max={30000}
+                step={10}
+                {...form.getInputProps('impairment.jitter_ms')}
+              />
+
+              <Select
+                label={
+                  <LabelWithTooltip 
+                    label="Jitter Distribution" 
+                    tooltip="Uniform: up to the jitter either way. Normal: the jitter is the standard deviation. Pareto: only ever later, by the jitter on average, with occasional long delays."
+                  />
+                }
+                description="How the jitter is distributed"
+                data={[
+                  { value: JitterDistribution.Uniform, label: 'Uniform' },
+                  { value: JitterDistribution.Normal, label: 'Normal' },
+                  { value: JitterDistribution.Pareto, label: 'Pareto (long tail)' },
+                ]}
+                {...form.getInputProps('impairment.jitter_distribution')}
+              />
+
+              <NumberInput
+                label={
+                  <LabelWithTooltip 
+                    label="Bandwidth (bytes/s)" 
+                    tooltip="Most bytes per second the link carries; messages that cannot be sent yet wait in the client's send queue. Set to 0 for no limit."
+                  />
+                }
+                description="Bandwidth of the link (0 = no limit)"
+                min={0}
+                step={10000}
+                {...form.getInputProps('impairment.bandwidth_bytes_per_sec')}
+              />
+
+              <NumberInput
+                label={
+                  <LabelWithTooltip 
+                    label="Burst (bytes)" 
+                    tooltip="Bytes the link sends at once, after being idle, before the bandwidth limit applies."
+                  />
+                }
+                description="Bytes sent at once before the limit applies"
+                min={0}
+                step={1024}
+                {...form.getInputProps('impairment.burst_bytes')}
+              />
+
+              <NumberInput
+                label={
+                  <LabelWithTooltip 
+                    label="Outage Interval (s)" 
+                    tooltip="Average time between outages, which start at random. Nothing is delivered during an outage; what was held up arrives when it ends. Set to 0 for no outages."
+                  />
+                }
+                description="Mean time between outages (0 = none)"
+                min={0}
+                step={1}
+                {...form.getInputProps('impairment.outage_interval_secs')}
+              />
+
+              <NumberInput
+                label={
+                  <LabelWithTooltip 
+                    label="Outage Duration (ms)" 
+                    tooltip="How long each outage lasts."
+                  />
+                }
+                description="Length of an outage"
+                min={0}
+                step={100}
+                {...form.getInputProps('impairment.outage_duration_ms')}
+              />
+
+              <NumberInput
+                label={
+                  <LabelWithTooltip 
+                    label="Disconnect Interval (s)" 
+                    tooltip="Each connection is dropped this long after it opens (or after this setting changes), without a close frame, as when a link is lost. The timer restarts when the client reconnects. Set to 0 to never drop them."
+                  />
+                }
+                description="Drop connections after this long (0 = never)"
+                min={0}
+                step={10}
+                {...form.getInputProps('impairment.disconnect_interval_secs')}
+              />
+            </>
           )}
 
           <Group justify="flex-end" mt="md">
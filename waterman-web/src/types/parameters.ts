@@ -22,11 +22,44 @@ export enum TestDuration {
   OpenEnded = "OpenEnded" // Runs until stopped
 }
 
+export enum CatchUpPolicy {
+  Burst = "Burst",        // a batch per overdue tick
+  Skip = "Skip",          // drop overdue ticks
+  Coalesce = "Coalesce"   // one batch for all overdue ticks
+}
+
+export enum OverflowPolicy {
+  Wait = "Wait",              // wait for room in the send queue; the stream skips what the client misses
+  DropOldest = "DropOldest",  // drop queued batches, oldest first
+  DropNewest = "DropNewest",  // drop the batch that does not fit
+  Coalesce = "Coalesce",      // merge it into the newest queued batch
+  Disconnect = "Disconnect"   // close the connection
+}
+
+export enum JitterDistribution {
+  Uniform = "Uniform",    // up to jitter_ms either side of the latency
+  Normal = "Normal",      // jitter_ms is the standard deviation
+  Pareto = "Pareto"       // only later, jitter_ms on average, long tail
+}
+
+export interface ImpairmentParams {
+  enabled: boolean;
+  latency_ms: number;               // one way
+  jitter_ms: number;
+  jitter_distribution: JitterDistribution;
+  bandwidth_bytes_per_sec: number;  // 0 = no limit
+  burst_bytes: number;
+  outage_interval_secs: number;     // mean time between outages (0 = none)
+  outage_duration_ms: number;
+  disconnect_interval_secs: number; // 0 = never
+}
+
 export interface DataVolumeParams {
   num_channels: number;           // 1, 10, 100, 1000
   float_precision: number;        // 1e4, 1e8, 1e16, 1e32
   data_rate_hz: number;           // 50Hz, 500Hz, 50kHz
   test_duration: TestDuration;    // 10s, 100s, 1000s, 3600s, open-ended
+  catch_up: CatchUpPolicy;        // when the generator falls behind data_rate_hz
 }
 
 export interface DataCharacteristicsParams {
@@ -38,13 +71,13 @@ export interface DataCharacteristicsParams {
 }
 
 export interface WebSocketParams {
-  batch_size: number;              // points per message
-  message_size: number;            // bytes (0 = auto)
-  message_frequency: number;       // may differ from data_rate
-  simulate_disconnects: boolean;
-  simulate_latency: boolean;
-  latency_ms: number;              // when simulating latency
-  disconnection_probability: number; // 0.0-1.0
+  batch_size: number;              // most points per message (0 = no limit)
+  message_size: number;            // most bytes per message (0 = no limit)
+  message_frequency: number;       // independent of data_rate (0 = with every sample)
+  overflow_policy: OverflowPolicy; // when a client's send queue is full
+  queue_max_messages: number;      // most batches queued per client
+  queue_max_bytes: number;         // most bytes queued per client (0 = no limit)
+  impairment: ImpairmentParams;    // simulated network link
 }
 
 export interface Parameters {